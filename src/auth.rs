@@ -0,0 +1,330 @@
+//! Credentials for the `MESSAGE-INTEGRITY` mechanism.
+//!
+//! > The short-term credential mechanism assumes that, prior to the STUN
+//! > transaction, the client and server have used some other protocol to
+//! > exchange a credential in the form of a username and password.  This
+//! > credential is time-limited.
+//! >
+//! > [RFC 5389 -- 10.1. Short-Term Credential Mechanism]
+//!
+//! [RFC 5389 -- 10.1. Short-Term Credential Mechanism]: https://tools.ietf.org/html/rfc5389#section-10.1
+use std::collections::HashMap;
+use std::fmt;
+use std::sync::{Arc, RwLock};
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5389::attributes::{
+    ErrorCode, Fingerprint, MessageIntegrity, Realm, Username,
+};
+use stun_codec::rfc5389::errors;
+use stun_codec::{Attribute, Message};
+
+use message::{ErrorResponse, Request, Response};
+use Error;
+
+/// The error code of the responses to the requests of which `MESSAGE-INTEGRITY` does not match
+/// the password of the user specified by the `USERNAME` attribute.
+pub const INTEGRITY_CHECK_FAILURE_CODEPOINT: u16 = 431;
+
+/// This trait allows for looking up the passwords used for verifying `MESSAGE-INTEGRITY` attributes.
+pub trait CredentialProvider: Send + Sync + 'static {
+    /// Returns the password of the given user.
+    ///
+    /// If the user is unknown, this will return `None`.
+    fn password(&self, username: &str) -> Option<String>;
+}
+impl<T: CredentialProvider + ?Sized> CredentialProvider for Arc<T> {
+    fn password(&self, username: &str) -> Option<String> {
+        (**self).password(username)
+    }
+}
+
+/// A fixed set of username and password pairs.
+#[derive(Debug, Default, Clone)]
+pub struct Credentials {
+    passwords: HashMap<String, String>,
+}
+impl Credentials {
+    /// Makes a new empty `Credentials` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Adds a user to the set.
+    ///
+    /// If the user already exists, its password is overwritten.
+    pub fn add_user(&mut self, username: &str, password: &str) -> &mut Self {
+        self.passwords
+            .insert(username.to_owned(), password.to_owned());
+        self
+    }
+
+    /// Removes a user from the set.
+    pub fn remove_user(&mut self, username: &str) -> &mut Self {
+        self.passwords.remove(username);
+        self
+    }
+}
+impl CredentialProvider for Credentials {
+    fn password(&self, username: &str) -> Option<String> {
+        self.passwords.get(username).cloned()
+    }
+}
+
+/// Key for computing and verifying the `MESSAGE-INTEGRITY` attributes of a transaction.
+///
+/// Unlike `CredentialProvider`, which serves a server with the passwords of many users,
+/// this represents the credential of a single transaction made by a client
+/// (see `Client::call_with_integrity_key`).
+/// For example, an ICE agent uses the password advertised by the peer of each candidate pair
+/// for the connectivity checks sent to the peer.
+#[derive(Debug, Clone)]
+pub enum IntegrityKey {
+    /// The password of the short-term credential mechanism.
+    ShortTerm(String),
+
+    /// The username, realm and password of the long-term credential mechanism.
+    LongTerm {
+        /// The username of the credential.
+        username: Username,
+
+        /// The realm of the credential.
+        realm: Realm,
+
+        /// The password of the credential.
+        password: String,
+    },
+}
+impl IntegrityKey {
+    /// Computes the `MESSAGE-INTEGRITY` attribute of the given message with this key.
+    pub fn compute<A: Attribute>(&self, message: &Message<A>) -> Result<MessageIntegrity, Error> {
+        let integrity = match *self {
+            IntegrityKey::ShortTerm(ref password) => {
+                MessageIntegrity::new_short_term_credential(message, password)
+            }
+            IntegrityKey::LongTerm {
+                ref username,
+                ref realm,
+                ref password,
+            } => MessageIntegrity::new_long_term_credential(message, username, realm, password),
+        };
+        track!(integrity.map_err(Error::from))
+    }
+
+    /// Returns `true` if the given (decoded) `MESSAGE-INTEGRITY` attribute has been computed
+    /// with this key.
+    pub fn verify(&self, integrity: &MessageIntegrity) -> bool {
+        match *self {
+            IntegrityKey::ShortTerm(ref password) => {
+                integrity.check_short_term_credential(password).is_ok()
+            }
+            IntegrityKey::LongTerm {
+                ref username,
+                ref realm,
+                ref password,
+            } => integrity
+                .check_long_term_credential(username, realm, password)
+                .is_ok(),
+        }
+    }
+}
+
+/// A `CredentialProvider` of which the underlying provider can be replaced at runtime.
+///
+/// This is useful for rotating credentials without restarting a server.
+/// The clones of an instance share the same underlying provider,
+/// so the new provider takes effect for subsequent lookups made via any of the clones.
+///
+/// Each lookup is served entirely by the provider that was current when the lookup started,
+/// and a request is verified (and its response is signed) using the single password
+/// obtained by that lookup.
+/// Thus replacing the provider never makes an in-flight verification see a mix of old and new keys.
+#[derive(Clone)]
+pub struct SwappableCredentials {
+    current: Arc<RwLock<Arc<dyn CredentialProvider>>>,
+}
+impl SwappableCredentials {
+    /// Makes a new `SwappableCredentials` instance that initially uses the given provider.
+    pub fn new<P: CredentialProvider>(provider: P) -> Self {
+        let provider: Arc<dyn CredentialProvider> = Arc::new(provider);
+        SwappableCredentials {
+            current: Arc::new(RwLock::new(provider)),
+        }
+    }
+
+    /// Replaces the underlying provider with the given one.
+    pub fn swap<P: CredentialProvider>(&self, provider: P) {
+        let provider: Arc<dyn CredentialProvider> = Arc::new(provider);
+        *self.current.write().unwrap_or_else(|e| e.into_inner()) = provider;
+    }
+
+    /// Returns the provider currently being used.
+    pub fn current(&self) -> Arc<dyn CredentialProvider> {
+        self.current
+            .read()
+            .unwrap_or_else(|e| e.into_inner())
+            .clone()
+    }
+}
+impl CredentialProvider for SwappableCredentials {
+    fn password(&self, username: &str) -> Option<String> {
+        self.current().password(username)
+    }
+}
+impl fmt::Debug for SwappableCredentials {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "SwappableCredentials {{ .. }}")
+    }
+}
+
+/// Policy for handling requests that have more than one `MESSAGE-INTEGRITY` attribute.
+///
+/// Only the first `MESSAGE-INTEGRITY` attribute of a message covers the message
+/// (i.e., the header and the attributes preceding it).
+/// A crafted message may contain duplicate `MESSAGE-INTEGRITY` attributes to confuse
+/// the verification, so such messages are rejected by default.
+///
+/// Regardless of the policy, the attributes following the first `MESSAGE-INTEGRITY`
+/// (except `FINGERPRINT`) are removed from a verified request before it is handed to the handler:
+///
+/// > With the exception of the FINGERPRINT
+/// > attribute, which appears after MESSAGE-INTEGRITY, agents MUST ignore
+/// > all other attributes that follow MESSAGE-INTEGRITY.
+/// >
+/// > [RFC 5389 -- 15.4. MESSAGE-INTEGRITY]
+///
+/// [RFC 5389 -- 15.4. MESSAGE-INTEGRITY]: https://tools.ietf.org/html/rfc5389#section-15.4
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum DuplicateIntegrityPolicy {
+    /// Rejects the requests with a `400 Bad Request` response.
+    ///
+    /// This is the default.
+    #[default]
+    Reject,
+
+    /// Verifies the first `MESSAGE-INTEGRITY` attribute, and ignores the others.
+    VerifyFirst,
+}
+
+/// Verifies incoming requests and signs outgoing responses on behalf of a server.
+pub(crate) trait Authenticate<A>: Send + Sync {
+    /// Verifies the `MESSAGE-INTEGRITY` of the given request.
+    ///
+    /// If succeeded, the request (from which the attributes not covered by
+    /// `MESSAGE-INTEGRITY` have been removed) and the password used for the verification
+    /// will be returned.
+    fn authenticate(
+        &self,
+        request: Request<A>,
+        policy: DuplicateIntegrityPolicy,
+    ) -> Result<(Request<A>, String), ErrorResponse<A>>;
+
+    /// Adds a `MESSAGE-INTEGRITY` attribute computed with `password` to the given response.
+    fn sign(&self, response: Response<A>, password: &str) -> Response<A>;
+}
+
+/// An `Authenticate` implementation based on the short-term credential mechanism.
+///
+/// The password used for verifying a request is looked up by the `USERNAME` of the request,
+/// and the verification results in one of the following:
+///
+/// - `400 Bad Request` if `USERNAME` or `MESSAGE-INTEGRITY` is missing
+///   (or `MESSAGE-INTEGRITY` is duplicated and the policy is `DuplicateIntegrityPolicy::Reject`),
+/// - `401 Unauthorized` if the user is unknown to the credential provider,
+/// - `431 Integrity Check Failure` if `MESSAGE-INTEGRITY` does not match the password of the user.
+pub(crate) struct ShortTermAuthenticator<P> {
+    provider: P,
+}
+impl<P: CredentialProvider> ShortTermAuthenticator<P> {
+    pub fn new(provider: P) -> Self {
+        ShortTermAuthenticator { provider }
+    }
+}
+impl<A, P> Authenticate<A> for ShortTermAuthenticator<P>
+where
+    A: Attribute
+        + TryAsRef<Username>
+        + TryAsRef<MessageIntegrity>
+        + From<ErrorCode>
+        + From<MessageIntegrity>,
+    P: CredentialProvider,
+{
+    fn authenticate(
+        &self,
+        request: Request<A>,
+        policy: DuplicateIntegrityPolicy,
+    ) -> Result<(Request<A>, String), ErrorResponse<A>> {
+        let integrities = request
+            .attributes()
+            .filter(|a| a.get_type().as_u16() == MessageIntegrity::CODEPOINT)
+            .count();
+        if integrities > 1 && policy == DuplicateIntegrityPolicy::Reject {
+            return Err(ErrorResponse::new(&request, errors::BadRequest.into()));
+        }
+        let request = strip_attributes_after_integrity(request);
+
+        let integrity = request.get_attribute::<MessageIntegrity>();
+        let username = request.get_attribute::<Username>();
+        let (integrity, username) = match (integrity, username) {
+            (Some(i), Some(u)) => (i, u),
+            _ => return Err(ErrorResponse::new(&request, errors::BadRequest.into())),
+        };
+        let password = match self.provider.password(username.name()) {
+            None => return Err(ErrorResponse::new(&request, errors::Unauthorized.into())),
+            Some(password) => password,
+        };
+        if integrity.check_short_term_credential(&password).is_err() {
+            let error = ErrorCode::new(
+                INTEGRITY_CHECK_FAILURE_CODEPOINT,
+                "Integrity Check Failure".to_owned(),
+            ).expect("never fails");
+            return Err(ErrorResponse::new(&request, error));
+        }
+        Ok((request, password))
+    }
+
+    fn sign(&self, response: Response<A>, password: &str) -> Response<A> {
+        match response {
+            Ok(mut m) => {
+                if let Ok(integrity) =
+                    MessageIntegrity::new_short_term_credential(m.as_ref(), password)
+                {
+                    m.add_attribute(integrity.into());
+                }
+                Ok(m)
+            }
+            Err(mut m) => {
+                if let Ok(integrity) =
+                    MessageIntegrity::new_short_term_credential(m.as_ref(), password)
+                {
+                    m.add_attribute(integrity.into());
+                }
+                Err(m)
+            }
+        }
+    }
+}
+
+/// Removes the attributes following the first `MESSAGE-INTEGRITY` attribute
+/// except `FINGERPRINT`.
+fn strip_attributes_after_integrity<A: Attribute>(request: Request<A>) -> Request<A> {
+    let is_integrity = |a: &A| a.get_type().as_u16() == MessageIntegrity::CODEPOINT;
+    let is_fingerprint = |a: &A| a.get_type().as_u16() == Fingerprint::CODEPOINT;
+    let position = request.attributes().position(is_integrity);
+    let covered = match position {
+        None => return request,
+        Some(i) => i + 1,
+    };
+    if request.attributes().skip(covered).all(is_fingerprint) {
+        return request;
+    }
+
+    // Note that the unknown attributes are not copied,
+    // since they are not used once the request has been verified.
+    let mut stripped = Request::with_transaction_id(request.method(), request.transaction_id());
+    for (i, attribute) in request.attributes().enumerate() {
+        if i < covered || is_fingerprint(attribute) {
+            stripped.add_attribute(attribute.clone());
+        }
+    }
+    stripped
+}
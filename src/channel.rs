@@ -1,22 +1,130 @@
 //! Channel for sending and receiving STUN messages.
 use fibers::sync::oneshot;
 use fibers_timeout_queue::TimeoutQueue;
-use futures::{Async, Future, Poll};
+use fibers_transport::{PeerAddr, TcpTransport};
+use futures::{Async, Future, Poll, Sink, Stream};
 use std;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet, VecDeque};
 use std::fmt;
-use std::time::Duration;
-use stun_codec::{Attribute, BrokenMessage, Message, MessageClass, Method, TransactionId};
-use trackable::error::ErrorKindExt;
+use std::mem;
+use std::net::SocketAddr;
+use std::sync::Arc;
+use std::time::{Duration, SystemTime};
+use stun_codec::rfc5389::attributes::Fingerprint;
+use stun_codec::{
+    Attribute, AttributeType, BrokenMessage, DecodedMessage, Message, MessageClass, Method,
+    TransactionId,
+};
+use trackable::error::{BoxError, ErrorKindExt};
 
 use message::{
     ErrorResponse, Indication, InvalidMessage, MessageError, MessageErrorKind, MessageResult,
     Request, Response, SuccessResponse,
 };
-use transport::StunTransport;
+use transport::{RawMessageCapture, SplitTransporter, StunTransport};
 use {Error, Result};
 
 type Reply<A> = oneshot::Monitored<Response<A>, MessageError>;
+type Transaction<A> = (Method, SystemTime, Reply<A>);
+
+/// The number of the most recently sent indications that a channel remembers
+/// for identifying the responses to them.
+const RECENT_INDICATIONS: usize = 64;
+
+/// Policy for handling received responses that do not match any outstanding transaction.
+///
+/// Such responses include the ones sent by (buggy or malicious) peers in reply to indications.
+/// Regardless of the policy, they never affect the state of the outstanding transactions.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum UnexpectedResponsePolicy {
+    /// Reports the response to the caller of `Channel::poll_recv` as `RecvMessage::Invalid`
+    /// (its error kind is `MessageErrorKind::UnexpectedResponse`).
+    ///
+    /// This is the default policy.
+    #[default]
+    Report,
+
+    /// Silently discards the response.
+    Drop,
+}
+
+/// Policy for checking the `FINGERPRINT` attributes of received messages.
+///
+/// > The FINGERPRINT attribute MAY be present in all STUN messages.
+/// >
+/// > [RFC 5389 -- 15.5. FINGERPRINT]
+///
+/// Note that `MessageDecoder` of `stun_codec` always verifies a `FINGERPRINT` attribute
+/// that the attribute set `A` can decode (e.g., the one of `rfc5389::Attribute`),
+/// and a message having a mismatched value is reported as `RecvMessage::Invalid`
+/// (its error kind is `MessageErrorKind::MalformedAttribute`) regardless of the policy.
+/// If `A` cannot decode the attribute, it is kept as an unknown (and unverified) attribute.
+///
+/// Messages violating the policy are reported as `RecvMessage::Invalid`
+/// (its error kind is `MessageErrorKind::InvalidInput`).
+///
+/// [RFC 5389 -- 15.5. FINGERPRINT]: https://tools.ietf.org/html/rfc5389#section-15.5
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum FingerprintPolicy {
+    /// Neither requires nor checks `FINGERPRINT` attributes.
+    ///
+    /// Unverified `FINGERPRINT` attributes (i.e., the ones unknown to `A`) are accepted.
+    Ignore,
+
+    /// Does not require `FINGERPRINT` attributes, but rejects a message that has
+    /// a `FINGERPRINT` attribute which has not been verified (i.e., the one unknown to `A`).
+    ///
+    /// This is the default policy and the most interoperable one,
+    /// because it accepts messages from the peers that do not use `FINGERPRINT` attributes.
+    #[default]
+    VerifyIfPresent,
+
+    /// Rejects a message that has no verified `FINGERPRINT` attribute.
+    Require,
+}
+
+/// Shared reference to a [`TransactionObserver`] implementation.
+///
+/// [`TransactionObserver`]: ./trait.TransactionObserver.html
+pub type SharedObserver<P> = Arc<dyn TransactionObserver<P> + Send + Sync + 'static>;
+
+/// This trait allows for observing the lifecycle events of request/response transactions.
+///
+/// An observer can be registered to a channel via `Channel::set_observer` method.
+/// All methods have no-op default implementations,
+/// so an implementation only needs to override the events it is interested in.
+#[allow(unused_variables)]
+pub trait TransactionObserver<P> {
+    /// Called when a new transaction has been registered to the channel.
+    fn on_start(&self, peer: &P, transaction_id: TransactionId, method: Method) {}
+
+    /// Called when the request message of a transaction has been written by the transporter.
+    ///
+    /// Note that this may be issued long after `on_start`
+    /// (e.g., `StunUdpTransporter` spaces consecutive transactions to the same peer).
+    fn on_send(&self, peer: &P, transaction_id: TransactionId) {}
+
+    /// Called when the request message of a transaction has been retransmitted.
+    ///
+    /// This event is only issued by the transporters that retransmit requests
+    /// (e.g., `StunUdpTransporter`).
+    fn on_retransmit(&self, peer: &P, transaction_id: TransactionId) {}
+
+    /// Called when a response message for a transaction has been received.
+    fn on_response(&self, peer: &P, transaction_id: TransactionId, class: MessageClass) {}
+
+    /// Called when a transaction has timed out.
+    fn on_timeout(&self, peer: &P, transaction_id: TransactionId) {}
+
+    /// Called when a transaction has been cancelled before it completes.
+    fn on_cancel(&self, peer: &P, transaction_id: TransactionId) {}
+
+    /// Called when a transaction has failed because its peer has been reported unreachable
+    /// (e.g., by an ICMP port-unreachable message).
+    ///
+    /// The future of such a transaction fails with a `MessageErrorKind::ConnectionRefused` error.
+    fn on_unreachable(&self, peer: &P, transaction_id: TransactionId) {}
+}
 
 /// [`Channel`] builder.
 ///
@@ -24,6 +132,14 @@ type Reply<A> = oneshot::Monitored<Response<A>, MessageError>;
 #[derive(Debug, Clone)]
 pub struct ChannelBuilder {
     request_timeout: Duration,
+    max_transactions_bytes: Option<usize>,
+    unexpected_response_policy: UnexpectedResponsePolicy,
+    log_unexpected_responses: bool,
+    raw_message_capture: Option<RawMessageCapture>,
+    fingerprint_policy: FingerprintPolicy,
+    ordered_casts: bool,
+    #[cfg(feature = "relaxed-matching")]
+    relaxed_response_matching: bool,
 }
 impl ChannelBuilder {
     /// The default value of `request_timeout`.
@@ -53,6 +169,98 @@ impl ChannelBuilder {
         self
     }
 
+    /// Sets the memory budget of the outstanding transactions managed by the channel.
+    ///
+    /// If registering a new transaction would make `Channel::transactions_bytes` exceed the budget,
+    /// the future returned by `Channel::call` fails with a `MessageErrorKind::Other` error
+    /// and the request is not sent.
+    ///
+    /// This is independent of (and checked before) the count based limit of the transporter
+    /// (e.g., `StunUdpTransporterBuilder::max_outstanding_transactions`).
+    ///
+    /// By default, there is no limit.
+    pub fn max_transactions_bytes(&mut self, max: usize) -> &mut Self {
+        self.max_transactions_bytes = Some(max);
+        self
+    }
+
+    /// Sets the policy for handling responses that do not match any outstanding transaction.
+    ///
+    /// The default value is `UnexpectedResponsePolicy::Report`.
+    pub fn unexpected_response_policy(&mut self, policy: UnexpectedResponsePolicy) -> &mut Self {
+        self.unexpected_response_policy = policy;
+        self
+    }
+
+    /// Sets whether the channel logs responses that do not match any outstanding transaction.
+    ///
+    /// If `true`, every such response is logged at the `warn` level.
+    /// The log also tells whether the response has the transaction ID of
+    /// one of the indications recently sent via the channel.
+    ///
+    /// The default value is `false`.
+    pub fn log_unexpected_responses(&mut self, enabled: bool) -> &mut Self {
+        self.log_unexpected_responses = enabled;
+        self
+    }
+
+    /// Sets the handle for retrieving the raw bytes of the received messages.
+    ///
+    /// If set, the channel attaches the bytes captured by the handle to
+    /// every `InvalidMessage` it reports (see `InvalidMessage::raw_bytes`).
+    /// The handle should be shared with the `CaptureDecoder` used by the transporter of the channel.
+    ///
+    /// By default, no bytes are attached.
+    pub fn raw_message_capture(&mut self, capture: RawMessageCapture) -> &mut Self {
+        self.raw_message_capture = Some(capture);
+        self
+    }
+
+    /// Sets the policy for checking the `FINGERPRINT` attributes of received messages.
+    ///
+    /// The default value is `FingerprintPolicy::VerifyIfPresent`.
+    pub fn fingerprint_policy(&mut self, policy: FingerprintPolicy) -> &mut Self {
+        self.fingerprint_policy = policy;
+        self
+    }
+
+    /// Sets whether the channel guarantees the write order of the indications cast to each peer.
+    ///
+    /// If `true`, an indication is not handed to the transporter until the previous indications
+    /// to the same peer have been written (i.e., `poll_send` of the transporter has completed).
+    /// The indications cast in the meantime are kept by the channel and are handed to
+    /// the transporter by `Channel::poll_send` in the order in which they were cast.
+    /// Thus, the indications to a peer are written in the order of `Channel::cast` calls
+    /// even if the transporter reorders the messages queued in it.
+    ///
+    /// Note that this only guarantees the order of writes, not the order of delivery.
+    /// For example, UDP datagrams may be reordered or lost by the network.
+    ///
+    /// The default value is `false`.
+    pub fn ordered_casts(&mut self, enabled: bool) -> &mut Self {
+        self.ordered_casts = enabled;
+        self
+    }
+
+    /// Enables or disables the relaxed response matching mode (**for debugging only**).
+    ///
+    /// In this mode, if a received response does not match any outstanding transaction,
+    /// the channel pairs it with the oldest outstanding transaction to the same peer instead of
+    /// reporting it as an unexpected response.
+    /// Every such pairing is logged at the `warn` level.
+    ///
+    /// This is useful for inspecting the responses of a misbehaving server that returns
+    /// wrong transaction IDs, but it defeats the purpose of transaction IDs.
+    /// **It MUST NOT be used in production.**
+    ///
+    /// This method is only available when the `relaxed-matching` feature is enabled,
+    /// and the mode is disabled by default.
+    #[cfg(feature = "relaxed-matching")]
+    pub fn relaxed_response_matching(&mut self, enabled: bool) -> &mut Self {
+        self.relaxed_response_matching = enabled;
+        self
+    }
+
     /// Makes a new `Channel` instance with the given settings.
     pub fn finish<A, T>(&self, transporter: T) -> Channel<A, T>
     where
@@ -63,7 +271,22 @@ impl ChannelBuilder {
             transporter,
             timeout_queue: TimeoutQueue::new(),
             request_timeout: self.request_timeout,
+            max_transactions_bytes: self.max_transactions_bytes,
             transactions: HashMap::new(),
+            observer: None,
+            unflushed_requests: Vec::new(),
+            unexpected_response_policy: self.unexpected_response_policy,
+            log_unexpected_responses: self.log_unexpected_responses,
+            raw_message_capture: self.raw_message_capture.clone(),
+            fingerprint_policy: self.fingerprint_policy,
+            unexpected_responses: 0,
+            recent_indications: VecDeque::new(),
+            paused: false,
+            ordered_casts: self.ordered_casts,
+            unflushed_casts: HashSet::new(),
+            deferred_casts: VecDeque::new(),
+            #[cfg(feature = "relaxed-matching")]
+            relaxed_response_matching: self.relaxed_response_matching,
         }
     }
 }
@@ -71,6 +294,14 @@ impl Default for ChannelBuilder {
     fn default() -> Self {
         ChannelBuilder {
             request_timeout: Duration::from_millis(Self::DEFAULT_REQUEST_TIMEOUT_MS),
+            max_transactions_bytes: None,
+            unexpected_response_policy: UnexpectedResponsePolicy::default(),
+            log_unexpected_responses: false,
+            raw_message_capture: None,
+            fingerprint_policy: FingerprintPolicy::default(),
+            ordered_casts: false,
+            #[cfg(feature = "relaxed-matching")]
+            relaxed_response_matching: false,
         }
     }
 }
@@ -84,7 +315,53 @@ where
     transporter: T,
     timeout_queue: TimeoutQueue<(T::PeerAddr, TransactionId)>,
     request_timeout: Duration,
-    transactions: HashMap<(T::PeerAddr, TransactionId), (Method, Reply<A>)>,
+    max_transactions_bytes: Option<usize>,
+    transactions: HashMap<(T::PeerAddr, TransactionId), Transaction<A>>,
+    observer: Option<SharedObserver<T::PeerAddr>>,
+    unflushed_requests: Vec<(T::PeerAddr, TransactionId)>,
+    unexpected_response_policy: UnexpectedResponsePolicy,
+    log_unexpected_responses: bool,
+    raw_message_capture: Option<RawMessageCapture>,
+    fingerprint_policy: FingerprintPolicy,
+    unexpected_responses: u64,
+    recent_indications: VecDeque<(T::PeerAddr, TransactionId)>,
+    paused: bool,
+    ordered_casts: bool,
+    unflushed_casts: HashSet<T::PeerAddr>,
+    deferred_casts: VecDeque<(T::PeerAddr, Message<A>)>,
+    #[cfg(feature = "relaxed-matching")]
+    relaxed_response_matching: bool,
+}
+impl<A, P, S, R> Channel<A, SplitTransporter<S, R>>
+where
+    A: Attribute,
+    P: PeerAddr,
+    S: Sink<SinkItem = (P, Message<A>)>,
+    S::SinkError: Into<BoxError>,
+    R: Stream<Item = (P, DecodedMessage<A>)>,
+    R::Error: Into<BoxError>,
+{
+    /// Makes a new `Channel` instance from a pair of sink and stream halves.
+    ///
+    /// This is equivalent to `Channel::new(SplitTransporter::new(sink, stream))`.
+    pub fn from_parts(sink: S, stream: R) -> Self {
+        Channel::new(SplitTransporter::new(sink, stream))
+    }
+}
+impl<A, T> Channel<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A> + TcpTransport,
+{
+    /// Returns the local address of the underlying TCP connection.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.transporter.local_addr()
+    }
+
+    /// Returns the remote address of the underlying TCP connection.
+    pub fn peer_addr(&self) -> SocketAddr {
+        self.transporter.peer_addr()
+    }
 }
 impl<A, T> fmt::Debug for Channel<A, T>
 where
@@ -122,21 +399,70 @@ where
             let e = MessageErrorKind::InvalidInput
                 .cause(format!("Transaction ID conflicts: transaction_id={:?}", id));
             tx.exit(Err(track!(e).into()));
+        } else if self.is_transactions_budget_exhausted() {
+            let e = MessageErrorKind::Other.cause(format!(
+                "Memory budget of outstanding transactions exhausted: transaction_id={:?}",
+                id
+            ));
+            tx.exit(Err(track!(e).into()));
         } else if let Err(e) = track!(
             self.transporter
                 .start_send(peer.clone(), request.into_message())
         ) {
             tx.exit(Err(e.into()));
         } else {
-            self.transactions.insert((peer.clone(), id), (method, tx));
+            if let Some(ref o) = self.observer {
+                o.on_start(&peer, id, method);
+                if !self.transporter.notifies_sent_requests() {
+                    self.unflushed_requests.push((peer.clone(), id));
+                }
+            }
+            self.transactions
+                .insert((peer.clone(), id), (method, SystemTime::now(), tx));
             self.timeout_queue.push((peer, id), self.request_timeout);
         }
         rx.map_err(MessageError::from)
     }
 
+    /// Cancels the outstanding transaction identified by the given peer and transaction ID.
+    ///
+    /// The future returned by the corresponding `call` will fail with an `ErrorKind::Other` error.
+    ///
+    /// If there is no such transaction, this will return `Ok(false)`.
+    pub fn cancel(&mut self, peer: &T::PeerAddr, transaction_id: TransactionId) -> Result<bool> {
+        if let Some((_, _, tx)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
+            if let Some(ref o) = self.observer {
+                o.on_cancel(peer, transaction_id);
+            }
+            let e = track!(MessageErrorKind::Other.cause("Transaction has been cancelled"));
+            tx.exit(Err(e.into()));
+            track!(self.transporter.finish_transaction(peer, transaction_id))?;
+            Ok(true)
+        } else {
+            Ok(false)
+        }
+    }
+
     /// Sends the given indication message to the destination peer.
+    ///
+    /// If the channel guarantees the write order of indications
+    /// (see `ChannelBuilder::ordered_casts`), the indication may be kept by the channel
+    /// until the previous ones to the peer are written.
     pub fn cast(&mut self, peer: T::PeerAddr, indication: Indication<A>) -> MessageResult<()> {
-        track!(self.transporter.start_send(peer, indication.into_message()))?;
+        let transaction_id = indication.transaction_id();
+        let message = indication.into_message();
+        if self.ordered_casts && self.is_cast_deferred(&peer) {
+            self.deferred_casts.push_back((peer.clone(), message));
+        } else {
+            track!(self.transporter.start_send(peer.clone(), message))?;
+            if self.ordered_casts {
+                self.unflushed_casts.insert(peer.clone());
+            }
+        }
+        if self.recent_indications.len() == RECENT_INDICATIONS {
+            self.recent_indications.pop_front();
+        }
+        self.recent_indications.push_back((peer, transaction_id));
         Ok(())
     }
 
@@ -149,6 +475,15 @@ where
         Ok(())
     }
 
+    /// Registers the observer that will be notified of the lifecycle events of transactions.
+    ///
+    /// The observer is also passed to the transporter of the channel
+    /// via `StunTransport::set_observer` method.
+    pub fn set_observer(&mut self, observer: SharedObserver<T::PeerAddr>) {
+        self.transporter.set_observer(observer.clone());
+        self.observer = Some(observer);
+    }
+
     /// Returns a reference to the transporter of the channel.
     pub fn transporter_ref(&self) -> &T {
         &self.transporter
@@ -159,23 +494,164 @@ where
         &mut self.transporter
     }
 
+    /// Returns the request timeout duration of the channel.
+    ///
+    /// A transaction fails with a `MessageErrorKind::Timeout` error if it has not been completed
+    /// within this duration since `call` was invoked.
+    /// Retransmissions performed by the transporter (e.g., `StunUdpTransporter`) do not extend it.
+    ///
+    /// See also `ChannelBuilder::request_timeout`.
+    pub fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// Returns the number of the received responses that did not match any outstanding transaction.
+    ///
+    /// See also `ChannelBuilder::unexpected_response_policy`.
+    pub fn unexpected_responses(&self) -> u64 {
+        self.unexpected_responses
+    }
+
     /// Returns the number of the outstanding request/response transactions in the channel.
     pub fn outstanding_transactions(&self) -> usize {
         self.transactions.len()
     }
 
+    /// Returns the metadata of the outstanding request/response transactions in the channel.
+    ///
+    /// The resulting entries are sorted by their start times.
+    ///
+    /// This allows for persisting a snapshot of the pending operations
+    /// (e.g., periodically or before shutting down), so that they can be identified
+    /// and reissued after the process restarts.
+    /// The times are represented as `SystemTime` for that purpose.
+    pub fn pending_transactions(&self) -> Vec<PendingTransaction<T::PeerAddr>> {
+        let mut transactions = self
+            .transactions
+            .iter()
+            .map(|(&(ref peer, transaction_id), &(method, started_at, _))| {
+                PendingTransaction {
+                    peer: peer.clone(),
+                    transaction_id,
+                    method,
+                    started_at,
+                    deadline: started_at + self.request_timeout,
+                }
+            }).collect::<Vec<_>>();
+        transactions.sort_by_key(|t| t.started_at);
+        transactions
+    }
+
+    /// Returns the estimated memory footprint (in bytes) of the outstanding transactions
+    /// managed by the channel.
+    ///
+    /// This is a rough estimate based on the size of an entry of the internal transaction map,
+    /// and does not include the memory used by the transporter
+    /// (e.g., the requests kept by `StunUdpTransporter` for retransmissions).
+    pub fn transactions_bytes(&self) -> usize {
+        self.transactions.len() * Self::transaction_entry_bytes()
+    }
+
+    fn transaction_entry_bytes() -> usize {
+        mem::size_of::<((T::PeerAddr, TransactionId), Transaction<A>)>()
+    }
+
+    fn is_transactions_budget_exhausted(&self) -> bool {
+        self.max_transactions_bytes.map_or(false, |max| {
+            self.transactions_bytes() + Self::transaction_entry_bytes() > max
+        })
+    }
+
+    /// Stops reading messages from the transporter until `resume` is called.
+    ///
+    /// While the channel is paused, `poll_recv` does not poll the transporter
+    /// (but still handles the timeouts of the outstanding transactions) and returns `Async::NotReady`.
+    /// Thus the incoming messages are left in the receive buffer of the OS,
+    /// which allows a slow consumer to apply backpressure without dropping messages:
+    ///
+    /// - Over TCP, once the receive buffer is full, the peer is throttled by the TCP flow control
+    ///   and no messages are lost.
+    /// - Over UDP, there is no flow control; once the receive buffer is full,
+    ///   subsequent datagrams are silently dropped by the OS.
+    ///
+    /// Note that the responses to the outstanding transactions are not read either,
+    /// so pausing a channel for a long time may make those transactions time out.
+    /// Sending messages is not affected.
+    pub fn pause(&mut self) {
+        self.paused = true;
+    }
+
+    /// Resumes reading messages from the transporter.
+    ///
+    /// Because a paused channel does not register its interest in the transporter,
+    /// the caller should poll the channel (i.e., call `poll_recv`) after resuming it.
+    pub fn resume(&mut self) {
+        self.paused = false;
+    }
+
+    /// Returns `true` if the channel is paused (see `pause`).
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
     /// Polls the transmission of the all outstanding messages in the channel have been completed.
     ///
     /// If it has been completed, this will return `Ok(Async::Ready(()))`.
     pub fn poll_send(&mut self) -> Poll<(), Error> {
-        Ok(track!(self.transporter.poll_send())?)
+        loop {
+            let ready = track!(self.transporter.poll_send())?;
+            if ready.is_ready() {
+                if let Some(ref o) = self.observer {
+                    for (peer, id) in self.unflushed_requests.drain(..) {
+                        o.on_send(&peer, id);
+                    }
+                }
+                self.unflushed_casts.clear();
+                if self.send_deferred_casts() {
+                    continue;
+                }
+            }
+            return Ok(ready);
+        }
+    }
+
+    fn is_cast_deferred(&self, peer: &T::PeerAddr) -> bool {
+        self.unflushed_casts.contains(peer) || self.deferred_casts.iter().any(|c| c.0 == *peer)
+    }
+
+    /// Hands the deferred indications, whose preceding ones have been written, to the transporter.
+    ///
+    /// Returns `true` if any indication has been handed.
+    fn send_deferred_casts(&mut self) -> bool {
+        let mut sent = false;
+        for (peer, message) in mem::replace(&mut self.deferred_casts, VecDeque::new()) {
+            if self.unflushed_casts.contains(&peer) {
+                self.deferred_casts.push_back((peer, message));
+                continue;
+            }
+            if let Err(e) = track!(self.transporter.start_send(peer.clone(), message)) {
+                warn!(
+                    "Failed to send a deferred indication: peer={:?}, error={}",
+                    peer, e
+                );
+            }
+            self.unflushed_casts.insert(peer);
+            sent = true;
+        }
+        sent
     }
 
     /// Polls reception of a message from a peer.
     #[cfg_attr(feature = "cargo-clippy", allow(type_complexity))]
     pub fn poll_recv(&mut self) -> Poll<Option<(T::PeerAddr, RecvMessage<A>)>, Error> {
         track!(self.handle_timeout())?;
-        while let Async::Ready(item) = track!(self.transporter.poll_recv())? {
+        while !self.paused {
+            let item = track!(self.transporter.poll_recv())?;
+            track!(self.handle_unreachable_peers())?;
+            let item = match item {
+                Async::NotReady => break,
+                Async::Ready(item) => item,
+            };
             if let Some((peer, message)) = item {
                 if let Some(item) = track!(self.handle_message(peer, message))? {
                     return Ok(Async::Ready(Some(item)));
@@ -187,13 +663,41 @@ where
         Ok(Async::NotReady)
     }
 
+    fn handle_unreachable_peers(&mut self) -> Result<()> {
+        while let Some(peer) = self.transporter.take_unreachable_peer() {
+            let ids = self
+                .transactions
+                .keys()
+                .filter(|k| k.0 == peer)
+                .map(|k| k.1)
+                .collect::<Vec<_>>();
+            for id in ids {
+                if let Some((_, _, tx)) = self.transactions.remove(&(peer.clone(), id)) {
+                    if let Some(ref o) = self.observer {
+                        o.on_unreachable(&peer, id);
+                    }
+                    let e = track!(MessageErrorKind::ConnectionRefused.cause(format!(
+                        "The peer is unreachable: transaction_id={:?}",
+                        id
+                    )));
+                    tx.exit(Err(e.into()));
+                }
+                track!(self.transporter.finish_transaction(&peer, id))?;
+            }
+        }
+        Ok(())
+    }
+
     fn handle_timeout(&mut self) -> Result<()> {
         let transactions = &mut self.transactions;
         while let Some((peer, id)) = self
             .timeout_queue
             .filter_pop(|entry| transactions.contains_key(entry))
         {
-            if let Some((_, tx)) = transactions.remove(&(peer.clone(), id)) {
+            if let Some((_, _, tx)) = transactions.remove(&(peer.clone(), id)) {
+                if let Some(ref o) = self.observer {
+                    o.on_timeout(&peer, id);
+                }
                 let e = track!(MessageErrorKind::Timeout.error());
                 tx.exit(Err(e.into()));
             }
@@ -207,20 +711,38 @@ where
         peer: T::PeerAddr,
         message: std::result::Result<Message<A>, BrokenMessage>,
     ) -> Result<Option<(T::PeerAddr, RecvMessage<A>)>> {
-        let message = match message {
+        let raw_bytes = self.raw_message_capture.as_ref().and_then(|c| c.take());
+        let mut message = match message {
             Err(broken) => Some(self.handle_broken_message(&broken)),
-            Ok(message) => match message.class() {
-                MessageClass::Indication => Some(self.handle_indication(message)),
-                MessageClass::Request => Some(self.handle_request(message)),
-                MessageClass::SuccessResponse => {
-                    track!(self.handle_success_response(&peer, message))?
-                }
-                MessageClass::ErrorResponse => track!(self.handle_error_response(&peer, message))?,
-            },
+            Ok(message) => track!(self.handle_decoded_message(&peer, message))?,
         };
+        if let (Some(RecvMessage::Invalid(m)), Some(bytes)) = (message.as_mut(), raw_bytes) {
+            m.set_raw_bytes(bytes);
+        }
         Ok(message.map(|m| (peer, m)))
     }
 
+    fn handle_decoded_message(
+        &mut self,
+        peer: &T::PeerAddr,
+        message: Message<A>,
+    ) -> Result<Option<RecvMessage<A>>> {
+        if let Err(error) = check_fingerprint(&message, self.fingerprint_policy) {
+            return Ok(Some(RecvMessage::Invalid(InvalidMessage::new(
+                message.method(),
+                message.class(),
+                message.transaction_id(),
+                track!(error),
+            ))));
+        }
+        match message.class() {
+            MessageClass::Indication => Ok(Some(self.handle_indication(message))),
+            MessageClass::Request => Ok(Some(self.handle_request(message))),
+            MessageClass::SuccessResponse => track!(self.handle_success_response(peer, message)),
+            MessageClass::ErrorResponse => track!(self.handle_error_response(peer, message)),
+        }
+    }
+
     fn handle_broken_message(&self, message: &BrokenMessage) -> RecvMessage<A> {
         let bytecodec_error_kind = *message.error().kind();
         let error = MessageErrorKind::MalformedAttribute.takes_over(message.error().clone());
@@ -256,6 +778,87 @@ where
         }
     }
 
+    fn take_transaction(
+        &mut self,
+        peer: &T::PeerAddr,
+        transaction_id: TransactionId,
+    ) -> Option<(TransactionId, Transaction<A>)> {
+        if let Some(t) = self.transactions.remove(&(peer.clone(), transaction_id)) {
+            return Some((transaction_id, t));
+        }
+        #[cfg(feature = "relaxed-matching")]
+        {
+            if self.relaxed_response_matching && !self.is_recent_indication(peer, transaction_id) {
+                let oldest = self
+                    .transactions
+                    .iter()
+                    .filter(|&(&(ref p, _), _)| p == peer)
+                    .min_by_key(|&(_, &(_, started_at, _))| started_at)
+                    .map(|(&(_, id), _)| id);
+                if let Some(oldest) = oldest {
+                    warn!(
+                        "[RELAXED MATCHING] Pairs a response with an unknown transaction ID \
+                         with the oldest outstanding transaction: peer={:?}, \
+                         response_transaction_id={:?}, paired_transaction_id={:?}",
+                        peer,
+                        transaction_id,
+                        oldest
+                    );
+                    return self
+                        .transactions
+                        .remove(&(peer.clone(), oldest))
+                        .map(|t| (oldest, t));
+                }
+            }
+        }
+        None
+    }
+
+    fn is_recent_indication(&self, peer: &T::PeerAddr, transaction_id: TransactionId) -> bool {
+        self.recent_indications
+            .iter()
+            .any(|entry| entry.1 == transaction_id && entry.0 == *peer)
+    }
+
+    fn handle_unexpected_response(
+        &mut self,
+        peer: &T::PeerAddr,
+        method: Method,
+        class: MessageClass,
+        transaction_id: TransactionId,
+    ) -> Option<RecvMessage<A>> {
+        self.unexpected_responses += 1;
+        if self.log_unexpected_responses {
+            if self.is_recent_indication(peer, transaction_id) {
+                warn!(
+                    "Received a response to an indication: peer={:?}, transaction_id={:?}, \
+                     method={:?}, class={:?}",
+                    peer, transaction_id, method, class
+                );
+            } else {
+                warn!(
+                    "Received a response with an unknown transaction ID: peer={:?}, \
+                     transaction_id={:?}, method={:?}, class={:?}",
+                    peer, transaction_id, method, class
+                );
+            }
+        }
+        match self.unexpected_response_policy {
+            UnexpectedResponsePolicy::Drop => None,
+            UnexpectedResponsePolicy::Report => {
+                let error = track!(
+                    MessageErrorKind::UnexpectedResponse.cause("Unknown transaction ID")
+                ).into();
+                Some(RecvMessage::Invalid(InvalidMessage::new(
+                    method,
+                    class,
+                    transaction_id,
+                    error,
+                )))
+            }
+        }
+    }
+
     fn handle_success_response(
         &mut self,
         peer: &T::PeerAddr,
@@ -264,7 +867,12 @@ where
         let class = message.class();
         let method = message.method();
         let transaction_id = message.transaction_id();
-        if let Some((method, tx)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
+        if let Some((transaction_id, (method, _, tx))) =
+            self.take_transaction(peer, transaction_id)
+        {
+            if let Some(ref o) = self.observer {
+                o.on_response(peer, transaction_id, class);
+            }
             track!(self.transporter.finish_transaction(&peer, transaction_id))?;
             let result = track!(SuccessResponse::from_message(message))
                 .and_then(|m| {
@@ -274,11 +882,7 @@ where
             tx.exit(result);
             Ok(None)
         } else {
-            let error =
-                track!(MessageErrorKind::UnexpectedResponse.cause("Unknown transaction ID")).into();
-            let message =
-                RecvMessage::Invalid(InvalidMessage::new(method, class, transaction_id, error));
-            Ok(Some(message))
+            Ok(self.handle_unexpected_response(peer, method, class, transaction_id))
         }
     }
 
@@ -290,7 +894,12 @@ where
         let class = message.class();
         let method = message.method();
         let transaction_id = message.transaction_id();
-        if let Some((method, tx)) = self.transactions.remove(&(peer.clone(), transaction_id)) {
+        if let Some((transaction_id, (method, _, tx))) =
+            self.take_transaction(peer, transaction_id)
+        {
+            if let Some(ref o) = self.observer {
+                o.on_response(peer, transaction_id, class);
+            }
             track!(self.transporter.finish_transaction(&peer, transaction_id))?;
             let result = track!(ErrorResponse::from_message(message))
                 .and_then(|m| {
@@ -300,15 +909,32 @@ where
             tx.exit(result);
             Ok(None)
         } else {
-            let error =
-                track!(MessageErrorKind::UnexpectedResponse.cause("Unknown transaction ID")).into();
-            let message =
-                RecvMessage::Invalid(InvalidMessage::new(method, class, transaction_id, error));
-            Ok(Some(message))
+            Ok(self.handle_unexpected_response(peer, method, class, transaction_id))
         }
     }
 }
 
+/// Metadata of an outstanding request/response transaction.
+///
+/// See `Channel::pending_transactions`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PendingTransaction<P> {
+    /// The destination peer of the request.
+    pub peer: P,
+
+    /// The transaction ID of the request.
+    pub transaction_id: TransactionId,
+
+    /// The method of the request.
+    pub method: Method,
+
+    /// The time when the transaction started.
+    pub started_at: SystemTime,
+
+    /// The time when the transaction will time out.
+    pub deadline: SystemTime,
+}
+
 /// Received message.
 ///
 /// Messages are received by calling `Channel::poll` method.
@@ -319,3 +945,28 @@ pub enum RecvMessage<A> {
     Indication(Indication<A>),
     Invalid(InvalidMessage),
 }
+
+fn check_fingerprint<A: Attribute>(
+    message: &Message<A>,
+    policy: FingerprintPolicy,
+) -> MessageResult<()> {
+    if policy == FingerprintPolicy::Ignore {
+        return Ok(());
+    }
+    let is_fingerprint = |t: AttributeType| t.as_u16() == Fingerprint::CODEPOINT;
+    track_assert!(
+        !message
+            .unknown_attributes()
+            .any(|a| is_fingerprint(a.get_type())),
+        MessageErrorKind::InvalidInput,
+        "Unverified FINGERPRINT attribute"
+    );
+    if policy == FingerprintPolicy::Require {
+        track_assert!(
+            message.attributes().any(|a| is_fingerprint(a.get_type())),
+            MessageErrorKind::InvalidInput,
+            "Missing FINGERPRINT attribute"
+        );
+    }
+    Ok(())
+}
@@ -4,29 +4,110 @@
 //! If you want more elaborate one, please consider create your own client using [`Channel`] directly.
 //!
 //! [`Channel`]: ../channel/struct.Channel.html
+use bytecodec::marker::Never;
 use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer;
 use fibers::Spawn;
+use fibers_transport;
+use futures::future::{self, Either, Loop};
 use futures::stream::Fuse;
 use futures::{Async, Future, IntoFuture, Poll, Stream};
+use std::collections::HashMap;
 use std::fmt;
+use std::hash::Hash;
 use std::marker::PhantomData;
-use stun_codec::Attribute;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5245::attributes::{IceControlled, IceControlling};
+use stun_codec::rfc5245::errors::RoleConflict;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::{
+    ErrorCode, Fingerprint, MessageIntegrity, Realm, Software, XorMappedAddress,
+};
+use stun_codec::rfc5389::errors::{ServerError, Unauthorized};
+use stun_codec::rfc5766::attributes::Lifetime;
+use stun_codec::rfc5780::attributes::{OtherAddress, ResponseOrigin};
+use stun_codec::{Attribute, Message, MessageDecoder, MessageEncoder, TransactionId};
+use trackable::error::ErrorKindExt;
 
-use channel::Channel;
-use message::{Indication, Request, Response};
-use transport::StunTransport;
-use {Error, Result};
+use auth::IntegrityKey;
+use channel::{Channel, PendingTransaction};
+use ice::IceRole;
+use message::{ErrorResponse, Indication, MessageErrorKind, Request, Response};
+use transport::{StunTransport, StunUdpTransporter, StunUdpTransporterBuilder, UdpBindPort};
+use {Error, ErrorKind, Result};
+
+type ExpectedSoftware<A> = (Arc<String>, fn(&Response<A>, &str) -> Result<()>);
 
 /// STUN client.
-#[derive(Debug, Clone)]
 pub struct Client<A, T>
 where
     A: Attribute,
     T: StunTransport<A>,
 {
     command_tx: mpsc::Sender<Command<A, T::PeerAddr>>,
+    error_response_to_err: Option<fn(ErrorResponse<A>) -> Error>,
+    max_transaction_duration: Duration,
+    decorator: Option<Arc<dyn DecorateRequest<A>>>,
+    new_transaction_retries: usize,
+    queued_indications: Arc<AtomicUsize>,
+    max_queued_indications: usize,
+    expired_indications: Arc<AtomicUsize>,
+    expected_software: Option<ExpectedSoftware<A>>,
+    expected_reflexive_address: Option<Arc<ExpectedAddress>>,
+    keepalive_mode: KeepaliveMode,
+    last_sent: Arc<LastSent<T::PeerAddr>>,
     _phantom: PhantomData<T>,
 }
+impl<A, T> Clone for Client<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    fn clone(&self) -> Self {
+        Client {
+            command_tx: self.command_tx.clone(),
+            error_response_to_err: self.error_response_to_err,
+            max_transaction_duration: self.max_transaction_duration,
+            decorator: self.decorator.clone(),
+            new_transaction_retries: self.new_transaction_retries,
+            queued_indications: self.queued_indications.clone(),
+            max_queued_indications: self.max_queued_indications,
+            expired_indications: self.expired_indications.clone(),
+            expected_software: self.expected_software.clone(),
+            expected_reflexive_address: self.expected_reflexive_address.clone(),
+            keepalive_mode: self.keepalive_mode,
+            last_sent: self.last_sent.clone(),
+            _phantom: PhantomData,
+        }
+    }
+}
+impl<A, T> fmt::Debug for Client<A, T>
+where
+    A: Attribute,
+    T: StunTransport<A>,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Client")
+            .field("command_tx", &self.command_tx)
+            .field(
+                "treat_error_responses_as_err",
+                &self.error_response_to_err.is_some(),
+            ).field("max_transaction_duration", &self.max_transaction_duration)
+            .field("decorator", &self.decorator.is_some())
+            .field("new_transaction_retries", &self.new_transaction_retries)
+            .field("max_queued_indications", &self.max_queued_indications)
+            .field(
+                "expected_software",
+                &self.expected_software.as_ref().map(|x| &x.0),
+            ).field("expected_reflexive_address", &self.expected_reflexive_address)
+            .field("keepalive_mode", &self.keepalive_mode)
+            .finish()
+    }
+}
 impl<A, T> Client<A, T>
 where
     A: Attribute + Send + 'static,
@@ -34,32 +115,759 @@ where
     T::PeerAddr: Send + 'static,
 {
     /// Makes a new `Client` instance that uses the given channel for sending/receiving messages.
+    ///
+    /// The number of the queued indications is unbounded.
     pub fn new<S>(spawner: &S, channel: Channel<A, T>) -> Self
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        Self::with_max_queued_indications(spawner, channel, usize::MAX)
+    }
+
+    /// Makes a new `Client` instance that can queue at most `max` indications.
+    ///
+    /// Indications sent by `cast` (or `cast_many`) are queued until the task driving
+    /// the channel hands them to the transporter.
+    /// If a producer casts indications faster than they are consumed,
+    /// the queue would grow indefinitely; the limit protects the memory from that.
+    /// If the limit is exceeded, `cast` fails with an `ErrorKind::ResourceLimit` error.
+    ///
+    /// The limit is shared by all clones of the client,
+    /// and does not affect requests sent by `call`.
+    /// (Outstanding transactions are limited by the channel and the transporter,
+    /// see `ChannelBuilder::max_transactions_bytes` and
+    /// `StunUdpTransporterBuilder::max_outstanding_transactions`.)
+    pub fn with_max_queued_indications<S>(
+        spawner: &S,
+        channel: Channel<A, T>,
+        max: usize,
+    ) -> Self
     where
         S: Spawn + Clone + Send + 'static,
     {
         let (command_tx, command_rx) = mpsc::channel();
+        let max_transaction_duration = channel.request_timeout();
+        let queued_indications = Arc::new(AtomicUsize::new(0));
+        let expired_indications = Arc::new(AtomicUsize::new(0));
+        let last_sent = Arc::new(LastSent::new());
         let channel_driver = ChannelDriver {
             spawner: spawner.clone(),
             channel: Ok(channel),
             command_rx: command_rx.fuse(),
+            queued_indications: queued_indications.clone(),
+            expired_indications: expired_indications.clone(),
+            last_sent: last_sent.clone(),
         };
         spawner.spawn(channel_driver);
         Client {
             command_tx,
+            error_response_to_err: None,
+            max_transaction_duration,
+            decorator: None,
+            new_transaction_retries: 0,
+            queued_indications,
+            max_queued_indications: max,
+            expired_indications,
+            expected_software: None,
+            expected_reflexive_address: None,
+            keepalive_mode: KeepaliveMode::default(),
+            last_sent,
             _phantom: PhantomData,
         }
     }
 
+    /// Returns the number of the indications queued by the client (and its clones)
+    /// that have not been handed to the channel yet.
+    pub fn queued_indications(&self) -> usize {
+        self.queued_indications.load(Ordering::SeqCst)
+    }
+
+    /// Returns the number of the indications cast by `cast_with_deadline`
+    /// (via the client or its clones) that have been dropped because their deadlines had passed.
+    pub fn expired_indications(&self) -> usize {
+        self.expired_indications.load(Ordering::SeqCst)
+    }
+
+    /// Returns the time when the client (or its clones) last handed a message
+    /// (i.e., a request or an indication) destined for `peer` to the channel.
+    ///
+    /// The times are only recorded for the peers to which keepalives are being sent
+    /// in the `KeepaliveMode::IdleOnly` mode (see `keep_binding_alive`).
+    /// `None` is returned if no such keepalive is running for `peer`
+    /// or no message has been sent to the peer since the keepalive started.
+    pub fn last_sent_time(&self, peer: &T::PeerAddr) -> Option<Instant> {
+        self.last_sent.get(peer)
+    }
+
+    /// Converts the client into a `BoxedClient` that hides the transport type.
+    pub fn boxed(self) -> BoxedClient<A, T::PeerAddr> {
+        Box::new(self)
+    }
+
+    /// Sets the decorator that is applied to every request and indication sent by the client.
+    ///
+    /// This is useful for adding common attributes (e.g., `SOFTWARE` or `USERNAME`)
+    /// without repeating them at each call site.
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn set_decorator<D>(&mut self, decorator: D) -> &mut Self
+    where
+        D: DecorateRequest<A>,
+    {
+        let decorator: Arc<dyn DecorateRequest<A>> = Arc::new(decorator);
+        self.decorator = Some(decorator);
+        self
+    }
+
+    fn decorate_request(&self, mut request: Request<A>) -> Request<A> {
+        if let Some(ref d) = self.decorator {
+            d.decorate_request(&mut request);
+        }
+        request
+    }
+
+    fn decorate_indication(&self, mut indication: Indication<A>) -> Indication<A> {
+        if let Some(ref d) = self.decorator {
+            d.decorate_indication(&mut indication);
+        }
+        indication
+    }
+
+    /// Returns the worst-case duration before a future returned by `call` fails with a timeout.
+    ///
+    /// This is the request timeout of the channel given to `Client::new`
+    /// (see `ChannelBuilder::request_timeout`).
+    /// The retransmission schedule of the transporter (e.g., RTO) does not affect the value,
+    /// because the transporter keeps retransmitting a request until the channel gives up on it.
+    ///
+    /// Note that the value does not include the time spent for queueing the request
+    /// to the channel, which is negligible in most cases.
+    pub fn max_transaction_duration(&self) -> Duration {
+        self.max_transaction_duration
+    }
+
+    /// Sets whether error responses resolve the futures returned by `call` as `Err`.
+    ///
+    /// If `true`, an error response is converted into an `ErrorKind::Other` error
+    /// that carries the code and reason phrase of its `ERROR-CODE` attribute.
+    ///
+    /// The default value is `false` (i.e., error responses are passed through as `Ok(Err(..))`).
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn treat_error_responses_as_err(&mut self, enabled: bool) -> &mut Self
+    where
+        A: TryAsRef<ErrorCode>,
+    {
+        if enabled {
+            let f: fn(ErrorResponse<A>) -> Error = error_response_to_err;
+            self.error_response_to_err = Some(f);
+        } else {
+            self.error_response_to_err = None;
+        }
+        self
+    }
+
+    /// Sets the `SOFTWARE` description that the responses to the client are expected to have.
+    ///
+    /// If `Some(..)`, a response (including an error response) that does not have
+    /// a `SOFTWARE` attribute with exactly the given description makes the future returned by
+    /// `call` (or its variants) fail with
+    /// an `ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput)` error.
+    /// This helps to detect responses sent by an unexpected server
+    /// (e.g., another STUN service listening on the same address by mistake).
+    ///
+    /// Note that the source address of a response is always pinned regardless of this setting:
+    /// a response is paired with a transaction only if it comes from the peer
+    /// to which the request was sent (see `Channel::call`),
+    /// and the ones from the other addresses are handled as unexpected responses
+    /// (see `ChannelBuilder::unexpected_response_policy`).
+    ///
+    /// The default value is `None` (i.e., `SOFTWARE` attributes are not checked).
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn expected_software(&mut self, software: Option<&str>) -> &mut Self
+    where
+        A: TryAsRef<Software>,
+    {
+        self.expected_software = software.map(|s| {
+            let f: fn(&Response<A>, &str) -> Result<()> = verify_response_software;
+            (Arc::new(s.to_owned()), f)
+        });
+        self
+    }
+
+    /// Sets the expectation on the reflexive (mapped) addresses reported by servers.
+    ///
+    /// If `Some(..)`, the `XOR-MAPPED-ADDRESS` of every response received by
+    /// `discover_reflexive_address`, `keep_binding_alive` and `watch_reflexive_address`
+    /// is checked against `expected`.
+    /// This is intended as a diagnostic aid in controlled environments
+    /// (e.g., a client behind a known 1:1 NAT), where an address outside the expectation
+    /// suggests misrouting or a man-in-the-middle.
+    /// See `ExpectedAddress` for how a mismatch is handled.
+    ///
+    /// The default value is `None` (i.e., reflexive addresses are not checked).
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn expected_reflexive_address(&mut self, expected: Option<ExpectedAddress>) -> &mut Self {
+        self.expected_reflexive_address = expected.map(Arc::new);
+        self
+    }
+
+    /// Sets the mode of the keepalives sent by `keep_binding_alive` and `watch_reflexive_address`.
+    ///
+    /// See `KeepaliveMode` for details.
+    ///
+    /// The default value is `KeepaliveMode::Always`.
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn keepalive_mode(&mut self, mode: KeepaliveMode) -> &mut Self {
+        self.keepalive_mode = mode;
+        self
+    }
+
+    /// Sets the number of the times a timed-out request is reissued as a new transaction.
+    ///
+    /// If a transaction started by `call` (or its variants) times out and the number of
+    /// the reissues is less than `count`, the client automatically sends the same request again
+    /// with a fresh transaction ID, instead of failing with a timeout error.
+    /// This may help when the path to the peer has changed in the middle of the transaction
+    /// (e.g., the binding of a NAT has been rebound).
+    ///
+    /// This differs from the other retry mechanisms as follows:
+    ///
+    /// - The retransmissions by `StunUdpTransporter` resend the request with
+    ///   the same transaction ID within a single transaction, until the transaction times out
+    ///   (see `ChannelBuilder::request_timeout`).
+    ///   A reissue starts only after all of them have failed to elicit a response.
+    /// - `call_with_retry` reissues a request when a `500 Server Error` response is received,
+    ///   and never retries timeouts by itself.
+    ///
+    /// Thus the worst-case duration of a call becomes `count + 1` times
+    /// the value of `max_transaction_duration`.
+    ///
+    /// Note that attributes that depend on the transaction ID (e.g., `MESSAGE-INTEGRITY`)
+    /// are copied as-is to the reissued requests, so they should be added by a decorator
+    /// (see `set_decorator`) rather than to the request.
+    ///
+    /// The default value is `0` (i.e., timeouts are never retried).
+    ///
+    /// This setting only affects this client and the clones made after calling this method.
+    pub fn retry_as_new_transaction(&mut self, count: usize) -> &mut Self {
+        self.new_transaction_retries = count;
+        self
+    }
+
     /// Sends the given request message to the destination peer and
     /// returns a future that waits the corresponding response.
+    ///
+    /// If the returned future is dropped before the response arrives,
+    /// the transaction is cancelled (i.e., the retransmissions of the request are stopped).
+    ///
+    /// See also `treat_error_responses_as_err`.
     pub fn call(
         &self,
         peer: T::PeerAddr,
         request: Request<A>,
     ) -> impl Future<Item = Response<A>, Error = Error> {
+        let error_response_to_err = self.error_response_to_err;
+        self.call_raw(peer, request)
+            .and_then(move |response| match (response, error_response_to_err) {
+                (Err(response), Some(f)) => Err(track!(f(response))),
+                (response, _) => Ok(response),
+            })
+    }
+
+    /// Same as `call` except that the request is retried when the server is overloaded.
+    ///
+    /// If a `500 Server Error` response is received and the number of the retries is less than
+    /// `policy.max_retries`, the request is sent again as a new transaction
+    /// (i.e., with a new transaction ID) after a delay.
+    /// The delay is the backoff hint conveyed by the `LIFETIME` attribute of the response
+    /// (see `UdpServer::overload_backoff_hint`) if any,
+    /// and is determined by `policy` otherwise.
+    /// In either case, the delay does not exceed `policy.max_delay`.
+    ///
+    /// Timeouts and other errors are not retried
+    /// (timeouts can be retried by enabling `retry_as_new_transaction`).
+    ///
+    /// Note that attributes that depend on the transaction ID (e.g., `MESSAGE-INTEGRITY`)
+    /// are copied as-is to the retried requests, so they should be added by a decorator
+    /// (see `set_decorator`) rather than to `request`.
+    pub fn call_with_retry(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        policy: RetryPolicy,
+    ) -> impl Future<Item = Response<A>, Error = Error>
+    where
+        A: TryAsRef<ErrorCode> + TryAsRef<Lifetime>,
+    {
+        let client = self.clone();
+        let error_response_to_err = self.error_response_to_err;
+        future::loop_fn((request, 0), move |(request, retries)| {
+            let next_request = renew_transaction_id(&request);
+            let policy = policy.clone();
+            client
+                .call_raw(peer.clone(), request)
+                .and_then(move |response| {
+                    let delay = match response {
+                        Err(ref r) if retries < policy.max_retries && is_server_error(r) => {
+                            let hint = r.get_attribute::<Lifetime>().map(|a| a.lifetime());
+                            Some(hint.map_or_else(|| policy.delay(retries), |h| policy.clamp(h)))
+                        }
+                        _ => None,
+                    };
+                    if let Some(delay) = delay {
+                        let future = timer::timeout(delay)
+                            .map_err(|e| track!(Error::from(ErrorKind::Other.cause(e))))
+                            .map(move |()| Loop::Continue((next_request, retries + 1)));
+                        Either::A(future)
+                    } else {
+                        Either::B(future::ok(Loop::Break(response)))
+                    }
+                })
+        }).and_then(move |response| match (response, error_response_to_err) {
+            (Err(response), Some(f)) => Err(track!(f(response))),
+            (response, _) => Ok(response),
+        })
+    }
+
+    fn call_raw(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        let max_retries = self.new_transaction_retries;
+        if max_retries == 0 {
+            return Either::A(self.call_once(peer, request));
+        }
+
+        let client = self.clone();
+        let future = future::loop_fn((request, 0), move |(request, retries)| {
+            let next_request = renew_transaction_id(&request);
+            client
+                .call_once(peer.clone(), request)
+                .then(move |result| match result {
+                    Err(ref e) if retries < max_retries && is_timeout(e) => {
+                        debug!(
+                            "Transaction timed out; reissues the request as a new transaction \
+                             (retries={})",
+                            retries + 1
+                        );
+                        Ok(Loop::Continue((next_request, retries + 1)))
+                    }
+                    Err(e) => Err(track!(e)),
+                    Ok(response) => Ok(Loop::Break(response)),
+                })
+        });
+        Either::B(future)
+    }
+
+    fn call_once(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+    ) -> impl Future<Item = Response<A>, Error = Error> {
+        let request = self.decorate_request(request);
         let (tx, rx) = oneshot::monitor();
-        let command = Command::Call(peer, request, tx);
+        let transaction_id = request.transaction_id();
+        let command = Command::Call(peer.clone(), request, tx);
+        let expected_software = self.expected_software.clone();
+
+        // The guard is made before the command is sent, so that the transaction is cancelled
+        // even if the returned future is dropped without being polled
+        let call = Call {
+            rx,
+            cancel: Some((self.command_tx.clone(), peer, transaction_id)),
+        };
+        track!(self.command_tx.send(command).map_err(Error::from))
+            .into_future()
+            .and_then(move |()| call)
+            .and_then(move |response| {
+                if let Some((software, verify)) = expected_software {
+                    track!(verify(&response, &software))?;
+                }
+                Ok(response)
+            })
+    }
+
+    /// Same as `call` except that the returned future also yields the given user context.
+    ///
+    /// This is convenient for correlating a response (or an error) with the application level
+    /// operation that issued the request, without maintaining a map from transaction IDs to contexts.
+    /// The context is handed back as-is regardless of whether the call succeeds or fails.
+    pub fn call_with_context<C>(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        context: C,
+    ) -> impl Future<Item = (C, Response<A>), Error = (C, Error)> {
+        self.call(peer, request).then(move |result| match result {
+            Ok(response) => Ok((context, response)),
+            Err(e) => Err((context, track!(e))),
+        })
+    }
+
+    /// Same as `call` except that the `MESSAGE-INTEGRITY` of the request and its response is
+    /// computed and verified with the given per-call key.
+    ///
+    /// This is intended for the cases where the credential differs per transaction
+    /// (e.g., ICE connectivity checks, each of which uses the password advertised by the peer).
+    ///
+    /// A `MESSAGE-INTEGRITY` attribute computed with `key` is appended to the request
+    /// after the decorator of the client (see `set_decorator`) has been applied.
+    /// If the request already has `MESSAGE-INTEGRITY` attributes (e.g., added by the decorator
+    /// with a client-wide credential), they are removed.
+    /// That is, the per-call key takes precedence over any client-wide credential.
+    /// If the request has a `FINGERPRINT` attribute, it is recomputed and moved after
+    /// the new `MESSAGE-INTEGRITY` attribute.
+    /// When the request is reissued as a new transaction (see `retry_as_new_transaction`),
+    /// the attribute is recomputed for the new transaction ID.
+    ///
+    /// # Errors
+    ///
+    /// If the response (including an error response) does not have a `MESSAGE-INTEGRITY` attribute
+    /// that matches `key`, the returned future will fail with
+    /// an `ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput)` error
+    /// (see [RFC 5389 -- 10.1.3. Receiving a Response]).
+    ///
+    /// [RFC 5389 -- 10.1.3. Receiving a Response]: https://tools.ietf.org/html/rfc5389#section-10.1.3
+    pub fn call_with_integrity_key(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        key: IntegrityKey,
+    ) -> impl Future<Item = Response<A>, Error = Error>
+    where
+        A: TryAsRef<MessageIntegrity> + From<MessageIntegrity> + From<Fingerprint>,
+    {
+        let error_response_to_err = self.error_response_to_err;
+        self.call_signed_raw(peer, request, key)
+            .and_then(move |response| match (response, error_response_to_err) {
+                (Err(response), Some(f)) => Err(track!(f(response))),
+                (response, _) => Ok(response),
+            })
+    }
+
+    /// Sends an ICE connectivity check and repairs a role conflict reported by the peer.
+    ///
+    /// The request is sent in the same manner as `call_with_integrity_key`
+    /// with an `ICE-CONTROLLING` or `ICE-CONTROLLED` attribute that asserts `role` and
+    /// conveys `tie_breaker` (existing ones in `request` are replaced).
+    ///
+    /// If the peer replies a `487 Role Conflict` error response, the check is retried once
+    /// as a new transaction with the opposite role, following
+    /// [RFC 8445 -- 7.2.5.1. Role Conflict].
+    /// The returned future yields the role the agent should take after the check
+    /// (i.e., `role.opposite()` if the check has been retried) along with the final response.
+    ///
+    /// See the `ice` module for the server side of the role conflict resolution.
+    ///
+    /// [RFC 8445 -- 7.2.5.1. Role Conflict]: https://tools.ietf.org/html/rfc8445#section-7.2.5.1
+    pub fn call_connectivity_check(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        key: IntegrityKey,
+        role: IceRole,
+        tie_breaker: u64,
+    ) -> impl Future<Item = (IceRole, Response<A>), Error = Error>
+    where
+        A: TryAsRef<MessageIntegrity>
+            + From<MessageIntegrity>
+            + From<Fingerprint>
+            + TryAsRef<ErrorCode>
+            + From<IceControlling>
+            + From<IceControlled>,
+    {
+        let client = self.clone();
+        let error_response_to_err = self.error_response_to_err;
+        let retry_request = with_ice_role(&request, role.opposite(), tie_breaker, None);
+        let request = with_ice_role(&request, role, tie_breaker, Some(request.transaction_id()));
+        let retry_key = key.clone();
+        self.call_signed_raw(peer.clone(), request, key)
+            .and_then(move |response| {
+                let is_role_conflict = match response {
+                    Err(ref r) => r
+                        .get_attribute::<ErrorCode>()
+                        .map_or(false, |e| e.code() == RoleConflict::CODEPOINT),
+                    Ok(_) => false,
+                };
+                if is_role_conflict {
+                    debug!(
+                        "Role conflict; retries the connectivity check as {:?}",
+                        role.opposite()
+                    );
+                    let future = client
+                        .call_signed_raw(peer, retry_request, retry_key)
+                        .map(move |response| (role.opposite(), response));
+                    Either::A(future)
+                } else {
+                    Either::B(future::ok((role, response)))
+                }
+            }).and_then(move |(role, response)| match (response, error_response_to_err) {
+                (Err(response), Some(f)) => Err(track!(f(response))),
+                (response, _) => Ok((role, response)),
+            })
+    }
+
+    fn call_signed_raw(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+        key: IntegrityKey,
+    ) -> impl Future<Item = Response<A>, Error = Error>
+    where
+        A: TryAsRef<MessageIntegrity> + From<MessageIntegrity> + From<Fingerprint>,
+    {
+        let mut client = self.clone();
+        let decorator: Arc<dyn DecorateRequest<A>> = Arc::new(IntegrityDecorator {
+            inner: self.decorator.clone(),
+            key: key.clone(),
+        });
+        client.decorator = Some(decorator);
+        client.call_raw(peer, request).and_then(move |response| {
+            track!(verify_response_integrity(&response, &key))?;
+            Ok(response)
+        })
+    }
+
+    /// Same as `call` except that the outcome of the transaction is classified into `CallOutcome`.
+    ///
+    /// The returned future never fails.
+    /// Error responses are always reported as `CallOutcome::Completed`
+    /// regardless of the `treat_error_responses_as_err` setting.
+    pub fn call_detailed(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+    ) -> impl Future<Item = CallOutcome<A>, Error = Never> {
+        self.call_raw(peer, request).then(|result| {
+            Ok(match result {
+                Ok(response) => CallOutcome::Completed(response),
+                Err(ref e) if is_timeout(e) => CallOutcome::TimedOut,
+                Err(e) => CallOutcome::TransportError(track!(e)),
+            })
+        })
+    }
+
+    /// Probes the capabilities of the given server.
+    ///
+    /// This sends a Binding request to `server` and inspects the response
+    /// (see `ServerCapabilities` for the details).
+    /// It is useful for adapting the behavior of the client to the server
+    /// (e.g., before relying on RFC 5780 features or authentication).
+    ///
+    /// Note that the request goes through the decorator of the client (see `set_decorator`),
+    /// so a server that requires authentication does not challenge the request if the decorator
+    /// adds valid credentials to it.
+    ///
+    /// # Errors
+    ///
+    /// If the server does not respond before the transaction times out,
+    /// the returned future will fail with
+    /// an `ErrorKind::InvalidMessage(MessageErrorKind::Timeout)` error.
+    pub fn probe_capabilities(
+        &self,
+        server: T::PeerAddr,
+    ) -> impl Future<Item = ServerCapabilities, Error = Error>
+    where
+        A: TryAsRef<ErrorCode>
+            + TryAsRef<Realm>
+            + TryAsRef<Software>
+            + TryAsRef<XorMappedAddress>
+            + TryAsRef<OtherAddress>
+            + TryAsRef<ResponseOrigin>,
+    {
+        let request = Request::new(rfc5389::methods::BINDING);
+        self.call_raw(server, request)
+            .map(|response| match response {
+                Ok(response) => ServerCapabilities::from_message(response.as_ref()),
+                Err(response) => ServerCapabilities::from_message(response.as_ref()),
+            })
+    }
+
+    /// Discovers the reflexive (mapped) address of the client by sending a Binding request
+    /// to `server`.
+    ///
+    /// See also `expected_reflexive_address`.
+    ///
+    /// # Errors
+    ///
+    /// If the server replies an error response or a response without `XOR-MAPPED-ADDRESS`,
+    /// or the address violates the strict expectation of the client,
+    /// the returned future will fail.
+    pub fn discover_reflexive_address(
+        &self,
+        server: T::PeerAddr,
+    ) -> impl Future<Item = SocketAddr, Error = Error>
+    where
+        A: TryAsRef<ErrorCode> + TryAsRef<XorMappedAddress>,
+    {
+        let expected = self.expected_reflexive_address.clone();
+        let request = Request::new(rfc5389::methods::BINDING);
+        self.call_raw(server, request).and_then(move |response| {
+            let response = track!(response.map_err(error_response_to_err))?;
+            let mapped = response
+                .get_attribute::<XorMappedAddress>()
+                .map(|a| a.address());
+            let mapped = track_assert_some!(
+                mapped,
+                ErrorKind::Other,
+                "No XOR-MAPPED-ADDRESS attribute"
+            );
+            track!(check_reflexive_address(&expected, mapped))?;
+            Ok(mapped)
+        })
+    }
+
+    /// Discovers the reflexive (mapped) address of the client, and keeps the NAT binding alive.
+    ///
+    /// The returned future sends a Binding request to `server` every `interval`.
+    /// Because all requests are sent from the same socket (i.e., the transporter of the client),
+    /// they keep the mapping of the NAT between the client and the server alive,
+    /// provided that `interval` is shorter than the binding timeout of the NAT.
+    ///
+    /// The `XOR-MAPPED-ADDRESS` of each response is compared with the previous one,
+    /// and `on_change(previous, current)` is called when the address is discovered for
+    /// the first time (`previous` is `None` in that case) or has changed.
+    /// A change indicates that the NAT has rebound the mapping
+    /// (e.g., because the previous mapping expired), so the new address should be
+    /// advertised to the peers that rely on it.
+    ///
+    /// Timeouts of individual requests do not stop the keepalives
+    /// (the next request is sent after `interval`), since the connectivity may recover.
+    ///
+    /// If the keepalive mode of the client is `KeepaliveMode::IdleOnly`
+    /// (see `keepalive_mode`), the requests are only sent when no other message has been
+    /// sent to `server` within `interval`.
+    /// The first request is always sent to discover the address.
+    ///
+    /// The returned future never completes successfully;
+    /// drop it to stop the keepalives.
+    ///
+    /// # Errors
+    ///
+    /// If the server replies an error response or a response without `XOR-MAPPED-ADDRESS`,
+    /// the address violates the strict expectation of the client
+    /// (see `expected_reflexive_address`), or the channel fails, the returned future will fail.
+    pub fn keep_binding_alive<F>(
+        &self,
+        server: T::PeerAddr,
+        interval: Duration,
+        on_change: F,
+    ) -> impl Future<Item = Never, Error = Error>
+    where
+        A: TryAsRef<ErrorCode> + TryAsRef<XorMappedAddress>,
+        F: FnMut(Option<SocketAddr>, SocketAddr) + Send + 'static,
+    {
+        let client = self.clone();
+        let expected = self.expected_reflexive_address.clone();
+        let watch = if self.keepalive_mode == KeepaliveMode::IdleOnly {
+            Some(LastSent::watch(&self.last_sent, server.clone()))
+        } else {
+            None
+        };
+        future::loop_fn((None, on_change), move |(mapped, mut on_change)| {
+            if let (Some(watch), Some(_)) = (watch.as_ref(), mapped) {
+                let idle = watch.get().map(|t| t.elapsed());
+                if let Some(idle) = idle {
+                    if idle < interval {
+                        debug!("Keepalive skipped: other traffic has been sent recently");
+                        let future = timer::timeout(interval - idle)
+                            .map_err(|e| track!(Error::from(ErrorKind::Other.cause(e))))
+                            .map(move |()| Loop::Continue((mapped, on_change)));
+                        return Either::A(future);
+                    }
+                }
+            }
+
+            let request = Request::new(rfc5389::methods::BINDING);
+            let expected = expected.clone();
+            let future = client
+                .call_raw(server.clone(), request)
+                .then(move |result| {
+                    let mapped = match result {
+                        Err(ref e) if is_timeout(e) => {
+                            debug!("Keepalive Binding request timed out");
+                            mapped
+                        }
+                        Err(e) => return Err(track!(e)),
+                        Ok(Err(response)) => return Err(track!(error_response_to_err(response))),
+                        Ok(Ok(response)) => {
+                            let current = response
+                                .get_attribute::<XorMappedAddress>()
+                                .map(|a| a.address());
+                            let current = track_assert_some!(
+                                current,
+                                ErrorKind::Other,
+                                "No XOR-MAPPED-ADDRESS attribute"
+                            );
+                            track!(check_reflexive_address(&expected, current))?;
+                            if mapped != Some(current) {
+                                on_change(mapped, current);
+                            }
+                            Some(current)
+                        }
+                    };
+                    Ok((mapped, on_change))
+                }).and_then(move |state| {
+                    timer::timeout(interval)
+                        .map_err(|e| track!(Error::from(ErrorKind::Other.cause(e))))
+                        .map(move |()| Loop::Continue(state))
+                });
+            Either::B(future)
+        })
+    }
+
+    /// Watches the reflexive (mapped) address of the client, and notifies its changes.
+    ///
+    /// This runs `keep_binding_alive` with `server` and `interval`,
+    /// and calls `on_change(old, new)` whenever the address differs from the last observed one
+    /// (the first discovery of the address is not notified).
+    /// It is useful for re-signaling the new address to the peers when the NAT has rebound
+    /// the mapping.
+    ///
+    /// The watch continues until the returned future is dropped or cancelled
+    /// via a `WatcherHandle` (see `ReflexiveAddressWatcher::handle`).
+    /// The latter is convenient when the future has been spawned.
+    pub fn watch_reflexive_address<F>(
+        &self,
+        server: T::PeerAddr,
+        interval: Duration,
+        mut on_change: F,
+    ) -> ReflexiveAddressWatcher
+    where
+        A: TryAsRef<ErrorCode> + TryAsRef<XorMappedAddress>,
+        F: FnMut(SocketAddr, SocketAddr) + Send + 'static,
+    {
+        let future = self.keep_binding_alive(server, interval, move |old, new| {
+            if let Some(old) = old {
+                on_change(old, new);
+            }
+        });
+        let (cancel_tx, cancel_rx) = mpsc::channel();
+        ReflexiveAddressWatcher {
+            future: Box::new(future),
+            cancel_tx,
+            cancel_rx,
+        }
+    }
+
+    /// Returns a future that yields the metadata of the outstanding transactions of the channel
+    /// used by the client (and its clones).
+    ///
+    /// See `Channel::pending_transactions` for details.
+    ///
+    /// # Errors
+    ///
+    /// If the channel has dropped or failed, the returned future will fail.
+    pub fn pending_transactions(
+        &self,
+    ) -> impl Future<Item = Vec<PendingTransaction<T::PeerAddr>>, Error = Error> {
+        let (tx, rx) = oneshot::monitor();
+        let command = Command::PendingTransactions(tx);
         track!(self.command_tx.send(command).map_err(Error::from))
             .into_future()
             .and_then(move |()| rx.map_err(|e| track!(Error::from(e))))
@@ -67,25 +875,873 @@ where
 
     /// Sends the given indication message to the destination peer.
     ///
+    /// # Ordering
+    ///
+    /// The indications cast by a thread are handed to the channel in the order in which
+    /// `cast` (or `cast_many`) is called, even if other clones of the client are concurrently
+    /// used by other threads, because all clones of a client share a single FIFO command queue
+    /// consumed by the task driving the channel.
+    ///
+    /// To guarantee that the indications to each peer are also written by the transporter
+    /// in that order, build the channel with `ChannelBuilder::ordered_casts` enabled.
+    /// Note that this guarantees the order of writes, not the order of delivery.
+    /// For example, UDP datagrams may be reordered or lost by the network.
+    ///
     /// # Errors
     ///
     /// If the channel being used by the client has dropped,
     /// this will return an `ErrorKind::Other` error.
+    ///
+    /// If the number of the queued indications has reached the limit
+    /// (see `with_max_queued_indications`), this will return an `ErrorKind::ResourceLimit` error.
     pub fn cast(&self, peer: T::PeerAddr, indication: Indication<A>) -> Result<()> {
+        track!(self.reserve_indications(1))?;
+        let indication = self.decorate_indication(indication);
         let command = Command::Cast(peer, indication);
-        track!(self.command_tx.send(command).map_err(Error::from))
+        track!(self.send_indication_command(command, 1))
+    }
+
+    /// Sends the given indication messages to their destination peers.
+    ///
+    /// All of the indications are queued to the underlying channel as a single command,
+    /// so this is cheaper than calling `cast` for each of them (e.g., when broadcasting to many peers).
+    ///
+    /// The indications are handed to the channel in the iteration order.
+    /// If the channel fails to send one of them, the failure is ignored (as is the case with `cast`)
+    /// and the remaining indications are still sent.
+    ///
+    /// # Errors
+    ///
+    /// If the channel being used by the client has dropped,
+    /// this will return an `ErrorKind::Other` error and none of the indications will be sent.
+    ///
+    /// If queueing the indications would exceed the limit (see `with_max_queued_indications`),
+    /// this will return an `ErrorKind::ResourceLimit` error
+    /// and none of the indications will be sent.
+    pub fn cast_many<I>(&self, indications: I) -> Result<()>
+    where
+        I: IntoIterator<Item = (T::PeerAddr, Indication<A>)>,
+    {
+        let indications = indications
+            .into_iter()
+            .map(|(peer, indication)| (peer, self.decorate_indication(indication)))
+            .collect::<Vec<_>>();
+        if indications.is_empty() {
+            return Ok(());
+        }
+        let count = indications.len();
+        track!(self.reserve_indications(count))?;
+        let command = Command::CastMany(indications);
+        track!(self.send_indication_command(command, count))
+    }
+
+    /// Sends the given indication message to the destination peer unless `deadline` has passed.
+    ///
+    /// This is useful for the indications that become useless if they are delayed
+    /// (e.g., keepalives whose interval has already passed).
+    /// If the indication is still queued in the client when `deadline` passes
+    /// (e.g., because the task driving the channel is congested),
+    /// it is dropped instead of being handed to the channel,
+    /// and is counted by `expired_indications`.
+    ///
+    /// Note that the deadline is checked only when the indication is handed to the channel.
+    /// After that, the indication is sent by the transporter regardless of the deadline.
+    ///
+    /// The ordering and errors are the same as `cast`.
+    pub fn cast_with_deadline(
+        &self,
+        peer: T::PeerAddr,
+        indication: Indication<A>,
+        deadline: Instant,
+    ) -> Result<()> {
+        track!(self.reserve_indications(1))?;
+        let indication = self.decorate_indication(indication);
+        let command = Command::CastWithDeadline(peer, indication, deadline);
+        track!(self.send_indication_command(command, 1))
+    }
+
+    fn reserve_indications(&self, count: usize) -> Result<()> {
+        let queued = self.queued_indications.fetch_add(count, Ordering::SeqCst);
+        if queued.saturating_add(count) > self.max_queued_indications {
+            self.queued_indications.fetch_sub(count, Ordering::SeqCst);
+            track_panic!(
+                ErrorKind::ResourceLimit,
+                "Too many queued indications: queued={}, limit={}",
+                queued,
+                self.max_queued_indications
+            );
+        }
+        Ok(())
+    }
+
+    fn send_indication_command(
+        &self,
+        command: Command<A, T::PeerAddr>,
+        count: usize,
+    ) -> Result<()> {
+        let result = track!(self.command_tx.send(command).map_err(Error::from));
+        if result.is_err() {
+            self.queued_indications.fetch_sub(count, Ordering::SeqCst);
+        }
+        result
+    }
+}
+impl<A> UdpClient<A>
+where
+    A: Attribute + Send + 'static,
+    A::Decoder: Send + 'static,
+    A::Encoder: Send + 'static,
+{
+    /// Binds a UDP socket to `bind_addr`, and makes a new `Client` instance that uses the socket.
+    ///
+    /// This is a shorthand for binding a `fibers_transport::UdpTransporter`
+    /// via `StunUdpTransporterBuilder::bind` with the default settings,
+    /// and wrapping it in a `Channel` with the default settings.
+    /// If you need to customize some of them, compose the client by hand as this method does.
+    ///
+    /// If the port of `bind_addr` is `0`, the socket is bound to an ephemeral port.
+    ///
+    /// # Errors
+    ///
+    /// If `bind_addr` is already in use, the returned future will fail with
+    /// an `ErrorKind::AddrInUse` error.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// # extern crate fibers_global;
+    /// # extern crate rustun;
+    /// # extern crate stun_codec;
+    /// use rustun::client::UdpClient;
+    /// use rustun::message::Request;
+    /// use stun_codec::rfc5389;
+    ///
+    /// # fn main() -> Result<(), rustun::Error> {
+    /// let bind_addr = "127.0.0.1:0".parse().unwrap();
+    /// let client = fibers_global::execute(UdpClient::udp(fibers_global::handle(), bind_addr))?;
+    ///
+    /// let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+    /// let _future = client.call("127.0.0.1:3478".parse().unwrap(), request);
+    /// # Ok(())
+    /// # }
+    /// ```
+    pub fn udp<S>(spawner: S, bind_addr: SocketAddr) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Clone + Send + 'static,
+    {
+        let port = match bind_addr.port() {
+            0 => UdpBindPort::Ephemeral,
+            port => UdpBindPort::Specific(port),
+        };
+        StunUdpTransporterBuilder::new()
+            .bind(bind_addr.ip(), port)
+            .map(move |transporter| Client::new(&spawner, Channel::new(transporter)))
+    }
+}
+
+/// A STUN client that uses UDP as the transport layer.
+///
+/// See `UdpClient::udp` for an easy way to make an instance.
+pub type UdpClient<A> = Client<
+    A,
+    StunUdpTransporter<A, fibers_transport::UdpTransporter<MessageEncoder<A>, MessageDecoder<A>>>,
+>;
+
+/// This trait allows for modifying the messages sent by a `Client` before they are encoded.
+///
+/// See `Client::set_decorator`.
+///
+/// Note that a decorator should not change the transaction ID of a message.
+#[allow(unused_variables)]
+pub trait DecorateRequest<A>: Send + Sync + 'static {
+    /// Modifies the given request message.
+    ///
+    /// The default implementation does nothing.
+    fn decorate_request(&self, request: &mut Request<A>) {}
+
+    /// Modifies the given indication message.
+    ///
+    /// The default implementation does nothing.
+    fn decorate_indication(&self, indication: &mut Indication<A>) {}
+}
+
+/// A decorator that signs requests with a per-call key after applying the client-wide decorator.
+struct IntegrityDecorator<A> {
+    inner: Option<Arc<dyn DecorateRequest<A>>>,
+    key: IntegrityKey,
+}
+impl<A> DecorateRequest<A> for IntegrityDecorator<A>
+where
+    A: Attribute
+        + TryAsRef<MessageIntegrity>
+        + From<MessageIntegrity>
+        + From<Fingerprint>
+        + 'static,
+{
+    fn decorate_request(&self, request: &mut Request<A>) {
+        if let Some(ref d) = self.inner {
+            d.decorate_request(request);
+        }
+        let mut signed = Request::with_transaction_id(request.method(), request.transaction_id());
+        let mut fingerprinted = false;
+        for attribute in request.attributes() {
+            let attribute_type = attribute.get_type().as_u16();
+            if attribute_type == Fingerprint::CODEPOINT {
+                fingerprinted = true;
+            } else if attribute_type != MessageIntegrity::CODEPOINT {
+                signed.add_attribute(attribute.clone());
+            }
+        }
+        match track!(self.key.compute(signed.as_ref())) {
+            Err(e) => warn!("Cannot compute MESSAGE-INTEGRITY: {}", e),
+            Ok(integrity) => signed.add_attribute(integrity.into()),
+        }
+
+        // `FINGERPRINT` must follow `MESSAGE-INTEGRITY`, and covers it
+        if fingerprinted {
+            match Fingerprint::new(signed.as_ref()) {
+                Err(e) => warn!("Cannot compute FINGERPRINT: {}", e),
+                Ok(fingerprint) => signed.add_attribute(fingerprint.into()),
+            }
+        }
+        *request = signed;
+    }
+
+    fn decorate_indication(&self, indication: &mut Indication<A>) {
+        if let Some(ref d) = self.inner {
+            d.decorate_indication(indication);
+        }
+    }
+}
+
+/// Object-safe interface of STUN clients.
+///
+/// This allows for handling clients that use different transports in a uniform manner
+/// (see `BoxedClient`).
+pub trait StunClient<A, P> {
+    /// Same as `Client::call`.
+    fn call(
+        &self,
+        peer: P,
+        request: Request<A>,
+    ) -> Box<dyn Future<Item = Response<A>, Error = Error> + Send + 'static>;
+
+    /// Same as `Client::cast`.
+    fn cast(&self, peer: P, indication: Indication<A>) -> Result<()>;
+}
+impl<A, T> StunClient<A, T::PeerAddr> for Client<A, T>
+where
+    A: Attribute + Send + 'static,
+    T: StunTransport<A> + Send + 'static,
+    T::PeerAddr: Send + 'static,
+{
+    fn call(
+        &self,
+        peer: T::PeerAddr,
+        request: Request<A>,
+    ) -> Box<dyn Future<Item = Response<A>, Error = Error> + Send + 'static> {
+        Box::new(Client::call(self, peer, request))
+    }
+
+    fn cast(&self, peer: T::PeerAddr, indication: Indication<A>) -> Result<()> {
+        track!(Client::cast(self, peer, indication))
+    }
+}
+
+/// A boxed STUN client of which the transport type is erased.
+///
+/// Note that the peer address type still depends on the transport
+/// (e.g., `SocketAddr` for UDP and `()` for TCP).
+///
+/// # Examples
+///
+/// ```no_run
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers_transport::UdpTransporter;
+/// use futures::Future;
+/// use rustun::channel::Channel;
+/// use rustun::client::{BoxedClient, Client};
+/// use rustun::transport::StunUdpTransporter;
+/// use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+///
+/// # fn main() {
+/// let bind_addr = "127.0.0.1:0".parse().unwrap();
+/// let transporter = fibers_global::execute(UdpTransporter::<
+///     MessageEncoder<rfc5389::Attribute>,
+///     MessageDecoder<rfc5389::Attribute>,
+/// >::bind(bind_addr))
+/// .unwrap();
+/// let channel = Channel::new(StunUdpTransporter::new(transporter));
+/// let clients: Vec<BoxedClient<rfc5389::Attribute>> =
+///     vec![Client::new(&fibers_global::handle(), channel).boxed()];
+/// # let _ = clients;
+/// # }
+/// ```
+pub type BoxedClient<A, P = SocketAddr> = Box<dyn StunClient<A, P> + Send + 'static>;
+
+/// Mode of the keepalives sent by `Client::keep_binding_alive`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Default)]
+pub enum KeepaliveMode {
+    /// Sends a keepalive every interval unconditionally.
+    ///
+    /// This is the default.
+    #[default]
+    Always,
+
+    /// Sends a keepalive only if no other message has been sent to the server within the interval.
+    ///
+    /// Any outgoing message refreshes the NAT binding as well as a keepalive,
+    /// so this saves the bandwidth of active clients.
+    /// Note that only the messages sent via the client (and its clones) are taken into account
+    /// (see `Client::last_sent_time`).
+    IdleOnly,
+}
+
+/// Retry policy used by `Client::call_with_retry`.
+#[derive(Debug, Clone)]
+pub struct RetryPolicy {
+    /// The maximum number of the retries.
+    pub max_retries: usize,
+
+    /// The delay before the first retry.
+    ///
+    /// The delay is doubled for each subsequent retry.
+    /// It is used only if the server does not suggest a delay.
+    pub initial_delay: Duration,
+
+    /// The maximum delay before a retry.
+    ///
+    /// Both the delays determined by the policy and the ones suggested by the server
+    /// are capped at this value, so a misbehaving server cannot stall the client indefinitely.
+    pub max_delay: Duration,
+}
+impl RetryPolicy {
+    fn delay(&self, retries: usize) -> Duration {
+        self.initial_delay
+            .checked_mul(1 << retries.min(16))
+            .map_or(self.max_delay, |delay| self.clamp(delay))
+    }
+
+    fn clamp(&self, delay: Duration) -> Duration {
+        delay.min(self.max_delay)
+    }
+}
+impl Default for RetryPolicy {
+    /// Returns a policy that retries at most 3 times,
+    /// with the initial delay of 500 milliseconds and the maximum delay of 30 seconds.
+    fn default() -> Self {
+        RetryPolicy {
+            max_retries: 3,
+            initial_delay: Duration::from_millis(500),
+            max_delay: Duration::from_secs(30),
+        }
+    }
+}
+
+fn is_server_error<A>(response: &ErrorResponse<A>) -> bool
+where
+    A: Attribute + TryAsRef<ErrorCode>,
+{
+    response
+        .get_attribute::<ErrorCode>()
+        .map_or(false, |e| e.code() == ServerError::CODEPOINT)
+}
+
+fn is_timeout(e: &Error) -> bool {
+    matches!(
+        *e.kind(),
+        ErrorKind::InvalidMessage(MessageErrorKind::Timeout)
+    )
+}
+
+fn renew_transaction_id<A: Attribute>(request: &Request<A>) -> Request<A> {
+    let mut renewed = Request::new(request.method());
+    for attribute in request.attributes() {
+        renewed.add_attribute(attribute.clone());
+    }
+    renewed
+}
+
+fn with_ice_role<A>(
+    request: &Request<A>,
+    role: IceRole,
+    tie_breaker: u64,
+    transaction_id: Option<TransactionId>,
+) -> Request<A>
+where
+    A: Attribute + From<IceControlling> + From<IceControlled>,
+{
+    let mut renewed = match transaction_id {
+        Some(id) => Request::with_transaction_id(request.method(), id),
+        None => Request::new(request.method()),
+    };
+    for attribute in request.attributes() {
+        let t = attribute.get_type().as_u16();
+        if t != IceControlling::CODEPOINT && t != IceControlled::CODEPOINT {
+            renewed.add_attribute(attribute.clone());
+        }
+    }
+    renewed.add_attribute(role.to_attribute(tie_breaker));
+    renewed
+}
+
+fn verify_response_integrity<A>(response: &Response<A>, key: &IntegrityKey) -> Result<()>
+where
+    A: Attribute + TryAsRef<MessageIntegrity>,
+{
+    let integrity = match *response {
+        Ok(ref m) => m.get_attribute::<MessageIntegrity>(),
+        Err(ref m) => m.get_attribute::<MessageIntegrity>(),
+    };
+    let integrity = track_assert_some!(
+        integrity,
+        ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput),
+        "The response has no MESSAGE-INTEGRITY attribute"
+    );
+    track_assert!(
+        key.verify(integrity),
+        ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput),
+        "MESSAGE-INTEGRITY of the response does not match the key"
+    );
+    Ok(())
+}
+
+fn verify_response_software<A>(response: &Response<A>, expected: &str) -> Result<()>
+where
+    A: Attribute + TryAsRef<Software>,
+{
+    let software = match *response {
+        Ok(ref m) => m.get_attribute::<Software>(),
+        Err(ref m) => m.get_attribute::<Software>(),
+    };
+    let software = track_assert_some!(
+        software,
+        ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput),
+        "The response has no SOFTWARE attribute (expected: {:?})",
+        expected
+    );
+    track_assert_eq!(
+        software.description(),
+        expected,
+        ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput),
+        "SOFTWARE of the response does not match the expected one"
+    );
+    Ok(())
+}
+
+fn error_response_to_err<A>(response: ErrorResponse<A>) -> Error
+where
+    A: Attribute + TryAsRef<ErrorCode>,
+{
+    match response.get_attribute::<ErrorCode>() {
+        Some(code) => track!(Error::from(code.clone())),
+        None => {
+            // The `ERROR-CODE` attribute was not decoded as a known attribute
+            track!(Error::from(
+                ErrorKind::Other.cause("STUN error response (ERROR-CODE is unavailable)")
+            ))
+        }
+    }
+}
+
+/// Future that watches the reflexive address of a client.
+///
+/// This is created by `Client::watch_reflexive_address`,
+/// and completes successfully when the watch is cancelled.
+#[must_use = "future do nothing unless polled"]
+pub struct ReflexiveAddressWatcher {
+    future: Box<dyn Future<Item = Never, Error = Error> + Send + 'static>,
+    cancel_tx: mpsc::Sender<()>,
+    cancel_rx: mpsc::Receiver<()>,
+}
+impl ReflexiveAddressWatcher {
+    /// Returns a handle for cancelling the watch.
+    pub fn handle(&self) -> WatcherHandle {
+        WatcherHandle {
+            cancel_tx: self.cancel_tx.clone(),
+        }
+    }
+}
+impl Future for ReflexiveAddressWatcher {
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(Some(())) = self.cancel_rx.poll().expect("never fails") {
+            return Ok(Async::Ready(()));
+        }
+        if let Async::Ready(_) = track!(self.future.poll())? {
+            unreachable!("`keep_binding_alive` never completes successfully");
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl fmt::Debug for ReflexiveAddressWatcher {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ReflexiveAddressWatcher {{ .. }}")
+    }
+}
+
+/// Handle for cancelling a `ReflexiveAddressWatcher`.
+#[derive(Debug, Clone)]
+pub struct WatcherHandle {
+    cancel_tx: mpsc::Sender<()>,
+}
+impl WatcherHandle {
+    /// Cancels the watch.
+    ///
+    /// The corresponding `ReflexiveAddressWatcher` completes successfully,
+    /// and no more Binding requests are sent by it.
+    /// If the watcher has already completed, this has no effect.
+    pub fn cancel(&self) {
+        let _ = self.cancel_tx.send(());
+    }
+}
+
+/// Expectation on the reflexive (mapped) addresses reported by servers.
+///
+/// This is used by `Client::expected_reflexive_address`.
+/// An address satisfies the expectation if its IP address is within one of the allowed prefixes
+/// (or no prefix is allowed) and its port equals the expected port (if any).
+///
+/// A reflexive address that does not satisfy the expectation is logged at the `warn` level.
+/// In the strict mode, the operation that received it also fails with
+/// an `ErrorKind::Other` error.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rustun;
+/// use rustun::client::ExpectedAddress;
+///
+/// # fn main() {
+/// let mut expected = ExpectedAddress::new();
+/// expected
+///     .allow_prefix("203.0.113.0".parse().unwrap(), 24)
+///     .port(Some(40000))
+///     .strict(true);
+/// assert!(expected.contains("203.0.113.5:40000".parse().unwrap()));
+/// assert!(!expected.contains("198.51.100.5:40000".parse().unwrap()));
+/// assert!(!expected.contains("203.0.113.5:40001".parse().unwrap()));
+/// # }
+/// ```
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ExpectedAddress {
+    prefixes: Vec<(IpAddr, u8)>,
+    port: Option<u16>,
+    strict: bool,
+}
+impl ExpectedAddress {
+    /// Makes a new `ExpectedAddress` instance that accepts any address.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Allows the given IP address.
+    pub fn allow_address(&mut self, addr: IpAddr) -> &mut Self {
+        let len = if addr.is_ipv4() { 32 } else { 128 };
+        self.allow_prefix(addr, len)
+    }
+
+    /// Allows the IP addresses of which the first `len` bits are the same as those of `prefix`.
+    ///
+    /// `len` is truncated to the bit length of the address family of `prefix`.
+    pub fn allow_prefix(&mut self, prefix: IpAddr, len: u8) -> &mut Self {
+        self.prefixes.push((prefix, len));
+        self
+    }
+
+    /// Sets the expected port.
+    ///
+    /// The default value is `None` (i.e., any port is accepted).
+    pub fn port(&mut self, port: Option<u16>) -> &mut Self {
+        self.port = port;
+        self
+    }
+
+    /// Sets whether a mismatch is regarded as an error (`true`) or just logged (`false`).
+    ///
+    /// The default value is `false`.
+    pub fn strict(&mut self, strict: bool) -> &mut Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Returns `true` if the given address satisfies the expectation, otherwise `false`.
+    pub fn contains(&self, addr: SocketAddr) -> bool {
+        if self.port.map_or(false, |port| port != addr.port()) {
+            return false;
+        }
+        self.prefixes.is_empty()
+            || self
+                .prefixes
+                .iter()
+                .any(|&(prefix, len)| is_in_prefix(addr.ip(), prefix, len))
+    }
+}
+
+fn is_in_prefix(addr: IpAddr, prefix: IpAddr, len: u8) -> bool {
+    let (addr, prefix, bits) = match (addr, prefix) {
+        (IpAddr::V4(a), IpAddr::V4(p)) => (u128::from(u32::from(a)), u128::from(u32::from(p)), 32),
+        (IpAddr::V6(a), IpAddr::V6(p)) => (u128::from(a), u128::from(p), 128),
+        _ => return false,
+    };
+    let len = u32::from(len).min(bits);
+    if len == 0 {
+        return true;
+    }
+    let shift = bits - len;
+    (addr >> shift) == (prefix >> shift)
+}
+
+fn check_reflexive_address(
+    expected: &Option<Arc<ExpectedAddress>>,
+    addr: SocketAddr,
+) -> Result<()> {
+    if let Some(ref expected) = *expected {
+        if !expected.contains(addr) {
+            warn!("Unexpected reflexive address: {}", addr);
+            track_assert!(
+                !expected.strict,
+                ErrorKind::Other,
+                "Unexpected reflexive address: {}",
+                addr
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Capabilities of a STUN server discovered by `Client::probe_capabilities`.
+///
+/// The capabilities are derived from the response to a Binding request.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct ServerCapabilities {
+    /// The code of the `ERROR-CODE` attribute if the server replied an error response.
+    ///
+    /// This is `None` if the server replied a success response.
+    pub error_code: Option<u16>,
+
+    /// The address conveyed by the `XOR-MAPPED-ADDRESS` attribute of the response, if any.
+    pub mapped_address: Option<SocketAddr>,
+
+    /// The address conveyed by the `OTHER-ADDRESS` attribute of the response, if any.
+    ///
+    /// > The OTHER-ADDRESS attribute is used in Binding Responses.  It informs
+    /// > the client of the source IP address and port that would be used if
+    /// > the client requested the "change IP" and "change port" behavior.
+    /// >
+    /// > [RFC 5780 -- 7.4. OTHER-ADDRESS]
+    ///
+    /// [RFC 5780 -- 7.4. OTHER-ADDRESS]: https://tools.ietf.org/html/rfc5780#section-7.4
+    pub other_address: Option<SocketAddr>,
+
+    /// The address conveyed by the `RESPONSE-ORIGIN` attribute of the response, if any.
+    pub response_origin: Option<SocketAddr>,
+
+    /// The description conveyed by the `SOFTWARE` attribute of the response, if any.
+    pub software: Option<String>,
+
+    /// The realm conveyed by the `REALM` attribute of the response, if any.
+    ///
+    /// A server that uses the long-term credential mechanism includes this in its challenge.
+    pub realm: Option<String>,
+}
+impl ServerCapabilities {
+    /// Returns `true` if the server replied a success response to the Binding request.
+    pub fn supports_binding(&self) -> bool {
+        self.error_code.is_none()
+    }
+
+    /// Returns `true` if the server challenged the Binding request with
+    /// a `401 Unauthorized` error response (i.e., the server requires authentication).
+    pub fn requires_authentication(&self) -> bool {
+        self.error_code == Some(Unauthorized::CODEPOINT)
+    }
+
+    /// Returns `true` if the server supports NAT behavior discovery
+    /// (i.e., the response contained both `OTHER-ADDRESS` and `RESPONSE-ORIGIN` attributes).
+    ///
+    /// A server that has no alternate address does not include `OTHER-ADDRESS`,
+    /// so the tests that require "change IP" and "change port" behaviors cannot be performed
+    /// against such a server.
+    pub fn supports_nat_behavior_discovery(&self) -> bool {
+        self.other_address.is_some() && self.response_origin.is_some()
+    }
+
+    fn from_message<A>(message: &Message<A>) -> Self
+    where
+        A: Attribute
+            + TryAsRef<ErrorCode>
+            + TryAsRef<Realm>
+            + TryAsRef<Software>
+            + TryAsRef<XorMappedAddress>
+            + TryAsRef<OtherAddress>
+            + TryAsRef<ResponseOrigin>,
+    {
+        ServerCapabilities {
+            error_code: message.get_attribute::<ErrorCode>().map(|a| a.code()),
+            mapped_address: message
+                .get_attribute::<XorMappedAddress>()
+                .map(|a| a.address()),
+            other_address: message.get_attribute::<OtherAddress>().map(|a| a.address()),
+            response_origin: message
+                .get_attribute::<ResponseOrigin>()
+                .map(|a| a.address()),
+            software: message
+                .get_attribute::<Software>()
+                .map(|a| a.description().to_owned()),
+            realm: message
+                .get_attribute::<Realm>()
+                .map(|a| a.text().to_owned()),
+        }
+    }
+}
+
+/// The outcome of a transaction issued by `Client::call_detailed`.
+#[derive(Debug)]
+pub enum CallOutcome<A> {
+    /// A response (either a success or an error response) has been received.
+    Completed(Response<A>),
+
+    /// No response has been received before the transaction timed out.
+    TimedOut,
+
+    /// The transaction has failed for a reason other than a timeout
+    /// (e.g., the underlying transport has been broken).
+    TransportError(Error),
+}
+
+/// The times when the messages were last sent to the peers watched by idle-only keepalives.
+///
+/// Only the watched peers are recorded, so the map is bounded by the number of the keepalives
+/// and no lock is taken for sending messages while no peer is watched.
+struct LastSent<P> {
+    watchers: AtomicUsize,
+    peers: Mutex<HashMap<P, WatchedPeer>>,
+}
+impl<P: Clone + Eq + Hash> LastSent<P> {
+    fn new() -> Self {
+        LastSent {
+            watchers: AtomicUsize::new(0),
+            peers: Mutex::new(HashMap::new()),
+        }
+    }
+
+    fn watch(this: &Arc<Self>, peer: P) -> LastSentWatch<P> {
+        this.watchers.fetch_add(1, Ordering::SeqCst);
+        let mut peers = this.lock();
+        let p = peers.entry(peer.clone()).or_insert(WatchedPeer {
+            watchers: 0,
+            last_sent: None,
+        });
+        p.watchers += 1;
+        LastSentWatch {
+            last_sent: this.clone(),
+            peer,
+        }
+    }
+
+    fn record(&self, peer: &P) {
+        if self.watchers.load(Ordering::SeqCst) == 0 {
+            return;
+        }
+        if let Some(p) = self.lock().get_mut(peer) {
+            p.last_sent = Some(Instant::now());
+        }
+    }
+
+    fn get(&self, peer: &P) -> Option<Instant> {
+        self.lock().get(peer).and_then(|p| p.last_sent)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<P, WatchedPeer>> {
+        self.peers.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+struct WatchedPeer {
+    watchers: usize,
+    last_sent: Option<Instant>,
+}
+
+/// Keeps recording the send times of a peer until dropped.
+struct LastSentWatch<P: Clone + Eq + Hash> {
+    last_sent: Arc<LastSent<P>>,
+    peer: P,
+}
+impl<P: Clone + Eq + Hash> LastSentWatch<P> {
+    fn get(&self) -> Option<Instant> {
+        self.last_sent.get(&self.peer)
+    }
+}
+impl<P: Clone + Eq + Hash> Drop for LastSentWatch<P> {
+    fn drop(&mut self) {
+        let mut peers = self.last_sent.lock();
+        let unwatched = peers.get_mut(&self.peer).map_or(false, |p| {
+            p.watchers -= 1;
+            p.watchers == 0
+        });
+        if unwatched {
+            peers.remove(&self.peer);
+        }
+        self.last_sent.watchers.fetch_sub(1, Ordering::SeqCst);
     }
 }
 
 enum Command<A, P> {
     Call(P, Request<A>, oneshot::Monitored<Response<A>, Error>),
     Cast(P, Indication<A>),
+    CastMany(Vec<(P, Indication<A>)>),
+    CastWithDeadline(P, Indication<A>, Instant),
+    Cancel(P, TransactionId),
+    PendingTransactions(oneshot::Monitored<Vec<PendingTransaction<P>>, Error>),
 }
 impl<A, P> fmt::Debug for Command<A, P> {
     fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
         match self {
             Command::Call(..) => write!(f, "Call(..)"),
             Command::Cast(..) => write!(f, "Cast(..)"),
+            Command::CastMany(..) => write!(f, "CastMany(..)"),
+            Command::CastWithDeadline(..) => write!(f, "CastWithDeadline(..)"),
+            Command::Cancel(..) => write!(f, "Cancel(..)"),
+            Command::PendingTransactions(..) => write!(f, "PendingTransactions(..)"),
+        }
+    }
+}
+
+type CancelCall<A, P> = (mpsc::Sender<Command<A, P>>, P, TransactionId);
+
+/// A future that waits the response of a request sent by `Client::call`.
+///
+/// If this is dropped before completion, the corresponding transaction will be cancelled.
+struct Call<A, P> {
+    rx: oneshot::Monitor<Response<A>, Error>,
+    cancel: Option<CancelCall<A, P>>,
+}
+impl<A, P> Future for Call<A, P> {
+    type Item = Response<A>;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.rx.poll().map_err(|e| track!(Error::from(e)));
+        match result {
+            Ok(Async::NotReady) => {}
+            _ => {
+                self.cancel = None;
+            }
+        }
+        result
+    }
+}
+impl<A, P> Drop for Call<A, P> {
+    fn drop(&mut self) {
+        if let Some((command_tx, peer, transaction_id)) = self.cancel.take() {
+            let _ = command_tx.send(Command::Cancel(peer, transaction_id));
         }
     }
 }
@@ -98,6 +1754,9 @@ where
     spawner: S,
     channel: Result<Channel<A, T>>,
     command_rx: Fuse<mpsc::Receiver<Command<A, T::PeerAddr>>>,
+    queued_indications: Arc<AtomicUsize>,
+    expired_indications: Arc<AtomicUsize>,
+    last_sent: Arc<LastSent<T::PeerAddr>>,
 }
 impl<S, A, T> ChannelDriver<S, A, T>
 where
@@ -105,18 +1764,63 @@ where
     A: Attribute + Send + 'static,
     T: StunTransport<A> + Send + 'static,
 {
+    fn record_send(&self, peer: &T::PeerAddr) {
+        self.last_sent.record(peer);
+    }
+
     fn handle_command(&mut self, command: Command<A, T::PeerAddr>) {
         match command {
             Command::Cast(peer, indication) => {
+                self.queued_indications.fetch_sub(1, Ordering::SeqCst);
+                self.record_send(&peer);
                 if let Ok(channel) = self.channel.as_mut() {
                     let _ = channel.cast(peer, indication);
                 }
             }
+            Command::CastMany(indications) => {
+                self.queued_indications
+                    .fetch_sub(indications.len(), Ordering::SeqCst);
+                for (peer, _) in &indications {
+                    self.record_send(peer);
+                }
+                if let Ok(channel) = self.channel.as_mut() {
+                    for (peer, indication) in indications {
+                        let _ = channel.cast(peer, indication);
+                    }
+                }
+            }
+            Command::CastWithDeadline(peer, indication, deadline) => {
+                self.queued_indications.fetch_sub(1, Ordering::SeqCst);
+                if Instant::now() > deadline {
+                    self.expired_indications.fetch_add(1, Ordering::SeqCst);
+                    debug!(
+                        "STUN client: dropped an expired indication: transaction_id={:?}",
+                        indication.transaction_id()
+                    );
+                } else {
+                    self.record_send(&peer);
+                    if let Ok(channel) = self.channel.as_mut() {
+                        let _ = channel.cast(peer, indication);
+                    }
+                }
+            }
+            Command::Cancel(peer, transaction_id) => {
+                if let Ok(channel) = self.channel.as_mut() {
+                    if let Err(e) = track!(channel.cancel(&peer, transaction_id)) {
+                        self.channel = Err(e);
+                    }
+                }
+            }
+            Command::PendingTransactions(reply) => match self.channel {
+                Err(ref e) => reply.exit(Err(track!(e.clone()))),
+                Ok(ref channel) => reply.exit(Ok(channel.pending_transactions())),
+            },
             Command::Call(peer, request, reply) => match self.channel {
                 Err(ref e) => {
                     reply.exit(Err(track!(e.clone())));
                 }
                 Ok(ref mut channel) => {
+                    self.record_send(&peer);
                     let future =
                         channel
                             .call(peer, request)
@@ -179,3 +1883,29 @@ where
         Ok(Async::NotReady)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn retry_delay_is_capped() {
+        let policy = RetryPolicy::default();
+        assert_eq!(policy.delay(0), Duration::from_millis(500));
+        assert_eq!(policy.delay(2), Duration::from_secs(2));
+        assert_eq!(policy.delay(100), Duration::from_secs(30));
+        let hint = Duration::from_secs(10);
+        assert_eq!(policy.clamp(hint), hint);
+        let hint = Duration::from_secs(3600);
+        assert_eq!(policy.clamp(hint), Duration::from_secs(30));
+
+        // The exponential backoff does not overflow
+        let policy = RetryPolicy {
+            max_retries: 20,
+            initial_delay: Duration::from_secs(u64::MAX / 2),
+            max_delay: Duration::from_secs(60),
+        };
+        assert_eq!(policy.delay(0), Duration::from_secs(60));
+        assert_eq!(policy.delay(16), Duration::from_secs(60));
+    }
+}
@@ -1,15 +1,37 @@
 use bytecodec;
 use fibers::sync::oneshot::MonitorError;
 use fibers_transport;
+use std::error::Error as StdError;
 use std::io;
+use std::net::SocketAddr;
 use std::sync::mpsc::SendError;
 use stun_codec::rfc5389::attributes::ErrorCode;
 use stun_codec::AttributeType;
 use trackable::error::{self, ErrorKindExt, TrackableError};
+use trackable::{Location, Trackable};
 
 /// This crate specific `Error` type.
 #[derive(Debug, Clone, TrackableError)]
 pub struct Error(TrackableError<ErrorKind>);
+impl Error {
+    /// Returns the locations tracked by the error (e.g., by `track!`), oldest first.
+    ///
+    /// Each location has the module path, file and line where the error was tracked,
+    /// and the message attached there (if any).
+    pub fn tracked_locations(&self) -> &[Location] {
+        self.history().map_or(&[], |h| h.events())
+    }
+
+    /// Returns an iterator over the chain of the causes of the error, nearest first.
+    ///
+    /// This is useful for reporting the error in a structured form
+    /// rather than the flat string produced by `Display`.
+    pub fn causes(&self) -> ErrorCauses<'_> {
+        #[allow(deprecated)]
+        let next = self.0.cause();
+        ErrorCauses { next }
+    }
+}
 impl From<MonitorError<Error>> for Error {
     fn from(f: MonitorError<Error>) -> Self {
         f.unwrap_or_else(|| {
@@ -41,9 +63,11 @@ impl From<bytecodec::Error> for Error {
 }
 impl From<MessageError> for Error {
     fn from(f: MessageError) -> Self {
-        ErrorKind::InvalidMessage(f.kind().clone())
-            .takes_over(f)
-            .into()
+        let kind = match *f.kind() {
+            MessageErrorKind::ConnectionRefused => ErrorKind::ConnectionRefused,
+            ref kind => ErrorKind::InvalidMessage(kind.clone()),
+        };
+        kind.takes_over(f).into()
     }
 }
 impl From<ErrorCode> for Error {
@@ -64,6 +88,29 @@ impl From<fibers_transport::Error> for Error {
     }
 }
 
+/// An iterator over the chain of the causes of an `Error`.
+///
+/// This is created by calling `Error::causes`.
+#[derive(Debug)]
+pub struct ErrorCauses<'a> {
+    next: Option<&'a dyn StdError>,
+}
+impl<'a> Iterator for ErrorCauses<'a> {
+    type Item = &'a dyn StdError;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let current = self.next.take()?;
+
+        // NOTE: `TrackableError` only implements the (deprecated) `Error::cause` method,
+        // and the default implementation of it delegates to `Error::source`.
+        #[allow(deprecated)]
+        {
+            self.next = current.cause();
+        }
+        Some(current)
+    }
+}
+
 /// Possible error kinds.
 #[derive(Debug, Clone)]
 pub enum ErrorKind {
@@ -75,6 +122,22 @@ pub enum ErrorKind {
     /// This error does not affect the overall execution of a channel/client/server.
     InvalidMessage(MessageErrorKind),
 
+    /// The peer reported that the destination port is unreachable.
+    ///
+    /// This means that an ICMP port-unreachable message has been received in response to
+    /// a request sent over UDP (see `StunUdpTransporter::detect_unreachable_peers`).
+    ConnectionRefused,
+
+    /// The local address to be bound is already in use.
+    ///
+    /// The address is recorded in the tracking history of the error.
+    AddrInUse,
+
+    /// A resource limit has been exceeded (e.g., too many indications are queued).
+    ///
+    /// The operation may succeed if it is retried later.
+    ResourceLimit,
+
     /// Other errors.
     Other,
 }
@@ -96,7 +159,11 @@ impl From<MonitorError<MessageError>> for MessageError {
 impl From<Error> for MessageError {
     fn from(f: Error) -> Self {
         let original_error_kind = f.kind().clone();
-        track!(MessageErrorKind::Other.takes_over(f); original_error_kind).into()
+        let kind = match original_error_kind {
+            ErrorKind::ConnectionRefused => MessageErrorKind::ConnectionRefused,
+            _ => MessageErrorKind::Other,
+        };
+        track!(kind.takes_over(f); original_error_kind).into()
     }
 }
 impl From<fibers_transport::Error> for MessageError {
@@ -135,7 +202,49 @@ pub enum MessageErrorKind {
     /// Operation timed out.
     Timeout,
 
+    /// The destination peer of the transaction is unreachable.
+    ///
+    /// This is converted to `ErrorKind::ConnectionRefused` when converting to `Error`.
+    ConnectionRefused,
+
     /// Other errors.
     Other,
 }
 impl error::ErrorKind for MessageErrorKind {}
+
+/// Converts an error that occurred on binding a socket to `addr`.
+///
+/// If the address is already in use, the resulting error has the kind `ErrorKind::AddrInUse`.
+/// The address is recorded in the tracking history of the error.
+pub(crate) fn bind_error<E: Into<Error>>(e: E, addr: SocketAddr) -> Error {
+    let e = e.into();
+    let addr_in_use = e
+        .concrete_cause::<io::Error>()
+        .map_or(false, |e| e.kind() == io::ErrorKind::AddrInUse);
+    if addr_in_use {
+        track!(Error::from(ErrorKind::AddrInUse.takes_over(e)); addr)
+    } else {
+        track!(e; addr)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_exposes_tracked_locations_and_causes() {
+        let io_error = io::Error::new(io::ErrorKind::Other, "foo");
+        let e = track!(Error::from(io_error), "bar");
+        let e = track!(e);
+
+        let locations = e.tracked_locations();
+        assert_eq!(locations.len(), 2);
+        assert_eq!(locations[0].message(), "bar");
+        assert_eq!(locations[1].message(), "");
+        assert!(locations.iter().all(|l| l.file() == file!()));
+
+        let causes = e.causes().map(|c| c.to_string()).collect::<Vec<_>>();
+        assert_eq!(causes, ["foo"]);
+    }
+}
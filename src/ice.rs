@@ -0,0 +1,131 @@
+//! Helpers for the connectivity checks of ICE (Interactive Connectivity Establishment).
+//!
+//! This module implements the detection and repair of role conflicts described in
+//! [RFC 8445 -- 7.3.1.1. Detecting and Repairing Role Conflicts].
+//! See also `Client::call_connectivity_check` for the client side of the procedure.
+//!
+//! [RFC 8445 -- 7.3.1.1. Detecting and Repairing Role Conflicts]: https://tools.ietf.org/html/rfc8445#section-7.3.1.1
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5245::attributes::{IceControlled, IceControlling};
+use stun_codec::rfc5245::errors::RoleConflict;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::Attribute;
+
+use message::{ErrorResponse, Request};
+
+/// The role of an ICE agent.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum IceRole {
+    /// The controlling role (i.e., the agent that nominates the candidate pairs).
+    Controlling,
+
+    /// The controlled role.
+    Controlled,
+}
+impl IceRole {
+    /// Returns the other role.
+    pub fn opposite(self) -> Self {
+        match self {
+            IceRole::Controlling => IceRole::Controlled,
+            IceRole::Controlled => IceRole::Controlling,
+        }
+    }
+
+    /// Makes the attribute that asserts this role in a connectivity check
+    /// (i.e., `ICE-CONTROLLING` or `ICE-CONTROLLED` that conveys `tie_breaker`).
+    pub fn to_attribute<A>(self, tie_breaker: u64) -> A
+    where
+        A: From<IceControlling> + From<IceControlled>,
+    {
+        match self {
+            IceRole::Controlling => IceControlling::new(tie_breaker).into(),
+            IceRole::Controlled => IceControlled::new(tie_breaker).into(),
+        }
+    }
+
+    /// Returns the role asserted by the given request, along with the tie-breaker of the sender.
+    ///
+    /// If the request has neither `ICE-CONTROLLING` nor `ICE-CONTROLLED`, this will return `None`.
+    pub fn asserted_by<A>(request: &Request<A>) -> Option<(Self, u64)>
+    where
+        A: Attribute + TryAsRef<IceControlling> + TryAsRef<IceControlled>,
+    {
+        if let Some(a) = request.get_attribute::<IceControlling>() {
+            Some((IceRole::Controlling, a.prio()))
+        } else {
+            request
+                .get_attribute::<IceControlled>()
+                .map(|a| (IceRole::Controlled, a.prio()))
+        }
+    }
+}
+
+/// What an ICE agent should do with a received connectivity check.
+///
+/// See `resolve_role_conflict`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RoleConflictResolution {
+    /// There is no conflict, so the request should be processed as usual.
+    Proceed,
+
+    /// The agent should switch to the opposite role, and then process the request as usual.
+    SwitchRole,
+
+    /// The agent should keep its role and reply a `487 Role Conflict` error response
+    /// (see `role_conflict_response`).
+    RespondRoleConflict,
+}
+
+/// Determines how an ICE agent that has `role` and `tie_breaker` resolves the role conflict
+/// caused by the given connectivity check (if any).
+///
+/// A conflict occurs when the sender of the request asserts the same role as the agent.
+/// Then the agent having the larger tie-breaker (or the controlling agent if they are equal)
+/// keeps (or takes) the controlling role:
+///
+/// | local role  | request has        | local tie-breaker is ... | result                |
+/// |-------------|--------------------|--------------------------|-----------------------|
+/// | Controlling | `ICE-CONTROLLING`  | `>=` remote              | `RespondRoleConflict` |
+/// | Controlling | `ICE-CONTROLLING`  | `<` remote               | `SwitchRole`          |
+/// | Controlled  | `ICE-CONTROLLED`   | `>=` remote              | `SwitchRole`          |
+/// | Controlled  | `ICE-CONTROLLED`   | `<` remote               | `RespondRoleConflict` |
+///
+/// Otherwise (including the case that the request asserts no role), this returns `Proceed`.
+pub fn resolve_role_conflict<A>(
+    role: IceRole,
+    tie_breaker: u64,
+    request: &Request<A>,
+) -> RoleConflictResolution
+where
+    A: Attribute + TryAsRef<IceControlling> + TryAsRef<IceControlled>,
+{
+    match IceRole::asserted_by(request) {
+        Some((remote_role, remote_tie_breaker)) if remote_role == role => {
+            let local_wins = tie_breaker >= remote_tie_breaker;
+            match (role, local_wins) {
+                (IceRole::Controlling, true) | (IceRole::Controlled, false) => {
+                    RoleConflictResolution::RespondRoleConflict
+                }
+                (IceRole::Controlling, false) | (IceRole::Controlled, true) => {
+                    RoleConflictResolution::SwitchRole
+                }
+            }
+        }
+        _ => RoleConflictResolution::Proceed,
+    }
+}
+
+/// Makes a `487 Role Conflict` error response to the given request.
+///
+/// > The client asserted an ICE role (controlling or
+/// > controlled) that is in conflict with the role of the server.
+/// >
+/// > [RFC 5245 -- 21.3.  STUN Error Responses]
+///
+/// [RFC 5245 -- 21.3.  STUN Error Responses]: https://tools.ietf.org/html/rfc5245#section-21.3
+pub fn role_conflict_response<A>(request: &Request<A>) -> ErrorResponse<A>
+where
+    A: Attribute + From<ErrorCode>,
+{
+    ErrorResponse::new(request, RoleConflict.into())
+}
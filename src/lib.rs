@@ -78,15 +78,21 @@ extern crate fibers_global;
 extern crate fibers_timeout_queue;
 extern crate fibers_transport;
 extern crate futures;
+#[cfg(unix)]
+extern crate libc;
+#[macro_use]
+extern crate log;
 extern crate rand;
 extern crate stun_codec;
 #[macro_use]
 extern crate trackable;
 
-pub use error::{Error, ErrorKind};
+pub use error::{Error, ErrorCauses, ErrorKind};
 
+pub mod auth;
 pub mod channel;
 pub mod client;
+pub mod ice;
 pub mod message;
 pub mod server;
 pub mod transport;
@@ -98,22 +104,129 @@ pub type Result<T> = std::result::Result<T, Error>;
 
 #[cfg(test)]
 mod tests {
+    use bytecodec::{DecodeExt, EncodeExt};
     use factory::DefaultFactory;
     use fibers_global;
-    use fibers_transport::{TcpTransporter, UdpTransporter};
-    use futures::Future;
+    use fibers_transport::{
+        self, PollRecv, PollSend, TcpTransporter, Transport, UdpTransport, UdpTransporter,
+    };
+    use futures::{self, Async, Future, Poll};
+    use std::collections::VecDeque;
+    use std::io::{Read, Write};
+    use std::net::{self, SocketAddr, UdpSocket};
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::sync::{Arc, Mutex};
     use std::thread;
-    use std::time::Duration;
-    use stun_codec::rfc5389;
-    use stun_codec::{MessageDecoder, MessageEncoder};
+    use std::time::{Duration, Instant};
+    use stun_codec::rfc5389::attributes::{
+        ErrorCode, Fingerprint, MessageIntegrity, Software, Username, XorMappedAddress,
+    };
+    use stun_codec::{
+        rfc5389, rfc5780, Attribute, AttributeType, DecodedMessage, Message, MessageClass,
+        MessageDecoder, MessageEncoder, Method, TransactionId,
+    };
     use trackable::error::MainError;
 
-    use channel::Channel;
+    use auth::{Credentials, DuplicateIntegrityPolicy};
+    use channel::{
+        Channel, ChannelBuilder, FingerprintPolicy, RecvMessage, TransactionObserver,
+        UnexpectedResponsePolicy,
+    };
     use client::Client;
-    use message::Request;
+    use message::{Indication, MessageError, Request, Response, SuccessResponse};
     use server::{BindingHandler, TcpServer, UdpServer};
-    use transport::{StunTcpTransporter, StunUdpTransporter};
-    use Error;
+    use transport::{
+        AdaptiveRto, RtoStrategy, SplitTransporter, StunFrameDecoder, StunTcpTransporter,
+        StunUdpTransporter, StunUdpTransporterBuilder,
+    };
+    use {Error, ErrorKind};
+
+    type TestMessage = Message<rfc5389::Attribute>;
+
+    /// A UDP transporter that never touches the network.
+    ///
+    /// Messages handed by `start_send` are queued, and written (i.e., recorded in `written`)
+    /// by `poll_send` while `writable` is `true`.
+    /// `respond` can reply to each written message,
+    /// and `poll_recv` yields the replies along with the messages pushed onto `incoming`.
+    struct MockUdpTransporter {
+        writable: bool,
+        reverse_writes: bool,
+        queue: VecDeque<(SocketAddr, TestMessage)>,
+        written: Arc<Mutex<Vec<(SocketAddr, TestMessage)>>>,
+        incoming: VecDeque<(SocketAddr, DecodedMessage<rfc5389::Attribute>)>,
+        respond: Option<Box<dyn FnMut(&TestMessage) -> Option<TestMessage> + Send>>,
+    }
+    impl MockUdpTransporter {
+        fn written_ids(&self) -> Vec<(SocketAddr, TransactionId)> {
+            let written = self.written.lock().unwrap();
+            written
+                .iter()
+                .map(|&(peer, ref m)| (peer, m.transaction_id()))
+                .collect()
+        }
+    }
+    impl Default for MockUdpTransporter {
+        fn default() -> Self {
+            MockUdpTransporter {
+                writable: true,
+                reverse_writes: false,
+                queue: VecDeque::new(),
+                written: Arc::default(),
+                incoming: VecDeque::new(),
+                respond: None,
+            }
+        }
+    }
+    impl Transport for MockUdpTransporter {
+        type PeerAddr = SocketAddr;
+        type SendItem = TestMessage;
+        type RecvItem = DecodedMessage<rfc5389::Attribute>;
+
+        fn start_send(
+            &mut self,
+            peer: SocketAddr,
+            item: Self::SendItem,
+        ) -> fibers_transport::Result<()> {
+            self.queue.push_back((peer, item));
+            Ok(())
+        }
+
+        fn poll_send(&mut self) -> PollSend {
+            if !self.writable {
+                return Ok(Async::NotReady);
+            }
+            loop {
+                let entry = if self.reverse_writes {
+                    self.queue.pop_back()
+                } else {
+                    self.queue.pop_front()
+                };
+                let (peer, m) = match entry {
+                    None => break,
+                    Some(entry) => entry,
+                };
+                if let Some(reply) = self.respond.as_mut().and_then(|f| f(&m)) {
+                    self.incoming.push_back((peer, Ok(reply)));
+                }
+                self.written.lock().unwrap().push((peer, m));
+            }
+            Ok(Async::Ready(()))
+        }
+
+        fn poll_recv(&mut self) -> PollRecv<(SocketAddr, Self::RecvItem)> {
+            if let Some(item) = self.incoming.pop_front() {
+                Ok(Async::Ready(Some(item)))
+            } else {
+                Ok(Async::NotReady)
+            }
+        }
+    }
+    impl UdpTransport for MockUdpTransporter {
+        fn local_addr(&self) -> SocketAddr {
+            "127.0.0.1:3478".parse().unwrap()
+        }
+    }
 
     #[test]
     fn basic_udp_test() -> Result<(), MainError> {
@@ -167,4 +280,2504 @@ mod tests {
 
         Ok(())
     }
+
+    #[test]
+    fn udp_binding_returns_client_address() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let transporter = StunUdpTransporter::new(transporter);
+        let client_addr = transporter.local_addr();
+        let client = Client::new(&fibers_global::handle(), Channel::new(transporter));
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect("success response");
+        let mapped = response.get_attribute::<XorMappedAddress>().map(|a| a.address());
+        assert_eq!(mapped, Some(client_addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_binding_returns_client_address() -> Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        let transporter = track!(fibers_global::execute(
+            TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(server_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel =
+            Channel::<rfc5389::Attribute, _>::new(StunTcpTransporter::new(transporter));
+        let client_addr = channel.local_addr();
+        assert_eq!(channel.peer_addr(), server_addr);
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call((), request)))?;
+        let response = response.expect("success response");
+        let mapped = response.get_attribute::<XorMappedAddress>().map(|a| a.address());
+        assert_eq!(mapped, Some(client_addr));
+
+        Ok(())
+    }
+
+    #[test]
+    fn dropped_call_stops_retransmission() -> Result<(), MainError> {
+        use fibers::time::timer;
+        use futures::future::Either;
+
+        #[derive(Default)]
+        struct CancelCounter(AtomicUsize);
+        impl TransactionObserver<SocketAddr> for CancelCounter {
+            fn on_cancel(&self, _peer: &SocketAddr, _transaction_id: TransactionId) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        // The peer never responds
+        let peer_addr: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+
+        let counter = Arc::new(CancelCounter::default());
+        let transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(20))
+            .finish(MockUdpTransporter::default());
+        let mut channel = Channel::new(transporter);
+        channel.set_observer(counter.clone());
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        // A cancelled transaction is finished in the transporter (i.e., its retransmissions stop).
+        // `pending_transactions` is processed after the cancellation, so no sleep is needed.
+
+        // Dropped without being polled
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        drop(client.call(peer_addr, request));
+        let pending = track!(fibers_global::execute(client.pending_transactions()))?;
+        assert!(pending.is_empty());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        // Dropped in the middle of the retransmissions
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let call = client.call(peer_addr, request);
+        let timeout = timer::timeout(Duration::from_millis(50));
+        let timed_out = matches!(
+            fibers_global::execute(call.select2(timeout)),
+            Ok(Either::B(_))
+        );
+        assert!(timed_out, "the call should not complete");
+        let pending = track!(fibers_global::execute(client.pending_transactions()))?;
+        assert!(pending.is_empty());
+        assert_eq!(counter.0.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn spurious_response_to_indication_is_harmless() -> Result<(), MainError> {
+        let test = futures::lazy(|| -> Result<(), Error> {
+            let transporter = StunUdpTransporter::new(MockUdpTransporter::default());
+            let mut channel = ChannelBuilder::new()
+                .unexpected_response_policy(UnexpectedResponsePolicy::Drop)
+                .finish(transporter);
+            let peer = "127.0.0.1:9999".parse().unwrap();
+
+            let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let indication_id = indication.transaction_id();
+            track!(channel.cast(peer, indication))?;
+
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let request_id = request.transaction_id();
+            let mut response = channel.call(peer, request);
+
+            // A response to the indication
+            let spurious = Message::new(
+                MessageClass::SuccessResponse,
+                rfc5389::methods::BINDING,
+                indication_id,
+            );
+            channel
+                .transporter_mut()
+                .inner_mut()
+                .incoming
+                .push_back((peer, Ok(spurious)));
+            assert!(track!(channel.poll_recv())?.is_not_ready());
+            assert_eq!(channel.unexpected_responses(), 1);
+            assert_eq!(channel.outstanding_transactions(), 1);
+            assert!(track!(response.poll().map_err(Error::from))?.is_not_ready());
+
+            // The response to the request
+            let genuine = Message::new(
+                MessageClass::SuccessResponse,
+                rfc5389::methods::BINDING,
+                request_id,
+            );
+            channel
+                .transporter_mut()
+                .inner_mut()
+                .incoming
+                .push_back((peer, Ok(genuine)));
+            assert!(track!(channel.poll_recv())?.is_not_ready());
+            assert_eq!(channel.unexpected_responses(), 1);
+            assert_eq!(channel.outstanding_transactions(), 0);
+            match track!(response.poll().map_err(Error::from))? {
+                Async::Ready(response) => assert!(response.is_ok()),
+                Async::NotReady => panic!("the response has not been delivered"),
+            }
+            Ok(())
+        });
+        track!(fibers_global::execute(test))?;
+        Ok(())
+    }
+
+    fn signed_binding_request(username: &str, password: &str) -> Request<rfc5389::Attribute> {
+        let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let username = Username::new(username.to_owned()).expect("valid username");
+        request.add_attribute(username.into());
+        let integrity = MessageIntegrity::new_short_term_credential(request.as_ref(), password)
+            .expect("never fails");
+        request.add_attribute(integrity.into());
+        request
+    }
+
+    #[test]
+    fn server_verifies_integrity_per_user() -> Result<(), MainError> {
+        let mut credentials = Credentials::new();
+        credentials
+            .add_user("alice", "alice-password")
+            .add_user("bob", "bob-password");
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        server.credential_provider(credentials);
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let cases = [
+            ("alice", "alice-password", None),
+            ("bob", "bob-password", None),
+            ("alice", "bob-password", Some(431)),
+            ("bob", "wrong-password", Some(431)),
+            ("carol", "carol-password", Some(401)),
+        ];
+        for &(username, password, expected_error) in &cases {
+            let request = signed_binding_request(username, password);
+            let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+            match expected_error {
+                None => assert!(response.is_ok(), "user={}", username),
+                Some(code) => {
+                    let response = response.expect_err("error response");
+                    let actual = response.get_attribute::<ErrorCode>().map(|e| e.code());
+                    assert_eq!(actual, Some(code), "user={}", username);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_coalesces_responses() -> Result<(), MainError> {
+        let mut server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        server.coalesce_writes(true);
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        // Two requests in a single segment
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut requests = Vec::new();
+        for _ in 0..2 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            requests.extend_from_slice(&bytes);
+        }
+        let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&requests))?;
+
+        // Both responses are received by a single read
+        let mut buf = [0; 1024];
+        let size = track_any_err!(stream.read(&mut buf))?;
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        decoder.feed(&buf[..size]);
+        for _ in 0..2 {
+            let response = track!(decoder.decode_next())?.expect("complete message");
+            let response = response.expect("well-formed message");
+            assert_eq!(response.class(), MessageClass::SuccessResponse);
+        }
+        assert_eq!(decoder.buffered_bytes(), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_handles_messages_received_in_one_segment() -> Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        // Three requests in a single write
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut requests = Vec::new();
+        let mut transaction_ids = Vec::new();
+        for _ in 0..3 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            transaction_ids.push(request.transaction_id());
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            requests.extend_from_slice(&bytes);
+        }
+        let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&requests))?;
+
+        // All of them are answered in order
+        let mut buf = [0; 1024];
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        let mut responses = Vec::new();
+        while responses.len() < 3 {
+            let size = track_any_err!(stream.read(&mut buf))?;
+            assert_ne!(size, 0);
+            decoder.feed(&buf[..size]);
+            while let Some(response) = track!(decoder.decode_next())? {
+                responses.push(response.expect("well-formed message"));
+            }
+        }
+        for (response, transaction_id) in responses.iter().zip(transaction_ids.iter()) {
+            assert_eq!(response.class(), MessageClass::SuccessResponse);
+            assert_eq!(response.transaction_id(), *transaction_id);
+        }
+        assert_eq!(responses.len(), 3);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_replies_to_half_closed_connections() -> Result<(), MainError> {
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        // The request is immediately followed by a half-close
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        let bytes = track!(MessageEncoder::<rfc5389::Attribute>::default()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&bytes))?;
+        track_any_err!(stream.shutdown(net::Shutdown::Write))?;
+
+        // The response is still delivered before the server closes the connection
+        let mut buf = [0; 1024];
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        let mut responses = Vec::new();
+        loop {
+            let size = track_any_err!(stream.read(&mut buf))?;
+            if size == 0 {
+                break;
+            }
+            decoder.feed(&buf[..size]);
+            while let Some(response) = track!(decoder.decode_next())? {
+                responses.push(response.expect("well-formed message"));
+            }
+        }
+        assert_eq!(responses.len(), 1);
+        assert_eq!(responses[0].class(), MessageClass::SuccessResponse);
+        assert_eq!(responses[0].transaction_id(), transaction_id);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_closes_connections_of_which_writes_stall() -> Result<(), MainError> {
+        use server::{Action, HandleMessage};
+
+        // A handler that replies large responses, so that the socket buffers fill up quickly
+        #[derive(Default)]
+        struct LargeResponseHandler;
+        impl HandleMessage for LargeResponseHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut response = SuccessResponse::new(&request);
+                let description = "\u{3042}".repeat(127);
+                for _ in 0..4 {
+                    let software = Software::new(description.clone()).expect("valid description");
+                    response.add_attribute(software.into());
+                }
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let mut server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DefaultFactory::<LargeResponseHandler>::new(),
+        ))?;
+        server.write_stall_timeout(Some(Duration::from_millis(500)));
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        // A client that sends many requests but never reads the responses
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut requests = Vec::new();
+        for _ in 0..20_000 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            requests.extend_from_slice(&bytes);
+        }
+        let mut stalled = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stalled.write_all(&requests))?;
+
+        // The other connections are not affected
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let bytes = track!(encoder
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        track_any_err!(stream.write_all(&bytes))?;
+        let mut buf = [0; 4096];
+        assert_ne!(track_any_err!(stream.read(&mut buf))?, 0);
+
+        // The stalled connection is closed by the server
+        thread::sleep(Duration::from_secs(1));
+        track_any_err!(stalled.set_read_timeout(Some(Duration::from_secs(10))))?;
+        loop {
+            match stalled.read(&mut buf) {
+                Ok(0) => break,
+                Ok(_) => {}
+                Err(e) => {
+                    assert_eq!(e.kind(), std::io::ErrorKind::ConnectionReset);
+                    break;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_replies_to_unknown_method() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        // A method that is not modeled by `rfc5389::Attribute` nor `BindingHandler`.
+        //
+        // Note that stun_codec 0.1.13 mis-encodes the methods greater than `0xF`
+        // (the upper bits are not shifted correctly when the message type is built),
+        // so such methods do not survive an encode/decode round trip.
+        let method = Method::new(0xF).expect("valid method");
+        let request = Request::<rfc5389::Attribute>::new(method);
+        let transaction_id = request.transaction_id();
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect_err("error response");
+        assert_eq!(response.method(), method);
+        assert_eq!(response.transaction_id(), transaction_id);
+        let code = response.get_attribute::<ErrorCode>().map(|e| e.code());
+        assert_eq!(code, Some(400));
+
+        Ok(())
+    }
+
+    #[test]
+    fn udp_server_limits_the_rate_of_error_responses() -> Result<(), MainError> {
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        server.max_error_responses_per_second(Some(1));
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // `BindingHandler` replies `400 Bad Request` to the requests of unknown methods
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_millis(200))))?;
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let method = Method::new(0x123).expect("valid method");
+        for _ in 0..3 {
+            let request = Request::<rfc5389::Attribute>::new(method);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            track_any_err!(socket.send_to(&bytes, server_addr))?;
+        }
+
+        let mut buf = [0; 1024];
+        let mut responses = 0;
+        while socket.recv_from(&mut buf).is_ok() {
+            responses += 1;
+        }
+        assert_eq!(responses, 1);
+        assert_eq!(metrics.error_response_suppressions(), 2);
+
+        // Success responses are not limited
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let bytes = track!(encoder
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        track_any_err!(socket.send_to(&bytes, server_addr))?;
+        track_any_err!(socket.recv_from(&mut buf))?;
+
+        Ok(())
+    }
+
+    #[test]
+    fn udp_server_limits_the_rate_of_cached_error_responses() -> Result<(), MainError> {
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        server.response_cache_max_entries(16);
+        server.max_error_responses_per_second(Some(1));
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // Retransmits a request of an unknown method, which `BindingHandler` replies
+        // `400 Bad Request` to, so that the retransmissions are answered from the cache
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_millis(200))))?;
+        let method = Method::new(0xF).expect("valid method");
+        let request = Request::<rfc5389::Attribute>::new(method);
+        let bytes = track!(MessageEncoder::<rfc5389::Attribute>::default()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        for _ in 0..3 {
+            track_any_err!(socket.send_to(&bytes, server_addr))?;
+        }
+
+        let mut buf = [0; 1024];
+        let mut responses = 0;
+        while socket.recv_from(&mut buf).is_ok() {
+            responses += 1;
+        }
+        assert_eq!(responses, 1);
+        assert_eq!(metrics.response_cache_hits(), 2);
+        assert_eq!(metrics.error_response_suppressions(), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn udp_server_checks_the_amplification_of_cached_responses() -> Result<(), MainError> {
+        use server::{Action, HandleMessage};
+
+        struct LargeResponseHandler;
+        impl HandleMessage for LargeResponseHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut response = SuccessResponse::new(&request);
+                let software = Software::new("x".repeat(300)).expect("never fails");
+                response.add_attribute(software.into());
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            LargeResponseHandler,
+        ))?;
+        server.response_cache_max_entries(16);
+        server.max_amplification_factor(Some(2));
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_millis(200))))?;
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut buf = [0; 1024];
+
+        // The original request is large enough for the response
+        let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        let software = Software::new("x".repeat(200)).expect("never fails");
+        request.add_attribute(software.into());
+        let bytes = track!(encoder
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        track_any_err!(socket.send_to(&bytes, server_addr))?;
+        track_any_err!(socket.recv_from(&mut buf))?;
+
+        // The retransmission without the padding would amplify the cached response
+        let retransmission = Message::new(
+            MessageClass::Request,
+            rfc5389::methods::BINDING,
+            transaction_id,
+        );
+        let bytes = track!(encoder
+            .encode_into_bytes(retransmission)
+            .map_err(Error::from))?;
+        track_any_err!(socket.send_to(&bytes, server_addr))?;
+        assert!(socket.recv_from(&mut buf).is_err());
+        assert_eq!(metrics.response_cache_hits(), 1);
+        assert_eq!(metrics.amplification_suppressions(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn casts_are_written_in_submission_order() -> Result<(), MainError> {
+        const THREADS: usize = 4;
+        const CASTS_PER_THREAD: usize = 100;
+
+        let transporter = MockUdpTransporter::default();
+        let written = transporter.written.clone();
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+        let peer: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+
+        let threads = (0..THREADS)
+            .map(|_| {
+                let client = client.clone();
+                thread::spawn(move || {
+                    let mut submitted = Vec::new();
+                    for _ in 0..CASTS_PER_THREAD {
+                        let indication =
+                            Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                        submitted.push(indication.transaction_id());
+                        client.cast(peer, indication).expect("cast failed");
+                    }
+                    submitted
+                })
+            }).collect::<Vec<_>>();
+        let submitted = threads
+            .into_iter()
+            .map(|t| t.join().expect("thread panicked"))
+            .collect::<Vec<_>>();
+
+        for _ in 0..100 {
+            if written.lock().unwrap().len() == THREADS * CASTS_PER_THREAD {
+                break;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        let written = written
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|e| e.1.transaction_id())
+            .collect::<Vec<_>>();
+        assert_eq!(written.len(), THREADS * CASTS_PER_THREAD);
+        for ids in submitted {
+            let order = written
+                .iter()
+                .filter(|id| ids.contains(id))
+                .cloned()
+                .collect::<Vec<_>>();
+            assert_eq!(order, ids);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn ordered_casts_are_written_in_submission_order() -> Result<(), MainError> {
+        fn cast_many(ordered_casts: bool) -> Result<bool, Error> {
+            // The transporter writes the queued messages in the reverse order
+            let transporter = StunUdpTransporter::new(MockUdpTransporter {
+                reverse_writes: true,
+                ..MockUdpTransporter::default()
+            });
+            let mut channel = ChannelBuilder::new()
+                .ordered_casts(ordered_casts)
+                .finish(transporter);
+            let peers: [SocketAddr; 2] = [
+                "127.0.0.1:9998".parse().unwrap(),
+                "127.0.0.1:9999".parse().unwrap(),
+            ];
+            let mut submitted = Vec::new();
+            for i in 0..100 {
+                let peer = peers[i % 2];
+                let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+                submitted.push((peer, indication.transaction_id()));
+                track!(channel.cast(peer, indication))?;
+            }
+            assert!(track!(channel.poll_send())?.is_ready());
+
+            let written = channel.transporter_ref().inner_ref().written_ids();
+            assert_eq!(written.len(), submitted.len());
+            let ordered = peers.iter().all(|peer| {
+                let per_peer = |entries: &[(SocketAddr, TransactionId)]| {
+                    entries
+                        .iter()
+                        .filter(|e| e.0 == *peer)
+                        .cloned()
+                        .collect::<Vec<_>>()
+                };
+                per_peer(&written[..]) == per_peer(&submitted[..])
+            });
+            Ok(ordered)
+        }
+
+        let test = futures::lazy(|| -> Result<(), Error> {
+            assert!(!track!(cast_many(false))?);
+            assert!(track!(cast_many(true))?);
+            Ok(())
+        });
+        track!(fibers_global::execute(test))?;
+        Ok(())
+    }
+
+    #[test]
+    fn adaptive_rto_bounds_the_number_of_tracked_peers() {
+        let mut rto = AdaptiveRto::new();
+        rto.max_peers(100);
+
+        let peer = |i: u16| SocketAddr::from(([127, 0, 0, 1], i));
+        for i in 0..10_000 {
+            rto.on_response(peer(i), Some(Duration::from_millis(10)));
+            assert!(rto.tracked_peers() <= 100);
+        }
+        assert_eq!(rto.tracked_peers(), 100);
+
+        // The least recently used peers have been evicted
+        assert_eq!(rto.current_rto(peer(0)), None);
+        assert!(rto.current_rto(peer(9_999)).is_some());
+
+        // Touching a peer protects it from the next eviction
+        rto.on_response(peer(9_900), Some(Duration::from_millis(10)));
+        rto.on_response(peer(10_000), Some(Duration::from_millis(10)));
+        assert!(rto.current_rto(peer(9_900)).is_some());
+        assert_eq!(rto.current_rto(peer(9_901)), None);
+    }
+
+    #[test]
+    fn adaptive_rto_recovers_quickly_from_loss() -> Result<(), MainError> {
+        const CALLS: usize = 30;
+        let min_rto = Duration::from_millis(20);
+        let peer: SocketAddr = "127.0.0.1:9999".parse().unwrap();
+
+        // The peer drops every third request and immediately answers the others
+        let mut requests = 0;
+        let respond = move |m: &TestMessage| {
+            if m.class() != MessageClass::Request {
+                return None;
+            }
+            requests += 1;
+            if requests % 3 == 0 {
+                return None;
+            }
+            Some(Message::new(
+                MessageClass::SuccessResponse,
+                m.method(),
+                m.transaction_id(),
+            ))
+        };
+
+        // With the fixed schedule, every lost request would cost at least one second
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_secs(1))
+            .min_transaction_interval(Duration::from_millis(0))
+            .finish(MockUdpTransporter {
+                respond: Some(Box::new(respond)),
+                ..MockUdpTransporter::default()
+            });
+        let mut strategy = AdaptiveRto::new();
+        strategy.min_rto(min_rto);
+        transporter.set_rto_strategy(strategy);
+        let mut channel = Some(Channel::new(transporter));
+
+        let start_time = Instant::now();
+        let mut call: Option<
+            Box<dyn Future<Item = Response<rfc5389::Attribute>, Error = MessageError> + Send>,
+        > = None;
+        let mut completed = 0;
+        let test = futures::future::poll_fn(move || -> Poll<_, Error> {
+            loop {
+                {
+                    let channel = channel.as_mut().expect("never fails");
+                    if call.is_none() {
+                        let request = Request::new(rfc5389::methods::BINDING);
+                        call = Some(Box::new(channel.call(peer, request)));
+                    }
+                    track!(channel.poll_send())?;
+                    while let Async::Ready(Some(_)) = track!(channel.poll_recv())? {}
+                }
+                match track!(call.as_mut().expect("never fails").poll().map_err(Error::from))? {
+                    Async::NotReady => return Ok(Async::NotReady),
+                    Async::Ready(response) => {
+                        assert!(response.is_ok());
+                        call = None;
+                        completed += 1;
+                        if completed == CALLS {
+                            return Ok(Async::Ready(channel.take().expect("never fails")));
+                        }
+                    }
+                }
+            }
+        });
+        let channel = track!(fibers_global::execute(test))?;
+
+        let transporter = channel.transporter_ref();
+        assert!(transporter.inner_ref().written_ids().len() > CALLS);
+        assert_eq!(transporter.rto_for(peer), Some(min_rto));
+        assert!(start_time.elapsed() < Duration::from_secs(1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn paused_channel_does_not_lose_tcp_messages() -> Result<(), MainError> {
+        const MESSAGES: usize = 100;
+
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut indications = Vec::new();
+        let mut bytes = Vec::new();
+        for _ in 0..MESSAGES {
+            let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            indications.push(indication.transaction_id());
+            bytes.extend_from_slice(&track!(encoder
+                .encode_into_bytes(indication.into_message())
+                .map_err(Error::from))?);
+        }
+
+        let listener = track_any_err!(net::TcpListener::bind("127.0.0.1:0"))?;
+        let server_addr = track_any_err!(listener.local_addr())?;
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            stream.write_all(&bytes).unwrap();
+            stream
+        });
+
+        let transporter = track!(fibers_global::execute(
+            TcpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::connect(server_addr)
+                .map_err(Error::from)
+        ))?;
+        let transporter = StunTcpTransporter::new(transporter);
+        let mut channel = Channel::<rfc5389::Attribute, _>::new(transporter);
+        channel.pause();
+        assert!(channel.is_paused());
+
+        // Lets the server write all the messages while the channel is paused
+        let _stream = server.join().unwrap();
+        thread::sleep(Duration::from_millis(50));
+
+        let mut received = Vec::new();
+        let future = futures::future::poll_fn(move || -> Poll<Vec<TransactionId>, Error> {
+            if channel.is_paused() {
+                assert!(track!(channel.poll_recv())?.is_not_ready());
+                channel.resume();
+            }
+            while let Async::Ready(item) = track!(channel.poll_recv())? {
+                let (_, message) = item.expect("connection closed");
+                if let RecvMessage::Indication(indication) = message {
+                    received.push(indication.transaction_id());
+                    if received.len() == MESSAGES {
+                        return Ok(Async::Ready(received.clone()));
+                    }
+                } else {
+                    panic!("unexpected message");
+                }
+            }
+            Ok(Async::NotReady)
+        });
+        let received = track!(fibers_global::execute(future))?;
+        assert_eq!(received, indications);
+
+        Ok(())
+    }
+
+    #[test]
+    fn fingerprint_policy_is_applied_to_received_messages() -> Result<(), MainError> {
+        fn recv<A>(policy: FingerprintPolicy, message: DecodedMessage<A>) -> Result<bool, Error>
+        where
+            A: Attribute + Send + Sync + 'static,
+        {
+            let peer: SocketAddr = "127.0.0.1:3478".parse().unwrap();
+            let (tx, _rx) = futures::sync::mpsc::unbounded::<(SocketAddr, Message<A>)>();
+            let rx = futures::stream::iter_ok::<_, std::io::Error>(vec![(peer, message)]);
+            let mut channel = ChannelBuilder::new()
+                .fingerprint_policy(policy)
+                .finish::<A, _>(SplitTransporter::new(tx, rx));
+            match track!(channel.poll_recv())? {
+                Async::Ready(Some((_, RecvMessage::Request(_)))) => Ok(true),
+                Async::Ready(Some((_, RecvMessage::Invalid(_)))) => Ok(false),
+                _ => panic!("unexpected result"),
+            }
+        }
+
+        let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let plain = request.clone().into_message();
+        let fingerprint = track_any_err!(Fingerprint::new(request.as_ref()))?;
+        request.add_attribute(fingerprint.into());
+        let mut bytes = track_any_err!(
+            MessageEncoder::<rfc5389::Attribute>::new().encode_into_bytes(request.into_message())
+        )?;
+
+        // `FINGERPRINT` is known to `rfc5389::Attribute`, but unknown to `rfc5780::Attribute`
+        let verified: DecodedMessage<rfc5389::Attribute> =
+            track_any_err!(MessageDecoder::new().decode_from_bytes(&bytes))?;
+        let unverified: DecodedMessage<rfc5780::Attribute> =
+            track_any_err!(MessageDecoder::new().decode_from_bytes(&bytes))?;
+        let last = bytes.len() - 1;
+        bytes[last] ^= 0xFF;
+        let mismatched: DecodedMessage<rfc5389::Attribute> =
+            track_any_err!(MessageDecoder::new().decode_from_bytes(&bytes))?;
+
+        let test = futures::lazy(move || -> Result<(), Error> {
+            let policy = FingerprintPolicy::Ignore;
+            assert!(track!(recv(policy, Ok(plain.clone())))?);
+            assert!(track!(recv(policy, verified.clone()))?);
+            assert!(track!(recv(policy, unverified.clone()))?);
+            assert!(!track!(recv(policy, mismatched.clone()))?);
+
+            let policy = FingerprintPolicy::VerifyIfPresent;
+            assert!(track!(recv(policy, Ok(plain.clone())))?);
+            assert!(track!(recv(policy, verified.clone()))?);
+            assert!(!track!(recv(policy, unverified.clone()))?);
+            assert!(!track!(recv(policy, mismatched.clone()))?);
+
+            let policy = FingerprintPolicy::Require;
+            assert!(!track!(recv(policy, Ok(plain)))?);
+            assert!(track!(recv(policy, verified))?);
+            assert!(!track!(recv(policy, unverified))?);
+            assert!(!track!(recv(policy, mismatched))?);
+            Ok(())
+        });
+        track!(fibers_global::execute(test))?;
+        Ok(())
+    }
+
+    #[test]
+    fn udp_server_binds_within_port_range() -> Result<(), MainError> {
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        let used_port = track_any_err!(socket.local_addr())?.port();
+        let ip = "127.0.0.1".parse().unwrap();
+
+        let result = fibers_global::execute(UdpServer::start_in_port_range(
+            fibers_global::handle(),
+            ip,
+            used_port..=used_port,
+            BindingHandler,
+        ));
+        match result {
+            Err(ref e) if is_addr_in_use(e) => {}
+            _ => panic!("the port range should have been exhausted"),
+        }
+
+        let server = fibers_global::execute(UdpServer::start_in_port_range(
+            fibers_global::handle(),
+            ip,
+            used_port..=used_port.saturating_add(100),
+            BindingHandler,
+        ))?;
+        assert_ne!(server.local_addr().port(), used_port);
+        assert!(server.local_addr().port() <= used_port.saturating_add(100));
+
+        Ok(())
+    }
+
+    #[test]
+    fn udp_server_handler_can_be_swapped() -> Result<(), MainError> {
+        use server::{Action, HandleMessage};
+
+        struct SoftwareHandler(&'static str);
+        impl HandleMessage for SoftwareHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut response = SuccessResponse::new(&request);
+                response.add_attribute(Software::new(self.0.to_owned()).unwrap().into());
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            SoftwareHandler("old"),
+        ))?;
+        let server_addr = server.local_addr();
+        let swapper = server.handler_swapper();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+        for &expected in &["old", "new"] {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+            let response = response.expect("success response");
+            let software = response.get_attribute::<Software>().expect("SOFTWARE");
+            assert_eq!(software.description(), expected);
+
+            track!(swapper.swap(SoftwareHandler("new")))?;
+        }
+
+        Ok(())
+    }
+
+    #[cfg(feature = "pcap")]
+    #[test]
+    fn pcap_writer_wraps_messages_in_udp_packets() -> Result<(), MainError> {
+        use transport::PcapWriter;
+
+        #[derive(Clone, Default)]
+        struct SharedBuf(Arc<Mutex<Vec<u8>>>);
+        impl Write for SharedBuf {
+            fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+                self.0.lock().unwrap().write(buf)
+            }
+            fn flush(&mut self) -> std::io::Result<()> {
+                Ok(())
+            }
+        }
+
+        let buf = SharedBuf::default();
+        let pcap = track!(PcapWriter::new(buf.clone()))?;
+        assert_eq!(buf.0.lock().unwrap()[..4], [0xd4, 0xc3, 0xb2, 0xa1]);
+
+        let src = "127.0.0.1:5000".parse().unwrap();
+        let dst = "127.0.0.1:3478".parse().unwrap();
+        track!(pcap.write_udp_packet(src, dst, b"foo"))?;
+
+        let bytes = buf.0.lock().unwrap().clone();
+        let packet = &bytes[24 + 16..];
+        assert_eq!(packet.len(), 14 + 20 + 8 + 3);
+        assert_eq!(bytes[24 + 8..24 + 12], [packet.len() as u8, 0, 0, 0]);
+        assert_eq!(packet[12..14], [0x08, 0x00]); // IPv4
+        assert_eq!(packet[14 + 22..14 + 26], [0x0d, 0x96, 0, 11]); // port 3478, length 11
+        assert_eq!(&packet[14 + 28..], b"foo");
+
+        let ipv6 = "[::1]:3478".parse().unwrap();
+        assert!(pcap.write_udp_packet(src, ipv6, b"foo").is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn budgeted_message_decoder_works() -> Result<(), MainError> {
+        use message::MessageErrorKind;
+        use std::mem;
+        use transport::BudgetedMessageDecoder;
+
+        let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        for _ in 0..5 {
+            request.add_attribute(track!(Software::new("foo".to_owned()))?.into());
+        }
+        let message = request.clone().into_message();
+        let bytes = track!(MessageEncoder::new().encode_into_bytes(message))?;
+
+        let budget = mem::size_of::<rfc5389::Attribute>() * 3;
+        let mut decoder = BudgetedMessageDecoder::<rfc5389::Attribute>::new(budget);
+        let broken = track!(decoder.decode_from_bytes(&bytes))?;
+        assert!(broken.is_err());
+
+        // The over-budget message is reported as an invalid one by the channel
+        let peer = "127.0.0.1:9999".parse().unwrap();
+        let mut transporter = MockUdpTransporter::default();
+        transporter.incoming.push_back((peer, broken));
+        let mut channel = Channel::new(StunUdpTransporter::new(transporter));
+        let invalid = fibers_global::execute(futures::lazy(move || -> Result<_, Error> {
+            match track!(channel.poll_recv())? {
+                Async::Ready(Some((_, RecvMessage::Invalid(m)))) => Ok(m),
+                other => panic!("unexpected message: {:?}", other),
+            }
+        }))?;
+        assert_eq!(invalid.transaction_id(), request.transaction_id());
+        match *invalid.error().kind() {
+            MessageErrorKind::MalformedAttribute => {}
+            ref kind => panic!("unexpected error: {:?}", kind),
+        }
+
+        decoder.set_budget(mem::size_of::<rfc5389::Attribute>() * 5 + 15);
+        let message = track!(decoder.decode_from_bytes(&bytes))?.expect("message");
+        assert_eq!(message.attributes().count(), 5);
+
+        Ok(())
+    }
+
+    #[test]
+    fn router_dispatches_messages_to_sub_handlers() -> Result<(), MainError> {
+        use server::{Action, HandleMessage, Router};
+
+        struct Tag(&'static str);
+        impl HandleMessage for Tag {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut response = SuccessResponse::new(&request);
+                response.add_attribute(Software::new(self.0.to_owned()).unwrap().into());
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let mut router = Router::new(Tag("fallback"));
+        router
+            .route_if(|m| m.get_attribute::<Username>().is_some(), Tag("user"))
+            .route_method(rfc5389::methods::BINDING, Tag("binding"));
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            router,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<
+                MessageEncoder<rfc5389::Attribute>,
+                MessageDecoder<rfc5389::Attribute>,
+            >::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let username = track!(Username::new("foo".to_owned()))?;
+        let requests = vec![
+            (
+                Request::new(rfc5389::methods::BINDING).with_attribute(username.into()),
+                "user",
+            ),
+            (Request::new(rfc5389::methods::BINDING), "binding"),
+            // stun_codec 0.1.13 cannot round-trip the methods greater than `0xF`
+            (Request::new(track!(Method::new(0xF))?), "fallback"),
+        ];
+        for (request, expected) in requests {
+            let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+            let response = response.expect("success response");
+            let software = response.get_attribute::<Software>().expect("SOFTWARE");
+            assert_eq!(software.description(), expected);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_group_drains_servers() -> Result<(), MainError> {
+        use server::ServerGroup;
+
+        let addr = "127.0.0.1:0".parse().unwrap();
+        let udp = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            addr,
+            BindingHandler,
+        ))?;
+        let udp_addr = udp.local_addr();
+        let tcp = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            addr,
+            DefaultFactory::<BindingHandler>::new(),
+        ))?;
+        let tcp_addr = tcp.local_addr();
+
+        let mut group = ServerGroup::new(fibers_global::handle());
+        group.add_tcp_server(tcp).add_udp_server(udp);
+        assert_eq!(group.len(), 2);
+        thread::sleep(Duration::from_millis(50));
+
+        let mut stream = track_any_err!(net::TcpStream::connect(tcp_addr))?;
+        track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(5))))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let bytes = track!(MessageEncoder::new()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        track_any_err!(stream.write_all(&bytes))?;
+        let mut buf = [0; 1024];
+        assert_ne!(track_any_err!(stream.read(&mut buf))?, 0);
+
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(udp_addr, request)))?;
+        assert!(response.is_ok());
+
+        track!(fibers_global::execute(group.shutdown()))?;
+
+        // The connection has been closed by the server
+        assert_eq!(track_any_err!(stream.read(&mut buf))?, 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn expired_casts_are_dropped() -> Result<(), MainError> {
+        use message::parse_header;
+
+        let peer = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(peer.set_read_timeout(Some(Duration::from_millis(500))))?;
+        let peer_addr = track_any_err!(peer.local_addr())?;
+
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(
+                "127.0.0.1:0".parse().unwrap()
+            )
+            .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let expired = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let deadline = Instant::now() - Duration::from_secs(1);
+        track!(client.cast_with_deadline(peer_addr, expired, deadline))?;
+
+        let fresh = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let fresh_id = fresh.transaction_id();
+        let deadline = Instant::now() + Duration::from_secs(60);
+        track!(client.cast_with_deadline(peer_addr, fresh, deadline))?;
+
+        let mut buf = [0; 1024];
+        let (size, _) = track_any_err!(peer.recv_from(&mut buf))?;
+        assert_eq!(track!(parse_header(&buf[..size]))?.transaction_id(), fresh_id);
+        assert!(peer.recv_from(&mut buf).is_err());
+        assert_eq!(client.expired_indications(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    #[cfg(target_os = "linux")]
+    fn udp_transporter_reports_socket_buffer_usage() -> Result<(), MainError> {
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(
+                "127.0.0.1:0".parse().unwrap()
+            )
+            .map_err(Error::from)
+        ))?;
+        let transporter = StunUdpTransporter::<rfc5389::Attribute, _>::new(transporter);
+
+        let usage = transporter.recv_buffer_usage().expect("recv buffer usage");
+        assert_eq!(usage.used, 0);
+        assert!(usage.capacity > 0);
+        assert!(transporter.send_buffer_usage().expect("send buffer usage").capacity > 0);
+
+        // The datagram stays in the buffer because the transporter is never polled
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.send_to(b"foo", transporter.local_addr()))?;
+        thread::sleep(Duration::from_millis(50));
+        assert!(transporter.recv_buffer_usage().expect("recv buffer usage").used > 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn udp_client_can_be_made_in_one_call() -> Result<(), MainError> {
+        use client::UdpClient;
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client = track!(fibers_global::execute(UdpClient::udp(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+        )))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert!(response.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_rejects_responses_with_unexpected_software() -> Result<(), MainError> {
+        use message::MessageErrorKind;
+        use server::{Action, HandleMessage};
+
+        struct SoftwareHandler;
+        impl HandleMessage for SoftwareHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let mut response = SuccessResponse::new(&request);
+                response.add_attribute(Software::new("foo".to_owned()).unwrap().into());
+                Action::Reply(Ok(response))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            SoftwareHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let mut client = Client::new(&fibers_global::handle(), channel);
+
+        client.expected_software(Some("foo"));
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert!(response.is_ok());
+
+        client.expected_software(Some("bar"));
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let e = fibers_global::execute(client.call(server_addr, request)).err();
+        match e.as_ref().map(|e| e.kind()) {
+            Some(ErrorKind::InvalidMessage(MessageErrorKind::InvalidInput)) => {}
+            _ => panic!("{:?}", e),
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn stream_handler_works() -> Result<(), MainError> {
+        use futures::Stream;
+        use server::StreamHandler;
+
+        let (handler, requests) = StreamHandler::<rfc5389::Attribute>::new();
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            handler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // Replies to the first request, and drops the responder of the second one
+        let count = Arc::new(AtomicUsize::new(0));
+        let count2 = count.clone();
+        fibers_global::spawn(
+            requests
+                .for_each(move |(peer, request, responder)| {
+                    assert_eq!(responder.transaction_id(), request.transaction_id());
+                    if count2.fetch_add(1, Ordering::SeqCst) == 0 {
+                        let mut response = SuccessResponse::new(&request);
+                        response.add_attribute(XorMappedAddress::new(peer).into());
+                        responder.reply(Ok(response)).unwrap();
+                    }
+                    Ok(())
+                }).map_err(|_| ()),
+        );
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect("success response");
+        assert!(response.get_attribute::<XorMappedAddress>().is_some());
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect_err("error response");
+        let code = response.get_attribute::<ErrorCode>().map(|a| a.code());
+        assert_eq!(code, Some(rfc5389::errors::ServerError::CODEPOINT));
+        assert_eq!(count.load(Ordering::SeqCst), 2);
+
+        Ok(())
+    }
+
+    #[test]
+    fn responses_dropped_by_gone_server_are_counted() -> Result<(), MainError> {
+        use fibers::sync::oneshot;
+        use fibers::time::timer;
+        use server::{Action, HandleMessage};
+
+        struct DelayedHandler;
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let response = SuccessResponse::new(&request);
+                let future = timer::timeout(Duration::from_millis(200));
+                Action::FutureReply(Box::new(future.then(move |_| Ok(Ok(response)))))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DelayedHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        let (abort_tx, abort_rx) = oneshot::channel::<()>();
+        fibers_global::spawn(server.select2(abort_rx).map(|_| ()).map_err(|_| ()));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        fibers_global::spawn(client.call(server_addr, request).then(|_| Ok(())));
+
+        // Drops the server while the response is pending
+        thread::sleep(Duration::from_millis(50));
+        abort_tx.send(()).unwrap();
+        assert_eq!(metrics.dropped_responses(), 0);
+
+        thread::sleep(Duration::from_millis(400));
+        assert_eq!(metrics.dropped_responses(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_response_grace_period_works() -> Result<(), MainError> {
+        use factory::CloneFactory;
+        use fibers::time::timer;
+        use server::{Action, HandleMessage};
+
+        #[derive(Clone)]
+        struct DelayedHandler(Arc<AtomicUsize>);
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let completed = self.0.clone();
+                let response = SuccessResponse::new(&request);
+                let future = timer::timeout(Duration::from_millis(200)).then(move |_| {
+                    completed.fetch_add(1, Ordering::SeqCst);
+                    Ok(Ok(response))
+                });
+                Action::FutureReply(Box::new(future))
+            }
+        }
+
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let cases = [
+            (None, 1),
+            (Some(Duration::from_secs(1)), 1),
+            (Some(Duration::from_millis(10)), 0),
+        ];
+        for &(grace_period, expected) in &cases {
+            let completed = Arc::new(AtomicUsize::new(0));
+            let mut server = fibers_global::execute(TcpServer::start(
+                fibers_global::handle(),
+                "127.0.0.1:0".parse().unwrap(),
+                CloneFactory::new(DelayedHandler(completed.clone())),
+            ))?;
+            server.response_grace_period(grace_period);
+            let server_addr = server.local_addr();
+            fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+            // The client goes away before the response is issued
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+            track_any_err!(stream.write_all(&bytes))?;
+            thread::sleep(Duration::from_millis(50));
+            std::mem::drop(stream);
+
+            thread::sleep(Duration::from_millis(500));
+            let actual = completed.load(Ordering::SeqCst);
+            assert_eq!(actual, expected, "grace_period={:?}", grace_period);
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn tcp_server_cancels_future_replies_on_close_by_default() -> Result<(), MainError> {
+        use factory::CloneFactory;
+        use fibers::time::timer;
+        use server::{Action, HandleMessage};
+
+        struct DropCounter(Arc<AtomicUsize>);
+        impl Drop for DropCounter {
+            fn drop(&mut self) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        #[derive(Clone)]
+        struct DelayedHandler {
+            completed: Arc<AtomicUsize>,
+            dropped: Arc<AtomicUsize>,
+        }
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let completed = self.completed.clone();
+                let counter = DropCounter(self.dropped.clone());
+                let response = SuccessResponse::new(&request);
+                let future = timer::timeout(Duration::from_millis(200)).then(move |_| {
+                    let _ = &counter;
+                    completed.fetch_add(1, Ordering::SeqCst);
+                    Ok(Ok(response))
+                });
+                Action::FutureReply(Box::new(future))
+            }
+        }
+
+        let handler = DelayedHandler {
+            completed: Arc::new(AtomicUsize::new(0)),
+            dropped: Arc::new(AtomicUsize::new(0)),
+        };
+        let server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            CloneFactory::new(handler.clone()),
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // The client goes away while the `FutureReply` is pending
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let bytes = track!(MessageEncoder::<rfc5389::Attribute>::default()
+            .encode_into_bytes(request.into_message())
+            .map_err(Error::from))?;
+        let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+        track_any_err!(stream.write_all(&bytes))?;
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(handler.dropped.load(Ordering::SeqCst), 0);
+        std::mem::drop(stream);
+
+        thread::sleep(Duration::from_millis(100));
+        assert_eq!(handler.dropped.load(Ordering::SeqCst), 1);
+
+        thread::sleep(Duration::from_millis(300));
+        assert_eq!(handler.completed.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn reply_stream_sends_multiple_responses() -> Result<(), MainError> {
+        use server::{Action, HandleMessage};
+
+        struct PaginatedHandler;
+        impl HandleMessage for PaginatedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let responses = (0..3)
+                    .map(|i| {
+                        let mut response = SuccessResponse::new(&request);
+                        let software = Software::new(format!("page {}", i)).unwrap();
+                        response.add_attribute(software.into());
+                        Ok(response)
+                    }).collect::<Vec<_>>();
+                Action::ReplyStream(Box::new(futures::stream::iter_ok(responses)))
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            PaginatedHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_millis(500))))?;
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        let bytes = track_any_err!(
+            MessageEncoder::<rfc5389::Attribute>::new().encode_into_bytes(request.into_message())
+        )?;
+        track_any_err!(socket.send_to(&bytes, server_addr))?;
+
+        let mut buf = [0; 1024];
+        for i in 0..3 {
+            let (size, _) = track_any_err!(socket.recv_from(&mut buf))?;
+            let message: DecodedMessage<rfc5389::Attribute> =
+                track_any_err!(MessageDecoder::new().decode_from_bytes(&buf[..size]))?;
+            let message = message.expect("valid message");
+            assert_eq!(message.transaction_id(), transaction_id);
+            let software = message.get_attribute::<Software>().expect("SOFTWARE");
+            assert_eq!(software.description(), format!("page {}", i));
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn max_inflight_handlers_is_shared_by_connections() -> Result<(), MainError> {
+        use factory::CloneFactory;
+        use fibers::time::timer;
+        use server::{Action, HandleMessage};
+
+        #[derive(Default, Clone)]
+        struct DelayedHandler {
+            running: Arc<AtomicUsize>,
+            peak: Arc<AtomicUsize>,
+        }
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                if running > self.peak.load(Ordering::SeqCst) {
+                    self.peak.store(running, Ordering::SeqCst);
+                }
+                let response = SuccessResponse::new(&request);
+                let running = self.running.clone();
+                let future = timer::timeout(Duration::from_millis(100)).then(move |_| {
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(Ok(response))
+                });
+                Action::FutureReply(Box::new(future))
+            }
+        }
+
+        let handler = DelayedHandler::default();
+        let peak = handler.peak.clone();
+        let mut server = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            CloneFactory::new(handler),
+        ))?;
+        server.max_inflight_handlers(Some(1));
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+        thread::sleep(Duration::from_millis(50));
+
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut streams = Vec::new();
+        for _ in 0..3 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            let mut stream = track_any_err!(net::TcpStream::connect(server_addr))?;
+            track_any_err!(stream.set_read_timeout(Some(Duration::from_secs(1))))?;
+            track_any_err!(stream.write_all(&bytes))?;
+            streams.push(stream);
+        }
+
+        // The requests of all connections are handled one by one
+        let mut buf = [0; 1024];
+        for stream in &mut streams {
+            assert_ne!(track_any_err!(stream.read(&mut buf))?, 0);
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn server_counts_requests_per_method() -> Result<(), MainError> {
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        // stun_codec 0.1.13 cannot round-trip the methods greater than `0xF`
+        let other = Method::new(0xF).expect("valid method");
+        for &method in &[rfc5389::methods::BINDING, other, rfc5389::methods::BINDING] {
+            let request = Request::<rfc5389::Attribute>::new(method);
+            let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+            assert_eq!(response.is_ok(), method == rfc5389::methods::BINDING);
+        }
+
+        let counts = metrics.requests_per_method();
+        assert_eq!(counts.len(), 2);
+        assert_eq!(counts.get(&rfc5389::methods::BINDING), Some(&2));
+        assert_eq!(counts.get(&other), Some(&1));
+
+        Ok(())
+    }
+
+    #[test]
+    fn client_checks_reflexive_address() -> Result<(), MainError> {
+        use client::ExpectedAddress;
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let client_addr = transporter.local_addr();
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let mut client = Client::<rfc5389::Attribute, _>::new(&fibers_global::handle(), channel);
+
+        let mut expected = ExpectedAddress::new();
+        expected
+            .allow_prefix("127.0.0.0".parse().unwrap(), 8)
+            .port(Some(client_addr.port()))
+            .strict(true);
+        client.expected_reflexive_address(Some(expected));
+        let mapped = track!(fibers_global::execute(
+            client.discover_reflexive_address(server_addr)
+        ))?;
+        assert_eq!(mapped, client_addr);
+
+        let mut expected = ExpectedAddress::new();
+        expected.allow_prefix("10.0.0.0".parse().unwrap(), 8);
+        client.expected_reflexive_address(Some(expected.clone()));
+        assert!(fibers_global::execute(client.discover_reflexive_address(server_addr)).is_ok());
+
+        expected.strict(true);
+        client.expected_reflexive_address(Some(expected));
+        assert!(fibers_global::execute(client.discover_reflexive_address(server_addr)).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn per_call_integrity_precedes_fingerprint_of_decorator() -> Result<(), MainError> {
+        use auth::IntegrityKey;
+        use client::DecorateRequest;
+
+        struct FingerprintDecorator;
+        impl DecorateRequest<rfc5389::Attribute> for FingerprintDecorator {
+            fn decorate_request(&self, request: &mut Request<rfc5389::Attribute>) {
+                let fingerprint = Fingerprint::new(request.as_ref()).expect("never fails");
+                request.add_attribute(fingerprint.into());
+            }
+        }
+
+        let transporter = MockUdpTransporter::default();
+        let written = transporter.written.clone();
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let mut client = Client::new(&fibers_global::handle(), channel);
+        client.set_decorator(FingerprintDecorator);
+
+        let key = IntegrityKey::ShortTerm("bar".to_owned());
+        let peer = "127.0.0.1:9999".parse().unwrap();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let _call = client.call_with_integrity_key(peer, request, key.clone());
+        for _ in 0..100 {
+            if !written.lock().unwrap().is_empty() {
+                break;
+            }
+            thread::sleep(Duration::from_millis(10));
+        }
+        let request = written.lock().unwrap()[0].1.clone();
+
+        // `FINGERPRINT` is recomputed after `MESSAGE-INTEGRITY`
+        let types = request
+            .attributes()
+            .map(|a| a.get_type().as_u16())
+            .collect::<Vec<_>>();
+        assert_eq!(types, [MessageIntegrity::CODEPOINT, Fingerprint::CODEPOINT]);
+
+        // Both attributes are valid for the transmitted bytes
+        let bytes = track!(MessageEncoder::<rfc5389::Attribute>::default()
+            .encode_into_bytes(request)
+            .map_err(Error::from))?;
+        let decoded = track!(MessageDecoder::<rfc5389::Attribute>::default()
+            .decode_from_bytes(&bytes)
+            .map_err(Error::from))?;
+        let decoded = decoded.expect("valid FINGERPRINT");
+        let integrity = decoded
+            .get_attribute::<MessageIntegrity>()
+            .expect("MESSAGE-INTEGRITY");
+        assert!(key.verify(integrity));
+
+        Ok(())
+    }
+
+    #[test]
+    fn duplicate_integrity_policy_works() -> Result<(), MainError> {
+        fn add_integrity(request: &mut Request<rfc5389::Attribute>, password: &str) {
+            let integrity = MessageIntegrity::new_short_term_credential(request.as_ref(), password)
+                .expect("never fails");
+            request.add_attribute(integrity.into());
+        }
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let cases = [
+            (DuplicateIntegrityPolicy::Reject, "password", "wrong", Some(400)),
+            (DuplicateIntegrityPolicy::Reject, "wrong", "password", Some(400)),
+            (DuplicateIntegrityPolicy::VerifyFirst, "password", "wrong", None),
+            (DuplicateIntegrityPolicy::VerifyFirst, "wrong", "password", Some(431)),
+        ];
+        for &(policy, first, second, expected_error) in &cases {
+            let mut credentials = Credentials::new();
+            credentials.add_user("alice", "password");
+            let mut server = fibers_global::execute(UdpServer::start(
+                fibers_global::handle(),
+                "127.0.0.1:0".parse().unwrap(),
+                BindingHandler,
+            ))?;
+            server
+                .credential_provider(credentials)
+                .duplicate_integrity_policy(policy);
+            let server_addr = server.local_addr();
+            fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+            // The second `MESSAGE-INTEGRITY` covers the first one
+            let mut request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let username = Username::new("alice".to_owned()).expect("valid username");
+            request.add_attribute(username.into());
+            add_integrity(&mut request, first);
+            add_integrity(&mut request, second);
+
+            let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+            match expected_error {
+                None => {
+                    let response = response.expect("success response");
+                    let integrities = response
+                        .attributes()
+                        .filter(|a| a.get_type().as_u16() == MessageIntegrity::CODEPOINT)
+                        .count();
+                    assert_eq!(integrities, 1, "policy={:?}", policy);
+                }
+                Some(code) => {
+                    let response = response.expect_err("error response");
+                    let actual = response.get_attribute::<ErrorCode>().map(|e| e.code());
+                    assert_eq!(actual, Some(code), "policy={:?}", policy);
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    #[test]
+    fn idle_only_keepalives_are_suppressed_by_traffic() -> Result<(), MainError> {
+        use client::KeepaliveMode;
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<MessageEncoder<_>, MessageDecoder<_>>::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let mut client = Client::new(&fibers_global::handle(), channel);
+        client.keepalive_mode(KeepaliveMode::IdleOnly);
+
+        let interval = Duration::from_millis(100);
+        let keepalive = client.keep_binding_alive(server_addr, interval, |_, _| {});
+        fibers_global::spawn(keepalive.map(|_| ()).map_err(|e| panic!("{}", e)));
+        let keepalives = || {
+            metrics
+                .requests_per_method()
+                .get(&rfc5389::methods::BINDING)
+                .cloned()
+                .unwrap_or(0)
+        };
+
+        // Active: only the initial request (for discovering the address) is sent
+        for _ in 0..30 {
+            let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            track!(client.cast(server_addr, indication))?;
+            thread::sleep(Duration::from_millis(20));
+        }
+        assert_eq!(keepalives(), 1);
+        assert!(client.last_sent_time(&server_addr).is_some());
+
+        // Idle
+        thread::sleep(Duration::from_millis(350));
+        assert!(keepalives() >= 3, "keepalives={}", keepalives());
+
+        Ok(())
+    }
+
+    #[test]
+    fn handler_future_pool_without_queue_works() -> Result<(), MainError> {
+        use fibers::time::timer;
+        use server::{Action, HandleMessage, HandlerFuturePool};
+
+        #[derive(Default)]
+        struct DelayedHandler {
+            running: Arc<AtomicUsize>,
+            peak: Arc<AtomicUsize>,
+        }
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let running = self.running.fetch_add(1, Ordering::SeqCst) + 1;
+                if running > self.peak.load(Ordering::SeqCst) {
+                    self.peak.store(running, Ordering::SeqCst);
+                }
+                let response = SuccessResponse::new(&request);
+                let running = self.running.clone();
+                let future = timer::timeout(Duration::from_millis(100)).then(move |_| {
+                    running.fetch_sub(1, Ordering::SeqCst);
+                    Ok(Ok(response))
+                });
+                Action::FutureReply(Box::new(future))
+            }
+        }
+
+        let handler = DelayedHandler::default();
+        let peak = handler.peak.clone();
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            handler,
+        ))?;
+        server.handler_future_pool(HandlerFuturePool::Bounded {
+            workers: 1,
+            queue_len: 0,
+        });
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_secs(1))))?;
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        for _ in 0..3 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            track_any_err!(socket.send_to(&bytes, server_addr))?;
+        }
+
+        // Without a queue, the server stops receiving while the worker is busy,
+        // and all requests are handled one by one
+        let mut buf = [0; 1024];
+        for _ in 0..3 {
+            track_any_err!(socket.recv_from(&mut buf))?;
+        }
+        assert_eq!(peak.load(Ordering::SeqCst), 1);
+
+        Ok(())
+    }
+
+    #[cfg(any(target_os = "linux", target_os = "android"))]
+    #[test]
+    fn unreachable_udp_peer_fails_only_its_transactions() -> Result<(), MainError> {
+        use transport::UdpBindPort;
+
+        #[derive(Default)]
+        struct UnreachableCounter(AtomicUsize);
+        impl TransactionObserver<SocketAddr> for UnreachableCounter {
+            fn on_unreachable(&self, _peer: &SocketAddr, _transaction_id: TransactionId) {
+                self.0.fetch_add(1, Ordering::SeqCst);
+            }
+        }
+
+        let server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            BindingHandler,
+        ))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        // A port on which nobody is listening
+        let unreachable_addr = {
+            let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+            track_any_err!(socket.local_addr())?
+        };
+
+        let transporter = fibers_global::execute(
+            StunUdpTransporterBuilder::new()
+                .bind::<rfc5389::Attribute>("127.0.0.1".parse().unwrap(), UdpBindPort::Ephemeral),
+        )?;
+        let counter = Arc::new(UnreachableCounter::default());
+        let mut channel = Channel::new(transporter);
+        channel.set_observer(counter.clone());
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        // The transaction fails with the ICMP port-unreachable error
+        // rather than a timeout (which would take 39.5 seconds by default)
+        let started_at = Instant::now();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let e = fibers_global::execute(client.call(unreachable_addr, request))
+            .expect_err("unreachable peer");
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+        match *e.kind() {
+            ErrorKind::ConnectionRefused => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert_eq!(counter.0.load(Ordering::SeqCst), 1);
+
+        // The client can still communicate with the other peers
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        assert!(response.is_ok());
+
+        Ok(())
+    }
+
+    #[test]
+    fn attempt_stamper_leaves_signed_and_fingerprinted_requests_intact() -> Result<(), MainError> {
+        fn stamp(attempt: u32) -> rfc5389::Attribute {
+            Software::new(format!("attempt={}", attempt))
+                .expect("valid description")
+                .into()
+        }
+
+        let mut transporter = StunUdpTransporter::new(MockUdpTransporter::default());
+        transporter.set_attempt_stamper(Some(stamp));
+
+        let mut plain = Message::new(
+            MessageClass::Request,
+            rfc5389::methods::BINDING,
+            TransactionId::new([1; 12]),
+        );
+        plain.add_attribute(Username::new("foo".to_owned()).expect("valid username").into());
+        track!(transporter.start_send("127.0.0.1:10001".parse().unwrap(), plain))?;
+
+        let mut fingerprinted = Message::new(
+            MessageClass::Request,
+            rfc5389::methods::BINDING,
+            TransactionId::new([2; 12]),
+        );
+        let fingerprint = track_any_err!(Fingerprint::new(&fingerprinted))?;
+        fingerprinted.add_attribute(fingerprint.into());
+        track!(transporter.start_send("127.0.0.1:10002".parse().unwrap(), fingerprinted))?;
+
+        let queued = transporter
+            .inner_ref()
+            .queue
+            .iter()
+            .map(|e| &e.1)
+            .collect::<Vec<_>>();
+        assert_eq!(queued.len(), 2);
+
+        // The attribute is appended to the plain request
+        let types = |m: &Message<rfc5389::Attribute>| {
+            m.attributes()
+                .map(|a| a.get_type().as_u16())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(types(queued[0]), [Username::CODEPOINT, Software::CODEPOINT]);
+        assert_eq!(
+            queued[0]
+                .get_attribute::<Software>()
+                .map(|a| a.description()),
+            Some("attempt=1")
+        );
+
+        // `FINGERPRINT` remains the last attribute
+        assert_eq!(types(queued[1]), [Fingerprint::CODEPOINT]);
+
+        Ok(())
+    }
+
+    #[test]
+    fn response_origin_is_omitted_for_change_requests() -> Result<(), MainError> {
+        use server::{Action, HandleMessage};
+        use stun_codec::rfc5780::attributes::{ChangeRequest, ResponseOrigin};
+
+        struct EmptyHandler;
+        impl HandleMessage for EmptyHandler {
+            type Attribute = rfc5780::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                Action::Reply(Ok(SuccessResponse::new(&request)))
+            }
+        }
+
+        // A wildcard address cannot be reported as the origin of the responses
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "0.0.0.0:0".parse().unwrap(),
+            EmptyHandler,
+        ))?;
+        let result = server.include_response_origin(true).map(|_| ());
+        let e = result.expect_err("must fail");
+        match *e.kind() {
+            ErrorKind::InvalidInput => {}
+            _ => panic!("unexpected error: {}", e),
+        }
+        assert!(server.include_response_origin(false).is_ok());
+
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            EmptyHandler,
+        ))?;
+        track!(server.include_response_origin(true))?;
+        let server_addr = server.local_addr();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let client_addr = "127.0.0.1:0".parse().unwrap();
+        let transporter = track!(fibers_global::execute(
+            UdpTransporter::<
+                MessageEncoder<rfc5780::Attribute>,
+                MessageDecoder<rfc5780::Attribute>,
+            >::bind(client_addr)
+                .map_err(Error::from)
+        ))?;
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let client = Client::new(&fibers_global::handle(), channel);
+
+        let request = Request::new(rfc5389::methods::BINDING);
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect("success response");
+        let origin = response
+            .get_attribute::<ResponseOrigin>()
+            .map(|a| a.address());
+        assert_eq!(origin, Some(server_addr));
+
+        let request = Request::new(rfc5389::methods::BINDING)
+            .with_attribute(ChangeRequest::new(false, false).into());
+        let response = track!(fibers_global::execute(client.call(server_addr, request)))?;
+        let response = response.expect("success response");
+        assert!(response.get_attribute::<ResponseOrigin>().is_none());
+
+        Ok(())
+    }
+
+    #[test]
+    fn on_send_is_issued_when_request_is_written() -> Result<(), MainError> {
+        #[derive(Default)]
+        struct SendRecorder(Mutex<Vec<(&'static str, TransactionId)>>);
+        impl TransactionObserver<SocketAddr> for SendRecorder {
+            fn on_start(&self, _peer: &SocketAddr, transaction_id: TransactionId, _method: Method) {
+                self.0.lock().unwrap().push(("start", transaction_id));
+            }
+
+            fn on_send(&self, _peer: &SocketAddr, transaction_id: TransactionId) {
+                self.0.lock().unwrap().push(("send", transaction_id));
+            }
+        }
+
+        let recorder = Arc::new(SendRecorder::default());
+        let events = recorder.clone();
+        let test = futures::lazy(move || -> Result<(), Error> {
+            // The send buffer is full until `writable` is set
+            let transporter = StunUdpTransporter::new(MockUdpTransporter {
+                writable: false,
+                ..MockUdpTransporter::default()
+            });
+            let mut channel = Channel::new(transporter);
+            channel.set_observer(recorder);
+            let peer = "127.0.0.1:9999".parse().unwrap();
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let transaction_id = request.transaction_id();
+            let _response = channel.call(peer, request);
+
+            // Not written yet
+            assert!(track!(channel.poll_send())?.is_not_ready());
+            assert_eq!(*events.0.lock().unwrap(), [("start", transaction_id)]);
+
+            channel.transporter_mut().inner_mut().writable = true;
+            assert!(track!(channel.poll_send())?.is_ready());
+            assert_eq!(
+                *events.0.lock().unwrap(),
+                [("start", transaction_id), ("send", transaction_id)]
+            );
+            Ok(())
+        });
+        track!(fibers_global::execute(test))?;
+        Ok(())
+    }
+
+    #[test]
+    fn unknown_attribute_policy_is_applied_per_category() -> Result<(), MainError> {
+        use message::InvalidMessage;
+        use server::{
+            Action, HandleMessage, UnknownAttributePolicy, UnknownOptionalAttributes,
+            UnknownRequiredAttributes,
+        };
+        use stun_codec::rfc5389::attributes::UnknownAttributes;
+        use stun_codec::rfc5780::attributes::{ChangeRequest, OtherAddress};
+
+        // Reports the number of the unknown attributes seen by the handler
+        struct UnknownCounter(Arc<AtomicUsize>);
+        impl HandleMessage for UnknownCounter {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let unknowns = request.as_ref().unknown_attributes().count();
+                let mut response = SuccessResponse::new(&request);
+                let software = Software::new(format!("unknown={}", unknowns)).unwrap();
+                response.add_attribute(software.into());
+                Action::Reply(Ok(response))
+            }
+
+            fn handle_invalid_message(
+                &mut self,
+                _peer: SocketAddr,
+                _message: InvalidMessage,
+            ) -> Action<Response<Self::Attribute>> {
+                self.0.fetch_add(1, Ordering::SeqCst);
+                Action::NoReply
+            }
+        }
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_millis(200))))?;
+        let exchange = |server_addr, attribute: rfc5780::Attribute| -> Result<_, MainError> {
+            let request = Request::new(rfc5389::methods::BINDING).with_attribute(attribute);
+            let bytes = track!(MessageEncoder::default()
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            track_any_err!(socket.send_to(&bytes, server_addr))?;
+
+            let mut buf = [0; 1024];
+            let size = match socket.recv_from(&mut buf) {
+                Err(_) => return Ok(None),
+                Ok((size, _)) => size,
+            };
+            let response = track!(MessageDecoder::<rfc5389::Attribute>::default()
+                .decode_from_bytes(&buf[..size])
+                .map_err(Error::from))?;
+            Ok(Some(response.expect("well-formed message")))
+        };
+        let start = |policy| -> Result<_, MainError> {
+            let invalids = Arc::new(AtomicUsize::new(0));
+            let mut server = fibers_global::execute(UdpServer::start(
+                fibers_global::handle(),
+                "127.0.0.1:0".parse().unwrap(),
+                UnknownCounter(invalids.clone()),
+            ))?;
+            server.unknown_attribute_policy(policy);
+            let server_addr = server.local_addr();
+            fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+            Ok((server_addr, invalids))
+        };
+        let software = |m: &Message<rfc5389::Attribute>| {
+            m.get_attribute::<Software>()
+                .map(|a| a.description().to_owned())
+        };
+        // `OTHER-ADDRESS` is comprehension-optional, and `CHANGE-REQUEST` is comprehension-required
+        let optional = || OtherAddress::new("127.0.0.1:3478".parse().unwrap()).into();
+        let required = || ChangeRequest::new(true, false).into();
+
+        // Default: collected and delegated
+        let (server_addr, invalids) = start(UnknownAttributePolicy::default())?;
+        let response = exchange(server_addr, optional())?.expect("response");
+        assert_eq!(software(&response), Some("unknown=1".to_owned()));
+        assert!(exchange(server_addr, required())?.is_none());
+        assert_eq!(invalids.load(Ordering::SeqCst), 1);
+
+        // Ignored and rejected
+        let (server_addr, invalids) = start(UnknownAttributePolicy {
+            optional: UnknownOptionalAttributes::Ignore,
+            required: UnknownRequiredAttributes::Reject,
+        })?;
+        let response = exchange(server_addr, optional())?.expect("response");
+        assert_eq!(software(&response), Some("unknown=0".to_owned()));
+        let response = exchange(server_addr, required())?.expect("response");
+        assert_eq!(response.class(), MessageClass::ErrorResponse);
+        assert_eq!(
+            response.get_attribute::<ErrorCode>().map(|e| e.code()),
+            Some(420)
+        );
+        assert_eq!(
+            response
+                .get_attribute::<UnknownAttributes>()
+                .map(|a| a.unknowns().to_vec()),
+            Some(vec![AttributeType::new(ChangeRequest::CODEPOINT)])
+        );
+        assert_eq!(invalids.load(Ordering::SeqCst), 0);
+
+        // Dropped
+        let (server_addr, invalids) = start(UnknownAttributePolicy {
+            optional: UnknownOptionalAttributes::Collect,
+            required: UnknownRequiredAttributes::Drop,
+        })?;
+        assert!(exchange(server_addr, required())?.is_none());
+        assert_eq!(invalids.load(Ordering::SeqCst), 0);
+
+        Ok(())
+    }
+
+    #[test]
+    fn initial_rto_can_be_seeded_per_peer() -> Result<(), MainError> {
+        let peer: SocketAddr = "127.0.0.1:10001".parse().unwrap();
+        let other: SocketAddr = "127.0.0.1:10002".parse().unwrap();
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_secs(60))
+            .finish(MockUdpTransporter::default());
+        assert_eq!(transporter.rto_for(peer), None);
+        transporter.set_initial_rto_for(peer, Duration::from_millis(20));
+        assert_eq!(transporter.rto_for(peer), Some(Duration::from_millis(20)));
+        assert_eq!(transporter.rto_for(other), None);
+
+        // The first retransmission is sent after the seeded RTO rather than the default one
+        let written = transporter.inner_ref().written.clone();
+        let mut channel = Channel::new(transporter);
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let _response = channel.call(peer, request);
+        let started_at = Instant::now();
+        let rto = track!(fibers_global::execute(futures::future::poll_fn(
+            move || -> Poll<_, Error> {
+                track!(channel.poll_send())?;
+                if written.lock().unwrap().len() < 2 {
+                    return Ok(Async::NotReady);
+                }
+                Ok(Async::Ready(channel.transporter_ref().rto_for(peer)))
+            }
+        )))?;
+        assert!(started_at.elapsed() < Duration::from_secs(5));
+
+        // The doubled RTO is cached for the subsequent transactions
+        assert_eq!(rto, Some(Duration::from_millis(40)));
+
+        Ok(())
+    }
+
+    #[test]
+    fn overloaded_udp_server_rejects_requests() -> Result<(), MainError> {
+        use fibers::time::timer;
+        use server::{Action, HandleMessage};
+
+        struct DelayedHandler;
+        impl HandleMessage for DelayedHandler {
+            type Attribute = rfc5389::Attribute;
+
+            fn handle_call(
+                &mut self,
+                _peer: SocketAddr,
+                request: Request<Self::Attribute>,
+            ) -> Action<Response<Self::Attribute>> {
+                let response = SuccessResponse::new(&request);
+                let future = timer::timeout(Duration::from_millis(100)).then(|_| Ok(Ok(response)));
+                Action::FutureReply(Box::new(future))
+            }
+        }
+
+        let mut server = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            "127.0.0.1:0".parse().unwrap(),
+            DelayedHandler,
+        ))?;
+        server.max_inflight_handlers(Some(1));
+        server.reject_on_overload(true);
+        let server_addr = server.local_addr();
+        let metrics = server.metrics().clone();
+        fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        track_any_err!(socket.set_read_timeout(Some(Duration::from_secs(5))))?;
+        let mut encoder = MessageEncoder::<rfc5389::Attribute>::default();
+        let mut transaction_ids = Vec::new();
+        for _ in 0..2 {
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            transaction_ids.push(request.transaction_id());
+            let bytes = track!(encoder
+                .encode_into_bytes(request.into_message())
+                .map_err(Error::from))?;
+            track_any_err!(socket.send_to(&bytes, server_addr))?;
+        }
+
+        // The second request is rejected while the first one is being handled
+        let mut decoder = MessageDecoder::<rfc5389::Attribute>::default();
+        let mut buf = [0; 1024];
+        let mut responses = Vec::new();
+        for _ in 0..2 {
+            let (size, _) = track_any_err!(socket.recv_from(&mut buf))?;
+            let response = track!(decoder.decode_from_bytes(&buf[..size]).map_err(Error::from))?;
+            responses.push(response.expect("well-formed message"));
+        }
+        assert_eq!(responses[0].transaction_id(), transaction_ids[1]);
+        assert_eq!(responses[0].class(), MessageClass::ErrorResponse);
+        assert_eq!(
+            responses[0].get_attribute::<ErrorCode>().map(|e| e.code()),
+            Some(500)
+        );
+        assert_eq!(responses[1].transaction_id(), transaction_ids[0]);
+        assert_eq!(responses[1].class(), MessageClass::SuccessResponse);
+        assert_eq!(metrics.overload_rejections(), 1);
+
+        Ok(())
+    }
+
+    #[test]
+    fn binding_to_used_address_fails_with_addr_in_use() -> Result<(), MainError> {
+        use transport::UdpBindPort;
+
+        let socket = track_any_err!(UdpSocket::bind("127.0.0.1:0"))?;
+        let used_addr = track_any_err!(socket.local_addr())?;
+        let result = fibers_global::execute(UdpServer::start(
+            fibers_global::handle(),
+            used_addr,
+            BindingHandler,
+        ));
+        match result {
+            Err(ref e) if is_addr_in_use(e) => {}
+            _ => panic!("the UDP server should not bind to a used address"),
+        }
+
+        let port = UdpBindPort::Specific(used_addr.port());
+        let result = fibers_global::execute(
+            StunUdpTransporterBuilder::new().bind::<rfc5389::Attribute>(used_addr.ip(), port),
+        );
+        match result {
+            Err(ref e) if is_addr_in_use(e) => {}
+            _ => panic!("the UDP transporter should not bind to a used address"),
+        }
+
+        let listener = track_any_err!(net::TcpListener::bind("127.0.0.1:0"))?;
+        let used_addr = track_any_err!(listener.local_addr())?;
+        let result = fibers_global::execute(TcpServer::start(
+            fibers_global::handle(),
+            used_addr,
+            DefaultFactory::<BindingHandler>::new(),
+        ));
+        match result {
+            Err(ref e) if is_addr_in_use(e) => {}
+            _ => panic!("the TCP server should not bind to a used address"),
+        }
+
+        Ok(())
+    }
+
+    fn is_addr_in_use(e: &Error) -> bool {
+        matches!(*e.kind(), ErrorKind::AddrInUse)
+    }
 }
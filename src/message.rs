@@ -24,11 +24,20 @@
 //! > [RFC 5389 -- 3. Overview of Operation]
 //!
 //! [RFC 5389 -- 3. Overview of Operation]: https://tools.ietf.org/html/rfc5389#section-3
-use rand;
+use rand::rngs::{OsRng, StdRng};
+use rand::{self, Rng, SeedableRng};
 use std;
+use std::fmt;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+use std::process;
 use stun_codec::convert::TryAsRef;
 use stun_codec::rfc5389::attributes::ErrorCode;
-use stun_codec::{Attribute, Message, MessageClass, Method, TransactionId};
+use stun_codec::{
+    rfc5389, Attribute, AttributeType, Message, MessageClass, Method, TransactionId,
+};
+use trackable::error::ErrorKindExt;
+
+use ErrorKind;
 
 pub use error::{MessageError, MessageErrorKind};
 
@@ -42,6 +51,7 @@ pub struct InvalidMessage {
     class: MessageClass,
     transaction_id: TransactionId,
     error: MessageError,
+    raw_bytes: Option<Vec<u8>>,
 }
 impl InvalidMessage {
     /// Returns the method of the message.
@@ -64,6 +74,15 @@ impl InvalidMessage {
         &self.error
     }
 
+    /// Returns the raw bytes of the message as received.
+    ///
+    /// The bytes are only available if the channel that received the message has been given
+    /// a `RawMessageCapture` handle (see `ChannelBuilder::raw_message_capture`),
+    /// and they may be truncated to `RawMessageCapture::max_bytes`.
+    pub fn raw_bytes(&self) -> Option<&[u8]> {
+        self.raw_bytes.as_ref().map(|b| &b[..])
+    }
+
     pub(crate) fn new(
         method: Method,
         class: MessageClass,
@@ -75,8 +94,481 @@ impl InvalidMessage {
             class,
             transaction_id,
             error,
+            raw_bytes: None,
         }
     }
+
+    pub(crate) fn set_raw_bytes(&mut self, bytes: Vec<u8>) {
+        self.raw_bytes = Some(bytes);
+    }
+}
+
+/// The size of the fixed header of a STUN message in bytes.
+pub const HEADER_SIZE: usize = 20;
+
+/// The fixed value of the magic cookie field of a STUN message header.
+pub const MAGIC_COOKIE: u32 = 0x2112_A442;
+
+/// The fixed header of a STUN message.
+///
+/// This is returned by `parse_header` (see its documentation for more details).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct StunHeader {
+    class: MessageClass,
+    method: Method,
+    length: u16,
+    magic_cookie: u32,
+    transaction_id: TransactionId,
+}
+impl StunHeader {
+    /// Returns the class of the message.
+    pub fn class(&self) -> MessageClass {
+        self.class
+    }
+
+    /// Returns the method of the message.
+    pub fn method(&self) -> Method {
+        self.method
+    }
+
+    /// Returns the length of the message in bytes, not including the header.
+    pub fn length(&self) -> u16 {
+        self.length
+    }
+
+    /// Returns the value of the magic cookie field of the message.
+    pub fn magic_cookie(&self) -> u32 {
+        self.magic_cookie
+    }
+
+    /// Returns `true` if the magic cookie field has the value defined by [RFC 5389],
+    /// otherwise `false` (e.g., the message has been sent by a [RFC 3489] client).
+    ///
+    /// [RFC 5389]: https://tools.ietf.org/html/rfc5389
+    /// [RFC 3489]: https://tools.ietf.org/html/rfc3489
+    pub fn has_magic_cookie(&self) -> bool {
+        self.magic_cookie == MAGIC_COOKIE
+    }
+
+    /// Returns the transaction ID of the message.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+}
+
+/// Parses the fixed header at the beginning of `bytes` without decoding the attributes.
+///
+/// This is intended for the applications that need to classify STUN messages quickly
+/// (e.g., proxies that route messages to backends by their methods).
+/// It does not allocate, and it only looks at the first `HEADER_SIZE` bytes of `bytes`.
+///
+/// > All STUN messages MUST start with a 20-byte header followed by zero
+/// > or more Attributes.  The STUN header contains a STUN message type,
+/// > magic cookie, transaction ID, and message length.
+/// >
+/// > [RFC 5389 -- 6. STUN Message Structure]
+///
+/// # Errors
+///
+/// If `bytes` is shorter than `HEADER_SIZE`, the most significant 2 bits of it are not zeroes,
+/// or the message length is not a multiple of 4, an `ErrorKind::InvalidInput` error
+/// will be returned.
+///
+/// Note that the magic cookie is not validated (see `StunHeader::has_magic_cookie`),
+/// nor whether `bytes` contains the whole message.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate bytecodec;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use bytecodec::EncodeExt;
+/// use rustun::message::{parse_header, Request};
+/// use stun_codec::{rfc5389, MessageClass, MessageEncoder};
+///
+/// # fn main() {
+/// let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+/// let bytes = MessageEncoder::new()
+///     .encode_into_bytes(request.clone().into_message())
+///     .unwrap();
+///
+/// let header = parse_header(&bytes).unwrap();
+/// assert_eq!(header.class(), MessageClass::Request);
+/// assert_eq!(header.method(), rfc5389::methods::BINDING);
+/// assert_eq!(header.transaction_id(), request.transaction_id());
+/// assert!(header.has_magic_cookie());
+/// # }
+/// ```
+///
+/// [RFC 5389 -- 6. STUN Message Structure]: https://tools.ietf.org/html/rfc5389#section-6
+pub fn parse_header(bytes: &[u8]) -> ::Result<StunHeader> {
+    track_assert!(
+        bytes.len() >= HEADER_SIZE,
+        ErrorKind::InvalidInput,
+        "Too short STUN header: {} bytes",
+        bytes.len()
+    );
+
+    let message_type = (u16::from(bytes[0]) << 8) | u16::from(bytes[1]);
+    track_assert_eq!(
+        message_type >> 14,
+        0,
+        ErrorKind::InvalidInput,
+        "The first two bits of a STUN message must be zeroes"
+    );
+
+    let length = (u16::from(bytes[2]) << 8) | u16::from(bytes[3]);
+    track_assert_eq!(
+        length % 4,
+        0,
+        ErrorKind::InvalidInput,
+        "The length of a STUN message must be a multiple of 4"
+    );
+
+    let magic_cookie = (u32::from(bytes[4]) << 24)
+        | (u32::from(bytes[5]) << 16)
+        | (u32::from(bytes[6]) << 8)
+        | u32::from(bytes[7]);
+
+    let class = match ((message_type >> 4) & 0b01) | ((message_type >> 7) & 0b10) {
+        0b00 => MessageClass::Request,
+        0b01 => MessageClass::Indication,
+        0b10 => MessageClass::SuccessResponse,
+        _ => MessageClass::ErrorResponse,
+    };
+    let method = (message_type & 0b0000_0000_1111)
+        | ((message_type >> 1) & 0b0000_0111_0000)
+        | ((message_type >> 2) & 0b1111_1000_0000);
+    let method = track!(Method::new(method))?;
+
+    let mut transaction_id = [0; 12];
+    transaction_id.copy_from_slice(&bytes[8..HEADER_SIZE]);
+    Ok(StunHeader {
+        class,
+        method,
+        length,
+        magic_cookie,
+        transaction_id: TransactionId::new(transaction_id),
+    })
+}
+
+/// Encodes the given address into the value of an `XOR-MAPPED-ADDRESS` attribute.
+///
+/// The returned bytes consist of the address family, the X-Port and the X-Address fields
+/// (i.e., the attribute header is not included), which are obfuscated with `MAGIC_COOKIE` and
+/// `transaction_id` as described in [RFC 5389 -- 15.2. XOR-MAPPED-ADDRESS].
+///
+/// This is useful for producing the attribute value outside of the typed attribute machinery
+/// of `stun_codec` (e.g., when bridging with other systems).
+/// The same encoding is also used by the other XOR'ed address attributes
+/// (e.g., `XOR-PEER-ADDRESS` and `XOR-RELAYED-ADDRESS` of TURN).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::message::{xor_decode_addr, xor_encode_addr};
+/// use stun_codec::TransactionId;
+///
+/// # fn main() {
+/// let addr = "192.0.2.1:32853".parse().unwrap();
+/// let transaction_id = TransactionId::new([1; 12]);
+/// let bytes = xor_encode_addr(addr, transaction_id);
+/// assert_eq!(bytes, [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43]);
+/// assert_eq!(xor_decode_addr(&bytes, transaction_id).unwrap(), addr);
+/// # }
+/// ```
+///
+/// [RFC 5389 -- 15.2. XOR-MAPPED-ADDRESS]: https://tools.ietf.org/html/rfc5389#section-15.2
+pub fn xor_encode_addr(addr: SocketAddr, transaction_id: TransactionId) -> Vec<u8> {
+    let mask = xor_mask(transaction_id);
+    let port = addr.port() ^ (MAGIC_COOKIE >> 16) as u16;
+    let mut bytes = vec![0, 0, (port >> 8) as u8, port as u8];
+    match addr.ip() {
+        IpAddr::V4(ip) => {
+            bytes[1] = ADDRESS_FAMILY_IPV4;
+            bytes.extend(ip.octets().iter().zip(&mask).map(|(a, b)| a ^ b));
+        }
+        IpAddr::V6(ip) => {
+            bytes[1] = ADDRESS_FAMILY_IPV6;
+            bytes.extend(ip.octets().iter().zip(&mask).map(|(a, b)| a ^ b));
+        }
+    }
+    bytes
+}
+
+/// Decodes the value of an `XOR-MAPPED-ADDRESS` attribute into the address.
+///
+/// This is the inverse of `xor_encode_addr`.
+///
+/// # Errors
+///
+/// If the address family is unknown or the length of `bytes` does not match the family,
+/// this function will return an `ErrorKind::InvalidInput` error.
+pub fn xor_decode_addr(bytes: &[u8], transaction_id: TransactionId) -> ::Result<SocketAddr> {
+    track_assert!(
+        bytes.len() >= 4,
+        ErrorKind::InvalidInput,
+        "Too short XOR-MAPPED-ADDRESS: {} bytes",
+        bytes.len()
+    );
+    let mask = xor_mask(transaction_id);
+    let port = ((u16::from(bytes[2]) << 8) | u16::from(bytes[3])) ^ (MAGIC_COOKIE >> 16) as u16;
+    let xaddr = &bytes[4..];
+    let ip = match bytes[1] {
+        ADDRESS_FAMILY_IPV4 => {
+            track_assert_eq!(xaddr.len(), 4, ErrorKind::InvalidInput);
+            let mut octets = [0; 4];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = xaddr[i] ^ mask[i];
+            }
+            IpAddr::V4(Ipv4Addr::from(octets))
+        }
+        ADDRESS_FAMILY_IPV6 => {
+            track_assert_eq!(xaddr.len(), 16, ErrorKind::InvalidInput);
+            let mut octets = [0; 16];
+            for (i, o) in octets.iter_mut().enumerate() {
+                *o = xaddr[i] ^ mask[i];
+            }
+            IpAddr::V6(Ipv6Addr::from(octets))
+        }
+        family => track_panic!(
+            ErrorKind::InvalidInput,
+            "Unknown address family: {}",
+            family
+        ),
+    };
+    Ok(SocketAddr::new(ip, port))
+}
+
+const ADDRESS_FAMILY_IPV4: u8 = 0x01;
+const ADDRESS_FAMILY_IPV6: u8 = 0x02;
+
+/// Returns the concatenation of the magic cookie and the transaction ID.
+fn xor_mask(transaction_id: TransactionId) -> [u8; 16] {
+    let mut mask = [0; 16];
+    mask[0] = (MAGIC_COOKIE >> 24) as u8;
+    mask[1] = (MAGIC_COOKIE >> 16) as u8;
+    mask[2] = (MAGIC_COOKIE >> 8) as u8;
+    mask[3] = MAGIC_COOKIE as u8;
+    mask[4..].copy_from_slice(transaction_id.as_bytes());
+    mask
+}
+
+/// This trait allows for customizing how the transaction IDs of new messages are generated.
+///
+/// An instance can be used as follows:
+///
+/// ```
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::message::{NamespacedTransactionIdGenerator, Request, TransactionIdGenerator};
+/// use stun_codec::rfc5389::{methods::BINDING, Attribute};
+///
+/// # fn main() {
+/// let mut generator = NamespacedTransactionIdGenerator::new(0x1234_5678);
+/// let request = Request::<Attribute>::with_transaction_id(BINDING, generator.generate());
+/// assert_eq!(
+///     NamespacedTransactionIdGenerator::namespace_of(request.transaction_id()),
+///     0x1234_5678
+/// );
+/// # }
+/// ```
+pub trait TransactionIdGenerator {
+    /// Generates a new transaction ID.
+    fn generate(&mut self) -> TransactionId;
+}
+
+/// A `TransactionIdGenerator` implementation that generates completely random transaction IDs.
+///
+/// This is the strategy used by `Request::new` and `Indication::new`.
+/// The IDs are generated by the thread-local RNG of `rand` (i.e., `rand::thread_rng`),
+/// which is seeded from the operating system and reseeds itself periodically.
+/// Note that the RNG does not detect `fork`, so the child processes forked after an ID
+/// has been generated on the forking thread produce the same sequence of IDs as each other.
+/// Use `SeededTransactionIdGenerator` if it matters.
+#[derive(Debug, Default, Clone)]
+pub struct RandomTransactionIdGenerator;
+impl RandomTransactionIdGenerator {
+    /// Makes a new `RandomTransactionIdGenerator` instance.
+    pub fn new() -> Self {
+        RandomTransactionIdGenerator
+    }
+}
+impl TransactionIdGenerator for RandomTransactionIdGenerator {
+    fn generate(&mut self) -> TransactionId {
+        TransactionId::new(rand::random())
+    }
+}
+
+/// A `TransactionIdGenerator` implementation that splits the transaction ID space into namespaces.
+///
+/// The high 32 bits of a generated transaction ID are the namespace (e.g., an identifier of a client)
+/// and the low 64 bits are random.
+/// Thus the transaction IDs generated by clients that use distinct namespaces never collide,
+/// and the client that issued a transaction can be identified by its ID
+/// (see `NamespacedTransactionIdGenerator::namespace_of`).
+///
+/// Note that the namespace is visible on the wire, and the remaining 64 bits of randomness
+/// is less than the 96 bits recommended by RFC 5389.
+#[derive(Debug, Clone)]
+pub struct NamespacedTransactionIdGenerator {
+    namespace: u32,
+}
+impl NamespacedTransactionIdGenerator {
+    /// Makes a new `NamespacedTransactionIdGenerator` instance that uses the given namespace.
+    pub fn new(namespace: u32) -> Self {
+        NamespacedTransactionIdGenerator { namespace }
+    }
+
+    /// Returns the namespace used by this generator.
+    pub fn namespace(&self) -> u32 {
+        self.namespace
+    }
+
+    /// Extracts the namespace from a transaction ID generated by a `NamespacedTransactionIdGenerator`.
+    pub fn namespace_of(transaction_id: TransactionId) -> u32 {
+        let bytes = transaction_id.as_bytes();
+        (u32::from(bytes[0]) << 24)
+            | (u32::from(bytes[1]) << 16)
+            | (u32::from(bytes[2]) << 8)
+            | u32::from(bytes[3])
+    }
+}
+impl TransactionIdGenerator for NamespacedTransactionIdGenerator {
+    fn generate(&mut self) -> TransactionId {
+        let random: [u8; 8] = rand::random();
+        let mut bytes = [0; 12];
+        bytes[0] = (self.namespace >> 24) as u8;
+        bytes[1] = (self.namespace >> 16) as u8;
+        bytes[2] = (self.namespace >> 8) as u8;
+        bytes[3] = self.namespace as u8;
+        bytes[4..].copy_from_slice(&random);
+        TransactionId::new(bytes)
+    }
+}
+
+/// A `TransactionIdGenerator` implementation that owns a cryptographically secure RNG
+/// of which the seeding and reseeding are configurable.
+///
+/// Transaction IDs should be unique and unpredictable:
+///
+/// > It primarily serves to correlate requests with responses,
+/// > though it also plays a small role in helping to prevent certain types of attacks.
+/// > The server also uses the transaction ID as a key to identify each
+/// > transaction uniquely across all clients.  As such, the transaction ID
+/// > MUST be uniformly and randomly chosen from the interval 0 .. 2**96-1,
+/// > and SHOULD be cryptographically random.
+/// >
+/// > [RFC 5389 -- 6. STUN Message Structure]
+///
+/// If the IDs are predictable, an off-path attacker can forge responses that are accepted
+/// by a client, and if two processes generate the same IDs, their transactions can be confused
+/// by a server (e.g., by its response cache).
+///
+/// By default, the generator reseeds itself from the operating system when it detects that
+/// it runs in a process other than the one it was seeded in (i.e., after `fork`),
+/// so that forked processes never share the same sequence of IDs.
+/// Additionally, it can reseed itself every `reseed_interval` IDs (see `set_reseed_interval`).
+///
+/// [RFC 5389 -- 6. STUN Message Structure]: https://tools.ietf.org/html/rfc5389#section-6
+#[derive(Debug, Clone)]
+pub struct SeededTransactionIdGenerator {
+    rng: StdRng,
+    pid: u32,
+    reseed_on_fork: bool,
+    reseed_interval: Option<u64>,
+    generated: u64,
+}
+impl SeededTransactionIdGenerator {
+    /// Makes a new `SeededTransactionIdGenerator` instance seeded from the operating system.
+    ///
+    /// # Errors
+    ///
+    /// If the entropy source of the operating system is unavailable,
+    /// this will return an `ErrorKind::Other` error.
+    pub fn from_entropy() -> ::Result<Self> {
+        let rng = track!(os_seeded_rng())?;
+        Ok(Self::with_rng(rng))
+    }
+
+    /// Makes a new `SeededTransactionIdGenerator` instance seeded with the given value.
+    ///
+    /// The same seed produces the same sequence of IDs (until reseeded),
+    /// so this is intended for reproducible tests or for environments in which the seed is
+    /// obtained from a trusted entropy source other than the operating system.
+    /// Never use a fixed or guessable seed in production.
+    pub fn from_seed(seed: [u8; 32]) -> Self {
+        Self::with_rng(StdRng::from_seed(seed))
+    }
+
+    /// Sets whether the generator reseeds itself from the operating system when it detects that
+    /// the process has been forked.
+    ///
+    /// The default value is `true`.
+    pub fn set_reseed_on_fork(&mut self, enabled: bool) -> &mut Self {
+        self.reseed_on_fork = enabled;
+        self
+    }
+
+    /// Sets the number of the IDs generated before the generator reseeds itself
+    /// from the operating system.
+    ///
+    /// The default value is `None` (i.e., no periodic reseeding).
+    pub fn set_reseed_interval(&mut self, interval: Option<u64>) -> &mut Self {
+        self.reseed_interval = interval;
+        self
+    }
+
+    /// Reseeds the generator from the operating system.
+    ///
+    /// # Errors
+    ///
+    /// If the entropy source of the operating system is unavailable,
+    /// this will return an `ErrorKind::Other` error and the generator keeps the current state.
+    pub fn reseed(&mut self) -> ::Result<()> {
+        self.rng = track!(os_seeded_rng())?;
+        self.pid = process::id();
+        self.generated = 0;
+        Ok(())
+    }
+
+    fn with_rng(rng: StdRng) -> Self {
+        SeededTransactionIdGenerator {
+            rng,
+            pid: process::id(),
+            reseed_on_fork: true,
+            reseed_interval: None,
+            generated: 0,
+        }
+    }
+
+    fn needs_reseed(&self) -> bool {
+        (self.reseed_on_fork && self.pid != process::id())
+            || self.reseed_interval.map_or(false, |n| self.generated >= n)
+    }
+}
+impl TransactionIdGenerator for SeededTransactionIdGenerator {
+    fn generate(&mut self) -> TransactionId {
+        if self.needs_reseed() {
+            if let Err(e) = track!(self.reseed()) {
+                // The current state is still unpredictable to others unless the process has
+                // been forked, so the generation continues rather than failing.
+                warn!("Cannot reseed the transaction ID generator: {}", e);
+                self.pid = process::id();
+                self.generated = 0;
+            }
+        }
+        self.generated += 1;
+        TransactionId::new(self.rng.gen())
+    }
+}
+
+fn os_seeded_rng() -> ::Result<StdRng> {
+    let os_rng = track!(OsRng::new().map_err(|e| ErrorKind::Other.cause(e)))?;
+    track!(StdRng::from_rng(os_rng).map_err(|e| ErrorKind::Other.cause(e).into()))
 }
 
 /// Response message.
@@ -87,12 +579,15 @@ pub type Response<A> = std::result::Result<SuccessResponse<A>, ErrorResponse<A>>
 pub struct Request<A>(Message<A>);
 impl<A: Attribute> Request<A> {
     /// Makes a new request message.
+    ///
+    /// The transaction ID of the message is generated randomly.
     pub fn new(method: Method) -> Self {
-        Request(Message::new(
-            MessageClass::Request,
-            method,
-            TransactionId::new(rand::random()),
-        ))
+        Self::with_transaction_id(method, TransactionId::new(rand::random()))
+    }
+
+    /// Makes a new request message that has the given transaction ID.
+    pub fn with_transaction_id(method: Method, transaction_id: TransactionId) -> Self {
+        Request(Message::new(MessageClass::Request, method, transaction_id))
     }
 
     /// Converts `Message` to `Request`.
@@ -115,6 +610,11 @@ impl<A: Attribute> Request<A> {
     }
 
     /// Returns the method of the message.
+    ///
+    /// The method is not restricted to the ones known by this crate.
+    /// A request having an unknown method is delivered to handlers as is,
+    /// so that they can reply an error response (e.g., `400 Bad Request`) to it,
+    /// which has the same method and transaction ID as the request.
     pub fn method(&self) -> Method {
         self.0.method()
     }
@@ -145,6 +645,14 @@ impl<A: Attribute> Request<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Adds the given attribute to the tail of the attributes in the message, and returns the message.
+    ///
+    /// This is a chainable variant of `add_attribute` method.
+    pub fn with_attribute(mut self, attribute: A) -> Self {
+        self.add_attribute(attribute);
+        self
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -166,12 +674,15 @@ impl<A: Attribute> AsMut<Message<A>> for Request<A> {
 pub struct Indication<A>(Message<A>);
 impl<A: Attribute> Indication<A> {
     /// Makes a new indication message.
+    ///
+    /// The transaction ID of the message is generated randomly.
     pub fn new(method: Method) -> Self {
-        Indication(Message::new(
-            MessageClass::Indication,
-            method,
-            TransactionId::new(rand::random()),
-        ))
+        Self::with_transaction_id(method, TransactionId::new(rand::random()))
+    }
+
+    /// Makes a new indication message that has the given transaction ID.
+    pub fn with_transaction_id(method: Method, transaction_id: TransactionId) -> Self {
+        Indication(Message::new(MessageClass::Indication, method, transaction_id))
     }
 
     /// Converts `Message` to `Indication`.
@@ -224,6 +735,14 @@ impl<A: Attribute> Indication<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Adds the given attribute to the tail of the attributes in the message, and returns the message.
+    ///
+    /// This is a chainable variant of `add_attribute` method.
+    pub fn with_attribute(mut self, attribute: A) -> Self {
+        self.add_attribute(attribute);
+        self
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -253,6 +772,23 @@ impl<A: Attribute> SuccessResponse<A> {
         ))
     }
 
+    /// Makes a new `SuccessResponse` instance for the success response to the given request,
+    /// and copies the attributes of the types listed in `attribute_types` from the request.
+    ///
+    /// The copied attributes keep their order in the request.
+    /// Note that `Response<A>` is an alias of `Result`, so this is provided by `SuccessResponse`
+    /// (the error counterpart is `ErrorResponse::new`).
+    pub fn from_request_echoing(request: &Request<A>, attribute_types: &[AttributeType]) -> Self {
+        let mut response = Self::new(request);
+        for attribute in request
+            .attributes()
+            .filter(|a| attribute_types.contains(&a.get_type()))
+        {
+            response.add_attribute(attribute.clone());
+        }
+        response
+    }
+
     /// Converts `Message` to `SuccessResponse`.
     ///
     /// # Errors
@@ -303,6 +839,14 @@ impl<A: Attribute> SuccessResponse<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Adds the given attribute to the tail of the attributes in the message, and returns the message.
+    ///
+    /// This is a chainable variant of `add_attribute` method.
+    pub fn with_attribute(mut self, attribute: A) -> Self {
+        self.add_attribute(attribute);
+        self
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -395,6 +939,44 @@ impl<A: Attribute> ErrorResponse<A> {
         self.0.add_attribute(attribute);
     }
 
+    /// Adds the given attribute to the tail of the attributes in the message, and returns the message.
+    ///
+    /// This is a chainable variant of `add_attribute` method.
+    pub fn with_attribute(mut self, attribute: A) -> Self {
+        self.add_attribute(attribute);
+        self
+    }
+
+    /// Returns the typed representation of the `ERROR-CODE` attribute of the message.
+    ///
+    /// If the attribute is not known to `A` (i.e., `A::try_as_ref` fails),
+    /// this method will return `None`.
+    ///
+    /// To extract it from a `Response`, use `response.err()` or match on the response:
+    ///
+    /// ```
+    /// # extern crate rustun;
+    /// # extern crate stun_codec;
+    /// use rustun::message::{ErrorCodeKind, ErrorResponse, Request, Response};
+    /// use stun_codec::rfc5389;
+    /// use stun_codec::rfc5389::errors::StaleNonce;
+    ///
+    /// # fn main() {
+    /// let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+    /// let response: Response<_> = Err(ErrorResponse::new(&request, StaleNonce.into()));
+    ///
+    /// let error = response.err().and_then(|r| r.typed_error_code()).unwrap();
+    /// assert_eq!(error.kind(), ErrorCodeKind::StaleNonce);
+    /// assert_eq!(error.reason_phrase(), "Stale Nonce");
+    /// # }
+    /// ```
+    pub fn typed_error_code(&self) -> Option<TypedErrorCode>
+    where
+        A: TryAsRef<ErrorCode>,
+    {
+        self.get_attribute::<ErrorCode>().map(TypedErrorCode::from)
+    }
+
     /// Takes ownership of this instance, and returns the internal message.
     pub fn into_message(self) -> Message<A> {
         self.0
@@ -411,6 +993,319 @@ impl<A: Attribute> AsMut<Message<A>> for ErrorResponse<A> {
     }
 }
 
+/// Kind of the error codes conveyed by `ERROR-CODE` attributes.
+///
+/// The error codes defined by the RFCs supported by `stun_codec` are mapped to
+/// the dedicated variants, and the other ones are mapped to `ErrorCodeKind::Other`.
+///
+/// See [RFC 5389 -- 15.6. ERROR-CODE] for the meanings of the codes defined by RFC 5389.
+///
+/// [RFC 5389 -- 15.6. ERROR-CODE]: https://tools.ietf.org/html/rfc5389#section-15.6
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum ErrorCodeKind {
+    /// `300 Try Alternate` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    TryAlternate,
+
+    /// `400 Bad Request` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    BadRequest,
+
+    /// `401 Unauthorized` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    Unauthorized,
+
+    /// `403 Forbidden` ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    Forbidden,
+
+    /// `420 Unknown Attribute` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    UnknownAttribute,
+
+    /// `431 Integrity Check Failure` (see `auth::INTEGRITY_CHECK_FAILURE_CODEPOINT`).
+    IntegrityCheckFailure,
+
+    /// `437 Allocation Mismatch` ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    AllocationMismatch,
+
+    /// `438 Stale Nonce` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    StaleNonce,
+
+    /// `441 Wrong Credentials` ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    WrongCredentials,
+
+    /// `442 Unsupported Transport Protocol`
+    /// ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    UnsupportedTransportProtocol,
+
+    /// `486 Allocation Quota Reached` ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    AllocationQuotaReached,
+
+    /// `487 Role Conflict` ([RFC 5245](https://tools.ietf.org/html/rfc5245#section-21.3)).
+    RoleConflict,
+
+    /// `500 Server Error` ([RFC 5389](https://tools.ietf.org/html/rfc5389#section-15.6)).
+    ServerError,
+
+    /// `508 Insufficient Capacity` ([RFC 5766](https://tools.ietf.org/html/rfc5766#section-15)).
+    InsufficientCapacity,
+
+    /// Other error codes.
+    Other(u16),
+}
+impl ErrorCodeKind {
+    /// Makes a new `ErrorCodeKind` instance from the given numeric error code.
+    pub fn from_code(code: u16) -> Self {
+        match code {
+            300 => ErrorCodeKind::TryAlternate,
+            400 => ErrorCodeKind::BadRequest,
+            401 => ErrorCodeKind::Unauthorized,
+            403 => ErrorCodeKind::Forbidden,
+            420 => ErrorCodeKind::UnknownAttribute,
+            431 => ErrorCodeKind::IntegrityCheckFailure,
+            437 => ErrorCodeKind::AllocationMismatch,
+            438 => ErrorCodeKind::StaleNonce,
+            441 => ErrorCodeKind::WrongCredentials,
+            442 => ErrorCodeKind::UnsupportedTransportProtocol,
+            486 => ErrorCodeKind::AllocationQuotaReached,
+            487 => ErrorCodeKind::RoleConflict,
+            500 => ErrorCodeKind::ServerError,
+            508 => ErrorCodeKind::InsufficientCapacity,
+            _ => ErrorCodeKind::Other(code),
+        }
+    }
+
+    /// Returns the numeric error code.
+    pub fn code(self) -> u16 {
+        match self {
+            ErrorCodeKind::TryAlternate => 300,
+            ErrorCodeKind::BadRequest => 400,
+            ErrorCodeKind::Unauthorized => 401,
+            ErrorCodeKind::Forbidden => 403,
+            ErrorCodeKind::UnknownAttribute => 420,
+            ErrorCodeKind::IntegrityCheckFailure => 431,
+            ErrorCodeKind::AllocationMismatch => 437,
+            ErrorCodeKind::StaleNonce => 438,
+            ErrorCodeKind::WrongCredentials => 441,
+            ErrorCodeKind::UnsupportedTransportProtocol => 442,
+            ErrorCodeKind::AllocationQuotaReached => 486,
+            ErrorCodeKind::RoleConflict => 487,
+            ErrorCodeKind::ServerError => 500,
+            ErrorCodeKind::InsufficientCapacity => 508,
+            ErrorCodeKind::Other(code) => code,
+        }
+    }
+}
+impl From<u16> for ErrorCodeKind {
+    fn from(f: u16) -> Self {
+        ErrorCodeKind::from_code(f)
+    }
+}
+
+/// Typed representation of an `ERROR-CODE` attribute.
+///
+/// This is returned by `ErrorResponse::typed_error_code`.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct TypedErrorCode {
+    kind: ErrorCodeKind,
+    reason_phrase: String,
+}
+impl TypedErrorCode {
+    /// Returns the kind of the error code.
+    pub fn kind(&self) -> ErrorCodeKind {
+        self.kind
+    }
+
+    /// Returns the numeric error code.
+    pub fn code(&self) -> u16 {
+        self.kind.code()
+    }
+
+    /// Returns the reason phrase of the error.
+    pub fn reason_phrase(&self) -> &str {
+        &self.reason_phrase
+    }
+}
+impl<'a> From<&'a ErrorCode> for TypedErrorCode {
+    fn from(f: &'a ErrorCode) -> Self {
+        TypedErrorCode {
+            kind: ErrorCodeKind::from_code(f.code()),
+            reason_phrase: f.reason_phrase().to_owned(),
+        }
+    }
+}
+impl fmt::Display for TypedErrorCode {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} {}", self.code(), self.reason_phrase)
+    }
+}
+
+/// This trait allows for rendering attributes in a human-readable form.
+///
+/// It is used by `PrettyMessage`.
+/// The default implementations of the methods render only the type of an attribute,
+/// so an implementation for a user defined attribute set can override them
+/// to decode the values of the attributes it knows.
+#[allow(unused_variables)]
+pub trait DisplayAttribute: Attribute {
+    /// Returns the name of the attribute (e.g., `"XOR-MAPPED-ADDRESS"`) if it is known.
+    fn attribute_name(&self) -> Option<&'static str> {
+        None
+    }
+
+    /// Writes the value of the attribute in a human-readable form.
+    fn fmt_value(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "..")
+    }
+}
+impl DisplayAttribute for rfc5389::Attribute {
+    fn attribute_name(&self) -> Option<&'static str> {
+        use stun_codec::rfc5389::Attribute as Attr;
+        Some(match *self {
+            Attr::MappedAddress(_) => "MAPPED-ADDRESS",
+            Attr::Username(_) => "USERNAME",
+            Attr::MessageIntegrity(_) => "MESSAGE-INTEGRITY",
+            Attr::ErrorCode(_) => "ERROR-CODE",
+            Attr::UnknownAttributes(_) => "UNKNOWN-ATTRIBUTES",
+            Attr::Realm(_) => "REALM",
+            Attr::Nonce(_) => "NONCE",
+            Attr::XorMappedAddress(_) | Attr::XorMappedAddress2(_) => "XOR-MAPPED-ADDRESS",
+            Attr::Software(_) => "SOFTWARE",
+            Attr::AlternateServer(_) => "ALTERNATE-SERVER",
+            Attr::Fingerprint(_) => "FINGERPRINT",
+        })
+    }
+
+    fn fmt_value(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        use stun_codec::rfc5389::Attribute as Attr;
+        match *self {
+            Attr::MappedAddress(ref a) => write!(f, "{}", a.address()),
+            Attr::Username(ref a) => write!(f, "{:?}", a.name()),
+            Attr::MessageIntegrity(ref a) => fmt_hex(f, &a.hmac_sha1()[..]),
+            Attr::ErrorCode(ref a) => write!(f, "{} {:?}", a.code(), a.reason_phrase()),
+            Attr::UnknownAttributes(ref a) => {
+                write!(f, "[")?;
+                for (i, t) in a.unknowns().iter().enumerate() {
+                    if i > 0 {
+                        write!(f, ", ")?;
+                    }
+                    write!(f, "0x{:04x}", t.as_u16())?;
+                }
+                write!(f, "]")
+            }
+            Attr::Realm(ref a) => write!(f, "{:?}", a.text()),
+            Attr::Nonce(ref a) => write!(f, "{:?}", a.value()),
+            Attr::XorMappedAddress(ref a) => write!(f, "{}", a.address()),
+            Attr::XorMappedAddress2(ref a) => write!(f, "{}", a.address()),
+            Attr::Software(ref a) => write!(f, "{:?}", a.description()),
+            Attr::AlternateServer(ref a) => write!(f, "{}", a.address()),
+            Attr::Fingerprint(ref a) => write!(f, "0x{:08x}", a.crc32()),
+        }
+    }
+}
+
+/// Wrapper for rendering a message in a human-readable form.
+///
+/// The output consists of the method, class and transaction ID of the message,
+/// followed by the type and value of each attribute.
+/// The values of the attributes are rendered by `DisplayAttribute`,
+/// and those of the unknown attributes are rendered as hex dumps.
+///
+/// By default the message is rendered on a single line.
+/// The alternate form (i.e., `{:#}`) puts each attribute on its own line.
+///
+/// Nothing is rendered until the wrapper is actually formatted,
+/// so it costs almost nothing to pass it to a logging macro whose level is disabled
+/// (e.g., `debug!("Received: {}", PrettyMessage::new(&message))`).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::message::{PrettyMessage, Request};
+/// use stun_codec::rfc5389;
+/// use stun_codec::rfc5389::attributes::Software;
+///
+/// # fn main() {
+/// let software = Software::new("foo".to_owned()).unwrap();
+/// let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING)
+///     .with_attribute(software.into());
+/// println!("{:#}", PrettyMessage::new(request.as_ref()));
+/// // Binding Request (method=0x001, transaction_id=0x...)
+/// //   SOFTWARE (0x8022): "foo"
+/// # }
+/// ```
+pub struct PrettyMessage<'a, A: 'a>(&'a Message<A>);
+impl<'a, A: DisplayAttribute> PrettyMessage<'a, A> {
+    /// Makes a new `PrettyMessage` instance.
+    pub fn new(message: &'a Message<A>) -> Self {
+        PrettyMessage(message)
+    }
+}
+impl<'a, A: DisplayAttribute> fmt::Display for PrettyMessage<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let message = self.0;
+        let method = message.method();
+        if let Some(name) = method_name(method) {
+            write!(f, "{} ", name)?;
+        }
+        write!(
+            f,
+            "{:?} (method=0x{:03x}, transaction_id=0x",
+            message.class(),
+            method.as_u16()
+        )?;
+        fmt_hex(f, message.transaction_id().as_bytes())?;
+        write!(f, ")")?;
+
+        let (head, separator, tail) = if f.alternate() {
+            ("\n  ", "\n  ", "")
+        } else {
+            (" {", ", ", "}")
+        };
+        let mut is_empty = true;
+        for attr in message.attributes() {
+            write!(f, "{}", if is_empty { head } else { separator })?;
+            is_empty = false;
+            let name = attr.attribute_name().unwrap_or("UNKNOWN");
+            write!(f, "{} (0x{:04x}): ", name, attr.get_type().as_u16())?;
+            attr.fmt_value(f)?;
+        }
+        for attr in message.unknown_attributes() {
+            write!(f, "{}", if is_empty { head } else { separator })?;
+            is_empty = false;
+            write!(f, "UNKNOWN (0x{:04x}): 0x", attr.get_type().as_u16())?;
+            fmt_hex(f, attr.value())?;
+        }
+        if !is_empty {
+            write!(f, "{}", tail)?;
+        }
+        Ok(())
+    }
+}
+impl<'a, A> fmt::Debug for PrettyMessage<'a, A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PrettyMessage {{ .. }}")
+    }
+}
+
+fn method_name(method: Method) -> Option<&'static str> {
+    match method.as_u16() {
+        0x001 => Some("Binding"),
+        0x003 => Some("Allocate"),
+        0x004 => Some("Refresh"),
+        0x006 => Some("Send"),
+        0x007 => Some("Data"),
+        0x008 => Some("CreatePermission"),
+        0x009 => Some("ChannelBind"),
+        _ => None,
+    }
+}
+
+fn fmt_hex(f: &mut fmt::Formatter, bytes: &[u8]) -> fmt::Result {
+    for b in bytes {
+        write!(f, "{:02x}", b)?;
+    }
+    Ok(())
+}
+
 fn check_unknown_attributes<A: Attribute>(message: &Message<A>) -> MessageResult<()> {
     let required_unknowns = message
         .unknown_attributes()
@@ -427,3 +1322,164 @@ fn check_unknown_attributes<A: Attribute>(message: &Message<A>) -> MessageResult
     );
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::EncodeExt;
+    use stun_codec::rfc5389::attributes::{Software, Username, XorMappedAddress};
+    use stun_codec::MessageEncoder;
+    use trackable::error::MainError;
+
+    use super::*;
+
+    #[test]
+    fn pretty_message_renders_well_known_attributes() {
+        let transaction_id = TransactionId::new([1, 2, 3, 4, 5, 6, 7, 8, 9, 10, 11, 12]);
+        let method = rfc5389::methods::BINDING;
+        let request = Request::<rfc5389::Attribute>::with_transaction_id(method, transaction_id);
+        let error = ErrorCode::new(400, "Bad Request".to_owned()).unwrap();
+        let response = ErrorResponse::new(&request, error)
+            .with_attribute(XorMappedAddress::new("127.0.0.1:3478".parse().unwrap()).into());
+
+        let message = PrettyMessage::new(response.as_ref());
+        assert_eq!(
+            message.to_string(),
+            "Binding ErrorResponse (method=0x001, transaction_id=0x0102030405060708090a0b0c) \
+             {ERROR-CODE (0x0009): 400 \"Bad Request\", \
+             XOR-MAPPED-ADDRESS (0x0020): 127.0.0.1:3478}"
+        );
+        assert_eq!(
+            format!("{:#}", message),
+            "Binding ErrorResponse (method=0x001, transaction_id=0x0102030405060708090a0b0c)\n  \
+             ERROR-CODE (0x0009): 400 \"Bad Request\"\n  \
+             XOR-MAPPED-ADDRESS (0x0020): 127.0.0.1:3478"
+        );
+    }
+
+    #[test]
+    fn success_response_echoes_selected_attributes() {
+        let username = Username::new("foo".to_owned()).unwrap();
+        let software = Software::new("bar".to_owned()).unwrap();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING)
+            .with_attribute(username.clone().into())
+            .with_attribute(software.into());
+
+        let types = [AttributeType::new(Username::CODEPOINT)];
+        let response = SuccessResponse::from_request_echoing(&request, &types);
+        assert_eq!(response.method(), request.method());
+        assert_eq!(response.transaction_id(), request.transaction_id());
+        assert_eq!(response.get_attribute::<Username>(), Some(&username));
+        assert_eq!(response.get_attribute::<Software>(), None);
+    }
+
+    #[test]
+    fn seeded_transaction_id_generator_works() -> Result<(), MainError> {
+        let mut g0 = SeededTransactionIdGenerator::from_seed([7; 32]);
+        let mut g1 = SeededTransactionIdGenerator::from_seed([7; 32]);
+        let ids = (0..3).map(|_| g0.generate()).collect::<Vec<_>>();
+        assert_eq!((0..3).map(|_| g1.generate()).collect::<Vec<_>>(), ids);
+
+        // Reseeded from the operating system after every ID
+        let mut g2 = SeededTransactionIdGenerator::from_seed([7; 32]);
+        g2.set_reseed_interval(Some(1));
+        assert_eq!(g2.generate(), ids[0]);
+        assert_ne!(g2.generate(), ids[1]);
+
+        let mut g3 = track!(SeededTransactionIdGenerator::from_entropy())?;
+        assert_ne!(g3.generate(), g3.generate());
+
+        Ok(())
+    }
+
+    #[test]
+    fn parse_header_works() -> Result<(), MainError> {
+        // The header is built by hand, because stun_codec 0.1.13 mis-encodes
+        // the methods greater than `0xF`
+        let method = track!(Method::new(0xABC))?;
+        let transaction_id = TransactionId::new([7; 12]);
+        let mut bytes = vec![0x2B, 0x7C, 0x00, 0x08, 0x21, 0x12, 0xA4, 0x42];
+        bytes.extend_from_slice(transaction_id.as_bytes());
+        bytes.extend_from_slice(&[0x80, 0x22, 0x00, 0x03, b'f', b'o', b'o', 0x00]); // SOFTWARE
+
+        let header = track!(parse_header(&bytes))?;
+        assert_eq!(header.class(), MessageClass::ErrorResponse);
+        assert_eq!(header.method(), method);
+        assert_eq!(header.length() as usize, bytes.len() - 20);
+        assert_eq!(header.transaction_id(), transaction_id);
+        assert!(header.has_magic_cookie());
+
+        assert!(parse_header(&bytes[..19]).is_err());
+
+        let mut broken = bytes.clone();
+        broken[0] |= 0b1000_0000;
+        assert!(parse_header(&broken).is_err());
+
+        let mut broken = bytes.clone();
+        broken[3] += 1;
+        assert!(parse_header(&broken).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn xor_addr_helpers_work() -> Result<(), MainError> {
+        // RFC 5769 -- 2.2. Sample IPv4 Response, and 2.3. Sample IPv6 Response
+        let transaction_id = TransactionId::new([
+            0xb7, 0xe7, 0xa7, 0x01, 0xbc, 0x34, 0xd6, 0x86, 0xfa, 0x87, 0xdf, 0xae,
+        ]);
+
+        let v4: SocketAddr = "192.0.2.1:32853".parse().unwrap();
+        let v4_bytes = [0x00, 0x01, 0xa1, 0x47, 0xe1, 0x12, 0xa6, 0x43];
+        assert_eq!(xor_encode_addr(v4, transaction_id), v4_bytes);
+        assert_eq!(track!(xor_decode_addr(&v4_bytes, transaction_id))?, v4);
+
+        let v6: SocketAddr = "[2001:db8:1234:5678:11:2233:4455:6677]:32853"
+            .parse()
+            .unwrap();
+        let v6_bytes = [
+            0x00, 0x02, 0xa1, 0x47, 0x01, 0x13, 0xa9, 0xfa, 0xa5, 0xd3, 0xf1, 0x79, 0xbc, 0x25,
+            0xf4, 0xb5, 0xbe, 0xd2, 0xb9, 0xd9,
+        ];
+        assert_eq!(xor_encode_addr(v6, transaction_id), &v6_bytes[..]);
+        assert_eq!(track!(xor_decode_addr(&v6_bytes, transaction_id))?, v6);
+
+        // Consistent with `XorMappedAddress`
+        let mut response = Message::<rfc5389::Attribute>::new(
+            MessageClass::SuccessResponse,
+            rfc5389::methods::BINDING,
+            transaction_id,
+        );
+        response.add_attribute(XorMappedAddress::new(v6).into());
+        let bytes = track_any_err!(MessageEncoder::new().encode_into_bytes(response))?;
+        assert_eq!(&bytes[HEADER_SIZE + 4..], &v6_bytes[..]);
+
+        assert!(xor_decode_addr(&v4_bytes[..7], transaction_id).is_err());
+        assert!(xor_decode_addr(&[0x00, 0x03, 0xa1, 0x47], transaction_id).is_err());
+
+        Ok(())
+    }
+
+    #[test]
+    fn error_response_has_typed_error_code() {
+        for code in 300..700 {
+            assert_eq!(ErrorCodeKind::from_code(code).code(), code);
+        }
+        assert_eq!(ErrorCodeKind::from_code(401), ErrorCodeKind::Unauthorized);
+        assert_eq!(ErrorCodeKind::from_code(499), ErrorCodeKind::Other(499));
+
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let error = ErrorCode::new(420, "Unknown Attribute".to_owned()).unwrap();
+        let response = ErrorResponse::new(&request, error);
+        let error = response.typed_error_code().expect("typed error code");
+        assert_eq!(error.kind(), ErrorCodeKind::UnknownAttribute);
+        assert_eq!(error.code(), 420);
+        assert_eq!(error.reason_phrase(), "Unknown Attribute");
+        assert_eq!(error.to_string(), "420 Unknown Attribute");
+
+        let error = ErrorCode::new(499, "Custom Error".to_owned()).unwrap();
+        let response = ErrorResponse::new(&request, error);
+        let error = response.typed_error_code().expect("typed error code");
+        assert_eq!(error.kind(), ErrorCodeKind::Other(499));
+        assert_eq!(error.reason_phrase(), "Custom Error");
+    }
+}
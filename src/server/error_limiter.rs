@@ -0,0 +1,68 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::time::{Duration, Instant};
+
+/// The minimum number of the tracked peers that triggers the removal of the idle peers.
+const MIN_PURGE_THRESHOLD: usize = 1024;
+
+/// Limiter of the rate of the error responses sent to each peer.
+///
+/// This is a token bucket per peer, of which the capacity is the number of the tokens
+/// refilled per second (i.e., a peer can receive a burst of up to a second's worth of
+/// error responses).
+#[derive(Debug)]
+pub(crate) struct ErrorResponseLimiter {
+    rate: u32,
+    buckets: HashMap<SocketAddr, Bucket>,
+    purge_threshold: usize,
+}
+impl ErrorResponseLimiter {
+    pub fn new(rate: u32) -> Self {
+        ErrorResponseLimiter {
+            rate,
+            buckets: HashMap::new(),
+            purge_threshold: MIN_PURGE_THRESHOLD,
+        }
+    }
+
+    /// Consumes a token of `peer`, and returns `false` if there are no tokens left.
+    pub fn allow(&mut self, peer: SocketAddr, now: Instant) -> bool {
+        if self.buckets.len() >= self.purge_threshold {
+            self.remove_full_buckets(now);
+            self.purge_threshold = cmp::max(MIN_PURGE_THRESHOLD, self.buckets.len() * 2);
+        }
+
+        let capacity = f64::from(self.rate);
+        let bucket = self.buckets.entry(peer).or_insert(Bucket {
+            tokens: capacity,
+            updated_at: now,
+        });
+        let elapsed = duration_to_secs(now.duration_since(bucket.updated_at));
+        bucket.tokens = (bucket.tokens + elapsed * capacity).min(capacity);
+        bucket.updated_at = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+
+    // A bucket that has not been touched for a second is full again,
+    // so it is indistinguishable from a newly created one.
+    fn remove_full_buckets(&mut self, now: Instant) {
+        self.buckets
+            .retain(|_, b| now.duration_since(b.updated_at) < Duration::from_secs(1));
+    }
+}
+
+#[derive(Debug)]
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+fn duration_to_secs(d: Duration) -> f64 {
+    d.as_secs() as f64 + f64::from(d.subsec_nanos()) / 1_000_000_000.0
+}
@@ -0,0 +1,171 @@
+use factory::Factory;
+use fibers::sync::mpsc;
+use fibers::sync::oneshot::Monitor;
+use fibers::{BoxSpawn, Spawn};
+use futures::{future, stream, Future, Stream};
+use std::fmt;
+use std::net::SocketAddr;
+use stun_codec::Attribute;
+
+use {Error, Result};
+
+use super::{HandleMessage, TcpServer, UdpServer};
+
+/// A group of servers that are shut down together in a defined order.
+///
+/// The servers added to a group are spawned on the spawner of the group,
+/// and `shutdown` drains them one by one in the order in which they were added:
+///
+/// - A `UdpServer` stops receiving messages, and then flushes the replies that have been issued.
+/// - A `TcpServer` stops accepting connections, and then closes each connection
+///   after flushing the replies that have been issued over it
+///   (in the same manner as `TcpServer::max_connection_lifetime` is exceeded).
+///
+/// Note that the replies of the pending `Action::FutureReply` futures
+/// that have not completed before the drain are not sent.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate factory;
+/// # extern crate fibers_global;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// use factory::DefaultFactory;
+/// use rustun::server::{BindingHandler, ServerGroup, TcpServer, UdpServer};
+///
+/// # fn main() -> Result<(), rustun::Error> {
+/// let addr = "127.0.0.1:0".parse().unwrap();
+/// let udp = fibers_global::execute(UdpServer::start(fibers_global::handle(), addr, BindingHandler))?;
+/// let tcp = fibers_global::execute(TcpServer::start(
+///     fibers_global::handle(),
+///     addr,
+///     DefaultFactory::<BindingHandler>::new(),
+/// ))?;
+///
+/// let mut group = ServerGroup::new(fibers_global::handle());
+/// group.add_tcp_server(tcp).add_udp_server(udp);
+///
+/// // ...
+///
+/// // Drains the TCP server, and then the UDP server.
+/// fibers_global::execute(group.shutdown())?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerGroup {
+    spawner: BoxSpawn,
+    members: Vec<Member>,
+}
+impl ServerGroup {
+    /// Makes a new `ServerGroup` instance that spawns servers on the given spawner.
+    pub fn new<S>(spawner: S) -> Self
+    where
+        S: Spawn + Send + 'static,
+    {
+        ServerGroup {
+            spawner: spawner.boxed(),
+            members: Vec::new(),
+        }
+    }
+
+    /// Adds the given UDP server to the tail of the group, and spawns it.
+    pub fn add_udp_server<H>(&mut self, mut server: UdpServer<H>) -> &mut Self
+    where
+        H: HandleMessage,
+        UdpServer<H>: Send + 'static,
+    {
+        let local_addr = server.local_addr();
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        server.driver.shutdown_rx = Some(shutdown_rx);
+        let future = future::poll_fn(move || server.poll_drain());
+        self.add_member(local_addr, shutdown_tx, future);
+        self
+    }
+
+    /// Adds the given TCP server to the tail of the group, and spawns it.
+    pub fn add_tcp_server<S, H>(&mut self, mut server: TcpServer<S, H>) -> &mut Self
+    where
+        S: Spawn + Clone + Send + 'static,
+        H: Factory,
+        H::Item: HandleMessage + Send + 'static,
+        <<H::Item as HandleMessage>::Attribute as Attribute>::Decoder: Send + 'static,
+        <<H::Item as HandleMessage>::Attribute as Attribute>::Encoder: Send + 'static,
+        TcpServer<S, H>: Send + 'static,
+    {
+        let local_addr = server.local_addr();
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        server.shutdown_rx = Some(shutdown_rx);
+        let future = future::poll_fn(move || server.poll_drain());
+        self.add_member(local_addr, shutdown_tx, future);
+        self
+    }
+
+    /// Returns the number of the servers in the group.
+    pub fn len(&self) -> usize {
+        self.members.len()
+    }
+
+    /// Returns `true` if the group has no servers, otherwise `false`.
+    pub fn is_empty(&self) -> bool {
+        self.members.is_empty()
+    }
+
+    /// Shuts down the servers in the order in which they were added,
+    /// and returns a future that completes when all of them have stopped.
+    ///
+    /// A server is not requested to shut down until the previous one has been drained.
+    ///
+    /// # Errors
+    ///
+    /// If some servers have failed (either before or during the shutdown),
+    /// the returned future will fail with the error of the first of them
+    /// after all the other servers have stopped.
+    pub fn shutdown(self) -> impl Future<Item = (), Error = Error> + Send + 'static {
+        stream::iter_ok::<_, Error>(self.members)
+            .fold(None, |error: Option<Error>, member| {
+                member
+                    .shutdown()
+                    .then(move |result| -> Result<_> { Ok(error.or(result.err())) })
+            })
+            .and_then(|error| error.map_or(Ok(()), Err))
+    }
+
+    fn add_member<F>(&mut self, local_addr: SocketAddr, shutdown_tx: mpsc::Sender<()>, future: F)
+    where
+        F: Future<Item = (), Error = Error> + Send + 'static,
+    {
+        let monitor = self.spawner.spawn_monitor(future);
+        self.members.push(Member {
+            local_addr,
+            shutdown_tx,
+            monitor,
+        });
+    }
+}
+impl fmt::Debug for ServerGroup {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let addrs = self.members.iter().map(|m| m.local_addr).collect::<Vec<_>>();
+        write!(f, "ServerGroup {{ servers: {:?} }}", addrs)
+    }
+}
+
+struct Member {
+    local_addr: SocketAddr,
+    shutdown_tx: mpsc::Sender<()>,
+    monitor: Monitor<(), Error>,
+}
+impl Member {
+    fn shutdown(self) -> impl Future<Item = (), Error = Error> {
+        debug!("STUN server group: shutting down {}", self.local_addr);
+        let local_addr = self.local_addr;
+        let _ = self.shutdown_tx.send(());
+        self.monitor.then(move |result| -> Result<()> {
+            let result = track!(result.map_err(Error::from); local_addr);
+            if let Err(ref e) = result {
+                warn!("STUN server group: {} has failed: {}", local_addr, e);
+            }
+            result
+        })
+    }
+}
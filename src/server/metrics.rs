@@ -0,0 +1,239 @@
+use std::cmp;
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::Duration;
+use stun_codec::Method;
+
+/// The number of the buckets of `DelayHistogram`.
+///
+/// The upper bounds of the buckets are `2^0`, `2^1`, ..., `2^20` microseconds (about a second),
+/// and the last bucket holds the longer delays.
+const DELAY_BUCKETS: usize = 22;
+
+/// Metrics of a STUN server.
+///
+/// This is a cheap handle that can be cloned and read from any thread.
+#[derive(Debug, Clone, Default)]
+pub struct ServerMetrics {
+    inner: Arc<Inner>,
+}
+impl ServerMetrics {
+    /// Makes a new `ServerMetrics` instance.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Returns the number of duplicate requests that have been answered from the response cache.
+    pub fn response_cache_hits(&self) -> usize {
+        self.inner.response_cache_hits.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that have not been found in the response cache.
+    ///
+    /// This is only incremented when the response cache is enabled.
+    pub fn response_cache_misses(&self) -> usize {
+        self.inner.response_cache_misses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of responses that have been evicted from the response cache.
+    pub fn response_cache_evictions(&self) -> usize {
+        self.inner.response_cache_evictions.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that have been rejected because the server was overloaded.
+    pub fn overload_rejections(&self) -> usize {
+        self.inner.overload_rejections.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of requests that have been flagged as having low-entropy transaction IDs.
+    ///
+    /// See `LowEntropyTransactionIds` for details.
+    pub fn low_entropy_transaction_ids(&self) -> usize {
+        self.inner
+            .low_entropy_transaction_ids
+            .load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of responses that have been dropped because they would amplify
+    /// the requests beyond the limit.
+    ///
+    /// See `UdpServer::max_amplification_factor` for details.
+    pub fn amplification_suppressions(&self) -> usize {
+        self.inner
+            .amplification_suppressions
+            .load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of error responses that have been dropped because they exceeded
+    /// the rate limit.
+    ///
+    /// See `UdpServer::max_error_responses_per_second` for details.
+    pub fn error_response_suppressions(&self) -> usize {
+        self.inner
+            .error_response_suppressions
+            .load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of responses issued by `Action::FutureReply` that have been dropped
+    /// because the server had gone unexpectedly (i.e., without shutting down or finishing).
+    ///
+    /// The responses that complete after a server has stopped in an orderly manner
+    /// (e.g., after `ServerGroup::shutdown`) are not counted.
+    pub fn dropped_responses(&self) -> usize {
+        self.inner.dropped_responses.load(Ordering::Relaxed)
+    }
+
+    /// Returns the number of the received requests for each method.
+    ///
+    /// All received requests are counted regardless of how they are handled
+    /// (e.g., requests rejected because of overload are also counted).
+    /// The methods that have never been requested are not contained in the returned map.
+    pub fn requests_per_method(&self) -> HashMap<Method, usize> {
+        self.lock_requests_per_method().clone()
+    }
+
+    /// Returns the histogram of the queueing delays of the received requests.
+    ///
+    /// This is only recorded when the measurement is enabled
+    /// (see `UdpServer::measure_queueing_delay`).
+    pub fn queueing_delays(&self) -> DelayHistogram {
+        let buckets = self
+            .inner
+            .queueing_delay_buckets
+            .iter()
+            .enumerate()
+            .map(|(i, n)| {
+                let upper_bound = if i + 1 < DELAY_BUCKETS {
+                    Some(Duration::from_micros(1 << i))
+                } else {
+                    None
+                };
+                (upper_bound, n.load(Ordering::Relaxed))
+            }).collect();
+        let sum_micros = self.inner.queueing_delay_sum_micros.load(Ordering::Relaxed);
+        DelayHistogram {
+            buckets,
+            sum: Duration::from_micros(sum_micros as u64),
+        }
+    }
+
+    pub(crate) fn record_queueing_delay(&self, delay: Duration) {
+        let micros = delay
+            .as_secs()
+            .saturating_mul(1_000_000)
+            .saturating_add(u64::from(delay.subsec_micros()));
+        let index = if micros <= 1 {
+            0
+        } else {
+            64 - (micros - 1).leading_zeros() as usize
+        };
+        let index = cmp::min(index, DELAY_BUCKETS - 1);
+        self.inner.queueing_delay_buckets[index].fetch_add(1, Ordering::Relaxed);
+        self.inner
+            .queueing_delay_sum_micros
+            .fetch_add(micros as usize, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_response_cache_hits(&self) {
+        self.inner.response_cache_hits.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_response_cache_misses(&self) {
+        self.inner
+            .response_cache_misses
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn add_response_cache_evictions(&self, n: usize) {
+        self.inner
+            .response_cache_evictions
+            .fetch_add(n, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_overload_rejections(&self) {
+        self.inner
+            .overload_rejections
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_amplification_suppressions(&self) {
+        self.inner
+            .amplification_suppressions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_error_response_suppressions(&self) {
+        self.inner
+            .error_response_suppressions
+            .fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_requests(&self, method: Method) {
+        *self
+            .lock_requests_per_method()
+            .entry(method)
+            .or_insert(0) += 1;
+    }
+
+    fn lock_requests_per_method(&self) -> MutexGuard<'_, HashMap<Method, usize>> {
+        self.inner
+            .requests_per_method
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+    }
+
+    pub(crate) fn inc_dropped_responses(&self) {
+        self.inner.dropped_responses.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn inc_low_entropy_transaction_ids(&self) {
+        self.inner
+            .low_entropy_transaction_ids
+            .fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    response_cache_hits: AtomicUsize,
+    response_cache_misses: AtomicUsize,
+    response_cache_evictions: AtomicUsize,
+    overload_rejections: AtomicUsize,
+    low_entropy_transaction_ids: AtomicUsize,
+    amplification_suppressions: AtomicUsize,
+    error_response_suppressions: AtomicUsize,
+    dropped_responses: AtomicUsize,
+    requests_per_method: Mutex<HashMap<Method, usize>>,
+    queueing_delay_buckets: [AtomicUsize; DELAY_BUCKETS],
+    queueing_delay_sum_micros: AtomicUsize,
+}
+
+/// Snapshot of a histogram of delays.
+///
+/// See `ServerMetrics::queueing_delays`.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DelayHistogram {
+    buckets: Vec<(Option<Duration>, usize)>,
+    sum: Duration,
+}
+impl DelayHistogram {
+    /// Returns the buckets of the histogram in ascending order.
+    ///
+    /// Each entry is the pair of the (inclusive) upper bound of the bucket and
+    /// the number of the samples in the bucket.
+    /// The upper bounds are powers of two microseconds, and the one of the last bucket is `None`.
+    pub fn buckets(&self) -> &[(Option<Duration>, usize)] {
+        &self.buckets
+    }
+
+    /// Returns the number of the samples.
+    pub fn count(&self) -> usize {
+        self.buckets.iter().map(|&(_, n)| n).sum()
+    }
+
+    /// Returns the sum of the samples.
+    pub fn sum(&self) -> Duration {
+        self.sum
+    }
+}
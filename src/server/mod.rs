@@ -0,0 +1,2773 @@
+//! Basic STUN servers.
+//!
+//! This module provides only a basic STUN servers.
+//! If you want more elaborate one, please consider create your own server using [`Channel`] directly.
+//!
+//! [`Channel`]: ../channel/struct.Channel.html
+use bytecodec::marker::Never;
+use bytecodec::EncodeExt;
+use factory::DefaultFactory;
+use factory::Factory;
+use fibers::fiber;
+use fibers::sync::{mpsc, oneshot};
+use fibers::time::timer::{self, Timeout};
+use fibers::{BoxSpawn, Executor, Spawn};
+use fibers_transport::{self, FixedPeerTransporter, TcpTransport, UdpTransport};
+use futures::future::{self, Either, Loop};
+use futures::{Async, Future, IntoFuture, Poll, Stream};
+use rand;
+use std::cmp;
+use std::collections::{HashMap, VecDeque};
+use std::fmt;
+use std::net::{self, IpAddr, SocketAddr};
+use std::ops::RangeInclusive;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+use stun_codec::convert::TryAsRef;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::{ErrorCode, MessageIntegrity, UnknownAttributes, Username};
+use stun_codec::rfc5766::attributes::Lifetime;
+use stun_codec::rfc5780::attributes::{ChangeRequest, ResponseOrigin};
+use stun_codec::{
+    Attribute, AttributeType, DecodedMessage, Message, MessageClass, MessageDecoder,
+    MessageEncoder, Method, TransactionId,
+};
+use trackable::error::ErrorKindExt;
+
+use auth::{Authenticate, CredentialProvider, DuplicateIntegrityPolicy, ShortTermAuthenticator};
+use channel::{Channel, RecvMessage};
+use error::bind_error;
+use message::{
+    ErrorResponse, Indication, InvalidMessage, MessageErrorKind, Request, Response, SuccessResponse,
+};
+use transport::{
+    ensure_nonblocking, set_tcp_keepalive, CoalescingTcpTransporter, StunTcpTransporter,
+    StunTransport, StunUdpTransporter, TcpKeepalive, DEFAULT_TCP_BUFFER_SIZE,
+};
+use {Error, ErrorKind, Result};
+
+pub use self::group::ServerGroup;
+pub use self::metrics::{DelayHistogram, ServerMetrics};
+pub use self::peer_activity::{PeerActivity, PeerActivityTracker};
+pub use self::router::Router;
+pub use self::runtime::ServerRuntime;
+pub use self::stream::{IncomingRequest, RequestStream, Responder, StreamHandler};
+
+use self::error_limiter::ErrorResponseLimiter;
+use self::response_cache::{encoded_message_size, ResponseCache};
+
+mod error_limiter;
+mod group;
+mod metrics;
+mod peer_activity;
+mod response_cache;
+mod router;
+mod runtime;
+mod stream;
+
+/// The default TCP and UDP port for STUN.
+pub const DEFAULT_PORT: u16 = 3478;
+
+/// The default TLS port for STUN.
+pub const DEFAULT_TLS_PORT: u16 = 5349;
+
+/// The default maximum total bytes of the responses held in the response cache of a server.
+pub const DEFAULT_RESPONSE_CACHE_MAX_BYTES: usize = 1024 * 1024;
+
+/// The maximum number of the messages handled by a server before flushing the responses.
+const MAX_RECV_BATCH: usize = 64;
+
+/// The maximum number of the batches handled by a server before yielding the execution
+/// to the other fibers.
+const MAX_BATCHES_PER_POLL: usize = 16;
+
+/// Policy for handling unknown attributes contained in the requests received by a server.
+///
+/// > Attributes with type values between 0x0000 and 0x7FFF are
+/// > comprehension-required attributes, which means that the STUN agent
+/// > cannot successfully process the message unless it understands the
+/// > attribute.  Attributes with type values between 0x8000 and 0xFFFF are
+/// > comprehension-optional attributes, which means that those attributes
+/// > can be ignored by the STUN agent if it does not understand them.
+/// >
+/// > [RFC 5389 -- 15. STUN Attributes]
+///
+/// [RFC 5389 -- 15. STUN Attributes]: https://tools.ietf.org/html/rfc5389#section-15
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct UnknownAttributePolicy {
+    /// How to handle unknown comprehension-optional attributes.
+    pub optional: UnknownOptionalAttributes,
+
+    /// How to handle unknown comprehension-required attributes.
+    pub required: UnknownRequiredAttributes,
+}
+
+/// How to handle unknown comprehension-optional attributes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownOptionalAttributes {
+    /// Keeps the unknown attributes in the request passed to the handler.
+    ///
+    /// The handler can inspect them via `Request::as_ref().unknown_attributes()`.
+    ///
+    /// This is the default.
+    #[default]
+    Collect,
+
+    /// Removes the unknown attributes from the request before passing it to the handler.
+    Ignore,
+}
+
+/// How to handle unknown comprehension-required attributes.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UnknownRequiredAttributes {
+    /// Passes the request to `HandleMessage::handle_invalid_message` as an invalid message
+    /// that has a `MessageErrorKind::UnknownAttributes` error.
+    ///
+    /// This is the default.
+    #[default]
+    Delegate,
+
+    /// Replies a `420 Unknown Attribute` error response that has an `UNKNOWN-ATTRIBUTES` attribute
+    /// without invoking the handler.
+    ///
+    /// > If the message contains any comprehension-required attributes that are unknown to
+    /// > the server, the server MUST reply with a 420 (Unknown Attribute) error response.
+    /// >
+    /// > [RFC 5389 -- 7.3. Receiving a STUN Message]
+    ///
+    /// [RFC 5389 -- 7.3. Receiving a STUN Message]: https://tools.ietf.org/html/rfc5389#section-7.3
+    Reject,
+
+    /// Silently discards the request without invoking the handler.
+    Drop,
+}
+
+/// Configuration of how a server runs the futures returned by its handler
+/// (i.e., `Action::FutureReply` and `Action::FutureNoReply`).
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum HandlerFuturePool {
+    /// Spawns each future as an independent fiber.
+    ///
+    /// This is the default.
+    #[default]
+    Unbounded,
+
+    /// Runs at most `workers` futures concurrently.
+    ///
+    /// Futures that exceed the limit are queued, and up to `queue_len` futures can be queued.
+    /// While the queue is full, the server stops receiving new messages,
+    /// so that the backpressure is applied to the peers
+    /// (unless the server is configured to reject requests on overload).
+    ///
+    /// If `workers` is `0`, it is regarded as `1`.
+    Bounded {
+        /// The maximum number of the futures running concurrently.
+        workers: usize,
+
+        /// The maximum number of the futures waiting for a free worker.
+        queue_len: usize,
+    },
+}
+
+/// How to handle requests of which transaction IDs look suspiciously non-random.
+///
+/// The 96-bit transaction ID is the only value that an off-path attacker has to guess
+/// to forge a response to a client, so a well-behaved client picks it at random.
+/// A request having, for example, an all-zero transaction ID or one consisting of
+/// sequential bytes is likely to be forged or sent by a buggy client.
+///
+/// Note that this check is merely a heuristic:
+///
+/// - An attacker can trivially evade it by using random transaction IDs,
+///   so it never proves that a request is genuine.
+/// - Only a few well-known patterns (a single repeated byte, an arithmetic sequence of bytes,
+///   and a small counter preceded by zeros) are detected.
+///   Other predictable generators (e.g., a seeded PRNG) are not.
+/// - Random transaction IDs match the patterns with a negligible but non-zero probability.
+///
+/// The number of the flagged requests is counted by `ServerMetrics::low_entropy_transaction_ids`.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum LowEntropyTransactionIds {
+    /// Does not check transaction IDs.
+    ///
+    /// This is the default.
+    #[default]
+    Accept,
+
+    /// Logs a warning and counts the request, and then handles it as usual.
+    Flag,
+
+    /// Counts the request, and then silently discards it without invoking the handler.
+    Drop,
+}
+
+type HandlerFuture = Box<dyn Future<Item = (), Error = Never> + Send + 'static>;
+
+/// Cancellation signals of the `Action::FutureReply` futures of a connection.
+///
+/// Each running future is paired with a oneshot sender kept in `close_txs`.
+/// When this is dropped (i.e., the connection is closed), the senders are dropped,
+/// and the futures are cancelled after the grace period elapses.
+struct FutureReplyCancel {
+    grace_period: Duration,
+    next_id: u64,
+    close_txs: Arc<Mutex<HashMap<u64, oneshot::Sender<()>>>>,
+}
+impl FutureReplyCancel {
+    fn wrap(&mut self, future: HandlerFuture) -> HandlerFuture {
+        let id = self.next_id;
+        self.next_id = self.next_id.wrapping_add(1);
+
+        let (close_tx, close_rx) = oneshot::channel();
+        self.lock_close_txs().insert(id, close_tx);
+
+        let grace_period = self.grace_period;
+        let cancel = close_rx.then(move |_| timer::timeout(grace_period));
+        let close_txs = self.close_txs.clone();
+        Box::new(future.select2(cancel).then(move |_| {
+            close_txs
+                .lock()
+                .unwrap_or_else(|e| e.into_inner())
+                .remove(&id);
+            Ok(())
+        }))
+    }
+
+    fn lock_close_txs(&self) -> MutexGuard<'_, HashMap<u64, oneshot::Sender<()>>> {
+        self.close_txs.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl Drop for FutureReplyCancel {
+    fn drop(&mut self) {
+        self.lock_close_txs().clear();
+    }
+}
+impl fmt::Debug for FutureReplyCancel {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "FutureReplyCancel {{ grace_period: {:?}, .. }}",
+            self.grace_period
+        )
+    }
+}
+
+/// Counter of the outstanding handler futures, which is shared by the drivers of a server.
+///
+/// See `UdpServer::max_inflight_handlers`.
+#[derive(Debug, Clone)]
+struct InflightHandlers {
+    max: usize,
+    state: Arc<Mutex<InflightHandlersState>>,
+}
+impl InflightHandlers {
+    fn new(max: usize) -> Self {
+        InflightHandlers {
+            max: cmp::max(max, 1),
+            state: Arc::new(Mutex::new(InflightHandlersState::default())),
+        }
+    }
+
+    fn is_full(&self) -> bool {
+        self.lock_state().count >= self.max
+    }
+
+    /// Returns `true` if the limit has been reached,
+    /// and then `waker` will be notified when an outstanding future completes.
+    fn is_full_or_wait(&self, waker: &mpsc::Sender<()>) -> bool {
+        let mut state = self.lock_state();
+        if state.count < self.max {
+            return false;
+        }
+        state.wakers.push(waker.clone());
+        true
+    }
+
+    fn track(&self, future: HandlerFuture) -> HandlerFuture {
+        self.lock_state().count += 1;
+        let guard = InflightHandlerGuard(self.clone());
+        Box::new(future.then(move |result| {
+            drop(guard);
+            result
+        }))
+    }
+
+    fn release(&self) {
+        let wakers = {
+            let mut state = self.lock_state();
+            state.count -= 1;
+            state.wakers.drain(..).collect::<Vec<_>>()
+        };
+        for waker in wakers {
+            let _ = waker.send(());
+        }
+    }
+
+    fn lock_state(&self) -> MutexGuard<'_, InflightHandlersState> {
+        self.state.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct InflightHandlersState {
+    count: usize,
+    wakers: Vec<mpsc::Sender<()>>,
+}
+
+/// Decrements the counter when the future completes (or is dropped without completion).
+struct InflightHandlerGuard(InflightHandlers);
+impl Drop for InflightHandlerGuard {
+    fn drop(&mut self) {
+        self.0.release();
+    }
+}
+
+struct QueuedFutures(VecDeque<HandlerFuture>);
+impl fmt::Debug for QueuedFutures {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "QueuedFutures({})", self.0.len())
+    }
+}
+
+type UdpTransporter<A> = fibers_transport::UdpTransporter<MessageEncoder<A>, MessageDecoder<A>>;
+
+/// UDP based STUN server.
+#[derive(Debug)]
+#[must_use = "future do nothing unless polled"]
+pub struct UdpServer<H: HandleMessage> {
+    driver: HandlerDriver<H, StunUdpTransporter<H::Attribute, UdpTransporter<H::Attribute>>>,
+    swap_tx: mpsc::Sender<H>,
+    swap_rx: mpsc::Receiver<H>,
+}
+impl<H: HandleMessage> UdpServer<H> {
+    /// Starts the server.
+    ///
+    /// # Errors
+    ///
+    /// If `bind_addr` is already in use, the returned future will fail with
+    /// an `ErrorKind::AddrInUse` error.
+    /// If the socket cannot be put into nonblocking mode, the returned future will fail.
+    pub fn start<S>(
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler: H,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        debug!("STUN UDP server: binding to {}", bind_addr);
+        UdpTransporter::bind(bind_addr)
+            .map_err(move |e| track!(bind_error(e, bind_addr)))
+            .and_then(move |transporter| {
+                track!(Self::with_transporter(spawner.boxed(), handler, transporter))
+            })
+    }
+
+    /// Starts the server on a port within the given range.
+    ///
+    /// The ports in `ports` are tried sequentially, starting from a randomly chosen one,
+    /// until the server is successfully bound to `ip` and the port.
+    /// The chosen port can be retrieved via `local_addr`.
+    ///
+    /// This is useful when only a range of ports is open on the firewall.
+    ///
+    /// # Errors
+    ///
+    /// If all the ports in the range are already in use, the returned future will fail with
+    /// an `ErrorKind::AddrInUse` error.
+    /// If the range is empty, the returned future will fail with
+    /// an `ErrorKind::InvalidInput` error.
+    /// Other errors occurred while binding are reported immediately.
+    pub fn start_in_port_range<S>(
+        spawner: S,
+        ip: IpAddr,
+        ports: RangeInclusive<u16>,
+        handler: H,
+    ) -> impl Future<Item = Self, Error = Error>
+    where
+        S: Spawn + Send + 'static,
+    {
+        debug!(
+            "STUN UDP server: binding to {} within the port range {:?}",
+            ip, ports
+        );
+        bind_in_port_range(ip, ports, UdpTransporter::bind).and_then(move |transporter| {
+            track!(Self::with_transporter(spawner.boxed(), handler, transporter))
+        })
+    }
+
+    fn with_transporter(
+        spawner: BoxSpawn,
+        handler: H,
+        transporter: UdpTransporter<H::Attribute>,
+    ) -> Result<Self> {
+        track!(transporter.socket_ref().with_inner(ensure_nonblocking))?;
+        let local_addr = transporter.local_addr();
+        debug!("STUN UDP server: running on {}", local_addr);
+        let channel = Channel::new(StunUdpTransporter::new(transporter));
+        let driver = HandlerDriver::new(
+            spawner,
+            handler,
+            channel,
+            local_addr,
+            HandlerOptions::default(),
+            ServerMetrics::new(),
+        );
+        let (swap_tx, swap_rx) = mpsc::channel();
+        Ok(UdpServer {
+            driver,
+            swap_tx,
+            swap_rx,
+        })
+    }
+
+    /// Returns a handle of the server.
+    pub fn handle(&self) -> ServerHandle {
+        self.driver.handle()
+    }
+
+    /// Returns a swapper that replaces the handler of the server while the server is running.
+    ///
+    /// See `HandlerSwapper` for details.
+    pub fn handler_swapper(&self) -> HandlerSwapper<H> {
+        HandlerSwapper {
+            tx: self.swap_tx.clone(),
+        }
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.driver
+            .channel
+            .transporter_ref()
+            .inner_ref()
+            .local_addr()
+    }
+
+    /// Sets whether the server appends a `RESPONSE-ORIGIN` attribute to success responses.
+    ///
+    /// If `true`, the address to which the server is bound is added to the tail of
+    /// every success response sent by the server,
+    /// except for the responses to requests that contain `CHANGE-REQUEST`.
+    ///
+    /// > The server MUST include RESPONSE-ORIGIN in all Binding responses
+    /// > that it sends, with the exception of responses to requests that
+    /// > contain CHANGE-REQUEST.
+    /// >
+    /// > [RFC 5780 -- 7.3. Response-Origin]
+    ///
+    /// The default value is `false`.
+    ///
+    /// # Errors
+    ///
+    /// If `enabled` is `true` and the server is bound to an unspecified address (e.g., `0.0.0.0`),
+    /// this method fails with an `ErrorKind::InvalidInput` error,
+    /// because the source address of each response is then chosen by the OS.
+    ///
+    /// [RFC 5780 -- 7.3. Response-Origin]: https://tools.ietf.org/html/rfc5780#section-7.3
+    pub fn include_response_origin(&mut self, enabled: bool) -> Result<&mut Self>
+    where
+        H::Attribute: From<ResponseOrigin>,
+    {
+        let local_addr = self.local_addr();
+        track_assert!(
+            !(enabled && local_addr.ip().is_unspecified()),
+            ErrorKind::InvalidInput,
+            "RESPONSE-ORIGIN requires a concrete bind address: local_addr={}",
+            local_addr
+        );
+        let f: fn(SocketAddr) -> H::Attribute = |addr| ResponseOrigin::new(addr).into();
+        self.driver.options.response_origin = if enabled { Some(f) } else { None };
+        Ok(self)
+    }
+
+    /// Sets the maximum number of the responses held in the response cache of the server.
+    ///
+    /// When a request having the same peer and transaction ID as a cached response is received
+    /// (i.e., the request is a retransmission), the server replies the cached response
+    /// without invoking the handler.
+    /// If either limit of the cache is exceeded, the least recently used responses are evicted.
+    ///
+    /// If `0` is specified, the response cache is disabled.
+    ///
+    /// The default value is `0`.
+    pub fn response_cache_max_entries(&mut self, max: usize) -> &mut Self {
+        self.driver.options.response_cache_max_entries = max;
+        self.driver.update_response_cache_limits();
+        self
+    }
+
+    /// Sets the maximum total bytes of the responses held in the response cache of the server.
+    ///
+    /// The size of a response is measured as the length of its encoded form.
+    ///
+    /// The default value is `DEFAULT_RESPONSE_CACHE_MAX_BYTES`.
+    pub fn response_cache_max_bytes(&mut self, max: usize) -> &mut Self {
+        self.driver.options.response_cache_max_bytes = max;
+        self.driver.update_response_cache_limits();
+        self
+    }
+
+    /// Makes the server verify the `MESSAGE-INTEGRITY` of every incoming request
+    /// based on the short-term credential mechanism.
+    ///
+    /// A request that does not have both `USERNAME` and `MESSAGE-INTEGRITY` attributes is
+    /// rejected with a `400 Bad Request` response, and a request that has an unknown username
+    /// is rejected with a `401 Unauthorized` response.
+    /// The `MESSAGE-INTEGRITY` of a request is verified with the password of the user specified by
+    /// its `USERNAME`, and a mismatch is rejected with a `431 Integrity Check Failure` response.
+    /// The handler is only invoked for the requests that have been successfully verified,
+    /// and the responses to such requests are signed using the same password.
+    ///
+    /// To rotate credentials without restarting the server, pass a [`SwappableCredentials`].
+    ///
+    /// [`SwappableCredentials`]: ../auth/struct.SwappableCredentials.html
+    pub fn credential_provider<P>(&mut self, provider: P) -> &mut Self
+    where
+        P: CredentialProvider,
+        H::Attribute: TryAsRef<Username>
+            + TryAsRef<MessageIntegrity>
+            + From<ErrorCode>
+            + From<MessageIntegrity>,
+    {
+        let authenticator: Arc<dyn Authenticate<H::Attribute>> =
+            Arc::new(ShortTermAuthenticator::new(provider));
+        self.driver.options.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Sets the policy for handling requests that have more than one `MESSAGE-INTEGRITY` attribute.
+    ///
+    /// This only takes effect if the server has an authenticator (see `credential_provider`).
+    ///
+    /// The default value is `DuplicateIntegrityPolicy::Reject`.
+    pub fn duplicate_integrity_policy(&mut self, policy: DuplicateIntegrityPolicy) -> &mut Self {
+        self.driver.options.duplicate_integrity_policy = policy;
+        self
+    }
+
+    /// Makes the server echo back the `T` attribute of each request in the corresponding response.
+    ///
+    /// This is mainly intended for debugging with non-standard diagnostic attributes
+    /// (e.g., the attempt counter stamped by `StunUdpTransporter::set_attempt_stamper`).
+    /// The echoed attribute is appended after the attributes added by the handler.
+    pub fn echo_attribute<T>(&mut self) -> &mut Self
+    where
+        T: Attribute,
+        H::Attribute: TryAsRef<T> + From<T>,
+    {
+        self.driver
+            .options
+            .echo_attributes
+            .push(echo_attribute::<T, H::Attribute>);
+        self
+    }
+
+    /// Sets the policy for handling unknown attributes contained in requests.
+    ///
+    /// The default value is `UnknownAttributePolicy::default()`,
+    /// which keeps unknown comprehension-optional attributes and delegates the requests
+    /// having unknown comprehension-required attributes to the handler.
+    pub fn unknown_attribute_policy(&mut self, policy: UnknownAttributePolicy) -> &mut Self
+    where
+        H::Attribute: From<ErrorCode> + From<UnknownAttributes>,
+    {
+        let f: UnknownAttributesResponse<H::Attribute> = unknown_attributes_response;
+        self.driver.options.unknown_attribute_policy = policy;
+        self.driver.options.unknown_attributes_response = Some(f);
+        self
+    }
+
+    /// Sets how the server runs the futures returned by the handler.
+    ///
+    /// The default value is `HandlerFuturePool::Unbounded`.
+    pub fn handler_future_pool(&mut self, pool: HandlerFuturePool) -> &mut Self {
+        self.driver.options.handler_future_pool = pool;
+        self
+    }
+
+    /// Limits the number of the outstanding futures returned by the handler.
+    ///
+    /// The futures are counted from when they are spawned until they complete
+    /// (including the ones waiting in the queue of the handler future pool).
+    /// If `Some(max)` is specified, the server stops receiving new messages while `max` futures
+    /// are outstanding, so that the backpressure is applied to the peers
+    /// (unless the server is configured to reject requests on overload).
+    ///
+    /// If `Some(0)` is specified, it is regarded as `Some(1)`.
+    ///
+    /// The default value is `None` (i.e., the number is unlimited).
+    pub fn max_inflight_handlers(&mut self, max: Option<usize>) -> &mut Self {
+        self.driver.options.inflight_handlers = max.map(InflightHandlers::new);
+        self
+    }
+
+    /// Sets the threshold for logging slow handler invocations.
+    ///
+    /// If `Some(threshold)` is specified, a warning is logged (with the peer and method)
+    /// whenever a call of `HandleMessage::handle_call` takes longer than the threshold.
+    /// For `Action::FutureReply`, the time until the future completes is also checked,
+    /// which includes the time spent in the queue of the handler future pool.
+    ///
+    /// This is useful for detecting handlers that block the server (e.g., by synchronous I/O).
+    ///
+    /// The default value is `None`.
+    pub fn slow_handler_threshold(&mut self, threshold: Option<Duration>) -> &mut Self {
+        self.driver.options.slow_handler_threshold = threshold;
+        self
+    }
+
+    /// Sets how the server handles requests of which transaction IDs look non-random.
+    ///
+    /// See the documentation of `LowEntropyTransactionIds` for the limitations of the check.
+    ///
+    /// The default value is `LowEntropyTransactionIds::Accept`.
+    pub fn low_entropy_transaction_ids(&mut self, policy: LowEntropyTransactionIds) -> &mut Self {
+        self.driver.options.low_entropy_transaction_ids = policy;
+        self
+    }
+
+    /// Sets whether the server measures the queueing delays of the received requests.
+    ///
+    /// The queueing delay of a request is the time from when the server started draining
+    /// the batch of the received messages that contains the request
+    /// (i.e., when the server became ready to read it) to when `HandleMessage::handle_call`
+    /// is invoked for it.
+    /// Thus it grows when the handling of the preceding messages backs up the event loop,
+    /// but does not include the time spent in the receive buffer of the OS before the server
+    /// is woken up, nor the execution time of the handler itself
+    /// (see `slow_handler_threshold` for the latter).
+    ///
+    /// The delays are recorded in `ServerMetrics::queueing_delays`.
+    ///
+    /// The default value is `false`, because the measurement reads the clock for every request.
+    pub fn measure_queueing_delay(&mut self, enabled: bool) -> &mut Self {
+        self.driver.options.measure_queueing_delay = enabled;
+        self
+    }
+
+    /// Sets the maximum amplification factor of the responses to unauthenticated requests.
+    ///
+    /// Because the source address of a UDP datagram can be spoofed, a server replying
+    /// responses larger than the requests can be abused for reflection (amplification) attacks
+    /// against the owner of the spoofed address (see [RFC 5389 -- 16. Security Considerations]).
+    ///
+    /// If `Some(factor)` is specified, the server measures the encoded sizes of each request and
+    /// its response, and silently drops the response if it is more than `factor` times as large
+    /// as the request.
+    /// The number of the dropped responses is available via
+    /// `ServerMetrics::amplification_suppressions`.
+    ///
+    /// The retransmitted requests answered from the response cache
+    /// (see `response_cache_max_entries`) are checked against their own sizes as well.
+    ///
+    /// The requests authenticated by the server (see `credential_provider`) are exempt
+    /// from the check, because their sources have proven the knowledge of the credentials.
+    /// The responses generated by the server itself (e.g., the ones of `reject_on_overload`) are
+    /// not checked either, since they are small.
+    ///
+    /// The default value is `None`.
+    ///
+    /// [RFC 5389 -- 16. Security Considerations]: https://tools.ietf.org/html/rfc5389#section-16
+    pub fn max_amplification_factor(&mut self, factor: Option<usize>) -> &mut Self {
+        self.driver.options.max_amplification_factor = factor;
+        self
+    }
+
+    /// Sets the maximum number of the error responses sent to each peer per second.
+    ///
+    /// Error responses are usually generated for invalid or unauthenticated requests,
+    /// so a flood of such requests (possibly with spoofed source addresses) makes the server
+    /// emit a flood of error responses, which can be used for reflection attacks or fill up
+    /// the logs of the peers.
+    ///
+    /// If `Some(rate)` is specified, the server allows each peer (i.e., source address and port)
+    /// to receive up to `rate` error responses per second, with bursts of up to `rate` responses,
+    /// and silently drops the error responses exceeding the limit.
+    /// The requests are still processed by the handler as usual, and success responses are
+    /// never limited. The number of the dropped responses is available via
+    /// `ServerMetrics::error_response_suppressions`.
+    ///
+    /// If `Some(0)` is specified, all error responses are dropped.
+    ///
+    /// The default value is `None`.
+    pub fn max_error_responses_per_second(&mut self, rate: Option<u32>) -> &mut Self {
+        self.driver.error_response_limiter = rate.map(ErrorResponseLimiter::new);
+        self
+    }
+
+    /// Sets whether the server rejects requests while it is overloaded.
+    ///
+    /// The server is regarded as overloaded while the queue of the handler future pool is full
+    /// (see `handler_future_pool`) or the number of the outstanding handler futures
+    /// reaches the limit (see `max_inflight_handlers`).
+    ///
+    /// If `true`, the server keeps receiving messages even while overloaded, and replies
+    /// `500 Server Error` responses to the requests received in the meantime without invoking
+    /// the handler, so that well-behaved clients back off instead of retransmitting.
+    /// Indications and invalid messages received in the meantime are discarded.
+    /// The number of the rejected requests is available via `ServerMetrics::overload_rejections`.
+    ///
+    /// If `false`, the server stops receiving messages while overloaded.
+    ///
+    /// The default value is `false`.
+    pub fn reject_on_overload(&mut self, enabled: bool) -> &mut Self
+    where
+        H::Attribute: From<ErrorCode>,
+    {
+        let f: OverloadResponse<H::Attribute> = overload_response;
+        self.driver.options.overload_response = if enabled { Some(f) } else { None };
+        self
+    }
+
+    /// Sets the backoff hint attached to the error responses sent while the server is overloaded.
+    ///
+    /// STUN does not define an attribute equivalent to HTTP's `Retry-After`,
+    /// so the hint is conveyed by a `LIFETIME` attribute borrowed from TURN
+    /// ([RFC 5766 -- 14.2. LIFETIME], type `0x000D`, a 32-bit number of seconds).
+    /// The sub-second part of `hint` is truncated.
+    /// Clients that do not understand the convention simply ignore the attribute,
+    /// and `Client::call_with_retry` honors it.
+    ///
+    /// This only takes effect when `reject_on_overload` is enabled.
+    ///
+    /// The default value is `None` (i.e., no hint is attached).
+    ///
+    /// [RFC 5766 -- 14.2. LIFETIME]: https://tools.ietf.org/html/rfc5766#section-14.2
+    pub fn overload_backoff_hint(&mut self, hint: Option<Duration>) -> &mut Self
+    where
+        H::Attribute: From<Lifetime>,
+    {
+        let f: BackoffHintAttribute<H::Attribute> = backoff_hint_attribute;
+        self.driver.options.overload_backoff_hint = hint.map(|hint| (hint, f));
+        self
+    }
+
+    /// Returns a reference to the metrics of the server.
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.driver.metrics
+    }
+
+    /// Makes the server track the peers from which it has received messages within `window`.
+    ///
+    /// At most `max_peers` peers are tracked at a time, so the memory usage is bounded
+    /// regardless of the number of the peers.
+    /// If `0` is specified as `max_peers`, the tracking is disabled.
+    ///
+    /// The tracking is disabled by default.
+    pub fn track_peer_activity(&mut self, window: Duration, max_peers: usize) -> &mut Self {
+        self.driver.peer_activity.set_limits(window, max_peers);
+        self
+    }
+
+    /// Returns a reference to the tracker of the recently active peers of the server.
+    ///
+    /// See also `UdpServer::track_peer_activity`.
+    pub fn peer_activity(&self) -> &PeerActivityTracker {
+        &self.driver.peer_activity
+    }
+}
+impl<H: HandleMessage> Future for UdpServer<H> {
+    type Item = Never;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(()) = track!(self.poll_drain())? {
+            track_panic!(ErrorKind::Other, "STUN UDP server unexpectedly terminated");
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<H: HandleMessage> UdpServer<H> {
+    /// Runs the server until it is shut down (see `ServerGroup`) and the replies are flushed.
+    fn poll_drain(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(Some(handler)) = self.swap_rx.poll().expect("never fails") {
+            self.driver.replace_handler(handler);
+        }
+        track!(self.driver.poll())
+    }
+}
+
+/// Swapper of the handler of a running `UdpServer`.
+///
+/// This allows for replacing the handler (e.g., to reload the configuration of the handler)
+/// without stopping the server:
+/// the new handler takes over the processing of the messages received after the swap,
+/// while the socket, the server settings and the outstanding replies are preserved.
+///
+/// More specifically, the following state belongs to the server and is carried over:
+///
+/// - The socket (and thus the local address) of the server.
+/// - The settings of the server (e.g., authentication and response cache settings).
+/// - The response cache. A retransmitted request is answered by the cached response
+///   even if the response was made by the old handler.
+/// - The futures returned by the old handler via `Action::FutureReply`.
+///   They keep running, and their responses are sent when they complete.
+/// - The metrics of the server.
+///
+/// On the other hand, the state held by the old handler itself is not carried over,
+/// and the old handler is simply dropped
+/// (neither `handle_disconnect` nor any other method is called on it).
+/// The new handler receives the handle of the server via `HandleMessage::set_server_handle`
+/// before handling any message.
+///
+/// The swap is performed when the server is polled next time,
+/// so the messages that have already been handed to the old handler are not affected.
+/// If `swap` is called multiple times before that, the last handler wins.
+///
+/// The swapper can be cloned and (if `H` is `Send`) sent to other threads;
+/// a swap from any thread wakes up the server.
+pub struct HandlerSwapper<H> {
+    tx: mpsc::Sender<H>,
+}
+impl<H> HandlerSwapper<H> {
+    /// Replaces the handler of the server with `handler`.
+    ///
+    /// # Errors
+    ///
+    /// If the server has been dropped, this will return an `ErrorKind::Other` error.
+    pub fn swap(&self, handler: H) -> Result<()> {
+        track!(self.tx.send(handler).map_err(Error::from))
+    }
+}
+impl<H> Clone for HandlerSwapper<H> {
+    fn clone(&self) -> Self {
+        HandlerSwapper {
+            tx: self.tx.clone(),
+        }
+    }
+}
+impl<H> fmt::Debug for HandlerSwapper<H> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "HandlerSwapper {{ .. }}")
+    }
+}
+
+type TcpListener<A> = fibers_transport::TcpListener<
+    DefaultFactory<MessageEncoder<A>>,
+    DefaultFactory<MessageDecoder<A>>,
+>;
+
+type CoalescingTransporter<A> = CoalescingTcpTransporter<MessageEncoder<A>, MessageDecoder<A>>;
+
+type TcpTransporterBuilder<A> =
+    fibers_transport::TcpTransporterBuilder<MessageEncoder<A>, MessageDecoder<A>>;
+
+/// TCP based STUN server.
+#[must_use = "future do nothing unless polled"]
+pub struct TcpServer<S, H>
+where
+    H: Factory,
+    H::Item: HandleMessage,
+{
+    spawner: S,
+    handler_factory: H,
+    listener: TcpListener<<H::Item as HandleMessage>::Attribute>,
+    options: HandlerOptions<<H::Item as HandleMessage>::Attribute>,
+    metrics: ServerMetrics,
+    coalesce_writes: bool,
+    buffer_sizes: Option<(usize, usize)>,
+    keepalive: Option<TcpKeepalive>,
+    max_connection_lifetime: Option<Duration>,
+    write_stall_timeout: Option<Duration>,
+    response_grace_period: Option<Duration>,
+    shutdown_rx: Option<mpsc::Receiver<()>>,
+    shutting_down: bool,
+    connections: HashMap<u64, mpsc::Sender<()>>,
+    next_connection_id: u64,
+    closed_tx: mpsc::Sender<u64>,
+    closed_rx: mpsc::Receiver<u64>,
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: HandleMessage,
+{
+    /// Starts the server.
+    ///
+    /// # Errors
+    ///
+    /// If `bind_addr` is already in use, the returned future will fail with
+    /// an `ErrorKind::AddrInUse` error.
+    pub fn start(
+        spawner: S,
+        bind_addr: SocketAddr,
+        handler_factory: H,
+    ) -> impl Future<Item = Self, Error = Error> {
+        debug!("STUN TCP server: binding to {}", bind_addr);
+        TcpListener::listen(bind_addr)
+            .map_err(move |e| track!(bind_error(e, bind_addr)))
+            .map(move |listener| Self::with_listener(spawner, handler_factory, listener))
+    }
+
+    /// Starts the server on a port within the given range.
+    ///
+    /// The ports in `ports` are tried sequentially, starting from a randomly chosen one,
+    /// until the server is successfully bound to `ip` and the port.
+    /// The chosen port can be retrieved via `local_addr`.
+    ///
+    /// # Errors
+    ///
+    /// If all the ports in the range are already in use, the returned future will fail with
+    /// an `ErrorKind::AddrInUse` error.
+    /// If the range is empty, the returned future will fail with
+    /// an `ErrorKind::InvalidInput` error.
+    pub fn start_in_port_range(
+        spawner: S,
+        ip: IpAddr,
+        ports: RangeInclusive<u16>,
+        handler_factory: H,
+    ) -> impl Future<Item = Self, Error = Error> {
+        debug!(
+            "STUN TCP server: binding to {} within the port range {:?}",
+            ip, ports
+        );
+        bind_in_port_range(ip, ports, TcpListener::listen)
+            .map(move |listener| Self::with_listener(spawner, handler_factory, listener))
+    }
+
+    fn with_listener(
+        spawner: S,
+        handler_factory: H,
+        listener: TcpListener<<H::Item as HandleMessage>::Attribute>,
+    ) -> Self {
+        debug!("STUN TCP server: listening on {}", listener.local_addr());
+        let (closed_tx, closed_rx) = mpsc::channel();
+        TcpServer {
+            spawner,
+            handler_factory,
+            listener,
+            options: HandlerOptions::default(),
+            metrics: ServerMetrics::new(),
+            coalesce_writes: false,
+            buffer_sizes: None,
+            keepalive: None,
+            max_connection_lifetime: None,
+            write_stall_timeout: None,
+            response_grace_period: Some(Duration::from_secs(0)),
+            shutdown_rx: None,
+            shutting_down: false,
+            connections: HashMap::new(),
+            next_connection_id: 0,
+            closed_tx,
+            closed_rx,
+        }
+    }
+
+    /// Returns the address to which the server is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener.local_addr()
+    }
+
+    /// Sets whether the server appends a `RESPONSE-ORIGIN` attribute to success responses.
+    ///
+    /// If `true`, the local address of each connection is added to the tail of
+    /// every success response sent over the connection,
+    /// except for the responses to requests that contain `CHANGE-REQUEST`.
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `false`.
+    pub fn include_response_origin(&mut self, enabled: bool) -> &mut Self
+    where
+        <H::Item as HandleMessage>::Attribute: From<ResponseOrigin>,
+    {
+        let f: fn(SocketAddr) -> <H::Item as HandleMessage>::Attribute =
+            |addr| ResponseOrigin::new(addr).into();
+        self.options.response_origin = if enabled { Some(f) } else { None };
+        self
+    }
+
+    /// Sets the maximum number of the responses held in the response cache of each connection.
+    ///
+    /// See the documentation of `UdpServer::response_cache_max_entries` for details.
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `0` (i.e., disabled).
+    pub fn response_cache_max_entries(&mut self, max: usize) -> &mut Self {
+        self.options.response_cache_max_entries = max;
+        self
+    }
+
+    /// Sets the maximum total bytes of the responses held in the response cache of each connection.
+    ///
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `DEFAULT_RESPONSE_CACHE_MAX_BYTES`.
+    pub fn response_cache_max_bytes(&mut self, max: usize) -> &mut Self {
+        self.options.response_cache_max_bytes = max;
+        self
+    }
+
+    /// Makes the server verify the `MESSAGE-INTEGRITY` of every incoming request
+    /// based on the short-term credential mechanism.
+    ///
+    /// See the documentation of `UdpServer::credential_provider` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn credential_provider<P>(&mut self, provider: P) -> &mut Self
+    where
+        P: CredentialProvider,
+        <H::Item as HandleMessage>::Attribute: TryAsRef<Username>
+            + TryAsRef<MessageIntegrity>
+            + From<ErrorCode>
+            + From<MessageIntegrity>,
+    {
+        let authenticator: Arc<dyn Authenticate<<H::Item as HandleMessage>::Attribute>> =
+            Arc::new(ShortTermAuthenticator::new(provider));
+        self.options.authenticator = Some(authenticator);
+        self
+    }
+
+    /// Sets the policy for handling requests that have more than one `MESSAGE-INTEGRITY` attribute.
+    ///
+    /// See the documentation of `UdpServer::duplicate_integrity_policy` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn duplicate_integrity_policy(&mut self, policy: DuplicateIntegrityPolicy) -> &mut Self {
+        self.options.duplicate_integrity_policy = policy;
+        self
+    }
+
+    /// Makes the server echo back the `T` attribute of each request in the corresponding response.
+    ///
+    /// See the documentation of `UdpServer::echo_attribute` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn echo_attribute<T>(&mut self) -> &mut Self
+    where
+        T: Attribute,
+        <H::Item as HandleMessage>::Attribute: TryAsRef<T> + From<T>,
+    {
+        self.options
+            .echo_attributes
+            .push(echo_attribute::<T, <H::Item as HandleMessage>::Attribute>);
+        self
+    }
+
+    /// Sets the policy for handling unknown attributes contained in requests.
+    ///
+    /// See the documentation of `UdpServer::unknown_attribute_policy` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn unknown_attribute_policy(&mut self, policy: UnknownAttributePolicy) -> &mut Self
+    where
+        <H::Item as HandleMessage>::Attribute: From<ErrorCode> + From<UnknownAttributes>,
+    {
+        let f: UnknownAttributesResponse<<H::Item as HandleMessage>::Attribute> =
+            unknown_attributes_response;
+        self.options.unknown_attribute_policy = policy;
+        self.options.unknown_attributes_response = Some(f);
+        self
+    }
+
+    /// Sets how the server runs the futures returned by the handler.
+    ///
+    /// Note that the pool is created for each connection.
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `HandlerFuturePool::Unbounded`.
+    pub fn handler_future_pool(&mut self, pool: HandlerFuturePool) -> &mut Self {
+        self.options.handler_future_pool = pool;
+        self
+    }
+
+    /// Limits the number of the outstanding futures returned by the handlers of all connections.
+    ///
+    /// Unlike `handler_future_pool`, which applies to each connection,
+    /// the limit is shared by the connections accepted after this method is called.
+    /// While `max` futures are outstanding, the server stops receiving new messages
+    /// from any of the connections until some of the futures complete.
+    /// Note that the connections are driven concurrently, so the limit may be exceeded
+    /// by a few futures when multiple connections receive requests at the same time.
+    ///
+    /// See the documentation of `UdpServer::max_inflight_handlers` for details.
+    pub fn max_inflight_handlers(&mut self, max: Option<usize>) -> &mut Self {
+        self.options.inflight_handlers = max.map(InflightHandlers::new);
+        self
+    }
+
+    /// Sets the threshold for logging slow handler invocations.
+    ///
+    /// See the documentation of `UdpServer::slow_handler_threshold` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn slow_handler_threshold(&mut self, threshold: Option<Duration>) -> &mut Self {
+        self.options.slow_handler_threshold = threshold;
+        self
+    }
+
+    /// Sets how the server handles requests of which transaction IDs look non-random.
+    ///
+    /// See the documentation of `UdpServer::low_entropy_transaction_ids` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn low_entropy_transaction_ids(&mut self, policy: LowEntropyTransactionIds) -> &mut Self {
+        self.options.low_entropy_transaction_ids = policy;
+        self
+    }
+
+    /// Sets whether the server measures the queueing delays of the received requests.
+    ///
+    /// See the documentation of `UdpServer::measure_queueing_delay` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn measure_queueing_delay(&mut self, enabled: bool) -> &mut Self {
+        self.options.measure_queueing_delay = enabled;
+        self
+    }
+
+    /// Sets whether the server rejects requests while it is overloaded.
+    ///
+    /// See the documentation of `UdpServer::reject_on_overload` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn reject_on_overload(&mut self, enabled: bool) -> &mut Self
+    where
+        <H::Item as HandleMessage>::Attribute: From<ErrorCode>,
+    {
+        let f: OverloadResponse<<H::Item as HandleMessage>::Attribute> = overload_response;
+        self.options.overload_response = if enabled { Some(f) } else { None };
+        self
+    }
+
+    /// Sets the backoff hint attached to the error responses sent while the server is overloaded.
+    ///
+    /// See the documentation of `UdpServer::overload_backoff_hint` for details.
+    /// The setting only affects connections accepted after this method is called.
+    pub fn overload_backoff_hint(&mut self, hint: Option<Duration>) -> &mut Self
+    where
+        <H::Item as HandleMessage>::Attribute: From<Lifetime>,
+    {
+        let f: BackoffHintAttribute<<H::Item as HandleMessage>::Attribute> = backoff_hint_attribute;
+        self.options.overload_backoff_hint = hint.map(|hint| (hint, f));
+        self
+    }
+
+    /// Sets whether the server coalesces the messages sent to a connection within a poll cycle
+    /// into a single write.
+    ///
+    /// If `true`, the connections are handled by `CoalescingTcpTransporter`,
+    /// and, for example, the responses to the requests received in the same TCP segment
+    /// are sent in a single segment rather than one segment per response.
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `false`.
+    pub fn coalesce_writes(&mut self, enabled: bool) -> &mut Self {
+        self.coalesce_writes = enabled;
+        self
+    }
+
+    /// Sets the sizes (in bytes) of the read and write buffers allocated for each connection.
+    ///
+    /// The memory consumed by the buffers of a connection is `read_size + write_size` bytes,
+    /// so smaller buffers are preferable for a server that has many concurrent connections,
+    /// and larger ones reduce the system calls for connections exchanging many (or large)
+    /// messages. See the documentation of `DEFAULT_TCP_BUFFER_SIZE` for details.
+    ///
+    /// `fibers_transport::TcpTransporter` uses the same size for both buffers,
+    /// so unless `coalesce_writes` is enabled, the larger of the two sizes is used for both.
+    /// A size of `0` is treated as `1`.
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `DEFAULT_TCP_BUFFER_SIZE` for both buffers.
+    pub fn tcp_buffer_sizes(&mut self, read_size: usize, write_size: usize) -> &mut Self {
+        self.buffer_sizes = Some((cmp::max(read_size, 1), cmp::max(write_size, 1)));
+        self
+    }
+
+    /// Sets the TCP keepalive settings of the connections accepted by the server.
+    ///
+    /// A connection of which the peer is detected as dead by keepalive probes
+    /// is closed as an aborted one (see `TcpKeepalive` for details).
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `None` (i.e., keepalive is disabled).
+    pub fn tcp_keepalive(&mut self, keepalive: Option<TcpKeepalive>) -> &mut Self {
+        self.keepalive = keepalive;
+        self
+    }
+
+    /// Sets the maximum lifetime of the connections accepted by the server.
+    ///
+    /// A connection that has been open for longer than `lifetime` is closed by the server
+    /// regardless of its activity (unlike an idle timeout).
+    /// This bounds the exposure of a long-lived connection and forces clients to reconnect
+    /// (and re-authenticate).
+    ///
+    /// When the lifetime of a connection expires, the server stops reading messages from it,
+    /// calls `HandleMessage::handle_lifetime_exceeded` of the handler,
+    /// flushes the responses that have already been issued (including those of the completed
+    /// `Action::FutureReply` futures), and then closes the connection.
+    /// The responses of the handler futures that have not completed by then are discarded,
+    /// and the futures returned by `ServerHandle::flush` that are still pending fail.
+    /// Finally, `HandleMessage::handle_disconnect(true)` is called.
+    ///
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `None` (i.e., the lifetime is unlimited).
+    pub fn max_connection_lifetime(&mut self, lifetime: Option<Duration>) -> &mut Self {
+        self.max_connection_lifetime = lifetime;
+        self
+    }
+
+    /// Sets the maximum duration for which the writes to a connection may stall.
+    ///
+    /// If `Some(timeout)` is specified, the connections are handled by `CoalescingTcpTransporter`
+    /// regardless of `coalesce_writes`, because `fibers_transport::TcpTransporter` keeps retrying
+    /// a blocked write while its read buffer is full (i.e., while the client keeps sending requests).
+    /// Thus a client that stops reading the responses does not block the other connections.
+    /// The server also keeps reading and handling the requests from such a client,
+    /// and the responses to them are queued in memory until the client resumes reading.
+    ///
+    /// A connection of which the outgoing messages
+    /// have not been entirely written to the socket for `timeout` is aborted
+    /// (i.e., `HandleMessage::handle_channel_error` and then
+    /// `HandleMessage::handle_disconnect(false)` are called, and the connection is closed),
+    /// so that the memory consumed by the queued responses is bounded.
+    /// Note that a client that keeps sending requests faster than it reads the responses is also
+    /// regarded as stalled, because the queue is never emptied.
+    /// This also applies to the flush after the lifetime of a connection has been exceeded
+    /// (see `max_connection_lifetime`).
+    ///
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `None` (i.e., a stalled connection is kept open).
+    pub fn write_stall_timeout(&mut self, timeout: Option<Duration>) -> &mut Self {
+        self.write_stall_timeout = timeout;
+        self
+    }
+
+    /// Sets the grace period for the `Action::FutureReply` futures
+    /// (and the `Action::ReplyStream` streams) that are still running
+    /// when their connection is closed.
+    ///
+    /// Once a connection has been closed (e.g., the client has gone away or
+    /// `max_connection_lifetime` has been exceeded), the responses to its in-flight requests
+    /// can no longer be delivered.
+    /// If `Some(period)` is specified, such futures are kept running for at most `period`
+    /// after the connection is closed (e.g., so that their side effects can be completed),
+    /// and are cancelled (i.e., dropped) after that.
+    /// If `None` is specified, they are never cancelled and run to completion.
+    ///
+    /// In either case, the responses of the futures that complete after the connection is
+    /// closed are discarded.
+    /// `Action::FutureNoReply` futures are not affected by this setting.
+    ///
+    /// The setting only affects connections accepted after this method is called.
+    ///
+    /// The default value is `Some(Duration::from_secs(0))`,
+    /// i.e., the futures are cancelled as soon as their connection is closed.
+    pub fn response_grace_period(&mut self, period: Option<Duration>) -> &mut Self {
+        self.response_grace_period = period;
+        self
+    }
+
+    /// Returns a reference to the metrics of the server.
+    ///
+    /// The metrics are aggregated over all connections accepted by the server.
+    pub fn metrics(&self) -> &ServerMetrics {
+        &self.metrics
+    }
+}
+impl<S, H> Future for TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: HandleMessage + Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Decoder: Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Encoder: Send + 'static,
+{
+    type Item = Never;
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        if let Async::Ready(()) = track!(self.poll_drain())? {
+            track_panic!(ErrorKind::Other, "STUN TCP server unexpectedly terminated");
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: HandleMessage + Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Decoder: Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Encoder: Send + 'static,
+{
+    /// Runs the server until it is shut down (see `ServerGroup`) and its connections are closed.
+    fn poll_drain(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(Some(id)) = self.closed_rx.poll().expect("never fails") {
+            self.connections.remove(&id);
+        }
+        if !self.shutting_down && poll_shutdown_request(&mut self.shutdown_rx) {
+            debug!(
+                "STUN TCP server ({}): shutting down; closing {} connections",
+                self.local_addr(),
+                self.connections.len()
+            );
+            self.shutting_down = true;
+            for tx in self.connections.values() {
+                let _ = tx.send(());
+            }
+        }
+        if self.shutting_down {
+            if self.connections.is_empty() {
+                return Ok(Async::Ready(()));
+            } else {
+                return Ok(Async::NotReady);
+            }
+        }
+
+        while let Async::Ready(transporter) = track!(self.listener.poll())? {
+            if let Some(transporter) = transporter {
+                let peer_addr = transporter.peer_addr();
+                let local_addr = transporter.local_addr();
+                if let Err(e) = track!(transporter.stream_ref().with_inner(ensure_nonblocking)) {
+                    warn!(
+                        "STUN TCP server: dropped the connection from {}: {}",
+                        peer_addr, e
+                    );
+                    continue;
+                }
+                debug!(
+                    "STUN TCP server: accepted a connection from {} (local address: {})",
+                    peer_addr, local_addr
+                );
+                if self.keepalive.is_some() {
+                    if let Err(e) = set_tcp_keepalive(transporter.stream_ref(), self.keepalive) {
+                        warn!(
+                            "STUN TCP server: cannot enable keepalive for {}: {}",
+                            peer_addr, e
+                        );
+                    }
+                }
+                if self.coalesce_writes || self.write_stall_timeout.is_some() {
+                    let stream = transporter.stream_ref().clone();
+                    let (read_size, write_size) = self
+                        .buffer_sizes
+                        .unwrap_or((DEFAULT_TCP_BUFFER_SIZE, DEFAULT_TCP_BUFFER_SIZE));
+                    match track!(CoalescingTransporter::with_buffer_sizes(
+                        stream,
+                        MessageEncoder::default(),
+                        MessageDecoder::default(),
+                        read_size,
+                        write_size
+                    )) {
+                        Err(e) => warn!(
+                            "STUN TCP server: dropped the connection from {}: {}",
+                            peer_addr, e
+                        ),
+                        Ok(transporter) => self.spawn_connection(transporter),
+                    }
+                } else if let Some((read_size, write_size)) = self.buffer_sizes {
+                    let stream = transporter.stream_ref().clone();
+                    match track!(TcpTransporterBuilder::new()
+                        .buf_size(cmp::max(read_size, write_size))
+                        .finish(stream))
+                    {
+                        Err(e) => warn!(
+                            "STUN TCP server: dropped the connection from {}: {}",
+                            peer_addr, e
+                        ),
+                        Ok(transporter) => self.spawn_connection(transporter),
+                    }
+                } else {
+                    self.spawn_connection(transporter);
+                }
+            } else {
+                track_panic!(ErrorKind::Other, "STUN TCP server unexpectedly terminated");
+            }
+        }
+        Ok(Async::NotReady)
+    }
+}
+impl<S, H> TcpServer<S, H>
+where
+    S: Spawn + Clone + Send + 'static,
+    H: Factory,
+    H::Item: HandleMessage + Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Decoder: Send + 'static,
+    <<H::Item as HandleMessage>::Attribute as Attribute>::Encoder: Send + 'static,
+{
+    fn spawn_connection<T>(&mut self, transporter: T)
+    where
+        T: TcpTransport<
+                SendItem = Message<<H::Item as HandleMessage>::Attribute>,
+                RecvItem = DecodedMessage<<H::Item as HandleMessage>::Attribute>,
+            > + Send
+            + 'static,
+    {
+        let peer_addr = transporter.peer_addr();
+        let local_addr = transporter.local_addr();
+        let transporter =
+            FixedPeerTransporter::new(peer_addr, (), StunTcpTransporter::new(transporter));
+        let channel = Channel::new(transporter);
+        let handler = self.handler_factory.create();
+        let mut future = HandlerDriver::new(
+            self.spawner.clone().boxed(),
+            handler,
+            channel,
+            local_addr,
+            self.options.clone(),
+            self.metrics.clone(),
+        );
+        if let Some(lifetime) = self.max_connection_lifetime {
+            future.lifetime = Some(timer::timeout(lifetime));
+        }
+        future.write_stall_timeout = self.write_stall_timeout;
+        if let Some(period) = self.response_grace_period {
+            future.cancel_future_replies_on_close(period);
+        }
+
+        let (shutdown_tx, shutdown_rx) = mpsc::channel();
+        future.shutdown_rx = Some(shutdown_rx);
+        let id = self.next_connection_id;
+        self.next_connection_id += 1;
+        self.connections.insert(id, shutdown_tx);
+        let closed_tx = self.closed_tx.clone();
+
+        self.spawner.spawn(future.then(move |result| {
+            match result {
+                Ok(()) => debug!("STUN TCP server: connection from {} closed", peer_addr),
+                Err(e) => debug!(
+                    "STUN TCP server: connection from {} aborted: {}",
+                    peer_addr, e
+                ),
+            }
+            let _ = closed_tx.send(id);
+            Ok(())
+        }));
+    }
+}
+impl<S, H> fmt::Debug for TcpServer<S, H>
+where
+    H: Factory,
+    H::Item: HandleMessage,
+{
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TcpServer {{ .. }}")
+    }
+}
+
+/// Action instructed by an operation of a message handler.
+pub enum Action<T> {
+    /// Replies an response to the client immediately.
+    Reply(T),
+
+    /// Replies an response to the client in the future.
+    FutureReply(Box<Future<Item = T, Error = Never> + Send + 'static>),
+
+    /// Does not reply to the client.
+    NoReply,
+
+    /// Does not reply to the client, but does something for handling the incoming message.
+    FutureNoReply(Box<Future<Item = (), Error = Never> + Send + 'static>),
+
+    /// Replies an response to the client immediately via the given transport
+    /// instead of the one on which the request arrived.
+    ///
+    /// This is useful, for example, for a server that receives requests over UDP
+    /// but must send responses from another socket (e.g., to honor a `RESPONSE-PORT`
+    /// or `CHANGE-REQUEST` attribute, or a deployment-specific policy).
+    ///
+    /// The response is processed in the same manner as `Action::Reply`
+    /// (e.g., a `RESPONSE-ORIGIN` attribute is appended and the response is signed if the server
+    /// is so configured), except that it is not stored in the response cache of the server.
+    /// Note that `RESPONSE-ORIGIN` (if any) conveys the address of the server,
+    /// not that of the given transport.
+    ReplyVia(T, Box<dyn ReplyTransport<T>>),
+
+    /// Replies the responses yielded by the given stream to the client one by one.
+    ///
+    /// This is intended for the extensions that send more than one response message
+    /// for a single request (e.g., paginated data).
+    /// Each response is processed in the same manner as `Action::FutureReply`
+    /// as soon as it is yielded, and every response should have the transaction ID
+    /// of the request.
+    /// If the server has the response cache enabled, a retransmitted request is answered with
+    /// the last response that has been sent.
+    ///
+    /// Note that the basic client (`client::Client`) completes a transaction
+    /// when the first response arrives, and the subsequent responses are handled as
+    /// unexpected ones (see `ChannelBuilder::unexpected_response_policy`).
+    /// So the clients of such extensions need to receive the responses by using
+    /// `Channel` directly.
+    ReplyStream(Box<dyn Stream<Item = T, Error = Never> + Send + 'static>),
+}
+impl<T: fmt::Debug> fmt::Debug for Action<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Action::Reply(t) => write!(f, "Reply({:?})", t),
+            Action::FutureReply(_) => write!(f, "FutureReply(_)"),
+            Action::NoReply => write!(f, "NoReply"),
+            Action::FutureNoReply(_) => write!(f, "FutureNoReply(_)"),
+            Action::ReplyVia(t, _) => write!(f, "ReplyVia({:?}, _)", t),
+            Action::ReplyStream(_) => write!(f, "ReplyStream(_)"),
+        }
+    }
+}
+
+/// This trait allows for sending the responses of a server via a transport other than
+/// the one on which the requests arrived.
+///
+/// See `Action::ReplyVia`.
+pub trait ReplyTransport<T>: Send + 'static {
+    /// Sends `message` to `peer`.
+    ///
+    /// This is called by the task that drives the handler, so it should not block.
+    fn reply(&mut self, peer: SocketAddr, message: T) -> Result<()>;
+}
+impl<A: Attribute + Send + 'static> ReplyTransport<Response<A>> for Arc<net::UdpSocket> {
+    /// Encodes the response and sends it from the socket.
+    ///
+    /// If the socket is in nonblocking mode and the send buffer of the socket is full,
+    /// this fails and the response is dropped, as is the case with a lost datagram.
+    fn reply(&mut self, peer: SocketAddr, message: Response<A>) -> Result<()> {
+        let message = match message {
+            Ok(m) => m.into_message(),
+            Err(m) => m.into_message(),
+        };
+        let bytes = track!(
+            MessageEncoder::default()
+                .encode_into_bytes(message)
+                .map_err(Error::from)
+        )?;
+        track!(self.send_to(&bytes, peer).map_err(Error::from))?;
+        Ok(())
+    }
+}
+impl<T: Send + 'static> ReplyTransport<T> for mpsc::Sender<(SocketAddr, T)> {
+    /// Hands the message to the receiver.
+    ///
+    /// This allows for routing responses to an arbitrary transport (e.g., a TCP connection)
+    /// driven by the task owning the receiver.
+    fn reply(&mut self, peer: SocketAddr, message: T) -> Result<()> {
+        track!(self.send((peer, message)).map_err(Error::from))
+    }
+}
+
+/// Resolves the given action into the message to be replied, by running the embedded future
+/// (if any) to completion on `executor`.
+///
+/// This is intended for unit-testing `HandleMessage` implementations without any transport:
+///
+/// ```
+/// # extern crate fibers;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers::{Executor, InPlaceExecutor};
+/// use rustun::message::Request;
+/// use rustun::server::{drive_action, BindingHandler, HandleMessage};
+/// use stun_codec::rfc5389;
+///
+/// # fn main() {
+/// let mut executor = InPlaceExecutor::new().unwrap();
+/// let mut handler = BindingHandler;
+/// let request = Request::new(rfc5389::methods::BINDING);
+/// let action = handler.handle_call("127.0.0.1:3478".parse().unwrap(), request);
+/// let response = drive_action(&mut executor, action).unwrap();
+/// assert!(response.unwrap().is_ok());
+/// # }
+/// ```
+///
+/// `Action::NoReply` and `Action::FutureNoReply(_)` are resolved into `None`
+/// (the future of the latter is also run to completion).
+/// `Action::ReplyStream(_)` is resolved into the first item of the stream
+/// (the stream is also run to completion, and the other items are discarded).
+///
+/// # Errors
+///
+/// If the executor fails or the future panics, this will return an `ErrorKind::Other` error.
+pub fn drive_action<E, T>(executor: &mut E, action: Action<T>) -> Result<Option<T>>
+where
+    E: Executor,
+    T: Send + 'static,
+{
+    match action {
+        Action::Reply(t) | Action::ReplyVia(t, _) => Ok(Some(t)),
+        Action::NoReply => Ok(None),
+        Action::FutureReply(future) => {
+            let monitor = executor.handle().spawn_monitor(future);
+            let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+            let t = track!(result.map_err(|_| future_aborted()))?;
+            Ok(Some(t))
+        }
+        Action::FutureNoReply(future) => {
+            let monitor = executor.handle().spawn_monitor(future);
+            let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+            track!(result.map_err(|_| future_aborted()))?;
+            Ok(None)
+        }
+        Action::ReplyStream(stream) => {
+            let monitor = executor.handle().spawn_monitor(stream.collect());
+            let result = track!(executor.run_fiber(monitor).map_err(Error::from))?;
+            let items = track!(result.map_err(|_| future_aborted()))?;
+            Ok(items.into_iter().next())
+        }
+    }
+}
+
+/// Binds to `ip` and a port within `ports` by using `bind`.
+///
+/// The ports are tried sequentially (wrapping around at the end of the range),
+/// starting from a randomly chosen one, while the port being tried is in use.
+fn bind_in_port_range<B, F>(
+    ip: IpAddr,
+    ports: RangeInclusive<u16>,
+    mut bind: B,
+) -> impl Future<Item = F::Item, Error = Error>
+where
+    B: FnMut(SocketAddr) -> F,
+    F: Future<Error = fibers_transport::Error>,
+{
+    let low = u32::from(*ports.start());
+    let high = u32::from(*ports.end());
+    if low > high {
+        let e = ErrorKind::InvalidInput.cause(format!("Empty port range: {:?}", ports));
+        return Either::A(future::err(track!(e).into()));
+    }
+
+    let n = high - low + 1;
+    let offset = rand::random::<u32>() % n;
+    let candidates = (0..n).map(move |i| (low + (offset + i) % n) as u16);
+    Either::B(future::loop_fn(candidates, move |mut candidates| {
+        let port = if let Some(port) = candidates.next() {
+            port
+        } else {
+            let e = ErrorKind::AddrInUse.cause(format!(
+                "All ports in the range {}..={} of {} are in use",
+                low, high, ip
+            ));
+            return Either::A(future::err(track!(e).into()));
+        };
+        let addr = SocketAddr::new(ip, port);
+        Either::B(bind(addr).then(move |result| match result {
+            Ok(bound) => Ok(Loop::Break(bound)),
+            Err(e) => {
+                let e = track!(bind_error(e, addr));
+                if let ErrorKind::AddrInUse = *e.kind() {
+                    debug!("Port {} of {} is in use; tries the next one", port, ip);
+                    Ok(Loop::Continue(candidates))
+                } else {
+                    Err(e)
+                }
+            }
+        }))
+    }))
+}
+
+fn future_aborted() -> Error {
+    ErrorKind::Other.cause("The future has aborted").into()
+}
+
+/// This trait allows for handling messages sent by clients.
+#[allow(unused_variables)]
+pub trait HandleMessage {
+    /// The attributes that the handler can recognize.
+    type Attribute: Attribute + Send + 'static;
+
+    /// Handles a request message.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_call(
+        &mut self,
+        peer: SocketAddr,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::NoReply
+    }
+
+    /// Handles an indication message.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_cast(
+        &mut self,
+        peer: SocketAddr,
+        indication: Indication<Self::Attribute>,
+    ) -> Action<Never> {
+        Action::NoReply
+    }
+
+    /// Handles an invalid incoming message.
+    ///
+    /// Note that this method should not return `Action::Reply(_)`, `Action::ReplyVia(..)`
+    /// or `Action::FutureReply(_)`
+    /// if the class of `message` is not `MessageClass::Request`.
+    ///
+    /// The default implementation always returns `Action::NoReply`.
+    fn handle_invalid_message(
+        &mut self,
+        peer: SocketAddr,
+        message: InvalidMessage,
+    ) -> Action<Response<Self::Attribute>> {
+        Action::NoReply
+    }
+
+    /// Handles an error before the channel drops by the error.
+    ///
+    /// The default implementation does nothing.
+    fn handle_channel_error(&mut self, error: &Error) {}
+
+    /// Handles the termination of the channel.
+    ///
+    /// `clean` is `true` if the peer has closed the connection gracefully
+    /// (e.g., via `StunTcpTransporter::close`), and `false` if the channel has been
+    /// aborted by an error (`handle_channel_error` is called before this in that case).
+    ///
+    /// Note that a graceful close only means that the peer has finished sending messages
+    /// (i.e., TCP end-of-stream has been reached).
+    /// A peer process that exits without calling `close` may still be reported as clean,
+    /// because the operating system closes its sockets gracefully.
+    ///
+    /// The default implementation does nothing.
+    fn handle_disconnect(&mut self, clean: bool) {}
+
+    /// Handles the expiration of the maximum lifetime of the connection
+    /// (see `TcpServer::max_connection_lifetime`).
+    ///
+    /// After this is called, no more messages are handed to the handler,
+    /// and `handle_disconnect(true)` is called once the issued responses have been flushed.
+    ///
+    /// The default implementation does nothing.
+    fn handle_lifetime_exceeded(&mut self) {}
+
+    /// Receives the handle of the server (or the TCP connection) that drives this handler.
+    ///
+    /// This is called once before any message is handed to the handler.
+    ///
+    /// The default implementation does nothing.
+    fn set_server_handle(&mut self, handle: ServerHandle) {}
+}
+
+/// Handle of a running server.
+///
+/// In the case of `TcpServer`, a handle is associated with each connection.
+#[derive(Debug, Clone)]
+pub struct ServerHandle {
+    flush_tx: mpsc::Sender<oneshot::Monitored<(), Error>>,
+    local_addr: SocketAddr,
+}
+impl ServerHandle {
+    /// Returns the local address of the server (or the TCP connection).
+    ///
+    /// In the case of `TcpServer`, this is the address to which the client has connected,
+    /// which tells the handler which address of a multi-homed host the client is using
+    /// even if the server listens on a wildcard address.
+    /// In the case of `UdpServer`, this is the address to which the socket is bound
+    /// (so it may be a wildcard address).
+    pub fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+
+    /// Returns a future that waits until the all replies issued so far have been
+    /// handed to the underlying socket.
+    ///
+    /// Note that the replies issued by `Action::FutureReply` are covered only if
+    /// the future has been completed before this method is called.
+    ///
+    /// # Errors
+    ///
+    /// If the server has stopped or its transport has failed, the returned future will fail.
+    pub fn flush(&self) -> impl Future<Item = (), Error = Error> {
+        let (tx, rx) = oneshot::monitor();
+        track!(self.flush_tx.send(tx).map_err(Error::from))
+            .into_future()
+            .and_then(move |()| rx.map_err(|e| track!(Error::from(e))))
+    }
+}
+
+type FutureResponse<A> = (SocketAddr, Response<A>, ReplyContext<A>);
+
+/// Hands the responses issued by `Action::FutureReply` over to `HandlerDriver`.
+struct FutureResponseSender<A> {
+    tx: mpsc::Sender<FutureResponse<A>>,
+    closing: Arc<AtomicBool>,
+    metrics: ServerMetrics,
+    local_addr: SocketAddr,
+}
+impl<A: Attribute> FutureResponseSender<A> {
+    fn send(&self, response: FutureResponse<A>) {
+        if let Err(e) = self.tx.send(response) {
+            // The driver has gone. If it had been closing (or had finished),
+            // the response is discarded as expected; otherwise the driver has been dropped
+            // in the middle of its operation, which is worth reporting.
+            if !self.closing.load(Ordering::SeqCst) {
+                let (peer, response, _) = e.0;
+                let m = match response {
+                    Ok(ref m) => m.as_ref(),
+                    Err(ref m) => m.as_ref(),
+                };
+                warn!(
+                    "STUN server ({}): dropped a response because the server has gone: \
+                     peer={}, method={:?}, transaction_id={:?}",
+                    self.local_addr,
+                    peer,
+                    m.method(),
+                    m.transaction_id()
+                );
+                self.metrics.inc_dropped_responses();
+            }
+        }
+    }
+}
+
+/// Per-request state that is applied to the response in `HandlerDriver::reply`.
+#[derive(Clone)]
+struct ReplyContext<A> {
+    echoed_attributes: Vec<A>,
+    password: Option<String>,
+    request_size: Option<usize>,
+    has_change_request: bool,
+}
+impl<A> Default for ReplyContext<A> {
+    fn default() -> Self {
+        ReplyContext {
+            echoed_attributes: Vec::new(),
+            password: None,
+            request_size: None,
+            has_change_request: false,
+        }
+    }
+}
+
+fn unknown_attributes_response<A>(message: &InvalidMessage) -> Option<ErrorResponse<A>>
+where
+    A: Attribute + From<ErrorCode> + From<UnknownAttributes>,
+{
+    if let MessageErrorKind::UnknownAttributes(ref unknowns) = *message.error().kind() {
+        let mut response = Message::new(
+            MessageClass::ErrorResponse,
+            message.method(),
+            message.transaction_id(),
+        );
+        let error: ErrorCode = rfc5389::errors::UnknownAttribute.into();
+        response.add_attribute(error.into());
+        response.add_attribute(UnknownAttributes::new(unknowns.clone()).into());
+        ErrorResponse::from_message(response).ok()
+    } else {
+        None
+    }
+}
+
+fn overload_response<A>(request: &Request<A>) -> ErrorResponse<A>
+where
+    A: Attribute + From<ErrorCode>,
+{
+    let error = ErrorCode::new(
+        rfc5389::errors::ServerError::CODEPOINT,
+        "Server is overloaded; please try again later".to_owned(),
+    ).expect("never fails");
+    ErrorResponse::new(request, error)
+}
+
+fn backoff_hint_attribute<A: From<Lifetime>>(hint: Duration) -> Option<A> {
+    Lifetime::new(hint).ok().map(A::from)
+}
+
+fn warn_if_slow_handler(
+    threshold: Option<Duration>,
+    start_time: Instant,
+    peer: SocketAddr,
+    method: Method,
+    operation: &str,
+) {
+    if let Some(threshold) = threshold {
+        let elapsed = start_time.elapsed();
+        if elapsed > threshold {
+            warn!(
+                "STUN server: slow handler ({}): peer={}, method={:?}, elapsed={:?}",
+                operation, peer, method, elapsed
+            );
+        }
+    }
+}
+
+fn is_low_entropy_transaction_id(transaction_id: TransactionId) -> bool {
+    let bytes = transaction_id.as_bytes();
+
+    // A single repeated byte (e.g., all zeros) or an arithmetic sequence (e.g., `0, 1, 2, ...`).
+    let step = bytes[1].wrapping_sub(bytes[0]);
+    if bytes.windows(2).all(|w| w[1].wrapping_sub(w[0]) == step) {
+        return true;
+    }
+
+    // A small counter (i.e., the upper 64 bits are all zeros).
+    bytes[..8].iter().all(|&b| b == 0)
+}
+
+fn strip_unknown_attributes<A: Attribute>(request: Request<A>) -> Request<A> {
+    if request.as_ref().unknown_attributes().next().is_none() {
+        return request;
+    }
+    let mut message = Message::new(
+        MessageClass::Request,
+        request.method(),
+        request.transaction_id(),
+    );
+    for attribute in request.attributes() {
+        message.add_attribute(attribute.clone());
+    }
+    Request::from_message(message).unwrap_or(request)
+}
+
+fn has_change_request<A: Attribute>(request: &Request<A>) -> bool {
+    let is_change_request = |t: AttributeType| t.as_u16() == ChangeRequest::CODEPOINT;
+    request
+        .attributes()
+        .any(|a| is_change_request(a.get_type()))
+        || request
+            .as_ref()
+            .unknown_attributes()
+            .any(|a| is_change_request(a.get_type()))
+}
+
+fn echo_attribute<T, A>(request: &Request<A>) -> Option<A>
+where
+    T: Attribute,
+    A: Attribute + TryAsRef<T> + From<T>,
+{
+    request.get_attribute::<T>().cloned().map(A::from)
+}
+
+type EchoAttribute<A> = fn(&Request<A>) -> Option<A>;
+type UnknownAttributesResponse<A> = fn(&InvalidMessage) -> Option<ErrorResponse<A>>;
+type OverloadResponse<A> = fn(&Request<A>) -> ErrorResponse<A>;
+type BackoffHintAttribute<A> = fn(Duration) -> Option<A>;
+
+struct HandlerOptions<A> {
+    response_origin: Option<fn(SocketAddr) -> A>,
+    response_cache_max_entries: usize,
+    response_cache_max_bytes: usize,
+    authenticator: Option<Arc<dyn Authenticate<A>>>,
+    duplicate_integrity_policy: DuplicateIntegrityPolicy,
+    echo_attributes: Vec<EchoAttribute<A>>,
+    unknown_attribute_policy: UnknownAttributePolicy,
+    unknown_attributes_response: Option<UnknownAttributesResponse<A>>,
+    handler_future_pool: HandlerFuturePool,
+    inflight_handlers: Option<InflightHandlers>,
+    overload_response: Option<OverloadResponse<A>>,
+    overload_backoff_hint: Option<(Duration, BackoffHintAttribute<A>)>,
+    slow_handler_threshold: Option<Duration>,
+    low_entropy_transaction_ids: LowEntropyTransactionIds,
+    max_amplification_factor: Option<usize>,
+    measure_queueing_delay: bool,
+}
+impl<A> Default for HandlerOptions<A> {
+    fn default() -> Self {
+        HandlerOptions {
+            response_origin: None,
+            response_cache_max_entries: 0,
+            response_cache_max_bytes: DEFAULT_RESPONSE_CACHE_MAX_BYTES,
+            authenticator: None,
+            duplicate_integrity_policy: DuplicateIntegrityPolicy::default(),
+            echo_attributes: Vec::new(),
+            unknown_attribute_policy: UnknownAttributePolicy::default(),
+            unknown_attributes_response: None,
+            handler_future_pool: HandlerFuturePool::default(),
+            inflight_handlers: None,
+            overload_response: None,
+            overload_backoff_hint: None,
+            slow_handler_threshold: None,
+            low_entropy_transaction_ids: LowEntropyTransactionIds::default(),
+            max_amplification_factor: None,
+            measure_queueing_delay: false,
+        }
+    }
+}
+impl<A> Clone for HandlerOptions<A> {
+    fn clone(&self) -> Self {
+        HandlerOptions {
+            response_origin: self.response_origin,
+            response_cache_max_entries: self.response_cache_max_entries,
+            response_cache_max_bytes: self.response_cache_max_bytes,
+            authenticator: self.authenticator.clone(),
+            duplicate_integrity_policy: self.duplicate_integrity_policy,
+            echo_attributes: self.echo_attributes.clone(),
+            unknown_attribute_policy: self.unknown_attribute_policy,
+            unknown_attributes_response: self.unknown_attributes_response,
+            handler_future_pool: self.handler_future_pool,
+            inflight_handlers: self.inflight_handlers.clone(),
+            overload_response: self.overload_response,
+            overload_backoff_hint: self.overload_backoff_hint,
+            slow_handler_threshold: self.slow_handler_threshold,
+            low_entropy_transaction_ids: self.low_entropy_transaction_ids,
+            max_amplification_factor: self.max_amplification_factor,
+            measure_queueing_delay: self.measure_queueing_delay,
+        }
+    }
+}
+impl<A> fmt::Debug for HandlerOptions<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("HandlerOptions")
+            .field("response_origin", &self.response_origin.is_some())
+            .field(
+                "response_cache_max_entries",
+                &self.response_cache_max_entries,
+            ).field("response_cache_max_bytes", &self.response_cache_max_bytes)
+            .field("authenticator", &self.authenticator.is_some())
+            .field(
+                "duplicate_integrity_policy",
+                &self.duplicate_integrity_policy,
+            ).field("echo_attributes", &self.echo_attributes.len())
+            .field("unknown_attribute_policy", &self.unknown_attribute_policy)
+            .field("handler_future_pool", &self.handler_future_pool)
+            .field(
+                "max_inflight_handlers",
+                &self.inflight_handlers.as_ref().map(|h| h.max),
+            ).field("reject_on_overload", &self.overload_response.is_some())
+            .field(
+                "overload_backoff_hint",
+                &self.overload_backoff_hint.map(|(hint, _)| hint),
+            ).field("slow_handler_threshold", &self.slow_handler_threshold)
+            .field(
+                "low_entropy_transaction_ids",
+                &self.low_entropy_transaction_ids,
+            ).field("max_amplification_factor", &self.max_amplification_factor)
+            .field("measure_queueing_delay", &self.measure_queueing_delay)
+            .finish()
+    }
+}
+
+#[derive(Debug)]
+struct HandlerDriver<H, T>
+where
+    H: HandleMessage,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    spawner: BoxSpawn,
+    handler: H,
+    channel: Channel<H::Attribute, T>,
+    local_addr: SocketAddr,
+    options: HandlerOptions<H::Attribute>,
+    metrics: ServerMetrics,
+    peer_activity: PeerActivityTracker,
+    response_cache: ResponseCache<H::Attribute>,
+    response_tx: mpsc::Sender<FutureResponse<H::Attribute>>,
+    response_rx: mpsc::Receiver<FutureResponse<H::Attribute>>,
+    closing: Arc<AtomicBool>,
+    future_reply_cancel: Option<FutureReplyCancel>,
+    running_futures: usize,
+    queued_futures: QueuedFutures,
+    future_done_tx: mpsc::Sender<()>,
+    future_done_rx: mpsc::Receiver<()>,
+    inflight_wakeup_tx: mpsc::Sender<()>,
+    inflight_wakeup_rx: mpsc::Receiver<()>,
+    flush_tx: mpsc::Sender<oneshot::Monitored<(), Error>>,
+    flush_rx: mpsc::Receiver<oneshot::Monitored<(), Error>>,
+    flush_waiters: Vec<oneshot::Monitored<(), Error>>,
+    lifetime: Option<Timeout>,
+    lifetime_exceeded: bool,
+    write_stall_timeout: Option<Duration>,
+    write_stall: Option<Timeout>,
+    batch_started_at: Option<Instant>,
+    error_response_limiter: Option<ErrorResponseLimiter>,
+    shutdown_rx: Option<mpsc::Receiver<()>>,
+    shutting_down: bool,
+    peer_closed: bool,
+}
+impl<H, T> HandlerDriver<H, T>
+where
+    H: HandleMessage,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    fn new(
+        spawner: BoxSpawn,
+        mut handler: H,
+        channel: Channel<H::Attribute, T>,
+        local_addr: SocketAddr,
+        options: HandlerOptions<H::Attribute>,
+        metrics: ServerMetrics,
+    ) -> Self {
+        let (response_tx, response_rx) = mpsc::channel();
+        let (future_done_tx, future_done_rx) = mpsc::channel();
+        let (inflight_wakeup_tx, inflight_wakeup_rx) = mpsc::channel();
+        let (flush_tx, flush_rx) = mpsc::channel();
+        handler.set_server_handle(ServerHandle {
+            flush_tx: flush_tx.clone(),
+            local_addr,
+        });
+        let response_cache = ResponseCache::new(
+            options.response_cache_max_entries,
+            options.response_cache_max_bytes,
+        );
+        HandlerDriver {
+            spawner,
+            handler,
+            channel,
+            local_addr,
+            options,
+            metrics,
+            peer_activity: PeerActivityTracker::default(),
+            response_cache,
+            response_tx,
+            response_rx,
+            closing: Arc::new(AtomicBool::new(false)),
+            future_reply_cancel: None,
+            running_futures: 0,
+            queued_futures: QueuedFutures(VecDeque::new()),
+            future_done_tx,
+            future_done_rx,
+            inflight_wakeup_tx,
+            inflight_wakeup_rx,
+            flush_tx,
+            flush_rx,
+            flush_waiters: Vec::new(),
+            lifetime: None,
+            lifetime_exceeded: false,
+            write_stall_timeout: None,
+            write_stall: None,
+            batch_started_at: None,
+            error_response_limiter: None,
+            shutdown_rx: None,
+            shutting_down: false,
+            peer_closed: false,
+        }
+    }
+
+    fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            flush_tx: self.flush_tx.clone(),
+            local_addr: self.local_addr,
+        }
+    }
+
+    fn replace_handler(&mut self, mut handler: H) {
+        handler.set_server_handle(self.handle());
+        self.handler = handler;
+        debug!(
+            "STUN server ({}): the handler has been replaced",
+            self.local_addr
+        );
+    }
+
+    fn poll_send(&mut self) -> Result<()> {
+        while let Async::Ready(Some(waiter)) = self.flush_rx.poll().expect("never fails") {
+            self.flush_waiters.push(waiter);
+        }
+        match track!(self.channel.poll_send()) {
+            Err(e) => {
+                self.handler.handle_channel_error(&e);
+                for waiter in self.flush_waiters.drain(..) {
+                    waiter.exit(Err(track!(e.clone())));
+                }
+                Err(e)
+            }
+            Ok(Async::NotReady) => track!(self.check_write_stall()),
+            Ok(Async::Ready(())) => {
+                self.write_stall = None;
+                for waiter in self.flush_waiters.drain(..) {
+                    waiter.exit(Ok(()));
+                }
+                Ok(())
+            }
+        }
+    }
+
+    /// Makes the driver cancel the `Action::FutureReply` futures after `grace_period`
+    /// since the driver is dropped.
+    fn cancel_future_replies_on_close(&mut self, grace_period: Duration) {
+        self.future_reply_cancel = Some(FutureReplyCancel {
+            grace_period,
+            next_id: 0,
+            close_txs: Arc::new(Mutex::new(HashMap::new())),
+        });
+    }
+
+    fn cancellable_future_reply(&mut self, future: HandlerFuture) -> HandlerFuture {
+        if let Some(ref mut cancel) = self.future_reply_cancel {
+            cancel.wrap(future)
+        } else {
+            future
+        }
+    }
+
+    fn future_response_sender(&self) -> FutureResponseSender<H::Attribute> {
+        FutureResponseSender {
+            tx: self.response_tx.clone(),
+            closing: self.closing.clone(),
+            metrics: self.metrics.clone(),
+            local_addr: self.local_addr,
+        }
+    }
+
+    fn spawn_handler_future(&mut self, future: HandlerFuture) {
+        let future = match self.options.inflight_handlers {
+            None => future,
+            Some(ref h) => h.track(future),
+        };
+        self.run_handler_future(future);
+    }
+
+    fn run_handler_future(&mut self, future: HandlerFuture) {
+        match self.options.handler_future_pool {
+            HandlerFuturePool::Unbounded => {
+                self.spawner.spawn(future.map_err(|_| unreachable!()));
+            }
+            HandlerFuturePool::Bounded { workers, .. } => {
+                if self.running_futures < workers.max(1) {
+                    self.running_futures += 1;
+                    let done_tx = self.future_done_tx.clone();
+                    self.spawner.spawn(future.then(move |_| {
+                        let _ = done_tx.send(());
+                        Ok(())
+                    }));
+                } else {
+                    self.queued_futures.0.push_back(future);
+                }
+            }
+        }
+    }
+
+    fn handle_finished_futures(&mut self) -> bool {
+        let mut did_something = false;
+        while let Async::Ready(Some(())) = self.future_done_rx.poll().expect("never fails") {
+            self.running_futures -= 1;
+            if let Some(future) = self.queued_futures.0.pop_front() {
+                self.run_handler_future(future);
+            }
+            did_something = true;
+        }
+        did_something
+    }
+
+    /// Returns `true` if the queue of the handler future pool is full
+    /// or `max_inflight_handlers` futures are outstanding.
+    fn is_overloaded(&self) -> bool {
+        self.is_handler_future_pool_full()
+            || self
+                .options
+                .inflight_handlers
+                .as_ref()
+                .map_or(false, |h| h.is_full())
+    }
+
+    /// Returns `true` if the driver should stop receiving messages because of the overload.
+    ///
+    /// If `max_inflight_handlers` futures are outstanding, the driver is woken up
+    /// when one of them (possibly of another connection) completes.
+    fn should_pause_recv(&self) -> bool {
+        if self.options.overload_response.is_some() {
+            return false;
+        }
+        self.is_handler_future_pool_full()
+            || self
+                .options
+                .inflight_handlers
+                .as_ref()
+                .map_or(false, |h| h.is_full_or_wait(&self.inflight_wakeup_tx))
+    }
+
+    fn is_handler_future_pool_full(&self) -> bool {
+        match self.options.handler_future_pool {
+            HandlerFuturePool::Unbounded => false,
+            HandlerFuturePool::Bounded { workers, queue_len } => {
+                self.running_futures >= workers.max(1) && self.queued_futures.0.len() >= queue_len
+            }
+        }
+    }
+
+    fn drain_inflight_wakeups(&mut self) {
+        while let Async::Ready(Some(())) = self.inflight_wakeup_rx.poll().expect("never fails") {}
+    }
+
+    fn update_response_cache_limits(&mut self) {
+        let evicted = self.response_cache.set_limits(
+            self.options.response_cache_max_entries,
+            self.options.response_cache_max_bytes,
+        );
+        self.metrics.add_response_cache_evictions(evicted);
+    }
+
+    fn reply(
+        &mut self,
+        peer: SocketAddr,
+        response: Response<H::Attribute>,
+        context: ReplyContext<H::Attribute>,
+    ) -> Result<()> {
+        let request_size = context.request_size;
+        let authenticated = context.password.is_some();
+        let response = self.prepare_response(response, context);
+        if self.is_amplifying(peer, &response, request_size)
+            || self.is_error_response_limited(peer, &response)
+        {
+            return Ok(());
+        }
+        if self.response_cache.is_enabled() {
+            let evicted = self.response_cache.insert(peer, &response, authenticated);
+            self.metrics.add_response_cache_evictions(evicted);
+        }
+        track!(self.channel.reply(peer, response))?;
+        Ok(())
+    }
+
+    fn reply_via(
+        &mut self,
+        peer: SocketAddr,
+        response: Response<H::Attribute>,
+        context: ReplyContext<H::Attribute>,
+        mut transport: Box<dyn ReplyTransport<Response<H::Attribute>>>,
+    ) {
+        let request_size = context.request_size;
+        let response = self.prepare_response(response, context);
+        if self.is_amplifying(peer, &response, request_size)
+            || self.is_error_response_limited(peer, &response)
+        {
+            return;
+        }
+        if let Err(e) = track!(transport.reply(peer, response)) {
+            warn!(
+                "STUN server: cannot send a response to {} via the given transport: {}",
+                peer, e
+            );
+        }
+    }
+
+    fn is_amplifying(
+        &self,
+        peer: SocketAddr,
+        response: &Response<H::Attribute>,
+        request_size: Option<usize>,
+    ) -> bool {
+        let (factor, request_size) = match (self.options.max_amplification_factor, request_size) {
+            (Some(factor), Some(request_size)) => (factor, request_size),
+            _ => return false,
+        };
+        let message = match response.clone() {
+            Ok(m) => m.into_message(),
+            Err(m) => m.into_message(),
+        };
+        let response_size = match encoded_message_size(message) {
+            None => return false,
+            Some(size) => size,
+        };
+        if response_size <= request_size.saturating_mul(factor) {
+            return false;
+        }
+        debug!(
+            "STUN server: drops an amplifying response: peer={}, request_size={}, \
+             response_size={}",
+            peer, request_size, response_size
+        );
+        self.metrics.inc_amplification_suppressions();
+        true
+    }
+
+    fn is_error_response_limited(
+        &mut self,
+        peer: SocketAddr,
+        response: &Response<H::Attribute>,
+    ) -> bool {
+        if response.is_ok() {
+            return false;
+        }
+        let allowed = self
+            .error_response_limiter
+            .as_mut()
+            .map_or(true, |limiter| limiter.allow(peer, Instant::now()));
+        if allowed {
+            return false;
+        }
+        debug!(
+            "STUN server: drops an error response exceeding the rate limit: peer={}",
+            peer
+        );
+        self.metrics.inc_error_response_suppressions();
+        true
+    }
+
+    fn prepare_response(
+        &self,
+        mut response: Response<H::Attribute>,
+        context: ReplyContext<H::Attribute>,
+    ) -> Response<H::Attribute> {
+        for attribute in context.echoed_attributes {
+            match response {
+                Ok(ref mut m) => m.add_attribute(attribute),
+                Err(ref mut m) => m.add_attribute(attribute),
+            }
+        }
+        let response_origin = self.options.response_origin;
+        if let (Ok(response), Some(f)) = (response.as_mut(), response_origin) {
+            if !context.has_change_request {
+                response.add_attribute(f(self.local_addr));
+            }
+        }
+        let password = context.password;
+        if let (Some(password), Some(a)) = (password, self.options.authenticator.as_ref()) {
+            response = a.sign(response, &password);
+        }
+        response
+    }
+
+    fn handle_message(
+        &mut self,
+        peer: SocketAddr,
+        message: RecvMessage<H::Attribute>,
+    ) -> Result<()> {
+        if let RecvMessage::Request(ref m) = message {
+            self.metrics.inc_requests(m.method());
+        }
+        if self.is_overloaded() {
+            if let Some(f) = self.options.overload_response {
+                if let RecvMessage::Request(m) = message {
+                    self.metrics.inc_overload_rejections();
+                    let mut response = f(&m);
+                    if let Some((hint, g)) = self.options.overload_backoff_hint {
+                        if let Some(attribute) = g(hint) {
+                            response.add_attribute(attribute);
+                        }
+                    }
+                    track!(self.reply(peer, Err(response), ReplyContext::default()))?;
+                }
+                return Ok(());
+            }
+        }
+        match message {
+            RecvMessage::Indication(m) => self.handle_indication(peer, m),
+            RecvMessage::Request(m) => track!(self.handle_request(peer, m))?,
+            RecvMessage::Invalid(m) => track!(self.handle_invalid_message(peer, m))?,
+        }
+        Ok(())
+    }
+
+    fn handle_indication(&mut self, peer: SocketAddr, indication: Indication<H::Attribute>) {
+        match self.handler.handle_cast(peer, indication) {
+            Action::NoReply => {}
+            Action::FutureNoReply(future) => self.spawn_handler_future(future),
+            _ => unreachable!(),
+        }
+    }
+
+    fn handle_request(&mut self, peer: SocketAddr, request: Request<H::Attribute>) -> Result<()> {
+        let policy = self.options.low_entropy_transaction_ids;
+        if policy != LowEntropyTransactionIds::Accept
+            && is_low_entropy_transaction_id(request.transaction_id())
+        {
+            self.metrics.inc_low_entropy_transaction_ids();
+            if policy == LowEntropyTransactionIds::Drop {
+                return Ok(());
+            }
+            warn!(
+                "STUN server: low-entropy transaction ID: peer={}, method={:?}, id={:?}",
+                peer,
+                request.method(),
+                request.transaction_id()
+            );
+        }
+        let request_size = self
+            .options
+            .max_amplification_factor
+            .and_then(|_| encoded_message_size(request.as_ref().clone()));
+        if self.response_cache.is_enabled() {
+            let cached = self.response_cache.get(peer, request.transaction_id());
+            if let Some((response, authenticated)) = cached {
+                self.metrics.inc_response_cache_hits();
+
+                // The retransmission may be smaller than the original request
+                let request_size = if authenticated { None } else { request_size };
+                if !self.is_amplifying(peer, &response, request_size)
+                    && !self.is_error_response_limited(peer, &response)
+                {
+                    track!(self.channel.reply(peer, response))?;
+                }
+                return Ok(());
+            }
+            self.metrics.inc_response_cache_misses();
+        }
+        let (request, password) = if let Some(a) = self.options.authenticator.clone() {
+            match a.authenticate(request, self.options.duplicate_integrity_policy) {
+                Err(response) => {
+                    let context = ReplyContext {
+                        request_size,
+                        ..ReplyContext::default()
+                    };
+                    track!(self.reply(peer, Err(response), context))?;
+                    return Ok(());
+                }
+                Ok((request, password)) => (request, Some(password)),
+            }
+        } else {
+            (request, None)
+        };
+        let request = match self.options.unknown_attribute_policy.optional {
+            UnknownOptionalAttributes::Collect => request,
+            UnknownOptionalAttributes::Ignore => strip_unknown_attributes(request),
+        };
+        let context = ReplyContext {
+            echoed_attributes: self
+                .options
+                .echo_attributes
+                .iter()
+                .filter_map(|f| f(&request))
+                .collect(),
+            request_size: if password.is_some() {
+                None
+            } else {
+                request_size
+            },
+            password,
+            has_change_request: has_change_request(&request),
+        };
+        let method = request.method();
+        let start_time = Instant::now();
+        if let Some(batch_started_at) = self.batch_started_at {
+            self.metrics
+                .record_queueing_delay(start_time.duration_since(batch_started_at));
+        }
+        let action = self.handler.handle_call(peer, request);
+        let threshold = self.options.slow_handler_threshold;
+        warn_if_slow_handler(threshold, start_time, peer, method, "handle_call");
+        match action {
+            Action::NoReply => {}
+            Action::FutureNoReply(future) => self.spawn_handler_future(future),
+            Action::Reply(m) => track!(self.reply(peer, m, context))?,
+            Action::ReplyVia(m, transport) => self.reply_via(peer, m, context, transport),
+            Action::FutureReply(future) => {
+                let sender = self.future_response_sender();
+                let future = self.cancellable_future_reply(Box::new(future.map(move |response| {
+                    warn_if_slow_handler(threshold, start_time, peer, method, "FutureReply");
+                    sender.send((peer, response, context));
+                })));
+                self.spawn_handler_future(future);
+            }
+            Action::ReplyStream(stream) => {
+                let sender = self.future_response_sender();
+                let future = stream.for_each(move |response| {
+                    sender.send((peer, response, context.clone()));
+                    Ok(())
+                });
+                let future = self.cancellable_future_reply(Box::new(future));
+                self.spawn_handler_future(future);
+            }
+        }
+        Ok(())
+    }
+
+    fn handle_invalid_message(&mut self, peer: SocketAddr, message: InvalidMessage) -> Result<()> {
+        let is_unknown_attributes = match *message.error().kind() {
+            MessageErrorKind::UnknownAttributes(_) => message.class() == MessageClass::Request,
+            _ => false,
+        };
+        if is_unknown_attributes {
+            match self.options.unknown_attribute_policy.required {
+                UnknownRequiredAttributes::Delegate => {}
+                UnknownRequiredAttributes::Drop => return Ok(()),
+                UnknownRequiredAttributes::Reject => {
+                    let response = self
+                        .options
+                        .unknown_attributes_response
+                        .and_then(|f| f(&message));
+                    if let Some(response) = response {
+                        track!(self.reply(peer, Err(response), ReplyContext::default()))?;
+                    }
+                    return Ok(());
+                }
+            }
+        }
+        match self.handler.handle_invalid_message(peer, message) {
+            Action::NoReply => {}
+            Action::FutureNoReply(future) => self.spawn_handler_future(future),
+            Action::Reply(m) => track!(self.reply(peer, m, ReplyContext::default()))?,
+            Action::ReplyVia(m, transport) => {
+                self.reply_via(peer, m, ReplyContext::default(), transport)
+            }
+            Action::FutureReply(future) => {
+                let sender = self.future_response_sender();
+                let future = self.cancellable_future_reply(Box::new(future.map(move |response| {
+                    sender.send((peer, response, ReplyContext::default()));
+                })));
+                self.spawn_handler_future(future);
+            }
+            Action::ReplyStream(stream) => {
+                let sender = self.future_response_sender();
+                let future = stream.for_each(move |response| {
+                    sender.send((peer, response, ReplyContext::default()));
+                    Ok(())
+                });
+                let future = self.cancellable_future_reply(Box::new(future));
+                self.spawn_handler_future(future);
+            }
+        }
+        Ok(())
+    }
+}
+impl<H, T> Future for HandlerDriver<H, T>
+where
+    H: HandleMessage,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    type Item = ();
+    type Error = Error;
+
+    fn poll(&mut self) -> Poll<Self::Item, Self::Error> {
+        let result = self.poll_channel();
+        match result {
+            Ok(Async::NotReady) => return Ok(Async::NotReady),
+            Ok(Async::Ready(())) => self.handler.handle_disconnect(true),
+            Err(_) => self.handler.handle_disconnect(false),
+        }
+        self.closing.store(true, Ordering::SeqCst);
+        result
+    }
+}
+impl<H, T> HandlerDriver<H, T>
+where
+    H: HandleMessage,
+    T: StunTransport<H::Attribute, PeerAddr = SocketAddr>,
+{
+    fn poll_channel(&mut self) -> Poll<(), Error> {
+        if !self.lifetime_exceeded && self.poll_lifetime() {
+            debug!(
+                "STUN server: the lifetime of the connection (local address: {}) has been exceeded",
+                self.local_addr
+            );
+            self.lifetime_exceeded = true;
+            self.handler.handle_lifetime_exceeded();
+        }
+        if !self.shutting_down && poll_shutdown_request(&mut self.shutdown_rx) {
+            debug!("STUN server ({}): shutting down", self.local_addr);
+            self.shutting_down = true;
+        }
+        if self.lifetime_exceeded || self.shutting_down || self.peer_closed {
+            self.closing.store(true, Ordering::SeqCst);
+            return track!(self.poll_close());
+        }
+
+        let mut did_something = true;
+        let mut batches = 0;
+        while did_something {
+            if batches == MAX_BATCHES_PER_POLL {
+                // Gives the other fibers (e.g., the ones of the other connections) a chance to run,
+                // since a peer that keeps sending messages would monopolize the scheduler otherwise
+                return fiber::yield_poll();
+            }
+            batches += 1;
+            did_something = self.handle_finished_futures();
+            self.drain_inflight_wakeups();
+
+            // Handles a batch of the received messages before flushing the responses,
+            // so that a transporter can coalesce them (see `TcpServer::coalesce_writes`).
+            if self.options.measure_queueing_delay {
+                self.batch_started_at = Some(Instant::now());
+            }
+            for _ in 0..MAX_RECV_BATCH {
+                if self.should_pause_recv() {
+                    break;
+                }
+                match track!(self.channel.poll_recv()) {
+                    Err(e) => {
+                        self.handler.handle_channel_error(&e);
+                        return Err(e);
+                    }
+                    Ok(Async::NotReady) => break,
+                    Ok(Async::Ready(None)) => {
+                        // The peer will send no more messages (e.g., it has half-closed
+                        // the connection), but it may still be waiting for the responses
+                        debug!(
+                            "STUN server ({}): the peer has closed the channel",
+                            self.local_addr
+                        );
+                        self.peer_closed = true;
+                        self.closing.store(true, Ordering::SeqCst);
+                        return track!(self.poll_close());
+                    }
+                    Ok(Async::Ready(Some((peer, message)))) => {
+                        self.peer_activity.record(peer);
+                        track!(self.handle_message(peer, message))?;
+                        did_something = true;
+                    }
+                }
+            }
+            track!(self.poll_send())?;
+            if let Async::Ready(item) = self.response_rx.poll().expect("never fails") {
+                let (peer, response, context) = item.expect("never fails");
+                track!(self.reply(peer, response, context))?;
+                did_something = true;
+            }
+        }
+        Ok(Async::NotReady)
+    }
+
+    fn poll_lifetime(&mut self) -> bool {
+        match self.lifetime.as_mut().map(|t| t.poll()) {
+            None | Some(Ok(Async::NotReady)) => false,
+            Some(Ok(Async::Ready(()))) | Some(Err(_)) => true,
+        }
+    }
+
+    /// Flushes the issued responses, and then finishes the channel.
+    fn poll_close(&mut self) -> Poll<(), Error> {
+        while let Async::Ready(item) = self.response_rx.poll().expect("never fails") {
+            let (peer, response, context) = item.expect("never fails");
+            track!(self.reply(peer, response, context))?;
+        }
+        match track!(self.channel.poll_send()) {
+            Err(e) => {
+                self.handler.handle_channel_error(&e);
+                Err(e)
+            }
+            Ok(Async::NotReady) => {
+                track!(self.check_write_stall())?;
+                Ok(Async::NotReady)
+            }
+            Ok(Async::Ready(())) => Ok(Async::Ready(())),
+        }
+    }
+
+    /// Called while the outgoing messages are pending,
+    /// and fails if they have been pending longer than `write_stall_timeout`.
+    fn check_write_stall(&mut self) -> Result<()> {
+        let timeout = match self.write_stall_timeout {
+            None => return Ok(()),
+            Some(timeout) => timeout,
+        };
+        let expired = match self
+            .write_stall
+            .get_or_insert_with(|| timer::timeout(timeout))
+            .poll()
+        {
+            Ok(Async::NotReady) => false,
+            Ok(Async::Ready(())) | Err(_) => true,
+        };
+        if expired {
+            let e: Error = ErrorKind::Other
+                .cause(format!("Writes have stalled for {:?}", timeout))
+                .into();
+            let e = track!(e; self.local_addr);
+            self.handler.handle_channel_error(&e);
+            return Err(e);
+        }
+        Ok(())
+    }
+}
+
+/// Returns `true` if a shutdown has been requested via `rx`.
+///
+/// If the sender has been dropped without requesting a shutdown, `rx` is discarded.
+fn poll_shutdown_request(rx: &mut Option<mpsc::Receiver<()>>) -> bool {
+    let requested = match rx.as_mut().map(|rx| rx.poll().expect("never fails")) {
+        None | Some(Async::NotReady) => return false,
+        Some(Async::Ready(requested)) => requested.is_some(),
+    };
+    *rx = None;
+    requested
+}
+
+/// Example `BINDING` request handler.
+///
+/// Note that this is provided only for test and example purposes.
+#[derive(Debug, Default, Clone)]
+pub struct BindingHandler;
+impl HandleMessage for BindingHandler {
+    type Attribute = rfc5389::Attribute;
+
+    fn handle_call(
+        &mut self,
+        peer: SocketAddr,
+        request: Request<Self::Attribute>,
+    ) -> Action<Response<Self::Attribute>> {
+        if request.method() == rfc5389::methods::BINDING {
+            let mut response = SuccessResponse::new(&request);
+            response.add_attribute(rfc5389::attributes::XorMappedAddress::new(peer).into());
+            Action::Reply(Ok(response))
+        } else {
+            let response = ErrorResponse::new(&request, rfc5389::errors::BadRequest.into());
+            Action::Reply(Err(response))
+        }
+    }
+}
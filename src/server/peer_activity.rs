@@ -0,0 +1,227 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, Instant};
+
+/// Activity of a peer observed by a server.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PeerActivity {
+    /// The address of the peer.
+    pub peer: SocketAddr,
+
+    /// The number of the messages received from the peer since it became active.
+    pub messages: u64,
+
+    /// The time when the last message was received from the peer.
+    pub last_seen: Instant,
+}
+
+/// Tracker of the peers from which a server has recently received messages.
+///
+/// This is a cheap handle that can be cloned and read from any thread.
+///
+/// A peer is regarded as active while the time elapsed since its last message is within
+/// the window of the tracker.
+/// The number of the tracked peers is bounded; if the limit is reached,
+/// the least recently seen peer is evicted to make room for a new one.
+///
+/// Tracking is disabled by default (i.e., the maximum number of the tracked peers is `0`).
+#[derive(Clone, Default)]
+pub struct PeerActivityTracker {
+    enabled: Arc<AtomicBool>,
+    inner: Arc<Mutex<Inner>>,
+}
+impl PeerActivityTracker {
+    /// Returns `true` if the tracker records the activity of peers.
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::SeqCst)
+    }
+
+    /// Returns the activity of the peers that are currently active.
+    ///
+    /// The resulting entries are sorted by the addresses of the peers.
+    pub fn snapshot(&self) -> Vec<PeerActivity> {
+        let mut inner = self.lock();
+        inner.remove_inactive_peers(Instant::now());
+        let mut peers = inner
+            .peers
+            .values()
+            .map(|e| e.activity.clone())
+            .collect::<Vec<_>>();
+        peers.sort_by_key(|p| (p.peer.ip(), p.peer.port()));
+        peers
+    }
+
+    pub(crate) fn set_limits(&self, window: Duration, max_peers: usize) {
+        let mut inner = self.lock();
+        inner.window = window;
+        inner.max_peers = max_peers;
+        inner.remove_inactive_peers(Instant::now());
+        while inner.peers.len() > inner.max_peers {
+            inner.evict_least_recently_seen();
+        }
+        self.enabled.store(max_peers > 0, Ordering::SeqCst);
+    }
+
+    pub(crate) fn record(&self, peer: SocketAddr) {
+        // Avoids taking the lock for every received message if the tracking is disabled
+        if !self.is_enabled() {
+            return;
+        }
+        let mut guard = self.lock();
+        let inner = &mut *guard;
+        if inner.max_peers == 0 {
+            return;
+        }
+
+        let now = Instant::now();
+        let window = inner.window;
+        let seqno = inner.next_seqno;
+        inner.next_seqno += 1;
+        if let Some(e) = inner.peers.get_mut(&peer) {
+            if now.duration_since(e.activity.last_seen) > window {
+                e.activity.messages = 0;
+            }
+            e.activity.messages += 1;
+            e.activity.last_seen = now;
+            let old_seqno = e.seqno;
+            e.seqno = seqno;
+            inner.lru.remove(&old_seqno);
+            inner.lru.insert(seqno, peer);
+            return;
+        }
+        if inner.peers.len() >= inner.max_peers {
+            inner.remove_inactive_peers(now);
+            if inner.peers.len() >= inner.max_peers {
+                inner.evict_least_recently_seen();
+            }
+        }
+        let activity = PeerActivity {
+            peer,
+            messages: 1,
+            last_seen: now,
+        };
+        inner.peers.insert(peer, Entry { activity, seqno });
+        inner.lru.insert(seqno, peer);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Inner> {
+        self.inner.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+impl fmt::Debug for PeerActivityTracker {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let inner = self.lock();
+        write!(
+            f,
+            "PeerActivityTracker {{ window: {:?}, max_peers: {}, peers: {} }}",
+            inner.window,
+            inner.max_peers,
+            inner.peers.len()
+        )
+    }
+}
+
+#[derive(Debug)]
+struct Entry {
+    activity: PeerActivity,
+    seqno: u64,
+}
+
+/// The peers are ordered by `lru` from the least recently seen one,
+/// so both removing the inactive peers and evicting the oldest one
+/// only look at the head of the order.
+#[derive(Debug, Default)]
+struct Inner {
+    window: Duration,
+    max_peers: usize,
+    next_seqno: u64,
+    peers: HashMap<SocketAddr, Entry>,
+    lru: BTreeMap<u64, SocketAddr>,
+}
+impl Inner {
+    fn remove_inactive_peers(&mut self, now: Instant) {
+        loop {
+            let (seqno, peer) = match self.lru.iter().next() {
+                None => break,
+                Some((&seqno, &peer)) => (seqno, peer),
+            };
+            let last_seen = self.peers[&peer].activity.last_seen;
+            if now.duration_since(last_seen) <= self.window {
+                break;
+            }
+            self.lru.remove(&seqno);
+            self.peers.remove(&peer);
+        }
+    }
+
+    fn evict_least_recently_seen(&mut self) {
+        let seqno = match self.lru.keys().next() {
+            None => return,
+            Some(&seqno) => seqno,
+        };
+        let peer = self.lru.remove(&seqno).expect("never fails");
+        self.peers.remove(&peer);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+
+    use super::*;
+
+    fn addr(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    fn peers(tracker: &PeerActivityTracker) -> Vec<(u16, u64)> {
+        tracker
+            .snapshot()
+            .into_iter()
+            .map(|p| (p.peer.port(), p.messages))
+            .collect()
+    }
+
+    #[test]
+    fn disabled_tracker_records_nothing() {
+        let tracker = PeerActivityTracker::default();
+        assert!(!tracker.is_enabled());
+        tracker.record(addr(1));
+        assert!(tracker.snapshot().is_empty());
+    }
+
+    #[test]
+    fn least_recently_seen_peer_is_evicted() {
+        let tracker = PeerActivityTracker::default();
+        tracker.set_limits(Duration::from_secs(60), 2);
+        assert!(tracker.is_enabled());
+
+        tracker.record(addr(1));
+        tracker.record(addr(2));
+        tracker.record(addr(1));
+        tracker.record(addr(3));
+        assert_eq!(peers(&tracker), [(1, 2), (3, 1)]);
+
+        tracker.set_limits(Duration::from_secs(60), 1);
+        assert_eq!(peers(&tracker), [(3, 1)]);
+
+        tracker.set_limits(Duration::from_secs(60), 0);
+        assert!(!tracker.is_enabled());
+        assert!(tracker.snapshot().is_empty());
+    }
+
+    #[test]
+    fn inactive_peers_are_removed() {
+        let tracker = PeerActivityTracker::default();
+        tracker.set_limits(Duration::from_millis(50), 2);
+
+        tracker.record(addr(1));
+        thread::sleep(Duration::from_millis(100));
+        tracker.record(addr(2));
+        tracker.record(addr(3));
+        assert_eq!(peers(&tracker), [(2, 1), (3, 1)]);
+    }
+}
@@ -0,0 +1,235 @@
+use bytecodec::{Encode, SizedEncode};
+use std::collections::{BTreeMap, HashMap};
+use std::fmt;
+use std::net::SocketAddr;
+use stun_codec::{Attribute, Message, MessageEncoder, TransactionId};
+
+use message::Response;
+
+type Key = (SocketAddr, TransactionId);
+
+/// LRU cache of the responses sent by a server.
+///
+/// This is used for answering retransmitted requests without invoking the handler again.
+pub(crate) struct ResponseCache<A> {
+    max_entries: usize,
+    max_bytes: usize,
+    total_bytes: usize,
+    next_seqno: u64,
+    entries: HashMap<Key, Entry<A>>,
+    lru: BTreeMap<u64, Key>,
+}
+impl<A: Attribute> ResponseCache<A> {
+    pub fn new(max_entries: usize, max_bytes: usize) -> Self {
+        ResponseCache {
+            max_entries,
+            max_bytes,
+            total_bytes: 0,
+            next_seqno: 0,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+        }
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.max_entries > 0 && self.max_bytes > 0
+    }
+
+    /// Updates the limits of the cache, and returns the number of the evicted entries.
+    pub fn set_limits(&mut self, max_entries: usize, max_bytes: usize) -> usize {
+        self.max_entries = max_entries;
+        self.max_bytes = max_bytes;
+        self.evict()
+    }
+
+    /// Returns the cached response and whether its request has been authenticated.
+    pub fn get(
+        &mut self,
+        peer: SocketAddr,
+        transaction_id: TransactionId,
+    ) -> Option<(Response<A>, bool)> {
+        let seqno = self.next_seqno;
+        if let Some(entry) = self.entries.get_mut(&(peer, transaction_id)) {
+            self.lru.remove(&entry.seqno);
+            self.lru.insert(seqno, (peer, transaction_id));
+            self.next_seqno += 1;
+            entry.seqno = seqno;
+            Some((entry.response.clone(), entry.authenticated))
+        } else {
+            None
+        }
+    }
+
+    /// Inserts the given response, and returns the number of the evicted entries.
+    ///
+    /// `authenticated` indicates whether the request of the response has been authenticated.
+    pub fn insert(
+        &mut self,
+        peer: SocketAddr,
+        response: &Response<A>,
+        authenticated: bool,
+    ) -> usize {
+        if !self.is_enabled() {
+            return 0;
+        }
+        let size = match encoded_size(response) {
+            None => return 0,
+            Some(size) => size,
+        };
+        if size > self.max_bytes {
+            return 0;
+        }
+
+        let key = (peer, transaction_id(response));
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        if let Some(old) = self.entries.remove(&key) {
+            self.lru.remove(&old.seqno);
+            self.total_bytes -= old.size;
+        }
+        self.entries.insert(
+            key,
+            Entry {
+                response: response.clone(),
+                authenticated,
+                size,
+                seqno,
+            },
+        );
+        self.lru.insert(seqno, key);
+        self.total_bytes += size;
+        self.evict()
+    }
+
+    fn evict(&mut self) -> usize {
+        let mut evicted = 0;
+        while self.entries.len() > self.max_entries || self.total_bytes > self.max_bytes {
+            let seqno = match self.lru.keys().next() {
+                None => break,
+                Some(&seqno) => seqno,
+            };
+            let key = self.lru.remove(&seqno).expect("never fails");
+            let entry = self.entries.remove(&key).expect("never fails");
+            self.total_bytes -= entry.size;
+            evicted += 1;
+        }
+        evicted
+    }
+}
+impl<A> fmt::Debug for ResponseCache<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "ResponseCache {{ max_entries: {}, max_bytes: {}, entries: {}, total_bytes: {} }}",
+            self.max_entries,
+            self.max_bytes,
+            self.entries.len(),
+            self.total_bytes
+        )
+    }
+}
+
+struct Entry<A> {
+    response: Response<A>,
+    authenticated: bool,
+    size: usize,
+    seqno: u64,
+}
+
+fn transaction_id<A: Attribute>(response: &Response<A>) -> TransactionId {
+    match response {
+        Ok(m) => m.transaction_id(),
+        Err(m) => m.transaction_id(),
+    }
+}
+
+fn encoded_size<A: Attribute>(response: &Response<A>) -> Option<usize> {
+    let message = response
+        .clone()
+        .map(|m| m.into_message())
+        .unwrap_or_else(|m| m.into_message());
+    encoded_message_size(message)
+}
+
+pub(super) fn encoded_message_size<A: Attribute>(message: Message<A>) -> Option<usize> {
+    let mut encoder = MessageEncoder::<A>::default();
+    encoder.start_encoding(message).ok()?;
+    Some(encoder.exact_requiring_bytes() as usize)
+}
+
+#[cfg(test)]
+mod tests {
+    use stun_codec::rfc5389;
+
+    use super::*;
+    use message::{Request, SuccessResponse};
+
+    fn response() -> Response<rfc5389::Attribute> {
+        let request = Request::new(rfc5389::methods::BINDING);
+        Ok(SuccessResponse::new(&request))
+    }
+
+    fn peer(port: u16) -> SocketAddr {
+        SocketAddr::from(([127, 0, 0, 1], port))
+    }
+
+    #[test]
+    fn cached_response_is_keyed_by_peer_and_transaction_id() {
+        let mut cache = ResponseCache::new(10, 1024);
+        let response = response();
+        let transaction_id = transaction_id(&response);
+        assert_eq!(cache.insert(peer(1), &response, true), 0);
+
+        let authenticated = cache.get(peer(1), transaction_id).map(|(_, a)| a);
+        assert_eq!(authenticated, Some(true));
+        assert!(cache.get(peer(2), transaction_id).is_none());
+        assert!(cache.get(peer(1), TransactionId::new([0; 12])).is_none());
+    }
+
+    #[test]
+    fn least_recently_used_entry_is_evicted() {
+        let mut cache = ResponseCache::new(2, 1024);
+        let (a, b, c) = (response(), response(), response());
+        cache.insert(peer(1), &a, false);
+        cache.insert(peer(1), &b, false);
+
+        // `a` becomes the most recently used one
+        assert!(cache.get(peer(1), transaction_id(&a)).is_some());
+        assert_eq!(cache.insert(peer(1), &c, false), 1);
+
+        assert!(cache.get(peer(1), transaction_id(&a)).is_some());
+        assert!(cache.get(peer(1), transaction_id(&b)).is_none());
+        assert!(cache.get(peer(1), transaction_id(&c)).is_some());
+    }
+
+    #[test]
+    fn total_size_of_entries_is_bounded() {
+        // A success response without attributes consists of the 20-byte header only
+        let mut cache = ResponseCache::new(10, 50);
+        let (a, b, c) = (response(), response(), response());
+        assert_eq!(cache.insert(peer(1), &a, false), 0);
+        assert_eq!(cache.insert(peer(1), &b, false), 0);
+        assert_eq!(cache.insert(peer(1), &c, false), 1);
+        assert!(cache.get(peer(1), transaction_id(&a)).is_none());
+
+        // Too large to be cached at all
+        let mut cache = ResponseCache::new(10, 19);
+        assert_eq!(cache.insert(peer(1), &a, false), 0);
+        assert!(cache.get(peer(1), transaction_id(&a)).is_none());
+    }
+
+    #[test]
+    fn shrinking_limits_evicts_entries() {
+        let mut cache = ResponseCache::new(10, 1024);
+        for _ in 0..3 {
+            cache.insert(peer(1), &response(), false);
+        }
+        assert_eq!(cache.set_limits(1, 1024), 2);
+
+        let mut cache = ResponseCache::new(0, 1024);
+        assert!(!cache.is_enabled());
+        let response = response();
+        cache.insert(peer(1), &response, false);
+        assert!(cache.get(peer(1), transaction_id(&response)).is_none());
+    }
+}
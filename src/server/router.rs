@@ -0,0 +1,187 @@
+use bytecodec::marker::Never;
+use std::fmt;
+use std::net::SocketAddr;
+use stun_codec::{Attribute, Message, Method};
+
+use message::{Indication, InvalidMessage, Request, Response};
+use Error;
+
+use super::{Action, HandleMessage, ServerHandle};
+
+type BoxHandler<A> = Box<dyn HandleMessage<Attribute = A> + Send + 'static>;
+type BoxPredicate<A> = Box<dyn Fn(&Message<A>) -> bool + Send + 'static>;
+
+/// A `HandleMessage` implementation that dispatches incoming messages to sub-handlers.
+///
+/// This allows for composing independent STUN services that share an endpoint
+/// (e.g., services distinguished by methods or attributes) instead of writing a monolithic handler.
+///
+/// Each route is either a method (see `route_method`) or a predicate (see `route_if`),
+/// and an incoming message is handed to the handler of the first matching route
+/// in the order of registration.
+/// If no route matches, the message is handed to the fallback handler.
+///
+/// Since the attributes of an invalid message are not available,
+/// an invalid message is dispatched only by the method routes
+/// (i.e., predicate routes are skipped for it).
+///
+/// The other events (e.g., `handle_channel_error` and `set_server_handle`) are
+/// notified to all of the sub-handlers and the fallback handler.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use rustun::server::{BindingHandler, HandleMessage, Router};
+/// use stun_codec::rfc5389;
+///
+/// struct NotFound;
+/// impl HandleMessage for NotFound {
+///     type Attribute = rfc5389::Attribute;
+/// }
+///
+/// # fn main() {
+/// let mut router = Router::new(NotFound);
+/// router.route_method(rfc5389::methods::BINDING, BindingHandler);
+/// # }
+/// ```
+pub struct Router<A: Attribute> {
+    routes: Vec<(Matcher<A>, BoxHandler<A>)>,
+    fallback: BoxHandler<A>,
+}
+impl<A> Router<A>
+where
+    A: Attribute + Send + 'static,
+{
+    /// Makes a new `Router` instance that has no routes.
+    ///
+    /// All messages are handed to `fallback` until routes are registered.
+    pub fn new<H>(fallback: H) -> Self
+    where
+        H: HandleMessage<Attribute = A> + Send + 'static,
+    {
+        Router {
+            routes: Vec::new(),
+            fallback: Box::new(fallback),
+        }
+    }
+
+    /// Registers a route that hands the messages having the given method to `handler`.
+    pub fn route_method<H>(&mut self, method: Method, handler: H) -> &mut Self
+    where
+        H: HandleMessage<Attribute = A> + Send + 'static,
+    {
+        self.routes.push((Matcher::Method(method), Box::new(handler)));
+        self
+    }
+
+    /// Registers a route that hands the messages satisfying `predicate` to `handler`.
+    ///
+    /// `predicate` is applied to requests and indications (not to invalid messages).
+    pub fn route_if<F, H>(&mut self, predicate: F, handler: H) -> &mut Self
+    where
+        F: Fn(&Message<A>) -> bool + Send + 'static,
+        H: HandleMessage<Attribute = A> + Send + 'static,
+    {
+        self.routes
+            .push((Matcher::Predicate(Box::new(predicate)), Box::new(handler)));
+        self
+    }
+
+    fn route(&mut self, message: &Message<A>) -> &mut BoxHandler<A> {
+        let i = self.routes.iter().position(|r| match r.0 {
+            Matcher::Method(method) => method == message.method(),
+            Matcher::Predicate(ref f) => f(message),
+        });
+        self.handler_mut(i)
+    }
+
+    fn route_invalid(&mut self, message: &InvalidMessage) -> &mut BoxHandler<A> {
+        let i = self.routes.iter().position(|r| match r.0 {
+            Matcher::Method(method) => method == message.method(),
+            Matcher::Predicate(_) => false,
+        });
+        self.handler_mut(i)
+    }
+
+    fn handler_mut(&mut self, i: Option<usize>) -> &mut BoxHandler<A> {
+        match i {
+            Some(i) => &mut self.routes[i].1,
+            None => &mut self.fallback,
+        }
+    }
+
+    fn handlers_mut(&mut self) -> impl Iterator<Item = &mut BoxHandler<A>> {
+        self.routes
+            .iter_mut()
+            .map(|r| &mut r.1)
+            .chain(Some(&mut self.fallback))
+    }
+}
+impl<A> HandleMessage for Router<A>
+where
+    A: Attribute + Send + 'static,
+{
+    type Attribute = A;
+
+    fn handle_call(&mut self, peer: SocketAddr, request: Request<A>) -> Action<Response<A>> {
+        self.route(request.as_ref()).handle_call(peer, request)
+    }
+
+    fn handle_cast(&mut self, peer: SocketAddr, indication: Indication<A>) -> Action<Never> {
+        self.route(indication.as_ref()).handle_cast(peer, indication)
+    }
+
+    fn handle_invalid_message(
+        &mut self,
+        peer: SocketAddr,
+        message: InvalidMessage,
+    ) -> Action<Response<A>> {
+        self.route_invalid(&message).handle_invalid_message(peer, message)
+    }
+
+    fn handle_channel_error(&mut self, error: &Error) {
+        for h in self.handlers_mut() {
+            h.handle_channel_error(error);
+        }
+    }
+
+    fn handle_disconnect(&mut self, clean: bool) {
+        for h in self.handlers_mut() {
+            h.handle_disconnect(clean);
+        }
+    }
+
+    fn handle_lifetime_exceeded(&mut self) {
+        for h in self.handlers_mut() {
+            h.handle_lifetime_exceeded();
+        }
+    }
+
+    fn set_server_handle(&mut self, handle: ServerHandle) {
+        for h in self.handlers_mut() {
+            h.set_server_handle(handle.clone());
+        }
+    }
+}
+impl<A: Attribute> fmt::Debug for Router<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "Router {{ routes: [")?;
+        for (i, r) in self.routes.iter().enumerate() {
+            if i != 0 {
+                write!(f, ", ")?;
+            }
+            match r.0 {
+                Matcher::Method(method) => write!(f, "{:?}", method)?,
+                Matcher::Predicate(_) => write!(f, "Predicate(_)")?,
+            }
+        }
+        write!(f, "], .. }}")
+    }
+}
+
+enum Matcher<A> {
+    Method(Method),
+    Predicate(BoxPredicate<A>),
+}
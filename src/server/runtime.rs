@@ -0,0 +1,110 @@
+use bytecodec::marker::Never;
+use fibers::executor::ThreadPoolExecutorHandle;
+use fibers::sync::oneshot::{self, Monitor};
+use fibers::{Executor, Spawn, ThreadPoolExecutor};
+use futures::Future;
+use std::fmt;
+use std::thread::{self, JoinHandle};
+
+use {Error, ErrorKind, Result};
+
+/// Dedicated thread pool for running servers.
+///
+/// This isolates the processing of STUN messages from the other fibers of an application:
+/// the servers (and the futures returned by their handlers) run only on the worker threads
+/// owned by the runtime.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate futures;
+/// # extern crate rustun;
+/// use futures::Future;
+/// use rustun::server::{BindingHandler, ServerRuntime, UdpServer};
+///
+/// # fn main() -> Result<(), rustun::Error> {
+/// let runtime = ServerRuntime::new(2)?;
+/// let addr = "127.0.0.1:0".parse().unwrap();
+/// let server = UdpServer::start(runtime.handle(), addr, BindingHandler);
+/// let _monitor = runtime.spawn(server.and_then(|server| server));
+///
+/// // ...
+///
+/// runtime.shutdown()?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct ServerRuntime {
+    handle: ThreadPoolExecutorHandle,
+    shutdown_tx: oneshot::Sender<()>,
+    thread: JoinHandle<Result<()>>,
+}
+impl ServerRuntime {
+    /// Makes a new `ServerRuntime` instance that has `threads` worker threads.
+    ///
+    /// Note that `ThreadPoolExecutor` of `fibers` runs an I/O poller thread for each worker thread,
+    /// in addition to the thread that dispatches newly spawned fibers to the workers.
+    ///
+    /// # Errors
+    ///
+    /// If `threads` is `0`, this will return an `ErrorKind::InvalidInput` error.
+    pub fn new(threads: usize) -> Result<Self> {
+        track_assert_ne!(threads, 0, ErrorKind::InvalidInput);
+        let executor = track!(ThreadPoolExecutor::with_thread_count(threads).map_err(Error::from))?;
+        let handle = executor.handle();
+        let (shutdown_tx, shutdown_rx) = oneshot::channel();
+        let thread = thread::spawn(move || {
+            let mut executor = executor;
+            // The pool stops when either `shutdown` is called or the runtime is dropped
+            let _ = track!(executor.run_future(shutdown_rx).map_err(Error::from))?;
+            Ok(())
+        });
+        Ok(ServerRuntime {
+            handle,
+            shutdown_tx,
+            thread,
+        })
+    }
+
+    /// Returns the handle of the thread pool.
+    ///
+    /// This is intended to be passed as the spawner of `UdpServer::start` or `TcpServer::start`,
+    /// so that the fibers spawned by the server (e.g., TCP connections and handler futures)
+    /// also run on the pool.
+    pub fn handle(&self) -> ThreadPoolExecutorHandle {
+        self.handle.clone()
+    }
+
+    /// Spawns the given future (typically a server) on the thread pool.
+    ///
+    /// The returned monitor can be used to detect the termination of the future.
+    /// If the monitor is dropped, the future keeps running until the runtime shuts down.
+    pub fn spawn<F>(&self, future: F) -> Monitor<Never, Error>
+    where
+        F: Future<Item = Never, Error = Error> + Send + 'static,
+    {
+        self.handle.spawn_monitor(future)
+    }
+
+    /// Stops the thread pool and waits until the thread driving it exits.
+    ///
+    /// All the fibers running on the pool (including servers) are discarded.
+    /// Note that the worker threads exit asynchronously, shortly after this method returns.
+    ///
+    /// # Errors
+    ///
+    /// If the pool has been aborted (e.g., by a panic of a worker thread),
+    /// this will return an `ErrorKind::Other` error.
+    pub fn shutdown(self) -> Result<()> {
+        let _ = self.shutdown_tx.send(());
+        match self.thread.join() {
+            Err(_) => track_panic!(ErrorKind::Other, "The runtime thread has panicked"),
+            Ok(result) => track!(result),
+        }
+    }
+}
+impl fmt::Debug for ServerRuntime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "ServerRuntime {{ .. }}")
+    }
+}
@@ -0,0 +1,166 @@
+use bytecodec::marker::Never;
+use fibers::sync::{mpsc, oneshot};
+use futures::{Future, Poll, Stream};
+use std::fmt;
+use std::net::SocketAddr;
+use stun_codec::rfc5389;
+use stun_codec::rfc5389::attributes::ErrorCode;
+use stun_codec::{Attribute, TransactionId};
+
+use message::{ErrorResponse, Request, Response};
+use {ErrorKind, Result};
+
+use super::{Action, HandleMessage};
+
+/// An incoming request along with the peer that sent it and the handle for replying to it.
+pub type IncomingRequest<A> = (SocketAddr, Request<A>, Responder<A>);
+
+/// A `HandleMessage` implementation that hands incoming requests over to a `RequestStream`.
+///
+/// This is an alternative front-end of servers for the applications that prefer
+/// pulling requests from a stream to implementing `HandleMessage` by themselves.
+/// Each request is yielded by the stream along with a `Responder`,
+/// which can be used to send the response later from anywhere (e.g., from another task).
+///
+/// If the `Responder` of a request is dropped without replying, or the stream has been dropped
+/// before the request arrives, a `500 Server Error` response is sent to the client.
+///
+/// Indications and invalid messages are handled in the default manner of `HandleMessage`.
+///
+/// Since this is an ordinary handler, it can be combined with the other handlers
+/// (e.g., as a sub-handler of `Router`).
+/// And it can be cloned, so `TcpServer` can make a handler for each connection by
+/// a factory that clones the one made by `StreamHandler::new`.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers_global;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use futures::{Future, Stream};
+/// use rustun::message::SuccessResponse;
+/// use rustun::server::{StreamHandler, UdpServer};
+/// use stun_codec::rfc5389;
+///
+/// # fn main() -> Result<(), rustun::Error> {
+/// let (handler, requests) = StreamHandler::<rfc5389::Attribute>::new();
+/// let addr = "127.0.0.1:0".parse().unwrap();
+/// let server = fibers_global::execute(UdpServer::start(fibers_global::handle(), addr, handler))?;
+/// fibers_global::spawn(server.map(|_| ()).map_err(|e| panic!("{}", e)));
+///
+/// fibers_global::spawn(requests.for_each(|(_peer, request, responder)| {
+///     let _ = responder.reply(Ok(SuccessResponse::new(&request)));
+///     Ok(())
+/// }).map_err(|_| ()));
+/// # Ok(())
+/// # }
+/// ```
+pub struct StreamHandler<A: Attribute> {
+    tx: mpsc::Sender<IncomingRequest<A>>,
+}
+impl<A: Attribute> StreamHandler<A> {
+    /// Makes a new `StreamHandler` instance and the stream of the requests it receives.
+    ///
+    /// The stream terminates when the handler and all of its clones have been dropped
+    /// (e.g., the servers using them have stopped).
+    pub fn new() -> (Self, RequestStream<A>) {
+        let (tx, rx) = mpsc::channel();
+        (StreamHandler { tx }, RequestStream { rx })
+    }
+}
+impl<A: Attribute> Clone for StreamHandler<A> {
+    fn clone(&self) -> Self {
+        StreamHandler {
+            tx: self.tx.clone(),
+        }
+    }
+}
+impl<A> HandleMessage for StreamHandler<A>
+where
+    A: Attribute + From<ErrorCode> + Send + 'static,
+{
+    type Attribute = A;
+
+    fn handle_call(&mut self, peer: SocketAddr, request: Request<A>) -> Action<Response<A>> {
+        let fallback = ErrorResponse::new(&request, rfc5389::errors::ServerError.into());
+        let (tx, rx) = oneshot::channel();
+        let responder = Responder {
+            transaction_id: request.transaction_id(),
+            tx,
+        };
+        if self.tx.send((peer, request, responder)).is_err() {
+            debug!("STUN server: the request stream has been dropped");
+            return Action::Reply(Err(fallback));
+        }
+        let future = rx.or_else(move |_| {
+            debug!("STUN server: a responder has been dropped without replying");
+            Ok(Err(fallback))
+        });
+        Action::FutureReply(Box::new(future))
+    }
+}
+impl<A: Attribute> fmt::Debug for StreamHandler<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "StreamHandler {{ .. }}")
+    }
+}
+
+/// Stream of the requests received by a `StreamHandler`.
+///
+/// This is created by `StreamHandler::new`.
+#[must_use = "streams do nothing unless polled"]
+pub struct RequestStream<A: Attribute> {
+    rx: mpsc::Receiver<IncomingRequest<A>>,
+}
+impl<A: Attribute> Stream for RequestStream<A> {
+    type Item = IncomingRequest<A>;
+    type Error = Never;
+
+    fn poll(&mut self) -> Poll<Option<Self::Item>, Self::Error> {
+        Ok(self.rx.poll().expect("never fails"))
+    }
+}
+impl<A: Attribute> fmt::Debug for RequestStream<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "RequestStream {{ .. }}")
+    }
+}
+
+/// Handle for replying to a request yielded by `RequestStream`.
+pub struct Responder<A: Attribute> {
+    transaction_id: TransactionId,
+    tx: oneshot::Sender<Response<A>>,
+}
+impl<A: Attribute> Responder<A> {
+    /// Returns the transaction ID of the request to be replied.
+    pub fn transaction_id(&self) -> TransactionId {
+        self.transaction_id
+    }
+
+    /// Sends the given response to the client that issued the request.
+    ///
+    /// # Errors
+    ///
+    /// If the server has stopped (or the connection over which the request arrived
+    /// has been closed), this function will return an `ErrorKind::Other` error.
+    pub fn reply(self, response: Response<A>) -> Result<()> {
+        track_assert!(
+            self.tx.send(response).is_ok(),
+            ErrorKind::Other,
+            "The server has stopped: transaction_id={:?}",
+            self.transaction_id
+        );
+        Ok(())
+    }
+}
+impl<A: Attribute> fmt::Debug for Responder<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "Responder {{ transaction_id: {:?}, .. }}",
+            self.transaction_id
+        )
+    }
+}
@@ -0,0 +1,172 @@
+use bytecodec::{self, ByteCount, Decode, DecodeExt, Eos};
+use std::cmp;
+use std::fmt;
+use std::marker::PhantomData;
+use std::mem;
+use stun_codec::{Attribute, DecodedMessage, MessageDecoder};
+
+use message::HEADER_SIZE;
+
+/// The size of the header (i.e., type and length fields) of an attribute.
+const ATTRIBUTE_HEADER_SIZE: usize = 4;
+
+/// Decoder that limits the estimated memory usage of the decoded representation of each message.
+///
+/// Even if a message is small enough to be received, decoding it may allocate much more memory
+/// than its encoded size (e.g., because it contains many tiny attributes).
+/// This decoder buffers the raw bytes of each message and, before the attributes are decoded,
+/// estimates the size of the decoded representation from the attribute headers.
+///
+/// The estimated size of each attribute is `mem::size_of::<A>()` plus the length of its value.
+/// If the sum exceeds `budget`, the attribute that exceeds it and the following ones
+/// are never decoded, and the message is reported as a broken one (i.e., `Channel::poll_recv` returns it as `RecvMessage::Invalid`,
+/// and servers reply to it as they do to other malformed requests).
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers_transport::UdpTransporterBuilder;
+/// use rustun::transport::{BudgetedMessageDecoder, StunUdpTransporter};
+/// use stun_codec::{rfc5389, MessageEncoder};
+///
+/// # fn main() {
+/// let decoder = BudgetedMessageDecoder::<rfc5389::Attribute>::new(16 * 1024);
+/// let encoder = MessageEncoder::<rfc5389::Attribute>::new();
+/// let future = UdpTransporterBuilder::with_codec(encoder, decoder).bind("127.0.0.1:0".parse().unwrap());
+/// let transporter = StunUdpTransporter::new(fibers_global::execute(future).unwrap());
+/// # let _ = transporter;
+/// # }
+/// ```
+pub struct BudgetedMessageDecoder<A: Attribute> {
+    budget: usize,
+    buf: Vec<u8>,
+    _phantom: PhantomData<A>,
+}
+impl<A: Attribute> BudgetedMessageDecoder<A> {
+    /// Makes a new `BudgetedMessageDecoder` instance.
+    pub fn new(budget: usize) -> Self {
+        BudgetedMessageDecoder {
+            budget,
+            buf: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Returns the budget of the decoder in bytes.
+    pub fn budget(&self) -> usize {
+        self.budget
+    }
+
+    /// Sets the budget of the decoder in bytes.
+    pub fn set_budget(&mut self, budget: usize) -> &mut Self {
+        self.budget = budget;
+        self
+    }
+
+    fn message_size(&self) -> Option<usize> {
+        if self.buf.len() < HEADER_SIZE {
+            None
+        } else {
+            let length = (usize::from(self.buf[2]) << 8) | usize::from(self.buf[3]);
+            Some(HEADER_SIZE + length)
+        }
+    }
+
+    /// Estimates the size of the decoded attributes from the buffered message, and returns
+    /// the offset of the first attribute with which the estimation exceeds the budget.
+    fn find_over_budget_attribute(&self) -> Option<usize> {
+        let mut estimation = 0;
+        let mut offset = HEADER_SIZE;
+        while offset + ATTRIBUTE_HEADER_SIZE <= self.buf.len() {
+            let value_len =
+                (usize::from(self.buf[offset + 2]) << 8) | usize::from(self.buf[offset + 3]);
+            estimation += mem::size_of::<A>() + value_len;
+            if estimation > self.budget {
+                return Some(offset);
+            }
+            offset += ATTRIBUTE_HEADER_SIZE + (value_len + 3) / 4 * 4;
+        }
+        None
+    }
+
+    /// Decodes the buffered message up to the attribute at `offset`.
+    ///
+    /// The length in the header claims the attributes that are not fed to `MessageDecoder`,
+    /// so it reports the message as a broken one.
+    fn decode_within_budget(&self, offset: usize) -> bytecodec::Result<DecodedMessage<A>> {
+        let mut decoder = MessageDecoder::<A>::new();
+        track!(decoder.decode(&self.buf[..offset], Eos::new(false)))?;
+        let message = track!(decoder.finish_decoding())?;
+        track_assert!(
+            message.is_err(),
+            bytecodec::ErrorKind::Other,
+            "Cannot make a broken message"
+        );
+        Ok(message)
+    }
+}
+impl<A: Attribute> Decode for BudgetedMessageDecoder<A> {
+    type Item = DecodedMessage<A>;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        let mut offset = 0;
+        if self.buf.len() < HEADER_SIZE {
+            let n = cmp::min(buf.len(), HEADER_SIZE - self.buf.len());
+            self.buf.extend_from_slice(&buf[..n]);
+            offset += n;
+        }
+        if let Some(size) = self.message_size() {
+            let n = cmp::min(buf.len() - offset, size - self.buf.len());
+            self.buf.extend_from_slice(&buf[offset..][..n]);
+            offset += n;
+        }
+        if offset == buf.len() && eos.is_reached() && !self.buf.is_empty() && !self.is_idle() {
+            track_panic!(
+                bytecodec::ErrorKind::UnexpectedEos,
+                "Incomplete STUN message: {} bytes",
+                self.buf.len()
+            );
+        }
+        Ok(offset)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        track_assert!(self.is_idle(), bytecodec::ErrorKind::IncompleteDecoding);
+        let result = if let Some(offset) = self.find_over_budget_attribute() {
+            warn!(
+                "Decoding the attribute of a STUN message at offset {} exceeds the budget ({} bytes)",
+                offset, self.budget
+            );
+            track!(self.decode_within_budget(offset))
+        } else {
+            track!(MessageDecoder::<A>::new().decode_from_bytes(&self.buf))
+        };
+        self.buf.clear();
+        result
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        match self.message_size() {
+            None => ByteCount::Finite((HEADER_SIZE - self.buf.len()) as u64),
+            Some(size) => ByteCount::Finite((size - self.buf.len()) as u64),
+        }
+    }
+
+    fn is_idle(&self) -> bool {
+        self.message_size() == Some(self.buf.len())
+    }
+}
+impl<A: Attribute> fmt::Debug for BudgetedMessageDecoder<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "BudgetedMessageDecoder {{ budget: {}, buffered_bytes: {} }}",
+            self.budget,
+            self.buf.len()
+        )
+    }
+}
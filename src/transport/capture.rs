@@ -0,0 +1,208 @@
+use bytecodec::{ByteCount, Decode, Eos, Result};
+use std::cmp;
+use std::mem;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard};
+
+/// Handle for retrieving the raw bytes of the messages captured by `CaptureDecoder`.
+///
+/// The clones of an instance share the same captured bytes and limit.
+///
+/// A channel that has been given the handle (see `ChannelBuilder::raw_message_capture`)
+/// attaches the captured bytes to every `InvalidMessage` it reports,
+/// so the bytes of a message that failed to decode can be inspected or replayed in a unit test.
+#[derive(Debug, Clone, Default)]
+pub struct RawMessageCapture {
+    inner: Arc<Inner>,
+}
+impl RawMessageCapture {
+    /// The default value of `max_bytes`.
+    ///
+    /// This is large enough to hold any message received via UDP without fragmentation.
+    pub const DEFAULT_MAX_BYTES: usize = 1500;
+
+    /// Makes a new `RawMessageCapture` instance that captures at most `max_bytes` bytes per message.
+    ///
+    /// If `max_bytes` is `0`, the capture is disabled.
+    pub fn new(max_bytes: usize) -> Self {
+        let capture = Self::default();
+        capture.set_max_bytes(max_bytes);
+        capture
+    }
+
+    /// Returns the maximum number of the bytes captured per message.
+    ///
+    /// The bytes of a longer message are truncated to this length.
+    pub fn max_bytes(&self) -> usize {
+        self.inner.max_bytes.load(Ordering::Relaxed)
+    }
+
+    /// Sets the maximum number of the bytes captured per message.
+    pub fn set_max_bytes(&self, max_bytes: usize) {
+        self.inner.max_bytes.store(max_bytes, Ordering::Relaxed);
+    }
+
+    /// Takes the (possibly truncated) bytes of the most recently decoded message.
+    ///
+    /// Note that the decoder is expected to be driven by the same task as the consumer of
+    /// the decoded messages, so that the captured bytes correspond to the last message
+    /// yielded by the transporter using the decoder.
+    pub fn take(&self) -> Option<Vec<u8>> {
+        self.lock().take()
+    }
+
+    fn store(&self, bytes: Vec<u8>) {
+        *self.lock() = Some(bytes);
+    }
+
+    fn lock(&self) -> MutexGuard<'_, Option<Vec<u8>>> {
+        self.inner.last.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[derive(Debug, Default)]
+struct Inner {
+    max_bytes: AtomicUsize,
+    last: Mutex<Option<Vec<u8>>>,
+}
+
+/// Decoder that captures the raw bytes of the messages decoded by the inner decoder.
+///
+/// The captured bytes can be retrieved via the associated `RawMessageCapture` handle.
+///
+/// # Examples
+///
+/// ```
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers_transport::UdpTransporterBuilder;
+/// use rustun::channel::ChannelBuilder;
+/// use rustun::transport::{CaptureDecoder, RawMessageCapture, StunUdpTransporter};
+/// use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+///
+/// # fn main() {
+/// let capture = RawMessageCapture::new(RawMessageCapture::DEFAULT_MAX_BYTES);
+/// let decoder = CaptureDecoder::new(MessageDecoder::<rfc5389::Attribute>::new(), capture.clone());
+/// let encoder = MessageEncoder::<rfc5389::Attribute>::new();
+/// let future = UdpTransporterBuilder::with_codec(encoder, decoder).bind("127.0.0.1:0".parse().unwrap());
+/// let transporter = StunUdpTransporter::new(fibers_global::execute(future).unwrap());
+/// let channel = ChannelBuilder::new()
+///     .raw_message_capture(capture)
+///     .finish::<rfc5389::Attribute, _>(transporter);
+/// # let _ = channel;
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct CaptureDecoder<D> {
+    inner: D,
+    capture: RawMessageCapture,
+    buf: Vec<u8>,
+}
+impl<D: Decode> CaptureDecoder<D> {
+    /// Makes a new `CaptureDecoder` instance.
+    pub fn new(inner: D, capture: RawMessageCapture) -> Self {
+        CaptureDecoder {
+            inner,
+            capture,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the capture handle of the decoder.
+    pub fn capture(&self) -> &RawMessageCapture {
+        &self.capture
+    }
+
+    /// Returns a reference to the inner decoder.
+    pub fn inner_ref(&self) -> &D {
+        &self.inner
+    }
+}
+impl<D: Decode> Decode for CaptureDecoder<D> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> Result<usize> {
+        let size = match self.inner.decode(buf, eos) {
+            Err(e) => {
+                // Discards the bytes of the broken message, so they don't leak into the next one
+                self.buf.clear();
+                return Err(track!(e));
+            }
+            Ok(size) => size,
+        };
+        let max_bytes = self.capture.max_bytes();
+        if max_bytes > self.buf.len() {
+            let n = cmp::min(size, max_bytes - self.buf.len());
+            self.buf.extend_from_slice(&buf[..n]);
+        }
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> Result<Self::Item> {
+        let bytes = mem::take(&mut self.buf);
+        if self.capture.max_bytes() > 0 {
+            self.capture.store(bytes);
+        }
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::{ErrorKind, Result};
+
+    use super::*;
+
+    /// A decoder that takes all the given bytes, and refuses `0xFF`.
+    #[derive(Debug, Default)]
+    struct BytesDecoder {
+        bytes: Vec<u8>,
+    }
+    impl Decode for BytesDecoder {
+        type Item = Vec<u8>;
+
+        fn decode(&mut self, buf: &[u8], _eos: Eos) -> Result<usize> {
+            if buf.contains(&0xFF) {
+                self.bytes.clear();
+                track_panic!(ErrorKind::InvalidInput, "broken message");
+            }
+            self.bytes.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn finish_decoding(&mut self) -> Result<Self::Item> {
+            Ok(mem::take(&mut self.bytes))
+        }
+
+        fn requiring_bytes(&self) -> ByteCount {
+            ByteCount::Unknown
+        }
+
+        fn is_idle(&self) -> bool {
+            self.bytes.is_empty()
+        }
+    }
+
+    #[test]
+    fn broken_message_is_not_captured_with_the_next_one() {
+        let capture = RawMessageCapture::new(RawMessageCapture::DEFAULT_MAX_BYTES);
+        let mut decoder = CaptureDecoder::new(BytesDecoder::default(), capture.clone());
+        assert_eq!(decoder.decode(&[1, 2], Eos::new(false)).ok(), Some(2));
+        assert!(decoder.decode(&[0xFF, 3], Eos::new(true)).is_err());
+
+        assert_eq!(decoder.decode(&[4, 5, 6], Eos::new(true)).ok(), Some(3));
+        assert_eq!(decoder.finish_decoding().ok(), Some(vec![4, 5, 6]));
+        assert_eq!(capture.take(), Some(vec![4, 5, 6]));
+    }
+}
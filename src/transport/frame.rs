@@ -0,0 +1,174 @@
+use bytecodec::DecodeExt;
+use std::fmt;
+use std::marker::PhantomData;
+use stun_codec::{Attribute, DecodedMessage, MessageDecoder};
+use trackable::error::ErrorKindExt;
+
+use {ErrorKind, Result};
+
+/// The size of the fixed header of a STUN message.
+const HEADER_SIZE: usize = 20;
+
+/// Decoder that splits a byte stream into STUN messages.
+///
+/// The boundaries of the messages are determined by the length field of the STUN header
+/// in the same manner as the TCP transporter does:
+///
+/// > The message length MUST contain the size, in bytes, of the message
+/// > not including the 20-byte STUN header.
+/// >
+/// > [RFC 5389 -- 6. STUN Message Structure]
+///
+/// This is useful for decoding captured traffic or messages received via a custom transport.
+///
+/// [RFC 5389 -- 6. STUN Message Structure]: https://tools.ietf.org/html/rfc5389#section-6
+pub struct StunFrameDecoder<A: Attribute> {
+    buf: Vec<u8>,
+    _phantom: PhantomData<A>,
+}
+impl<A: Attribute> StunFrameDecoder<A> {
+    /// Makes a new `StunFrameDecoder` instance.
+    pub fn new() -> Self {
+        StunFrameDecoder {
+            buf: Vec::new(),
+            _phantom: PhantomData,
+        }
+    }
+
+    /// Appends the given bytes to the internal buffer of the decoder.
+    pub fn feed(&mut self, bytes: &[u8]) {
+        self.buf.extend_from_slice(bytes);
+    }
+
+    /// Returns the number of the buffered bytes that have not been decoded yet.
+    pub fn buffered_bytes(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// Decodes the next message from the buffered bytes.
+    ///
+    /// If the buffer does not contain a complete message yet, this will return `Ok(None)`.
+    ///
+    /// Note that a message whose attributes are malformed is returned as `Ok(Some(Err(..)))`,
+    /// and the decoder can continue decoding the subsequent messages.
+    ///
+    /// # Errors
+    ///
+    /// If the buffered bytes do not start with a valid STUN header,
+    /// this will return an `ErrorKind::InvalidInput` error.
+    /// In that case, the framing of the stream has been lost and the decoder should be discarded.
+    pub fn decode_next(&mut self) -> Result<Option<DecodedMessage<A>>> {
+        if self.buf.len() < HEADER_SIZE {
+            return Ok(None);
+        }
+        track_assert_eq!(
+            self.buf[0] >> 6,
+            0,
+            ErrorKind::InvalidInput,
+            "The most significant 2 bits of a STUN message must be zeroes"
+        );
+        let length = (usize::from(self.buf[2]) << 8) | usize::from(self.buf[3]);
+        track_assert_eq!(
+            length % 4,
+            0,
+            ErrorKind::InvalidInput,
+            "The length of a STUN message must be a multiple of 4"
+        );
+
+        let frame_size = HEADER_SIZE + length;
+        if self.buf.len() < frame_size {
+            return Ok(None);
+        }
+        let message = MessageDecoder::<A>::default()
+            .decode_from_bytes(&self.buf[..frame_size])
+            .map_err(|e| ErrorKind::InvalidInput.takes_over(e));
+        self.buf.drain(..frame_size);
+        Ok(Some(track!(message)?))
+    }
+}
+impl<A: Attribute> Default for StunFrameDecoder<A> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+impl<A: Attribute> fmt::Debug for StunFrameDecoder<A> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "StunFrameDecoder {{ buffered_bytes: {} }}",
+            self.buf.len()
+        )
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytecodec::EncodeExt;
+    use stun_codec::rfc5389::attributes::Software;
+    use stun_codec::{rfc5389, Message, MessageClass, MessageEncoder, TransactionId};
+
+    use super::*;
+
+    fn encode(transaction_id: TransactionId) -> Vec<u8> {
+        let mut message = Message::new(
+            MessageClass::Request,
+            rfc5389::methods::BINDING,
+            transaction_id,
+        );
+        message.add_attribute(Software::new("foo".to_owned()).unwrap().into());
+        MessageEncoder::<rfc5389::Attribute>::default()
+            .encode_into_bytes(message)
+            .unwrap()
+    }
+
+    #[test]
+    fn messages_fed_in_pieces_are_decoded() {
+        let mut bytes = encode(TransactionId::new([1; 12]));
+        bytes.extend(encode(TransactionId::new([2; 12])));
+
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        let mut messages = Vec::new();
+        for b in &bytes {
+            decoder.feed(&[*b]);
+            while let Some(message) = decoder.decode_next().unwrap() {
+                messages.push(message.unwrap());
+            }
+        }
+        assert_eq!(messages.len(), 2);
+        assert_eq!(messages[0].transaction_id(), TransactionId::new([1; 12]));
+        assert_eq!(messages[1].transaction_id(), TransactionId::new([2; 12]));
+        assert_eq!(decoder.buffered_bytes(), 0);
+    }
+
+    #[test]
+    fn broken_message_does_not_lose_framing() {
+        // A `SOFTWARE` attribute of which the value is not a valid UTF-8 string
+        let mut bytes = encode(TransactionId::new([1; 12]));
+        let value_offset = HEADER_SIZE + 4;
+        bytes[value_offset] = 0xFF;
+        bytes.extend(encode(TransactionId::new([2; 12])));
+
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        decoder.feed(&bytes);
+        assert!(decoder.decode_next().unwrap().unwrap().is_err());
+        let message = decoder.decode_next().unwrap().unwrap().unwrap();
+        assert_eq!(message.transaction_id(), TransactionId::new([2; 12]));
+        assert!(decoder.decode_next().unwrap().is_none());
+    }
+
+    #[test]
+    fn invalid_header_is_rejected() {
+        let mut bytes = encode(TransactionId::new([1; 12]));
+        bytes[0] |= 0x80;
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        decoder.feed(&bytes);
+        let e = decoder.decode_next().unwrap_err();
+        assert!(matches!(*e.kind(), ErrorKind::InvalidInput));
+
+        let mut bytes = encode(TransactionId::new([1; 12]));
+        bytes[3] += 1;
+        let mut decoder = StunFrameDecoder::<rfc5389::Attribute>::new();
+        decoder.feed(&bytes);
+        assert!(decoder.decode_next().is_err());
+    }
+}
@@ -1,11 +1,45 @@
 //! Transport layer abstractions and its built-in implementations.
 use fibers_transport::{FixedPeerTransporter, PeerAddr, Result, Transport};
+use std::io;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+use std::net::SocketAddr;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::net::{Ipv4Addr, Ipv6Addr, SocketAddrV4, SocketAddrV6};
+#[cfg(unix)]
+use std::os::unix::io::AsRawFd;
 use stun_codec::{Attribute, DecodedMessage, Message, TransactionId};
 
-pub use self::tcp::StunTcpTransporter;
-pub use self::udp::{StunUdpTransporter, StunUdpTransporterBuilder};
+use channel::SharedObserver;
+#[cfg(unix)]
+use {Error, ErrorKind};
 
+pub use self::budget::BudgetedMessageDecoder;
+pub use self::capture::{CaptureDecoder, RawMessageCapture};
+pub use self::frame::StunFrameDecoder;
+#[cfg(feature = "pcap")]
+pub use self::pcap::{PcapTransporter, PcapWriter};
+pub use self::rto::{AdaptiveRto, FixedRto, RtoStrategy};
+pub use self::split::SplitTransporter;
+pub use self::tcp::{
+    CoalescingTcpTransporter, StunTcpTransporter, TcpKeepalive, DEFAULT_TCP_BUFFER_SIZE,
+};
+pub use self::transform::{
+    ByteTransform, TransformDecoder, TransformEncoder, TransformTransport, XorTransform,
+};
+pub use self::udp::{StunUdpTransporter, StunUdpTransporterBuilder, UdpBindPort};
+
+pub(crate) use self::tcp::set_tcp_keepalive;
+
+mod budget;
+mod capture;
+mod frame;
+#[cfg(feature = "pcap")]
+mod pcap;
+mod rto;
+mod split;
 mod tcp;
+mod transform;
 mod udp;
 
 /// This trait allows the implementation to be used as the transport layer for STUN.
@@ -19,6 +53,34 @@ where
         peer: &Self::PeerAddr,
         transaction_id: TransactionId,
     ) -> Result<()>;
+
+    /// Sets the observer that will be notified of the transaction events issued by the transporter.
+    ///
+    /// The default implementation simply discards the observer.
+    #[allow(unused_variables)]
+    fn set_observer(&mut self, observer: SharedObserver<Self::PeerAddr>) {}
+
+    /// Returns `true` if the transporter notifies the observer of
+    /// `TransactionObserver::on_send` events by itself.
+    ///
+    /// If `false`, `Channel` issues the events when `poll_send` of the transporter completes.
+    ///
+    /// The default implementation always returns `false`.
+    fn notifies_sent_requests(&self) -> bool {
+        false
+    }
+
+    /// Takes one of the peers that have been reported as unreachable
+    /// (e.g., by ICMP port-unreachable messages on UDP) since the last call.
+    ///
+    /// `Channel` calls this method repeatedly after polling `poll_recv`,
+    /// and fails the outstanding transactions with the returned peers
+    /// with `ErrorKind::ConnectionRefused`.
+    ///
+    /// The default implementation always returns `None`.
+    fn take_unreachable_peer(&mut self) -> Option<Self::PeerAddr> {
+        None
+    }
 }
 impl<A, T, P> StunTransport<A> for FixedPeerTransporter<T, P>
 where
@@ -31,3 +93,229 @@ where
         track!(self.inner_mut().finish_transaction(&peer, transaction_id))
     }
 }
+
+/// Puts the given socket into nonblocking mode (if it is not yet) and verifies the mode.
+///
+/// A socket in blocking mode would stall the whole event loop,
+/// so it is better to reject such a socket as early as possible.
+#[cfg(unix)]
+pub(crate) fn ensure_nonblocking<S: AsRawFd>(socket: &S) -> ::Result<()> {
+    let fd = socket.as_raw_fd();
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    if flags == -1 {
+        return Err(track!(Error::from(io::Error::last_os_error())));
+    }
+    if flags & libc::O_NONBLOCK != 0 {
+        return Ok(());
+    }
+
+    if unsafe { libc::fcntl(fd, libc::F_SETFL, flags | libc::O_NONBLOCK) } == -1 {
+        return Err(track!(Error::from(io::Error::last_os_error())));
+    }
+    let flags = unsafe { libc::fcntl(fd, libc::F_GETFL) };
+    track_assert!(
+        flags != -1 && flags & libc::O_NONBLOCK != 0,
+        ErrorKind::Other,
+        "Cannot put the socket into nonblocking mode: fd={}",
+        fd
+    );
+    Ok(())
+}
+
+/// Puts the given socket into nonblocking mode (if it is not yet) and verifies the mode.
+///
+/// On non-Unix platforms, this does nothing because the sockets created by `fibers` are
+/// always in nonblocking mode.
+#[cfg(not(unix))]
+pub(crate) fn ensure_nonblocking<S>(_socket: &S) -> ::Result<()> {
+    Ok(())
+}
+
+/// Usage of a socket buffer maintained by the operating system.
+///
+/// See `StunUdpTransporter::recv_buffer_usage` and `StunUdpTransporter::send_buffer_usage`.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct SocketBufferUsage {
+    /// The number of the bytes allocated for the data queued in the buffer.
+    ///
+    /// Note that this includes the bookkeeping overhead of the kernel,
+    /// so it is larger than the total size of the queued datagrams.
+    pub used: usize,
+
+    /// The capacity of the buffer in bytes
+    /// (i.e., the effective value of `SO_RCVBUF` or `SO_SNDBUF`).
+    pub capacity: usize,
+}
+
+/// Returns the usage of the receive and send buffers of the given socket, in that order.
+///
+/// This uses `SO_MEMINFO` socket option (available since Linux 4.6),
+/// and returns `None` if the option is not available.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn socket_buffer_usage<S: AsRawFd>(
+    socket: &S,
+) -> Option<(SocketBufferUsage, SocketBufferUsage)> {
+    #[cfg(not(any(target_arch = "sparc", target_arch = "sparc64")))]
+    const SO_MEMINFO: libc::c_int = 55;
+    #[cfg(any(target_arch = "sparc", target_arch = "sparc64"))]
+    const SO_MEMINFO: libc::c_int = 0x39;
+    const SK_MEMINFO_RMEM_ALLOC: usize = 0;
+    const SK_MEMINFO_RCVBUF: usize = 1;
+    const SK_MEMINFO_WMEM_ALLOC: usize = 2;
+    const SK_MEMINFO_SNDBUF: usize = 3;
+    const SK_MEMINFO_VARS: usize = 9;
+
+    let mut info = [0u32; SK_MEMINFO_VARS];
+    let mut len = mem::size_of_val(&info) as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            SO_MEMINFO,
+            info.as_mut_ptr() as *mut libc::c_void,
+            &mut len,
+        )
+    };
+    if result == -1 || (len as usize) < (SK_MEMINFO_SNDBUF + 1) * mem::size_of::<u32>() {
+        return None;
+    }
+    let recv = SocketBufferUsage {
+        used: info[SK_MEMINFO_RMEM_ALLOC] as usize,
+        capacity: info[SK_MEMINFO_RCVBUF] as usize,
+    };
+    let send = SocketBufferUsage {
+        used: info[SK_MEMINFO_WMEM_ALLOC] as usize,
+        capacity: info[SK_MEMINFO_SNDBUF] as usize,
+    };
+    Some((recv, send))
+}
+
+/// Returns the usage of the receive and send buffers of the given socket, in that order.
+///
+/// The usage is not available on this platform, so this always returns `None`.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn socket_buffer_usage<S>(
+    _socket: &S,
+) -> Option<(SocketBufferUsage, SocketBufferUsage)> {
+    None
+}
+
+/// Makes the kernel queue the ICMP errors caused by the datagrams sent from the given UDP socket,
+/// along with the destination addresses of the datagrams (i.e., `IP_RECVERR` or `IPV6_RECVERR`).
+///
+/// The queued errors can be read by `take_unreachable_peers`.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn enable_icmp_error_queue<S: AsRawFd>(
+    socket: &S,
+    local_addr: SocketAddr,
+) -> io::Result<()> {
+    let (level, name) = match local_addr {
+        SocketAddr::V4(_) => (libc::SOL_IP, libc::IP_RECVERR),
+        SocketAddr::V6(_) => (libc::SOL_IPV6, libc::IPV6_RECVERR),
+    };
+    let enabled: libc::c_int = 1;
+    let result = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            level,
+            name,
+            &enabled as *const libc::c_int as *const libc::c_void,
+            mem::size_of_val(&enabled) as libc::socklen_t,
+        )
+    };
+    if result == -1 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+/// Makes the kernel queue the ICMP errors caused by the datagrams sent from the given UDP socket.
+///
+/// The errors are not queued on this platform, so this does nothing.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn enable_icmp_error_queue<S>(_socket: &S, _local_addr: SocketAddr) -> io::Result<()> {
+    Ok(())
+}
+
+/// Reads all the errors queued by `enable_icmp_error_queue`, and returns the destination addresses
+/// of the datagrams that caused ICMP port-unreachable errors.
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn take_unreachable_peers<S: AsRawFd>(socket: &S) -> io::Result<Vec<SocketAddr>> {
+    let mut peers = Vec::new();
+    loop {
+        let mut name: libc::sockaddr_storage = unsafe { mem::zeroed() };
+        let mut control = [0u64; 64];
+        let mut msg: libc::msghdr = unsafe { mem::zeroed() };
+        msg.msg_name = &mut name as *mut libc::sockaddr_storage as *mut libc::c_void;
+        msg.msg_namelen = mem::size_of_val(&name) as libc::socklen_t;
+        msg.msg_control = control.as_mut_ptr() as *mut libc::c_void;
+        msg.msg_controllen = mem::size_of_val(&control) as _;
+
+        let result = unsafe {
+            libc::recvmsg(
+                socket.as_raw_fd(),
+                &mut msg,
+                libc::MSG_ERRQUEUE | libc::MSG_DONTWAIT,
+            )
+        };
+        if result == -1 {
+            let e = io::Error::last_os_error();
+            if e.kind() == io::ErrorKind::WouldBlock {
+                return Ok(peers);
+            }
+            return Err(e);
+        }
+
+        let mut cmsg = unsafe { libc::CMSG_FIRSTHDR(&msg) };
+        while !cmsg.is_null() {
+            let header = unsafe { &*cmsg };
+            let is_recverr = (header.cmsg_level == libc::SOL_IP
+                && header.cmsg_type == libc::IP_RECVERR)
+                || (header.cmsg_level == libc::SOL_IPV6 && header.cmsg_type == libc::IPV6_RECVERR);
+            if is_recverr {
+                let error = unsafe {
+                    (libc::CMSG_DATA(cmsg) as *const libc::sock_extended_err).read_unaligned()
+                };
+                let is_icmp = error.ee_origin == libc::SO_EE_ORIGIN_ICMP
+                    || error.ee_origin == libc::SO_EE_ORIGIN_ICMP6;
+                if is_icmp && error.ee_errno == libc::ECONNREFUSED as u32 {
+                    if let Some(peer) = sockaddr_to_socket_addr(&name) {
+                        peers.push(peer);
+                    }
+                }
+            }
+            cmsg = unsafe { libc::CMSG_NXTHDR(&msg, cmsg) };
+        }
+    }
+}
+
+/// Reads all the errors queued by `enable_icmp_error_queue`, and returns the destination addresses
+/// of the datagrams that caused ICMP port-unreachable errors.
+///
+/// The errors are not queued on this platform, so this always returns an empty vector.
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+pub(crate) fn take_unreachable_peers<S>(_socket: &S) -> io::Result<Vec<SocketAddr>> {
+    Ok(Vec::new())
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn sockaddr_to_socket_addr(addr: &libc::sockaddr_storage) -> Option<SocketAddr> {
+    match libc::c_int::from(addr.ss_family) {
+        libc::AF_INET => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in) };
+            let ip = Ipv4Addr::from(u32::from_be(addr.sin_addr.s_addr));
+            Some(SocketAddr::V4(SocketAddrV4::new(ip, u16::from_be(addr.sin_port))))
+        }
+        libc::AF_INET6 => {
+            let addr = unsafe { &*(addr as *const _ as *const libc::sockaddr_in6) };
+            let ip = Ipv6Addr::from(addr.sin6_addr.s6_addr);
+            Some(SocketAddr::V6(SocketAddrV6::new(
+                ip,
+                u16::from_be(addr.sin6_port),
+                u32::from_be(addr.sin6_flowinfo),
+                addr.sin6_scope_id,
+            )))
+        }
+        _ => None,
+    }
+}
@@ -0,0 +1,334 @@
+use bytecodec::EncodeExt;
+use fibers_transport::{PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::Async;
+use std::fmt;
+use std::io::Write;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+use stun_codec::{Attribute, DecodedMessage, Message, MessageEncoder};
+
+use {Error, ErrorKind};
+
+const LINKTYPE_ETHERNET: u32 = 1;
+const SNAPLEN: u32 = 65_535;
+const ETHERNET_HEADER_SIZE: usize = 14;
+const IPV4_HEADER_SIZE: usize = 20;
+const IPV6_HEADER_SIZE: usize = 40;
+const UDP_HEADER_SIZE: usize = 8;
+const IPPROTO_UDP: u8 = 17;
+const TTL: u8 = 64;
+
+/// Writer of [pcap] files that contain STUN messages.
+///
+/// Each message is written as a packet wrapped in synthetic Ethernet, IP and UDP headers,
+/// so the resulting file can be analyzed by standard tools such as Wireshark
+/// (which has a STUN dissector).
+/// The MAC addresses of the Ethernet headers are all zeros.
+///
+/// The clones of an instance share the same underlying writer,
+/// so, for example, the traffic of multiple transporters can be written to a single file.
+///
+/// Note that Wireshark only recognizes the packets to or from the STUN port (`3478`)
+/// as STUN by default; for the other ports, use its "Decode As..." feature.
+///
+/// This is only available if the `pcap` feature is enabled.
+///
+/// [pcap]: https://wiki.wireshark.org/Development/LibpcapFileFormat
+#[derive(Clone)]
+pub struct PcapWriter {
+    inner: Arc<Mutex<Box<dyn Write + Send>>>,
+}
+impl PcapWriter {
+    /// Makes a new `PcapWriter` instance.
+    ///
+    /// The pcap global header is written to `writer` immediately.
+    pub fn new<W>(mut writer: W) -> ::Result<Self>
+    where
+        W: Write + Send + 'static,
+    {
+        let mut header = Vec::with_capacity(24);
+        put_u32(&mut header, 0xa1b2_c3d4);
+        put_u16(&mut header, 2); // version_major
+        put_u16(&mut header, 4); // version_minor
+        put_u32(&mut header, 0); // thiszone
+        put_u32(&mut header, 0); // sigfigs
+        put_u32(&mut header, SNAPLEN);
+        put_u32(&mut header, LINKTYPE_ETHERNET);
+        track!(writer.write_all(&header).map_err(Error::from))?;
+        Ok(PcapWriter {
+            inner: Arc::new(Mutex::new(Box::new(writer))),
+        })
+    }
+
+    /// Writes a UDP packet that carries `payload` from `src` to `dst`.
+    ///
+    /// The timestamp of the packet is the current time.
+    ///
+    /// # Errors
+    ///
+    /// If the address families of `src` and `dst` differ, or `payload` is too large to fit
+    /// in a UDP datagram, this will return an `ErrorKind::InvalidInput` error.
+    pub fn write_udp_packet(
+        &self,
+        src: SocketAddr,
+        dst: SocketAddr,
+        payload: &[u8],
+    ) -> ::Result<()> {
+        let packet = track!(udp_packet(src, dst, payload))?;
+        let elapsed = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+
+        let mut record = Vec::with_capacity(16 + packet.len());
+        put_u32(&mut record, elapsed.as_secs() as u32);
+        put_u32(&mut record, elapsed.subsec_micros());
+        put_u32(&mut record, packet.len() as u32);
+        put_u32(&mut record, packet.len() as u32);
+        record.extend_from_slice(&packet);
+
+        let mut writer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        track!(writer.write_all(&record).map_err(Error::from))?;
+        Ok(())
+    }
+
+    /// Flushes the underlying writer.
+    pub fn flush(&self) -> ::Result<()> {
+        let mut writer = self.inner.lock().unwrap_or_else(|e| e.into_inner());
+        track!(writer.flush().map_err(Error::from))
+    }
+}
+impl fmt::Debug for PcapWriter {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "PcapWriter {{ .. }}")
+    }
+}
+
+/// UDP transporter that writes the messages sent and received by the inner transporter
+/// to a pcap file.
+///
+/// This is intended to be placed between a UDP transporter and `StunUdpTransporter`:
+///
+/// ```no_run
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers_transport::UdpTransporter;
+/// use futures::Future;
+/// use rustun::channel::Channel;
+/// use rustun::transport::{PcapTransporter, PcapWriter, StunUdpTransporter};
+/// use rustun::Error;
+/// use std::fs::File;
+/// use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+///
+/// # fn main() -> Result<(), Error> {
+/// let pcap = PcapWriter::new(File::create("stun.pcap").map_err(Error::from)?)?;
+/// let addr = "127.0.0.1:0".parse().unwrap();
+/// let transporter = fibers_global::execute(
+///     UdpTransporter::<MessageEncoder<rfc5389::Attribute>, MessageDecoder<_>>::bind(addr)
+///         .map_err(Error::from),
+/// )?;
+/// let channel = Channel::new(StunUdpTransporter::new(PcapTransporter::new(transporter, pcap)));
+/// # let _ = channel;
+/// # Ok(())
+/// # }
+/// ```
+///
+/// Because the inner transporter exchanges decoded messages, the recorded bytes are
+/// the re-encoded messages rather than the bytes on the wire.
+/// Received messages that failed to decode are not recorded.
+/// Failures to write the pcap file are logged, and do not affect the transporter.
+///
+/// This is only available if the `pcap` feature is enabled.
+#[derive(Debug)]
+pub struct PcapTransporter<T> {
+    inner: T,
+    writer: PcapWriter,
+}
+impl<A, T> PcapTransporter<T>
+where
+    A: Attribute,
+    T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+{
+    /// Makes a new `PcapTransporter` instance.
+    pub fn new(inner: T, writer: PcapWriter) -> Self {
+        PcapTransporter { inner, writer }
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+
+    /// Returns a reference to the pcap writer.
+    pub fn writer(&self) -> &PcapWriter {
+        &self.writer
+    }
+
+    fn record(&self, src: SocketAddr, dst: SocketAddr, message: Message<A>) {
+        let result = track!(MessageEncoder::default()
+            .encode_into_bytes(message)
+            .map_err(Error::from))
+        .and_then(|bytes| track!(self.writer.write_udp_packet(src, dst, &bytes)));
+        if let Err(e) = result {
+            warn!("Cannot record a STUN message ({} => {}): {}", src, dst, e);
+        }
+    }
+}
+impl<A, T> Transport for PcapTransporter<T>
+where
+    A: Attribute,
+    T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+{
+    type PeerAddr = SocketAddr;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.record(self.inner.local_addr(), peer, item.clone());
+        track!(self.inner.start_send(peer, item))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        let item = track!(self.inner.poll_recv())?;
+        if let Async::Ready(Some((ref peer, Ok(ref message)))) = item {
+            self.record(*peer, self.inner.local_addr(), message.clone());
+        }
+        Ok(item)
+    }
+}
+impl<A, T> UdpTransport for PcapTransporter<T>
+where
+    A: Attribute,
+    T: UdpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+
+fn udp_packet(src: SocketAddr, dst: SocketAddr, payload: &[u8]) -> ::Result<Vec<u8>> {
+    let ip_header_size = if src.is_ipv4() {
+        IPV4_HEADER_SIZE
+    } else {
+        IPV6_HEADER_SIZE
+    };
+    let udp_len = UDP_HEADER_SIZE + payload.len();
+    track_assert!(
+        ip_header_size + udp_len <= 0xFFFF,
+        ErrorKind::InvalidInput,
+        "Too large payload: {} bytes",
+        payload.len()
+    );
+
+    let mut packet = Vec::with_capacity(ETHERNET_HEADER_SIZE + ip_header_size + udp_len);
+    packet.extend_from_slice(&[0; 12]); // destination and source MAC addresses
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            put_u16_be(&mut packet, 0x0800);
+            let start = packet.len();
+            packet.push(0x45); // version and IHL
+            packet.push(0); // DSCP and ECN
+            put_u16_be(&mut packet, (IPV4_HEADER_SIZE + udp_len) as u16);
+            put_u16_be(&mut packet, 0); // identification
+            put_u16_be(&mut packet, 0x4000); // flags (DF) and fragment offset
+            packet.push(TTL);
+            packet.push(IPPROTO_UDP);
+            put_u16_be(&mut packet, 0); // header checksum
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+            let checksum = internet_checksum(&[&packet[start..]]);
+            packet[start + 10] = (checksum >> 8) as u8;
+            packet[start + 11] = checksum as u8;
+
+            // The UDP checksum is optional over IPv4
+            put_udp_header(&mut packet, src.port(), dst.port(), udp_len, 0);
+        }
+        (IpAddr::V6(src_ip), IpAddr::V6(dst_ip)) => {
+            put_u16_be(&mut packet, 0x86DD);
+            put_u32_be(&mut packet, 0x6000_0000); // version, traffic class and flow label
+            put_u16_be(&mut packet, udp_len as u16);
+            packet.push(IPPROTO_UDP);
+            packet.push(TTL);
+            packet.extend_from_slice(&src_ip.octets());
+            packet.extend_from_slice(&dst_ip.octets());
+
+            let mut pseudo_header = Vec::with_capacity(40 + UDP_HEADER_SIZE);
+            pseudo_header.extend_from_slice(&src_ip.octets());
+            pseudo_header.extend_from_slice(&dst_ip.octets());
+            put_u32_be(&mut pseudo_header, udp_len as u32);
+            put_u32_be(&mut pseudo_header, u32::from(IPPROTO_UDP));
+            put_udp_header(&mut pseudo_header, src.port(), dst.port(), udp_len, 0);
+            let checksum = match internet_checksum(&[&pseudo_header, payload]) {
+                0 => 0xFFFF,
+                n => n,
+            };
+            put_udp_header(&mut packet, src.port(), dst.port(), udp_len, checksum);
+        }
+        _ => track_panic!(
+            ErrorKind::InvalidInput,
+            "Address family mismatch: {} => {}",
+            src,
+            dst
+        ),
+    }
+    packet.extend_from_slice(payload);
+    Ok(packet)
+}
+
+fn put_udp_header(buf: &mut Vec<u8>, src_port: u16, dst_port: u16, len: usize, checksum: u16) {
+    put_u16_be(buf, src_port);
+    put_u16_be(buf, dst_port);
+    put_u16_be(buf, len as u16);
+    put_u16_be(buf, checksum);
+}
+
+fn internet_checksum(chunks: &[&[u8]]) -> u16 {
+    let mut sum = 0u32;
+    let mut odd = None;
+    for &b in chunks.iter().flat_map(|c| c.iter()) {
+        if let Some(high) = odd.take() {
+            sum += (u32::from(high) << 8) | u32::from(b);
+        } else {
+            odd = Some(b);
+        }
+    }
+    if let Some(high) = odd {
+        sum += u32::from(high) << 8;
+    }
+    while sum > 0xFFFF {
+        sum = (sum & 0xFFFF) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+// The pcap headers are written in little-endian (as indicated by the magic number),
+// while the network headers are in big-endian.
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&[n as u8, (n >> 8) as u8]);
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    put_u16(buf, n as u16);
+    put_u16(buf, (n >> 16) as u16);
+}
+
+fn put_u16_be(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&[(n >> 8) as u8, n as u8]);
+}
+
+fn put_u32_be(buf: &mut Vec<u8>, n: u32) {
+    put_u16_be(buf, (n >> 16) as u16);
+    put_u16_be(buf, n as u16);
+}
@@ -0,0 +1,247 @@
+use std::cmp;
+use std::collections::{BTreeMap, HashMap};
+use std::net::SocketAddr;
+use std::time::Duration;
+
+/// This trait allows for customizing how `StunUdpTransporter` computes RTOs (Retransmission TimeOuts).
+///
+/// The transporter notifies the strategy of the events observed for each peer,
+/// and asks it for the RTO of every transmission of a request.
+///
+/// The default implementations of the methods realize the fixed schedule described in RFC 5389
+/// (see `FixedRto`).
+#[allow(unused_variables)]
+pub trait RtoStrategy: Send + 'static {
+    /// Returns the RTO of the initial transmission of a new transaction to `peer`.
+    ///
+    /// `default_rto` is the RTO that the fixed schedule would use, that is,
+    /// the RTO specified by `StunUdpTransporterBuilder::rto` (or `set_initial_rto_for`)
+    /// or the one cached from the previous transactions with the peer.
+    fn initial_rto(&mut self, peer: SocketAddr, default_rto: Duration) -> Duration {
+        default_rto
+    }
+
+    /// Returns the RTO of the retransmission that follows a transmission of which RTO is `rto`.
+    fn next_rto(&mut self, peer: SocketAddr, rto: Duration) -> Duration {
+        rto * 2
+    }
+
+    /// Called when a response to a request sent to `peer` has been received.
+    ///
+    /// `rtt` is the measured round-trip time.
+    /// It is `None` if the request has been retransmitted, because it is ambiguous which
+    /// transmission elicited the response (Karn's algorithm).
+    fn on_response(&mut self, peer: SocketAddr, rtt: Option<Duration>) {}
+
+    /// Called when a request to `peer` is retransmitted (i.e., a transmission is regarded as lost).
+    fn on_retransmit(&mut self, peer: SocketAddr) {}
+
+    /// Returns the current RTO estimate of the strategy for `peer`.
+    ///
+    /// If the strategy has no estimate of its own, this will return `None`
+    /// and the estimate of the fixed schedule is used (see `StunUdpTransporter::rto_for`).
+    fn current_rto(&self, peer: SocketAddr) -> Option<Duration> {
+        None
+    }
+}
+
+/// The fixed RTO schedule described in RFC 5389.
+///
+/// > A client SHOULD retransmit a STUN request message starting with an
+/// > interval of RTO ("Retransmission TimeOut"), doubling after each
+/// > retransmission.
+/// >
+/// > [RFC 5389 -- 7.2.1. Sending over UDP]
+///
+/// This is the default strategy of `StunUdpTransporter`.
+///
+/// [RFC 5389 -- 7.2.1. Sending over UDP]: https://tools.ietf.org/html/rfc5389#section-7.2.1
+#[derive(Debug, Default, Clone, Copy)]
+pub struct FixedRto;
+impl RtoStrategy for FixedRto {}
+
+/// An RTO strategy that adapts to the round-trip time and loss observed for each peer.
+///
+/// The RTO of the initial transmission is computed from the smoothed RTT and its variation
+/// in the same manner as TCP:
+///
+/// > When a subsequent RTT measurement R' is made, a host MUST set
+/// >
+/// > ```text
+/// > RTTVAR <- (1 - beta) * RTTVAR + beta * |SRTT - R'|
+/// > SRTT <- (1 - alpha) * SRTT + alpha * R'
+/// > ```
+/// >
+/// > The above SHOULD be computed using alpha=1/8 and beta=1/4.
+/// >
+/// > [RFC 6298 -- 2. The Basic Algorithm]
+///
+/// and the result is clamped to `[min_rto, max_rto]`.
+/// Until the first RTT sample of a peer is taken, the RTO of the fixed schedule is used.
+///
+/// The backoff factor between retransmissions is also adjusted per peer:
+/// it is `2` for a peer with no recent loss, and decreases towards `1.5` as the observed loss
+/// rate increases, because retransmissions caused by packet loss (rather than by an underestimated
+/// RTT) gain nothing from waiting longer.
+/// The resulting RTO is never larger than `max_rto`.
+///
+/// The estimates are kept for at most `max_peers` peers.
+/// If the limit is reached, the estimate of the least recently used peer is evicted,
+/// and the peer falls back to the RTO of the fixed schedule on its next contact.
+///
+/// [RFC 6298 -- 2. The Basic Algorithm]: https://tools.ietf.org/html/rfc6298#section-2
+#[derive(Debug, Clone)]
+pub struct AdaptiveRto {
+    min_rto: Duration,
+    max_rto: Duration,
+    max_peers: usize,
+    peers: HashMap<SocketAddr, RttEstimate>,
+    lru: BTreeMap<u64, SocketAddr>,
+    next_seqno: u64,
+}
+impl AdaptiveRto {
+    /// The default value of `min_rto`.
+    pub const DEFAULT_MIN_RTO_MS: u64 = 100;
+
+    /// The default value of `max_rto`.
+    ///
+    /// > A maximum value MAY be placed on RTO provided it is at least 60 seconds.
+    /// >
+    /// > [RFC 6298 -- 2. The Basic Algorithm]
+    ///
+    /// [RFC 6298 -- 2. The Basic Algorithm]: https://tools.ietf.org/html/rfc6298#section-2
+    pub const DEFAULT_MAX_RTO_MS: u64 = 60_000;
+
+    /// The default value of `max_peers`.
+    pub const DEFAULT_MAX_PEERS: usize = 10_000;
+
+    /// Makes a new `AdaptiveRto` instance with the default settings.
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the lower bound of the RTOs.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_MIN_RTO_MS)`.
+    pub fn min_rto(&mut self, rto: Duration) -> &mut Self {
+        self.min_rto = rto;
+        self
+    }
+
+    /// Sets the upper bound of the RTOs.
+    ///
+    /// The default value is `Duration::from_millis(DEFAULT_MAX_RTO_MS)`.
+    pub fn max_rto(&mut self, rto: Duration) -> &mut Self {
+        self.max_rto = rto;
+        self
+    }
+
+    /// Sets the maximum number of the peers of which estimates are kept.
+    ///
+    /// If `0` is specified, it is regarded as `1`.
+    ///
+    /// The default value is `DEFAULT_MAX_PEERS`.
+    pub fn max_peers(&mut self, max: usize) -> &mut Self {
+        self.max_peers = cmp::max(max, 1);
+        while self.peers.len() > self.max_peers {
+            self.evict_least_recently_used();
+        }
+        self
+    }
+
+    /// Returns the number of the peers of which estimates are currently kept.
+    pub fn tracked_peers(&self) -> usize {
+        self.peers.len()
+    }
+
+    fn clamp(&self, rto: Duration) -> Duration {
+        cmp::min(cmp::max(rto, self.min_rto), self.max_rto)
+    }
+
+    fn estimate_mut(&mut self, peer: SocketAddr) -> &mut RttEstimate {
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        if let Some(old) = self.peers.get(&peer).map(|e| e.seqno) {
+            self.lru.remove(&old);
+        } else if self.peers.len() >= self.max_peers {
+            self.evict_least_recently_used();
+        }
+        self.lru.insert(seqno, peer);
+        let estimate = self.peers.entry(peer).or_default();
+        estimate.seqno = seqno;
+        estimate
+    }
+
+    fn evict_least_recently_used(&mut self) {
+        let oldest = self.lru.keys().next().cloned();
+        if let Some(peer) = oldest.and_then(|seqno| self.lru.remove(&seqno)) {
+            self.peers.remove(&peer);
+        }
+    }
+}
+impl Default for AdaptiveRto {
+    fn default() -> Self {
+        AdaptiveRto {
+            min_rto: Duration::from_millis(Self::DEFAULT_MIN_RTO_MS),
+            max_rto: Duration::from_millis(Self::DEFAULT_MAX_RTO_MS),
+            max_peers: Self::DEFAULT_MAX_PEERS,
+            peers: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_seqno: 0,
+        }
+    }
+}
+impl RtoStrategy for AdaptiveRto {
+    fn initial_rto(&mut self, peer: SocketAddr, default_rto: Duration) -> Duration {
+        match self.current_rto(peer) {
+            Some(rto) => rto,
+            None => default_rto,
+        }
+    }
+
+    fn next_rto(&mut self, peer: SocketAddr, rto: Duration) -> Duration {
+        let loss_permille = self.peers.get(&peer).map_or(0, |e| e.loss_permille);
+        let next = rto * (2000 - loss_permille / 2) / 1000;
+        cmp::min(next, self.max_rto)
+    }
+
+    fn on_response(&mut self, peer: SocketAddr, rtt: Option<Duration>) {
+        let estimate = self.estimate_mut(peer);
+        estimate.loss_permille = estimate.loss_permille * 7 / 8;
+        if let Some(rtt) = rtt {
+            estimate.update(rtt);
+        }
+    }
+
+    fn on_retransmit(&mut self, peer: SocketAddr) {
+        let estimate = self.estimate_mut(peer);
+        estimate.loss_permille = estimate.loss_permille * 7 / 8 + 1000 / 8;
+    }
+
+    fn current_rto(&self, peer: SocketAddr) -> Option<Duration> {
+        let estimate = self.peers.get(&peer)?;
+        let srtt = estimate.srtt?;
+        let granularity = Duration::from_millis(1);
+        Some(self.clamp(srtt + cmp::max(granularity, estimate.rttvar * 4)))
+    }
+}
+
+#[derive(Debug, Default, Clone)]
+struct RttEstimate {
+    srtt: Option<Duration>,
+    rttvar: Duration,
+    loss_permille: u32,
+    seqno: u64,
+}
+impl RttEstimate {
+    fn update(&mut self, rtt: Duration) {
+        if let Some(srtt) = self.srtt {
+            let diff = if srtt > rtt { srtt - rtt } else { rtt - srtt };
+            self.rttvar = self.rttvar * 3 / 4 + diff / 4;
+            self.srtt = Some(srtt * 7 / 8 + rtt / 8);
+        } else {
+            self.rttvar = rtt / 2;
+            self.srtt = Some(rtt);
+        }
+    }
+}
@@ -0,0 +1,182 @@
+use fibers_transport::{Error, ErrorKind, PeerAddr, PollRecv, PollSend, Result, Transport};
+use futures::{Async, AsyncSink, Sink, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use stun_codec::{Attribute, DecodedMessage, Message, TransactionId};
+use trackable::error::{BoxError, ErrorKindExt};
+
+use super::StunTransport;
+
+/// Transporter assembled from a pair of sink and stream halves.
+///
+/// This allows for running a `Channel` over a transport that is not built on `fibers_transport`
+/// (e.g., a framed connection provided by another library and split into halves).
+/// The sink receives the outgoing messages along with their destination peers,
+/// and the stream yields the incoming messages along with their source peers.
+///
+/// The outgoing messages are queued by `start_send`, and are handed to the sink by `poll_send`.
+///
+/// The errors of the halves are reported as `fibers_transport::ErrorKind::Other` errors.
+///
+/// See also `Channel::from_parts`.
+pub struct SplitTransporter<S, R>
+where
+    S: Sink,
+{
+    sink: S,
+    stream: R,
+    outgoing_queue: VecDeque<S::SinkItem>,
+}
+impl<A, P, S, R> SplitTransporter<S, R>
+where
+    A: Attribute,
+    P: PeerAddr,
+    S: Sink<SinkItem = (P, Message<A>)>,
+    S::SinkError: Into<BoxError>,
+    R: Stream<Item = (P, DecodedMessage<A>)>,
+    R::Error: Into<BoxError>,
+{
+    /// Makes a new `SplitTransporter` instance.
+    pub fn new(sink: S, stream: R) -> Self {
+        SplitTransporter {
+            sink,
+            stream,
+            outgoing_queue: VecDeque::new(),
+        }
+    }
+
+    /// Returns a reference to the sink half.
+    pub fn sink_ref(&self) -> &S {
+        &self.sink
+    }
+
+    /// Returns a reference to the stream half.
+    pub fn stream_ref(&self) -> &R {
+        &self.stream
+    }
+
+    /// Takes ownership of this instance, and returns the sink and stream halves.
+    ///
+    /// Note that the messages that have not been accepted by the sink yet are discarded.
+    pub fn into_parts(self) -> (S, R) {
+        (self.sink, self.stream)
+    }
+}
+impl<S: Sink, R> fmt::Debug for SplitTransporter<S, R> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "SplitTransporter {{ outgoing_queue: {} }}",
+            self.outgoing_queue.len()
+        )
+    }
+}
+impl<A, P, S, R> Transport for SplitTransporter<S, R>
+where
+    A: Attribute,
+    P: PeerAddr,
+    S: Sink<SinkItem = (P, Message<A>)>,
+    S::SinkError: Into<BoxError>,
+    R: Stream<Item = (P, DecodedMessage<A>)>,
+    R::Error: Into<BoxError>,
+{
+    type PeerAddr = P;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.outgoing_queue.push_back((peer, item));
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        while let Some(item) = self.outgoing_queue.pop_front() {
+            match self.sink.start_send(item) {
+                Err(e) => return Err(track!(other_error(e))),
+                Ok(AsyncSink::Ready) => {}
+                Ok(AsyncSink::NotReady(item)) => {
+                    self.outgoing_queue.push_front(item);
+                    break;
+                }
+            }
+        }
+        match self.sink.poll_complete() {
+            Err(e) => Err(track!(other_error(e))),
+            Ok(Async::Ready(())) if self.outgoing_queue.is_empty() => Ok(Async::Ready(())),
+            Ok(_) => Ok(Async::NotReady),
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        self.stream
+            .poll()
+            .map_err(|e| track!(other_error(e)))
+    }
+}
+impl<A, P, S, R> StunTransport<A> for SplitTransporter<S, R>
+where
+    A: Attribute,
+    P: PeerAddr,
+    S: Sink<SinkItem = (P, Message<A>)>,
+    S::SinkError: Into<BoxError>,
+    R: Stream<Item = (P, DecodedMessage<A>)>,
+    R::Error: Into<BoxError>,
+{
+    fn finish_transaction(&mut self, _peer: &P, _transaction_id: TransactionId) -> Result<()> {
+        Ok(())
+    }
+}
+
+fn other_error<E: Into<BoxError>>(e: E) -> Error {
+    Error::from(ErrorKind::Other.cause(e))
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::{self, Poll, StartSend};
+    use std::io;
+    use std::net::SocketAddr;
+    use stun_codec::rfc5389;
+
+    use super::*;
+    use message::Request;
+
+    type TestMessage = Message<rfc5389::Attribute>;
+
+    #[derive(Default)]
+    struct RecordingSink {
+        started: Vec<(SocketAddr, TestMessage)>,
+        flushed: usize,
+    }
+    impl Sink for RecordingSink {
+        type SinkItem = (SocketAddr, TestMessage);
+        type SinkError = io::Error;
+
+        fn start_send(&mut self, item: Self::SinkItem) -> StartSend<Self::SinkItem, io::Error> {
+            self.started.push(item);
+            Ok(AsyncSink::Ready)
+        }
+
+        fn poll_complete(&mut self) -> Poll<(), io::Error> {
+            self.flushed = self.started.len();
+            Ok(Async::Ready(()))
+        }
+    }
+
+    #[test]
+    fn start_send_only_queues_messages() {
+        let stream =
+            futures::stream::empty::<(SocketAddr, DecodedMessage<rfc5389::Attribute>), io::Error>();
+        let mut transporter = SplitTransporter::new(RecordingSink::default(), stream);
+        let peer: SocketAddr = "127.0.0.1:3478".parse().unwrap();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        transporter
+            .start_send(peer, request.into_message())
+            .unwrap();
+        assert!(transporter.sink_ref().started.is_empty());
+
+        assert!(transporter.poll_send().unwrap().is_ready());
+        assert_eq!(transporter.sink_ref().started.len(), 1);
+        assert_eq!(transporter.sink_ref().flushed, 1);
+    }
+}
@@ -1,12 +1,83 @@
-use fibers_transport::{PollRecv, PollSend, Result, TcpTransport, Transport};
+use bytecodec::io::{BufferedIo, IoDecodeExt, IoEncodeExt};
+use bytecodec::{Decode, Encode};
+use fibers::net::TcpStream;
+use fibers_transport::{
+    Error, ErrorKind, PollRecv, PollSend, Result, TcpTransport, TcpTransporter, Transport,
+};
+use futures::Async;
+use std::collections::VecDeque;
+use std::fmt;
+use std::io;
+use std::net::{Shutdown, SocketAddr};
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::mem;
+#[cfg(any(target_os = "linux", target_os = "android"))]
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::Duration;
 use stun_codec::{Attribute, DecodedMessage, Message, TransactionId};
 
 use super::StunTransport;
 
+/// The default size of the read and write buffers of a TCP transporter in bytes.
+///
+/// This is the same as the default of `fibers_transport::TcpTransporter`.
+/// Typical STUN messages (e.g., Binding requests and responses) are at most a few hundred bytes,
+/// so a buffer of this size can hold dozens of them and lets a batch of messages be
+/// read (or written) by a single system call.
+///
+/// Note that the buffers are allocated with the given sizes and never grow.
+/// Because messages are decoded and encoded incrementally, a message larger than a buffer
+/// can still be received or sent (at the cost of additional system calls).
+/// Thus, smaller buffers reduce the memory per connection,
+/// and larger ones reduce the system calls when many (or large) messages are exchanged.
+pub const DEFAULT_TCP_BUFFER_SIZE: usize = 8192;
+
+/// TCP keepalive settings.
+///
+/// Keepalive probes detect a peer that has silently gone away
+/// (e.g., a NAT or load balancer between the peers dropped the connection state).
+/// Once the probes fail, the operating system aborts the connection,
+/// and the subsequent I/O on it fails with an error.
+/// Then the error is reported by `Channel::poll_recv`, so the outstanding transactions
+/// over the connection fail promptly rather than waiting for their timeouts,
+/// and a server handles the error as an abnormal disconnection
+/// (see `HandleMessage::handle_disconnect`).
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct TcpKeepalive {
+    /// The idle time before the first keepalive probe is sent (i.e., `TCP_KEEPIDLE`).
+    pub idle: Duration,
+
+    /// The interval between keepalive probes (i.e., `TCP_KEEPINTVL`).
+    ///
+    /// If `None`, the default value of the operating system is used.
+    /// This is only supported on Linux and Android, and is ignored on the other platforms.
+    pub interval: Option<Duration>,
+
+    /// The number of unacknowledged probes before the connection is regarded as dead
+    /// (i.e., `TCP_KEEPCNT`).
+    ///
+    /// If `None`, the default value of the operating system is used.
+    /// This is only supported on Linux and Android, and is ignored on the other platforms.
+    pub count: Option<u32>,
+}
+impl TcpKeepalive {
+    /// Makes a new `TcpKeepalive` instance that has the given idle time.
+    ///
+    /// The interval and count of the probes are the default values of the operating system.
+    pub fn new(idle: Duration) -> Self {
+        TcpKeepalive {
+            idle,
+            interval: None,
+            count: None,
+        }
+    }
+}
+
 /// TCP transport layer that can be used for STUN.
-#[derive(Debug)]
 pub struct StunTcpTransporter<T> {
     inner: T,
+    shutdown: Option<fn(&T) -> io::Result<()>>,
+    closed: bool,
 }
 impl<A, T> StunTcpTransporter<T>
 where
@@ -15,7 +86,11 @@ where
 {
     /// Makes a new `StunTcpTransporter` instance.
     pub fn new(inner: T) -> Self {
-        StunTcpTransporter { inner }
+        StunTcpTransporter {
+            inner,
+            shutdown: None,
+            closed: false,
+        }
     }
 
     /// Returns a reference to the inner transporter.
@@ -27,6 +102,51 @@ where
     pub fn inner_mut(&mut self) -> &mut T {
         &mut self.inner
     }
+
+    /// Returns `true` if `close` has been called on the transporter.
+    pub fn is_closed(&self) -> bool {
+        self.closed
+    }
+}
+impl<A, E, D> StunTcpTransporter<TcpTransporter<E, D>>
+where
+    A: Attribute,
+    E: Encode<Item = Message<A>>,
+    D: Decode<Item = DecodedMessage<A>>,
+{
+    /// Signals the peer that this side will send no more messages.
+    ///
+    /// The signal is a TCP half-close (i.e., `shutdown(SHUT_WR)`) that is performed
+    /// after the all queued messages have been written to the stream by `poll_send`.
+    /// Receiving messages (e.g., the responses to outstanding requests) is still possible
+    /// after calling this method.
+    ///
+    /// A server can distinguish a peer that closed its connection in this manner
+    /// from one that aborted the connection (see `HandleMessage::handle_disconnect`).
+    ///
+    /// Subsequent calls of `start_send` will fail with an `ErrorKind::InvalidInput` error.
+    pub fn close(&mut self) {
+        if !self.closed {
+            self.closed = true;
+            let shutdown: fn(&TcpTransporter<E, D>) -> io::Result<()> = shutdown_write;
+            self.shutdown = Some(shutdown);
+        }
+    }
+
+    /// Enables (or disables if `None`) TCP keepalive on the underlying stream.
+    ///
+    /// Keepalive is disabled by default.
+    pub fn set_keepalive(&self, keepalive: Option<TcpKeepalive>) -> Result<()> {
+        track!(set_tcp_keepalive(self.inner.stream_ref(), keepalive).map_err(Error::from))
+    }
+}
+impl<T: fmt::Debug> fmt::Debug for StunTcpTransporter<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("StunTcpTransporter")
+            .field("inner", &self.inner)
+            .field("closed", &self.closed)
+            .finish()
+    }
 }
 impl<A, T> Transport for StunTcpTransporter<T>
 where
@@ -38,17 +158,41 @@ where
     type RecvItem = DecodedMessage<A>;
 
     fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        track_assert!(
+            !self.closed,
+            ErrorKind::InvalidInput,
+            "The transporter has been closed"
+        );
         track!(self.inner.start_send((), item))
     }
 
     fn poll_send(&mut self) -> PollSend {
-        track!(self.inner.poll_send())
+        let ready = track!(self.inner.poll_send())?;
+        if ready.is_ready() {
+            if let Some(shutdown) = self.shutdown.take() {
+                track!(shutdown(&self.inner).map_err(Error::from))?;
+            }
+        }
+        Ok(ready)
     }
 
     fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
         track!(self.inner.poll_recv())
     }
 }
+impl<A, T> TcpTransport for StunTcpTransporter<T>
+where
+    A: Attribute,
+    T: TcpTransport<SendItem = Message<A>, RecvItem = DecodedMessage<A>>,
+{
+    fn peer_addr(&self) -> SocketAddr {
+        self.inner.peer_addr()
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
 impl<A, T> StunTransport<A> for StunTcpTransporter<T>
 where
     A: Attribute,
@@ -58,3 +202,235 @@ where
         Ok(())
     }
 }
+
+pub(crate) fn set_tcp_keepalive(
+    stream: &TcpStream,
+    keepalive: Option<TcpKeepalive>,
+) -> io::Result<()> {
+    stream.with_inner(|s| {
+        s.set_keepalive(keepalive.map(|k| k.idle))?;
+        if let Some(k) = keepalive {
+            set_keepalive_probes(s, k)?;
+        }
+        Ok(())
+    })
+}
+
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn set_keepalive_probes<S: AsRawFd>(socket: &S, keepalive: TcpKeepalive) -> io::Result<()> {
+    fn setsockopt(fd: RawFd, name: libc::c_int, value: libc::c_int) -> io::Result<()> {
+        let result = unsafe {
+            libc::setsockopt(
+                fd,
+                libc::IPPROTO_TCP,
+                name,
+                &value as *const libc::c_int as *const libc::c_void,
+                mem::size_of::<libc::c_int>() as libc::socklen_t,
+            )
+        };
+        if result == -1 {
+            Err(io::Error::last_os_error())
+        } else {
+            Ok(())
+        }
+    }
+
+    let fd = socket.as_raw_fd();
+    if let Some(interval) = keepalive.interval {
+        let secs = interval.as_secs().max(1).min(libc::c_int::MAX as u64);
+        setsockopt(fd, libc::TCP_KEEPINTVL, secs as libc::c_int)?;
+    }
+    if let Some(count) = keepalive.count {
+        let count = count.max(1).min(libc::c_int::MAX as u32);
+        setsockopt(fd, libc::TCP_KEEPCNT, count as libc::c_int)?;
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn set_keepalive_probes<S>(_socket: &S, _keepalive: TcpKeepalive) -> io::Result<()> {
+    Ok(())
+}
+
+fn shutdown_write<E: Encode, D: Decode>(transporter: &TcpTransporter<E, D>) -> io::Result<()> {
+    transporter
+        .stream_ref()
+        .with_inner(|s| s.shutdown(Shutdown::Write))
+}
+
+/// TCP transporter that coalesces the messages queued within a poll cycle into a single write.
+///
+/// `fibers_transport::TcpTransporter` writes each message to the stream as soon as it is queued.
+/// This transporter, on the other hand, only queues messages in `start_send`,
+/// and `poll_send` encodes as many of the queued messages as fit in the write buffer
+/// before writing the buffer to the stream.
+/// Thus, for example, the responses to requests that were received in the same TCP segment
+/// are sent to the peer in a single segment.
+///
+/// The framing of the messages is not affected, because each message is encoded in its entirety
+/// (the STUN header including the length field followed by the attributes)
+/// just after the preceding one.
+#[derive(Debug)]
+pub struct CoalescingTcpTransporter<E: Encode, D: Decode> {
+    stream: BufferedIo<TcpStream>,
+    peer_addr: SocketAddr,
+    local_addr: SocketAddr,
+    encoder: E,
+    decoder: D,
+    outgoing_queue: VecDeque<E::Item>,
+}
+impl<E, D> CoalescingTcpTransporter<E, D>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    /// Makes a new `CoalescingTcpTransporter` instance from the given `TcpStream`.
+    pub fn from_stream(stream: TcpStream) -> Result<Self> {
+        track!(Self::with_codec(stream, E::default(), D::default()))
+    }
+}
+impl<E: Encode, D: Decode> CoalescingTcpTransporter<E, D> {
+    /// Makes a new `CoalescingTcpTransporter` instance with the given encoder and decoder.
+    ///
+    /// The sizes of the read and write buffers are `DEFAULT_TCP_BUFFER_SIZE` bytes
+    /// (the same as `fibers_transport::TcpTransporter`).
+    pub fn with_codec(stream: TcpStream, encoder: E, decoder: D) -> Result<Self> {
+        track!(Self::with_buffer_sizes(
+            stream,
+            encoder,
+            decoder,
+            DEFAULT_TCP_BUFFER_SIZE,
+            DEFAULT_TCP_BUFFER_SIZE
+        ))
+    }
+
+    /// Makes a new `CoalescingTcpTransporter` instance that has the read and write buffers
+    /// of the given sizes (in bytes).
+    ///
+    /// See the documentation of `DEFAULT_TCP_BUFFER_SIZE` for the tradeoffs.
+    /// Note that the number of the messages coalesced into a single write is bounded by
+    /// the size of the write buffer.
+    ///
+    /// # Errors
+    ///
+    /// If either of the sizes is `0`, this will return an `ErrorKind::InvalidInput` error.
+    pub fn with_buffer_sizes(
+        stream: TcpStream,
+        encoder: E,
+        decoder: D,
+        read_buffer_size: usize,
+        write_buffer_size: usize,
+    ) -> Result<Self> {
+        track_assert_ne!(read_buffer_size, 0, ErrorKind::InvalidInput);
+        track_assert_ne!(write_buffer_size, 0, ErrorKind::InvalidInput);
+        let _ = stream.set_nodelay(true);
+        let peer_addr = track!(stream.peer_addr().map_err(Error::from))?;
+        let local_addr = track!(stream.local_addr().map_err(Error::from))?;
+        Ok(CoalescingTcpTransporter {
+            stream: BufferedIo::new(stream, read_buffer_size, write_buffer_size),
+            peer_addr,
+            local_addr,
+            encoder,
+            decoder,
+            outgoing_queue: VecDeque::new(),
+        })
+    }
+
+    /// Returns the number of unsent messages in the queue of the instance.
+    pub fn message_queue_len(&self) -> usize {
+        self.outgoing_queue.len() + if self.encoder.is_idle() { 0 } else { 1 }
+    }
+
+    /// Returns a reference to the TCP stream being used by the instance.
+    pub fn stream_ref(&self) -> &TcpStream {
+        self.stream.stream_ref()
+    }
+
+    /// Returns a mutable reference to the TCP stream being used by the instance.
+    pub fn stream_mut(&mut self) -> &mut TcpStream {
+        self.stream.stream_mut()
+    }
+
+    /// Enables (or disables if `None`) TCP keepalive on the underlying stream.
+    ///
+    /// Keepalive is disabled by default.
+    pub fn set_keepalive(&self, keepalive: Option<TcpKeepalive>) -> Result<()> {
+        track!(set_tcp_keepalive(self.stream_ref(), keepalive).map_err(Error::from))
+    }
+
+    fn fill_write_buf(&mut self) -> Result<()> {
+        loop {
+            track!(self
+                .encoder
+                .encode_to_write_buf(self.stream.write_buf_mut()))?;
+            if !self.encoder.is_idle() {
+                // The write buffer is full
+                return Ok(());
+            }
+            if let Some(item) = self.outgoing_queue.pop_front() {
+                track!(self.encoder.start_encoding(item))?;
+            } else {
+                return Ok(());
+            }
+        }
+    }
+}
+impl<E: Encode, D: Decode> Transport for CoalescingTcpTransporter<E, D> {
+    type PeerAddr = ();
+    type SendItem = E::Item;
+    type RecvItem = D::Item;
+
+    fn start_send(&mut self, (): Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        self.outgoing_queue.push_back(item);
+        Ok(())
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        loop {
+            track!(self.fill_write_buf())?;
+            track!(self.stream.execute_io())?;
+            if self.message_queue_len() == 0 && self.stream.write_buf_ref().is_empty() {
+                return Ok(Async::Ready(()));
+            }
+
+            // NOTE: `BufferedIo::would_block` is `false` while the read buffer is full,
+            // so the state of the write buffer is checked directly.
+            // Otherwise, this would keep retrying the write to a peer that does not read.
+            if self.stream.write_buf_ref().stream_state().would_block() || self.stream.is_eos() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        loop {
+            track!(self.stream.execute_io())?;
+            track!(self
+                .decoder
+                .decode_from_read_buf(self.stream.read_buf_mut()))?;
+
+            // NOTE: Complete messages remaining in the read buffer are yielded before
+            // `would_block` is checked, so a batch received by a single read is drained
+            // without waiting for the next readiness notification.
+            if self.decoder.is_idle() {
+                let item = track!(self.decoder.finish_decoding())?;
+                return Ok(Async::Ready(Some(((), item))));
+            }
+            if self.stream.is_eos() {
+                return Ok(Async::Ready(None));
+            }
+            if self.stream.would_block() {
+                return Ok(Async::NotReady);
+            }
+        }
+    }
+}
+impl<E: Encode, D: Decode> TcpTransport for CoalescingTcpTransporter<E, D> {
+    fn peer_addr(&self) -> SocketAddr {
+        self.peer_addr
+    }
+
+    fn local_addr(&self) -> SocketAddr {
+        self.local_addr
+    }
+}
@@ -0,0 +1,458 @@
+use bytecodec::{self, ByteCount, Decode, DecodeExt, Encode, EncodeExt, Eos, SizedEncode};
+use fibers_transport::{ErrorKind, PollRecv, PollSend, Result, Transport, UdpTransport};
+use futures::Async;
+use std::cmp;
+use std::fmt;
+use std::net::SocketAddr;
+use stun_codec::{
+    Attribute, DecodedMessage, Message, MessageDecoder, MessageEncoder, TransactionId,
+};
+use trackable::error::ErrorKindExt;
+
+use super::StunTransport;
+
+/// This trait allows for applying a reversible transformation (e.g., obfuscation) to
+/// the encoded bytes of messages.
+///
+/// `offset` is the position of the first byte of `buf` in the encoded bytes of the current message.
+/// Since both functions are applied to each message independently,
+/// the sender and receiver do not need to share any state other than the transform itself.
+pub trait ByteTransform {
+    /// Transforms the given bytes before they are sent.
+    fn transform(&self, offset: u64, buf: &mut [u8]);
+
+    /// Restores the original bytes from the given received bytes.
+    fn inverse(&self, offset: u64, buf: &mut [u8]);
+}
+
+/// A `ByteTransform` implementation that XORs the bytes with a pre-shared key.
+///
+/// This only hides the STUN framing from casual inspection, and is not a cryptographic protection.
+#[derive(Debug, Clone)]
+pub struct XorTransform {
+    key: Vec<u8>,
+}
+impl XorTransform {
+    /// Makes a new `XorTransform` instance.
+    ///
+    /// If `key` is empty, the transform does nothing.
+    pub fn new(key: Vec<u8>) -> Self {
+        XorTransform { key }
+    }
+
+    fn xor(&self, offset: u64, buf: &mut [u8]) {
+        if self.key.is_empty() {
+            return;
+        }
+        let key_len = self.key.len() as u64;
+        for (i, b) in buf.iter_mut().enumerate() {
+            *b ^= self.key[((offset + i as u64) % key_len) as usize];
+        }
+    }
+}
+impl ByteTransform for XorTransform {
+    fn transform(&self, offset: u64, buf: &mut [u8]) {
+        self.xor(offset, buf);
+    }
+
+    fn inverse(&self, offset: u64, buf: &mut [u8]) {
+        self.xor(offset, buf);
+    }
+}
+
+/// Transporter that applies a `ByteTransform` to the encoded bytes of the messages
+/// sent and received via the inner transporter.
+///
+/// The inner transporter handles the raw bytes of each message as a datagram,
+/// and the retransmission of requests is left to the outer transporter.
+/// For example, a UDP transporter that obfuscates STUN messages can be made as follows:
+///
+/// ```
+/// # extern crate bytecodec;
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use bytecodec::bytes::{BytesEncoder, RemainingBytesDecoder};
+/// use fibers_transport::UdpTransporterBuilder;
+/// use rustun::transport::{StunUdpTransporter, TransformTransport, XorTransform};
+/// use stun_codec::rfc5389;
+///
+/// # fn main() {
+/// let codec = (BytesEncoder::new(), RemainingBytesDecoder::new());
+/// let future = UdpTransporterBuilder::with_codec(codec.0, codec.1).bind("127.0.0.1:0".parse().unwrap());
+/// let inner = fibers_global::execute(future).unwrap();
+/// let transform = XorTransform::new(b"pre-shared key".to_vec());
+/// let transporter = StunUdpTransporter::<rfc5389::Attribute, _>::new(TransformTransport::new(inner, transform));
+/// # let _ = transporter;
+/// # }
+/// ```
+///
+/// Stream-oriented transporters need to know the boundaries of the transformed messages,
+/// so use `TransformEncoder` and `TransformDecoder` as their codec instead.
+pub struct TransformTransport<A: Attribute, T, X> {
+    inner: T,
+    transform: X,
+    encoder: MessageEncoder<A>,
+    decoder: MessageDecoder<A>,
+}
+impl<A, T, X> TransformTransport<A, T, X>
+where
+    A: Attribute,
+    T: Transport<SendItem = Vec<u8>, RecvItem = Vec<u8>>,
+    X: ByteTransform,
+{
+    /// Makes a new `TransformTransport` instance.
+    pub fn new(inner: T, transform: X) -> Self {
+        TransformTransport {
+            inner,
+            transform,
+            encoder: MessageEncoder::new(),
+            decoder: MessageDecoder::new(),
+        }
+    }
+
+    /// Returns a reference to the inner transporter.
+    pub fn inner_ref(&self) -> &T {
+        &self.inner
+    }
+
+    /// Returns a mutable reference to the inner transporter.
+    pub fn inner_mut(&mut self) -> &mut T {
+        &mut self.inner
+    }
+}
+impl<A: Attribute, T: fmt::Debug, X> fmt::Debug for TransformTransport<A, T, X> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "TransformTransport {{ inner: {:?}, .. }}", self.inner)
+    }
+}
+impl<A, T, X> Transport for TransformTransport<A, T, X>
+where
+    A: Attribute,
+    T: Transport<SendItem = Vec<u8>, RecvItem = Vec<u8>>,
+    X: ByteTransform,
+{
+    type PeerAddr = T::PeerAddr;
+    type SendItem = Message<A>;
+    type RecvItem = DecodedMessage<A>;
+
+    fn start_send(&mut self, peer: Self::PeerAddr, item: Self::SendItem) -> Result<()> {
+        let mut bytes = track!(self
+            .encoder
+            .encode_into_bytes(item)
+            .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+        self.transform.transform(0, &mut bytes);
+        track!(self.inner.start_send(peer, bytes))
+    }
+
+    fn poll_send(&mut self) -> PollSend {
+        track!(self.inner.poll_send())
+    }
+
+    fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
+        match track!(self.inner.poll_recv())? {
+            Async::Ready(Some((peer, mut bytes))) => {
+                self.transform.inverse(0, &mut bytes);
+                let message = track!(self
+                    .decoder
+                    .decode_from_bytes(&bytes)
+                    .map_err(|e| ErrorKind::InvalidInput.cause(e)))?;
+                Ok(Async::Ready(Some((peer, message))))
+            }
+            Async::Ready(None) => Ok(Async::Ready(None)),
+            Async::NotReady => Ok(Async::NotReady),
+        }
+    }
+}
+impl<A, T, X> UdpTransport for TransformTransport<A, T, X>
+where
+    A: Attribute,
+    T: UdpTransport<SendItem = Vec<u8>, RecvItem = Vec<u8>>,
+    X: ByteTransform,
+{
+    fn local_addr(&self) -> SocketAddr {
+        self.inner.local_addr()
+    }
+}
+impl<A, T, X> StunTransport<A> for TransformTransport<A, T, X>
+where
+    A: Attribute,
+    T: Transport<SendItem = Vec<u8>, RecvItem = Vec<u8>>,
+    X: ByteTransform,
+{
+    fn finish_transaction(
+        &mut self,
+        _peer: &Self::PeerAddr,
+        _transaction_id: TransactionId,
+    ) -> Result<()> {
+        Ok(())
+    }
+}
+
+/// Encoder that applies `ByteTransform::transform` to the bytes produced by the inner encoder.
+///
+/// This is the codec-level counterpart of `TransformTransport`, and can be plugged into any
+/// transporter, including the stream-oriented ones (e.g., TCP) that need the message boundaries:
+///
+/// ```
+/// # extern crate fibers_global;
+/// # extern crate fibers_transport;
+/// # extern crate futures;
+/// # extern crate rustun;
+/// # extern crate stun_codec;
+/// use fibers_transport::UdpTransporterBuilder;
+/// use rustun::transport::{StunUdpTransporter, TransformDecoder, TransformEncoder, XorTransform};
+/// use stun_codec::{rfc5389, MessageDecoder, MessageEncoder};
+///
+/// # fn main() {
+/// let transform = XorTransform::new(b"pre-shared key".to_vec());
+/// let encoder = TransformEncoder::new(MessageEncoder::<rfc5389::Attribute>::new(), transform.clone());
+/// let decoder = TransformDecoder::new(MessageDecoder::<rfc5389::Attribute>::new(), transform);
+/// let future = UdpTransporterBuilder::with_codec(encoder, decoder).bind("127.0.0.1:0".parse().unwrap());
+/// let transporter = StunUdpTransporter::new(fibers_global::execute(future).unwrap());
+/// # let _ = transporter;
+/// # }
+/// ```
+#[derive(Debug)]
+pub struct TransformEncoder<E, T> {
+    inner: E,
+    transform: T,
+    offset: u64,
+}
+impl<E: Encode, T: ByteTransform> TransformEncoder<E, T> {
+    /// Makes a new `TransformEncoder` instance.
+    pub fn new(inner: E, transform: T) -> Self {
+        TransformEncoder {
+            inner,
+            transform,
+            offset: 0,
+        }
+    }
+
+    /// Returns a reference to the inner encoder.
+    pub fn inner_ref(&self) -> &E {
+        &self.inner
+    }
+}
+impl<E: Encode, T: ByteTransform> Encode for TransformEncoder<E, T> {
+    type Item = E::Item;
+
+    fn encode(&mut self, buf: &mut [u8], eos: Eos) -> bytecodec::Result<usize> {
+        let size = track!(self.inner.encode(buf, eos))?;
+        self.transform.transform(self.offset, &mut buf[..size]);
+        self.offset += size as u64;
+        Ok(size)
+    }
+
+    fn start_encoding(&mut self, item: Self::Item) -> bytecodec::Result<()> {
+        self.offset = 0;
+        track!(self.inner.start_encoding(item))
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+impl<E: SizedEncode, T: ByteTransform> SizedEncode for TransformEncoder<E, T> {
+    fn exact_requiring_bytes(&self) -> u64 {
+        self.inner.exact_requiring_bytes()
+    }
+}
+
+/// Decoder that applies `ByteTransform::inverse` to the received bytes before passing them to
+/// the inner decoder.
+///
+/// See the documentation of [`TransformEncoder`] for an example.
+///
+/// [`TransformEncoder`]: ./struct.TransformEncoder.html
+#[derive(Debug)]
+pub struct TransformDecoder<D, T> {
+    inner: D,
+    transform: T,
+    offset: u64,
+    buf: Vec<u8>,
+}
+impl<D: Decode, T: ByteTransform> TransformDecoder<D, T> {
+    /// Makes a new `TransformDecoder` instance.
+    pub fn new(inner: D, transform: T) -> Self {
+        TransformDecoder {
+            inner,
+            transform,
+            offset: 0,
+            buf: Vec::new(),
+        }
+    }
+
+    /// Returns a reference to the inner decoder.
+    pub fn inner_ref(&self) -> &D {
+        &self.inner
+    }
+}
+impl<D: Decode, T: ByteTransform> Decode for TransformDecoder<D, T> {
+    type Item = D::Item;
+
+    fn decode(&mut self, buf: &[u8], eos: Eos) -> bytecodec::Result<usize> {
+        // The bytes that are not consumed by the inner decoder will be passed again,
+        // so only the ones that it requires are restored.
+        let n = match self.inner.requiring_bytes() {
+            ByteCount::Finite(n) => cmp::min(n, buf.len() as u64) as usize,
+            _ => buf.len(),
+        };
+        self.buf.clear();
+        self.buf.extend_from_slice(&buf[..n]);
+        self.transform.inverse(self.offset, &mut self.buf);
+        let eos = eos.back((buf.len() - n) as u64);
+        let size = track!(self.inner.decode(&self.buf, eos))?;
+        self.offset += size as u64;
+        Ok(size)
+    }
+
+    fn finish_decoding(&mut self) -> bytecodec::Result<Self::Item> {
+        self.offset = 0;
+        track!(self.inner.finish_decoding())
+    }
+
+    fn requiring_bytes(&self) -> ByteCount {
+        self.inner.requiring_bytes()
+    }
+
+    fn is_idle(&self) -> bool {
+        self.inner.is_idle()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::VecDeque;
+    use stun_codec::rfc5389::attributes::Software;
+    use stun_codec::{
+        rfc5389, Message, MessageClass, MessageDecoder, MessageEncoder, TransactionId,
+    };
+
+    use super::*;
+
+    /// A transporter that delivers the datagrams sent to any peer to itself.
+    #[derive(Debug, Default)]
+    struct LoopbackTransporter {
+        datagrams: VecDeque<(SocketAddr, Vec<u8>)>,
+    }
+    impl Transport for LoopbackTransporter {
+        type PeerAddr = SocketAddr;
+        type SendItem = Vec<u8>;
+        type RecvItem = Vec<u8>;
+
+        fn start_send(&mut self, peer: SocketAddr, item: Vec<u8>) -> Result<()> {
+            self.datagrams.push_back((peer, item));
+            Ok(())
+        }
+
+        fn poll_send(&mut self) -> PollSend {
+            Ok(Async::Ready(()))
+        }
+
+        fn poll_recv(&mut self) -> PollRecv<(SocketAddr, Vec<u8>)> {
+            Ok(Async::Ready(self.datagrams.pop_front()))
+        }
+    }
+
+    fn message(transaction_id: TransactionId) -> Message<rfc5389::Attribute> {
+        let mut message = Message::new(
+            MessageClass::Request,
+            rfc5389::methods::BINDING,
+            transaction_id,
+        );
+        message.add_attribute(Software::new("foo".to_owned()).unwrap().into());
+        message
+    }
+
+    #[test]
+    fn xor_transform_round_trips() {
+        let transform = XorTransform::new(b"key".to_vec());
+        let mut encoder = TransformEncoder::new(MessageEncoder::new(), transform.clone());
+        let bytes = encoder
+            .encode_into_bytes(message(TransactionId::new([1; 12])))
+            .unwrap();
+        let plain = MessageEncoder::new()
+            .encode_into_bytes(message(TransactionId::new([1; 12])))
+            .unwrap();
+        assert_eq!(bytes.len(), plain.len());
+        assert_ne!(bytes, plain);
+
+        let mut decoder = TransformDecoder::new(MessageDecoder::new(), transform);
+        let decoded: Message<rfc5389::Attribute> =
+            decoder.decode_from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(decoded.transaction_id(), TransactionId::new([1; 12]));
+
+        // The offset is reset for each message
+        let bytes = encoder
+            .encode_into_bytes(message(TransactionId::new([2; 12])))
+            .unwrap();
+        let decoded = decoder.decode_from_bytes(&bytes).unwrap().unwrap();
+        assert_eq!(decoded.transaction_id(), TransactionId::new([2; 12]));
+    }
+
+    #[test]
+    fn bytes_split_into_pieces_are_restored() {
+        let transform = XorTransform::new(b"pre-shared key".to_vec());
+        let mut encoder = TransformEncoder::new(MessageEncoder::new(), transform.clone());
+        let mut bytes = Vec::new();
+        for i in 0..2 {
+            let message = message(TransactionId::new([i; 12]));
+            bytes.extend(encoder.encode_into_bytes(message).unwrap());
+        }
+
+        let mut decoder = TransformDecoder::new(MessageDecoder::new(), transform);
+        let mut decoded: Vec<Message<rfc5389::Attribute>> = Vec::new();
+        let mut offset = 0;
+        while offset < bytes.len() {
+            let end = cmp::min(offset + 7, bytes.len());
+            offset += decoder
+                .decode(&bytes[offset..end], Eos::new(false))
+                .unwrap();
+            if decoder.is_idle() {
+                decoded.push(decoder.finish_decoding().unwrap().unwrap());
+            }
+        }
+        assert_eq!(decoded.len(), 2);
+        assert_eq!(decoded[0].transaction_id(), TransactionId::new([0; 12]));
+        assert_eq!(decoded[1].transaction_id(), TransactionId::new([1; 12]));
+    }
+
+    #[test]
+    fn transform_transport_works() {
+        let transform = XorTransform::new(b"key".to_vec());
+        let mut transporter = TransformTransport::new(LoopbackTransporter::default(), transform);
+        let peer = "127.0.0.1:3478".parse().unwrap();
+        transporter
+            .start_send(peer, message(TransactionId::new([3; 12])))
+            .unwrap();
+
+        let plain = MessageEncoder::new()
+            .encode_into_bytes(message(TransactionId::new([3; 12])))
+            .unwrap();
+        let datagram = &transporter.inner_ref().datagrams[0];
+        assert_eq!(datagram.1.len(), plain.len());
+        assert_ne!(datagram.1, plain);
+
+        match transporter.poll_recv().unwrap() {
+            Async::Ready(Some((p, Ok(m)))) => {
+                assert_eq!(p, peer);
+                assert_eq!(m.transaction_id(), TransactionId::new([3; 12]));
+            }
+            other => panic!("unexpected item: {:?}", other),
+        }
+    }
+
+    #[test]
+    fn empty_key_does_nothing() {
+        let transform = XorTransform::new(Vec::new());
+        let mut bytes = vec![1, 2, 3];
+        transform.transform(5, &mut bytes);
+        assert_eq!(bytes, [1, 2, 3]);
+    }
+}
@@ -1,11 +1,40 @@
+use bytecodec::{Decode, Encode};
 use fibers_timeout_queue::TimeoutQueue;
-use fibers_transport::{PollRecv, PollSend, Result, Transport, UdpTransport};
-use std::collections::{HashMap, HashSet, VecDeque};
-use std::net::SocketAddr;
-use std::time::{Duration, SystemTime, UNIX_EPOCH};
-use stun_codec::{Attribute, DecodedMessage, Message, MessageClass, TransactionId};
+use fibers_transport::{self, PollRecv, PollSend, Result, Transport, UdpTransport, UdpTransporter};
+use futures::{self, Async, Future};
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::fmt;
+use std::io;
+use std::mem;
+use std::net::{IpAddr, SocketAddr};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use stun_codec::rfc5389::attributes::{Fingerprint, MessageIntegrity};
+use stun_codec::{
+    Attribute, AttributeType, DecodedMessage, Message, MessageClass, MessageDecoder, MessageEncoder,
+    TransactionId,
+};
+use trackable::error::ErrorKindExt;
 
-use super::StunTransport;
+use super::{
+    enable_icmp_error_queue, ensure_nonblocking, socket_buffer_usage, take_unreachable_peers,
+    FixedRto, RtoStrategy, SocketBufferUsage, StunTransport,
+};
+use channel::SharedObserver;
+use error::bind_error;
+use {Error, ErrorKind};
+
+/// Local port to which a UDP socket is bound.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum UdpBindPort {
+    /// An ephemeral port chosen by the OS.
+    Ephemeral,
+
+    /// The specified port.
+    ///
+    /// Binding fails if the port is already in use.
+    /// The port number must not be `0` (use `Ephemeral` instead).
+    Specific(u16),
+}
 
 /// [`StunUdpTransporter`] builder.
 ///
@@ -14,6 +43,7 @@ use super::StunTransport;
 pub struct StunUdpTransporterBuilder {
     rto: Duration,
     rto_cache_duration: Duration,
+    rto_cache_capacity: usize,
     min_transaction_interval: Duration,
     max_outstanding_transactions: usize,
 }
@@ -46,6 +76,9 @@ impl StunUdpTransporterBuilder {
     /// [RFC 5389 -- 7.2.1. Sending over UDP]: https://tools.ietf.org/html/rfc5389#section-7.2.1
     pub const DEFAULT_RTO_CACHE_DURATION_MS: u64 = 10 * 60 * 1000;
 
+    /// The default maximum number of the peers of which RTOs are cached.
+    pub const DEFAULT_RTO_CACHE_CAPACITY: usize = 10_000;
+
     /// The default max concurrent transactions by a client to a server.
     ///
     /// > At any time, a client MAY have multiple outstanding STUN requests
@@ -97,6 +130,17 @@ impl StunUdpTransporterBuilder {
         self
     }
 
+    /// Sets the maximum number of the peers of which RTOs are cached by the resulting instance.
+    ///
+    /// If the limit is reached, the least recently cached RTO is discarded.
+    /// If `0` is specified, RTOs are not cached.
+    ///
+    /// The default value is `DEFAULT_RTO_CACHE_CAPACITY`.
+    pub fn rto_cache_capacity(&mut self, capacity: usize) -> &mut Self {
+        self.rto_cache_capacity = capacity;
+        self
+    }
+
     /// Sets the minimum interval of the consecutive request/response transactions of
     /// the resulting instance.
     ///
@@ -115,6 +159,10 @@ impl StunUdpTransporterBuilder {
     }
 
     /// Makes a new `StunUdpTransporter` instance with the given settings.
+    ///
+    /// Note that the resulting instance does not detect unreachable peers
+    /// unless `StunUdpTransporter::detect_unreachable_peers` is called
+    /// (`bind` makes an instance that detects them).
     pub fn finish<A, T>(&self, inner: T) -> StunUdpTransporter<A, T>
     where
         A: Attribute,
@@ -126,17 +174,80 @@ impl StunUdpTransporterBuilder {
             peers: HashMap::new(),
             rto: self.rto,
             rto_cache_duration: self.rto_cache_duration,
+            rto_cache: RtoCache::new(self.rto_cache_capacity),
             min_transaction_interval: self.min_transaction_interval,
             max_outstanding_transactions: self.max_outstanding_transactions,
+            observer: None,
+            unflushed_requests: Vec::new(),
+            unreachable_peers: VecDeque::new(),
+            recover_recv: None,
+            draining: VecDeque::new(),
+            attempt_stamper: None,
+            initial_rtos: HashMap::new(),
+            rto_strategy: Box::new(FixedRto),
         };
         StunUdpTransporter { inner }
     }
 }
+impl StunUdpTransporterBuilder {
+    /// Binds a UDP socket to the given local IP address and port,
+    /// and makes a new `StunUdpTransporter` instance that uses the socket.
+    ///
+    /// A specific port is useful for keeping NAT bindings consistent across restarts.
+    /// The address to which the socket has actually been bound can be retrieved via
+    /// `StunUdpTransporter::local_addr` method.
+    ///
+    /// Note that `SO_REUSEADDR` is not set on the socket:
+    /// on some platforms it would allow multiple UDP sockets to share the same port,
+    /// which defeats the purpose of binding to a specific port.
+    ///
+    /// # Errors
+    ///
+    /// If `UdpBindPort::Specific(0)` is given, the returned future will fail with an
+    /// `ErrorKind::InvalidInput` error.
+    /// If the port is already in use, the returned future will fail with an `ErrorKind::AddrInUse` error.
+    /// If the socket cannot be put into nonblocking mode, the returned future will fail.
+    pub fn bind<A>(
+        &self,
+        ip: IpAddr,
+        port: UdpBindPort,
+    ) -> impl Future<
+        Item = StunUdpTransporter<A, UdpTransporter<MessageEncoder<A>, MessageDecoder<A>>>,
+        Error = Error,
+    >
+    where
+        A: Attribute,
+    {
+        let builder = self.clone();
+        let addr: ::std::result::Result<_, Error> = match port {
+            UdpBindPort::Ephemeral => Ok(SocketAddr::new(ip, 0)),
+            UdpBindPort::Specific(0) => {
+                let e = ErrorKind::InvalidInput
+                    .cause("Port number 0 is not allowed (use `UdpBindPort::Ephemeral` instead)");
+                Err(track!(e).into())
+            }
+            UdpBindPort::Specific(port) => Ok(SocketAddr::new(ip, port)),
+        };
+        futures::future::result(addr)
+            .and_then(|addr| {
+                UdpTransporter::bind(addr).map_err(move |e| track!(bind_error(e, addr)))
+            })
+            .and_then(move |transporter| {
+                track!(transporter.socket_ref().with_inner(ensure_nonblocking))?;
+                let mut transporter = builder.finish(transporter);
+                track!(transporter
+                    .detect_unreachable_peers()
+                    .map_err(Error::from))?;
+                Ok(transporter)
+            })
+    }
+}
 impl Default for StunUdpTransporterBuilder {
     fn default() -> Self {
         StunUdpTransporterBuilder {
             rto: Duration::from_millis(Self::DEFAULT_RTO_MS),
             rto_cache_duration: Duration::from_millis(Self::DEFAULT_RTO_CACHE_DURATION_MS),
+            rto_cache_capacity: Self::DEFAULT_RTO_CACHE_CAPACITY,
             min_transaction_interval: Duration::from_millis(
                 Self::DEFAULT_MIN_TRANSACTION_INTERVAL_MS,
             ),
@@ -146,6 +257,12 @@ impl Default for StunUdpTransporterBuilder {
 }
 
 /// UDP transport layer that can be used for STUN.
+///
+/// Outgoing messages are never dropped because of a full socket send buffer:
+/// they are kept in the queue of the inner transporter (e.g., `fibers_transport::UdpTransporter`
+/// waits for the socket to become writable when a send fails with `EWOULDBLOCK`),
+/// and `poll_send` returns `Async::NotReady` until all of them have been written.
+/// The transactions of such messages are not failed in the meantime.
 #[derive(Debug)]
 pub struct StunUdpTransporter<A, T> {
     inner: RetransmitTransporter<A, T>,
@@ -158,10 +275,83 @@ where
     /// Makes a new `StunUdpTransporter` instance.
     ///
     /// This is equivalent to `StunUdpTransporterBuilder::new().finish(inner)`.
+    /// See `detect_unreachable_peers` for detecting the peers that are unreachable.
     pub fn new(inner: T) -> Self {
         StunUdpTransporterBuilder::new().finish(inner)
     }
 
+    /// Makes the transporter stamp the transmission attempt number on every outgoing request.
+    ///
+    /// **This is a non-standard extension intended only for debugging.**
+    ///
+    /// If `Some(stamp)` is specified, the attribute returned by `stamp(attempt)` is appended to
+    /// each transmission of a request, where `attempt` is `1` for the initial transmission and
+    /// is incremented by one at each retransmission.
+    /// Combined with a server that echoes the attribute back (see `UdpServer::echo_attribute`),
+    /// packet captures will show which transmission elicited the response.
+    ///
+    /// The attribute is appended after all the other attributes of the request.
+    /// Requests that have a `MESSAGE-INTEGRITY` or `FINGERPRINT` attribute are sent without
+    /// the attribute, because those attributes must be the last ones
+    /// (peers ignore the attributes following them),
+    /// and inserting the attribute before them would invalidate them.
+    /// It should have a comprehension-optional type (i.e., `0x8000`-`0xFFFF`) so that
+    /// peers that do not understand it simply ignore it.
+    ///
+    /// The default value is `None`.
+    pub fn set_attempt_stamper(&mut self, stamp: Option<fn(u32) -> A>) {
+        self.inner.attempt_stamper = stamp;
+    }
+
+    /// Returns the current RTO estimate for the given peer.
+    ///
+    /// If the RTO strategy of the transporter has its own estimate (e.g., `AdaptiveRto`),
+    /// that estimate is returned.
+    ///
+    /// Otherwise, the estimate of the fixed schedule is returned.
+    /// It is updated as transactions with the peer proceed:
+    /// if a transaction needs retransmissions, the largest RTO used for them is cached
+    /// for `rto_cache_duration` (regardless of whether the transaction has finished)
+    /// and used as the initial RTO of the subsequent transactions.
+    /// Once the cache expires (or is evicted, see `StunUdpTransporterBuilder::rto_cache_capacity`),
+    /// the estimate goes back to the initial RTO of the peer.
+    ///
+    /// If there is neither a cached RTO, an ongoing transaction with the peer nor
+    /// an initial RTO set by `set_initial_rto_for`, this will return `None`.
+    pub fn rto_for(&self, peer: SocketAddr) -> Option<Duration> {
+        let inner = &self.inner;
+        inner
+            .rto_strategy
+            .current_rto(peer)
+            .or_else(|| inner.rto_cache.get(peer))
+            .or_else(|| inner.initial_rtos.get(&peer).cloned())
+            .or_else(|| inner.peers.get(&peer).map(|_| inner.rto))
+    }
+
+    /// Sets the strategy for computing the RTOs of the requests sent by the transporter.
+    ///
+    /// The default strategy is `FixedRto` (i.e., the fixed schedule described in RFC 5389).
+    /// Use `AdaptiveRto` to adjust the RTOs to the observed round-trip time and loss of each peer.
+    pub fn set_rto_strategy<S: RtoStrategy>(&mut self, strategy: S) {
+        self.inner.rto_strategy = Box::new(strategy);
+    }
+
+    /// Sets the initial RTO for the given peer.
+    ///
+    /// This is useful for reusing the RTO learned in a previous session (e.g., via `rto_for`)
+    /// to avoid over-retransmission at a cold start.
+    /// The value takes effect from the next transaction with the peer that starts
+    /// when there is no ongoing transaction with the peer,
+    /// and it overrides the RTO specified by `StunUdpTransporterBuilder::rto` for the peer.
+    pub fn set_initial_rto_for(&mut self, peer: SocketAddr, rto: Duration) {
+        self.inner.initial_rtos.insert(peer, rto);
+    }
+
+    /// Returns the address to which the inner transporter is bound.
+    pub fn local_addr(&self) -> SocketAddr {
+        self.inner.inner.local_addr()
+    }
+
     /// Returns a reference to the inner transporter.
     pub fn inner_ref(&self) -> &T {
         &self.inner.inner
@@ -172,6 +362,103 @@ where
         &mut self.inner.inner
     }
 }
+impl<A, E, D> StunUdpTransporter<A, UdpTransporter<E, D>>
+where
+    A: Attribute,
+    E: Encode<Item = Message<A>>,
+    D: Decode<Item = DecodedMessage<A>>,
+{
+    /// Sends the given bytes as a single datagram to `peer` from the socket of the transporter.
+    ///
+    /// This is a low-level escape hatch for sending non-STUN datagrams (e.g., NAT hole-punching probes).
+    /// The datagram is written to the socket immediately, so it may overtake the STUN messages
+    /// queued in the transporter.
+    ///
+    /// Note that the responses to such datagrams will not be matched by `Channel`:
+    /// they are delivered as invalid messages (or dropped) and must be handled out-of-band.
+    ///
+    /// # Errors
+    ///
+    /// If the socket buffer is full, this will return an `ErrorKind::IoError` error
+    /// (its cause is an `io::ErrorKind::WouldBlock` error) and the datagram will not be sent.
+    pub fn send_raw(&self, peer: SocketAddr, bytes: &[u8]) -> Result<()> {
+        let size = track!(
+            self.inner
+                .inner
+                .socket_ref()
+                .with_inner(|s| s.send_to(bytes, &peer))
+                .map_err(fibers_transport::Error::from)
+        )?;
+        track_assert_eq!(size, bytes.len(), fibers_transport::ErrorKind::Other);
+        Ok(())
+    }
+
+    /// Makes the transporter detect the peers that are unreachable (i.e., not listening on the port)
+    /// by ICMP port-unreachable messages.
+    ///
+    /// Once this is called, the socket errors caused by ICMP messages no longer fail `poll_recv`
+    /// (without this, `fibers_transport::UdpTransporter` cannot receive any more datagrams
+    /// after such an error).
+    /// Instead, the transporter keeps receiving datagrams, and the outstanding transactions
+    /// with an unreachable peer are failed with an `ErrorKind::ConnectionRefused` error by `Channel`
+    /// (see `StunTransport::take_unreachable_peer`). The transactions with the other peers
+    /// are not affected.
+    ///
+    /// The unreachable peers can be determined only on Linux,
+    /// where the kernel reports the destination address of the datagram that caused the error
+    /// (`IP_RECVERR` is enabled on the socket for that purpose).
+    /// On the other platforms, the errors are just ignored,
+    /// and the transactions with unreachable peers time out as usual.
+    ///
+    /// On recovering from an error, the inner transporter is replaced with a new one
+    /// that shares the socket.
+    /// The messages that are still queued in the old one (i.e., the ones waiting for
+    /// the socket send buffer to become available) are written by `poll_send`
+    /// before the ones queued in the new one, so no outgoing message is lost.
+    ///
+    /// `StunUdpTransporterBuilder::bind` calls this method,
+    /// whereas the instances made by `StunUdpTransporter::new` and
+    /// `StunUdpTransporterBuilder::finish` do not detect unreachable peers until it is called.
+    pub fn detect_unreachable_peers(&mut self) -> Result<()>
+    where
+        E: Default,
+        D: Default,
+    {
+        let socket = self.inner.inner.socket_ref();
+        let local_addr = self.inner.inner.local_addr();
+        track!(socket
+            .with_inner(|s| enable_icmp_error_queue(s, local_addr))
+            .map_err(fibers_transport::Error::from))?;
+        self.inner.recover_recv = Some(recover_from_icmp_error);
+        Ok(())
+    }
+
+    /// Returns the current usage of the receive buffer of the socket.
+    ///
+    /// This is a best-effort measurement intended for tuning the buffer sizes;
+    /// if the platform does not report the usage (currently, it is reported only on Linux 4.6
+    /// or later), this will return `None`.
+    pub fn recv_buffer_usage(&self) -> Option<SocketBufferUsage> {
+        self.buffer_usage().map(|(recv, _)| recv)
+    }
+
+    /// Returns the current usage of the send buffer of the socket.
+    ///
+    /// See `recv_buffer_usage` for the availability of the usage.
+    ///
+    /// Note that the messages queued in the transporter itself
+    /// (i.e., the ones that have not been written to the socket yet) are not counted.
+    pub fn send_buffer_usage(&self) -> Option<SocketBufferUsage> {
+        self.buffer_usage().map(|(_, send)| send)
+    }
+
+    fn buffer_usage(&self) -> Option<(SocketBufferUsage, SocketBufferUsage)> {
+        self.inner
+            .inner
+            .socket_ref()
+            .with_inner(socket_buffer_usage)
+    }
+}
 impl<A, T> Transport for StunUdpTransporter<A, T>
 where
     A: Attribute,
@@ -205,20 +492,66 @@ where
     ) -> Result<()> {
         track!(self.inner.finish_transaction(peer, transaction_id))
     }
+
+    fn set_observer(&mut self, observer: SharedObserver<SocketAddr>) {
+        self.inner.observer = Some(observer);
+    }
+
+    fn notifies_sent_requests(&self) -> bool {
+        true
+    }
+
+    fn take_unreachable_peer(&mut self) -> Option<SocketAddr> {
+        self.inner.unreachable_peers.pop_front()
+    }
 }
 
+/// Takes the peers reported as unreachable after `poll_recv` of `T` fails,
+/// and makes a new `T` that receives datagrams from the same socket.
+type RecoverRecv<T> = fn(&T) -> Result<(T, Vec<SocketAddr>)>;
+
 /// An implementation of [`StunTransport`] that retransmits request messages for improving reliability.
 ///
 /// [`StunTransport`]: ./trait.StunTransport.html
-#[derive(Debug)]
 struct RetransmitTransporter<A, T> {
     inner: T,
     timeout_queue: TimeoutQueue<TimeoutEntry<A>>,
     peers: HashMap<SocketAddr, PeerState<A>>,
     rto: Duration,
     rto_cache_duration: Duration,
+    rto_cache: RtoCache,
     min_transaction_interval: Duration,
     max_outstanding_transactions: usize,
+    observer: Option<SharedObserver<SocketAddr>>,
+    unflushed_requests: Vec<(SocketAddr, TransactionId)>,
+    unreachable_peers: VecDeque<SocketAddr>,
+    recover_recv: Option<RecoverRecv<T>>,
+    draining: VecDeque<T>,
+    attempt_stamper: Option<fn(u32) -> A>,
+    initial_rtos: HashMap<SocketAddr, Duration>,
+    rto_strategy: Box<dyn RtoStrategy>,
+}
+impl<A: fmt::Debug, T: fmt::Debug> fmt::Debug for RetransmitTransporter<A, T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("RetransmitTransporter")
+            .field("inner", &self.inner)
+            .field("timeout_queue", &self.timeout_queue)
+            .field("peers", &self.peers)
+            .field("rto", &self.rto)
+            .field("rto_cache_duration", &self.rto_cache_duration)
+            .field("rto_cache", &self.rto_cache)
+            .field("min_transaction_interval", &self.min_transaction_interval)
+            .field(
+                "max_outstanding_transactions",
+                &self.max_outstanding_transactions,
+            ).field("unflushed_requests", &self.unflushed_requests)
+            .field("unreachable_peers", &self.unreachable_peers)
+            .field("recover_recv", &self.recover_recv.is_some())
+            .field("draining", &self.draining.len())
+            .field("attempt_stamper", &self.attempt_stamper.is_some())
+            .field("initial_rtos", &self.initial_rtos)
+            .finish()
+    }
 }
 impl<A, T> RetransmitTransporter<A, T>
 where
@@ -233,6 +566,29 @@ where
             .and_then(|d| self.min_transaction_interval.checked_sub(d))
     }
 
+    fn initial_rto(&self, peer: SocketAddr) -> Duration {
+        self.initial_rtos.get(&peer).cloned().unwrap_or(self.rto)
+    }
+
+    fn cached_rto(&self, peer: SocketAddr) -> Duration {
+        self.rto_cache
+            .get(peer)
+            .unwrap_or_else(|| self.initial_rto(peer))
+    }
+
+    fn cache_rto(&mut self, peer: SocketAddr, rto: Duration) {
+        if self.cached_rto(peer) < rto {
+            self.rto_cache.insert(peer, rto);
+            self.timeout_queue.push(
+                TimeoutEntry::ExpireRtoCache {
+                    peer,
+                    cached_rto: rto,
+                },
+                self.rto_cache_duration,
+            );
+        }
+    }
+
     fn peer_mut(&mut self, peer: SocketAddr) -> &mut PeerState<A> {
         self.peers.get_mut(&peer).expect("never fails")
     }
@@ -245,7 +601,7 @@ where
         first: bool,
     ) -> Result<()> {
         if !self.peers.contains_key(&peer) {
-            self.peers.insert(peer, PeerState::new(peer, self.rto));
+            self.peers.insert(peer, PeerState::new(peer));
         }
 
         if self.peers[&peer].waiting {
@@ -258,8 +614,18 @@ where
         } else if self.peers[&peer].transactions.len() >= self.max_outstanding_transactions {
             self.peer_mut(peer).pending(request, first);
         } else {
-            track!(self.inner.start_send(peer, request.clone()))?;
-            let timeout = self.peer_mut(peer).start_transaction(request);
+            let stamped = self.stamp_attempt(request.clone(), 1);
+            track!(self.send_to_inner(peer, stamped))?;
+            if self.observer.is_some() {
+                self.unflushed_requests
+                    .push((peer, request.transaction_id()));
+            }
+            let cached_rto = self.cached_rto(peer);
+            let rto = self.rto_strategy.initial_rto(peer, cached_rto);
+            let next_rto = self.rto_strategy.next_rto(peer, rto);
+            let timeout = self
+                .peer_mut(peer)
+                .start_transaction(request, rto, next_rto);
             self.timeout_queue.push(timeout.0, timeout.1);
         }
         Ok(())
@@ -291,21 +657,67 @@ where
         Ok(())
     }
 
+    fn handle_response(&mut self, peer: SocketAddr, message: &Message<A>) {
+        match message.class() {
+            MessageClass::SuccessResponse | MessageClass::ErrorResponse => {}
+            _ => return,
+        }
+        let transaction_id = message.transaction_id();
+        let rtt = match self.peers.get(&peer) {
+            Some(p) if p.transactions.contains(&transaction_id) => {
+                p.sent_at.get(&transaction_id).map(|t| t.elapsed())
+            }
+            _ => return,
+        };
+        self.rto_strategy.on_response(peer, rtt);
+    }
+
+    fn send_to_inner(&mut self, peer: SocketAddr, message: Message<A>) -> Result<()> {
+        track!(self.inner.start_send(peer, message))
+    }
+
+    /// Writes the messages queued in the transporters replaced on recovering from ICMP errors,
+    /// and then the ones queued in the current transporter, so that they are written in order.
+    fn poll_send_inner(&mut self) -> PollSend {
+        while let Some(ready) = self.draining.front_mut().map(|t| t.poll_send()) {
+            if track!(ready)?.is_not_ready() {
+                return Ok(Async::NotReady);
+            }
+            self.draining.pop_front();
+        }
+        track!(self.inner.poll_send())
+    }
+
+    fn stamp_attempt(&self, mut request: Message<A>, attempt: u32) -> Message<A> {
+        if let Some(stamp) = self.attempt_stamper {
+            if !has_integrity_or_fingerprint(&request) {
+                request.add_attribute(stamp(attempt));
+            }
+        }
+        request
+    }
+
     fn handle_retransmit(
         &mut self,
         peer: SocketAddr,
         request: Message<A>,
         rto: Duration,
+        attempt: u32,
     ) -> Result<()> {
-        if let Some(p) = self.peers.get_mut(&peer) {
-            if let Some(request) = p.retransmit(
-                request,
-                rto,
-                self.rto_cache_duration,
-                &mut self.timeout_queue,
-            ) {
-                track!(self.inner.start_send(peer, request))?;
+        let next_rto = self.rto_strategy.next_rto(peer, rto);
+        let request = if let Some(p) = self.peers.get_mut(&peer) {
+            p.retransmit(request, rto, next_rto, attempt, &mut self.timeout_queue)
+        } else {
+            None
+        };
+        if let Some(request) = request {
+            self.cache_rto(peer, rto);
+            self.rto_strategy.on_retransmit(peer);
+            if let Some(ref o) = self.observer {
+                o.on_retransmit(&peer, request.transaction_id());
             }
+            let request = self.stamp_attempt(request, attempt);
+            track!(self.inner.start_send(peer, request))?;
         }
         Ok(())
     }
@@ -323,7 +735,7 @@ where
         if item.class() == MessageClass::Request {
             track!(self.start_transaction(peer, item, true))
         } else {
-            track!(self.inner.start_send(peer, item))
+            track!(self.send_to_inner(peer, item))
         }
     }
 
@@ -334,14 +746,13 @@ where
                     peer,
                     request,
                     next_rto,
+                    attempt,
                 } => {
-                    track!(self.handle_retransmit(peer, request, next_rto))?;
+                    track!(self.handle_retransmit(peer, request, next_rto, attempt))?;
                 }
                 TimeoutEntry::ExpireRtoCache { peer, cached_rto } => {
-                    if let Some(p) = self.peers.get_mut(&peer) {
-                        if p.cached_rto == cached_rto {
-                            p.cached_rto = self.rto;
-                        }
+                    if self.rto_cache.get(peer) == Some(cached_rto) {
+                        self.rto_cache.remove(peer);
                     }
                 }
                 TimeoutEntry::AllowNextRequest { peer } => {
@@ -351,11 +762,36 @@ where
             }
         }
 
-        track!(self.inner.poll_send())
+        let ready = track!(self.poll_send_inner())?;
+        if ready.is_ready() {
+            if let Some(ref o) = self.observer {
+                for (peer, transaction_id) in self.unflushed_requests.drain(..) {
+                    o.on_send(&peer, transaction_id);
+                }
+            }
+        }
+        Ok(ready)
     }
 
     fn poll_recv(&mut self) -> PollRecv<(Self::PeerAddr, Self::RecvItem)> {
-        track!(self.inner.poll_recv())
+        loop {
+            let result = match (self.inner.poll_recv(), self.recover_recv) {
+                (Err(ref e), Some(recover)) if is_icmp_error(e) => {
+                    let (inner, peers) = track!(recover(&self.inner))?;
+                    self.unreachable_peers.extend(peers);
+
+                    // The old transporter keeps writing its queued messages (see `poll_send`)
+                    let old = mem::replace(&mut self.inner, inner);
+                    self.draining.push_back(old);
+                    continue;
+                }
+                (result, _) => track!(result),
+            };
+            if let Ok(Async::Ready(Some((peer, Ok(ref message))))) = result {
+                self.handle_response(peer, message);
+            }
+            return result;
+        }
     }
 }
 impl<A, T> StunTransport<A> for RetransmitTransporter<A, T>
@@ -373,6 +809,10 @@ where
         }
         track!(self.handle_pending_request(peer.clone()))
     }
+
+    fn notifies_sent_requests(&self) -> bool {
+        true
+    }
 }
 
 #[derive(Debug)]
@@ -381,6 +821,7 @@ enum TimeoutEntry<A> {
         peer: SocketAddr,
         request: Message<A>,
         next_rto: Duration,
+        attempt: u32,
     },
     ExpireRtoCache {
         peer: SocketAddr,
@@ -395,22 +836,20 @@ enum TimeoutEntry<A> {
 struct PeerState<A> {
     peer: SocketAddr,
     transactions: HashSet<TransactionId>,
+    sent_at: HashMap<TransactionId, Instant>,
     pending_requests: VecDeque<Message<A>>,
     waiting: bool,
     last_transaction_start_time: SystemTime,
-    current_rto: Duration,
-    cached_rto: Duration,
 }
 impl<A: Attribute> PeerState<A> {
-    fn new(peer: SocketAddr, rto: Duration) -> Self {
+    fn new(peer: SocketAddr) -> Self {
         PeerState {
             peer,
             transactions: HashSet::new(),
+            sent_at: HashMap::new(),
             pending_requests: VecDeque::new(),
             waiting: false,
             last_transaction_start_time: UNIX_EPOCH,
-            current_rto: rto,
-            cached_rto: rto,
         }
     }
 
@@ -439,46 +878,343 @@ impl<A: Attribute> PeerState<A> {
         &mut self,
         request: Message<A>,
         rto: Duration,
-        rto_cache_duration: Duration,
+        next_rto: Duration,
+        attempt: u32,
         queue: &mut TimeoutQueue<TimeoutEntry<A>>,
     ) -> Option<Message<A>> {
         if self.transactions.contains(&request.transaction_id()) {
+            // Karn's algorithm: the RTT of a retransmitted request is ambiguous
+            self.sent_at.remove(&request.transaction_id());
             queue.push(
                 TimeoutEntry::Retransmit {
                     peer: self.peer,
                     request: request.clone(),
-                    next_rto: rto * 2,
+                    next_rto,
+                    attempt: attempt + 1,
                 },
                 rto,
             );
-            if self.cached_rto < rto {
-                self.cached_rto = rto;
-                queue.push(
-                    TimeoutEntry::ExpireRtoCache {
-                        peer: self.peer,
-                        cached_rto: rto,
-                    },
-                    rto_cache_duration,
-                );
-            }
             Some(request)
         } else {
             None
         }
     }
 
-    fn start_transaction(&mut self, request: Message<A>) -> (TimeoutEntry<A>, Duration) {
+    fn start_transaction(
+        &mut self,
+        request: Message<A>,
+        rto: Duration,
+        next_rto: Duration,
+    ) -> (TimeoutEntry<A>, Duration) {
         self.transactions.insert(request.transaction_id());
+        self.sent_at.insert(request.transaction_id(), Instant::now());
         self.last_transaction_start_time = SystemTime::now();
         let entry = TimeoutEntry::Retransmit {
             peer: self.peer,
             request,
-            next_rto: self.cached_rto * 2,
+            next_rto,
+            attempt: 2,
         };
-        (entry, self.cached_rto)
+        (entry, rto)
     }
 
     fn finish_transaction(&mut self, transaction_id: TransactionId) {
         self.transactions.remove(&transaction_id);
+        self.sent_at.remove(&transaction_id);
+    }
+}
+
+/// The RTOs cached for the peers (see `StunUdpTransporter::rto_for`).
+///
+/// This is kept apart from `PeerState`, which is removed once the transactions with the peer
+/// have finished, so that the subsequent transactions can start from the cached RTO.
+/// The least recently cached entry is evicted if the number of the entries exceeds `capacity`.
+#[derive(Debug)]
+struct RtoCache {
+    capacity: usize,
+    entries: HashMap<SocketAddr, (Duration, u64)>,
+    lru: BTreeMap<u64, SocketAddr>,
+    next_seqno: u64,
+}
+impl RtoCache {
+    fn new(capacity: usize) -> Self {
+        RtoCache {
+            capacity,
+            entries: HashMap::new(),
+            lru: BTreeMap::new(),
+            next_seqno: 0,
+        }
+    }
+
+    fn get(&self, peer: SocketAddr) -> Option<Duration> {
+        self.entries.get(&peer).map(|e| e.0)
+    }
+
+    fn insert(&mut self, peer: SocketAddr, rto: Duration) {
+        if self.capacity == 0 {
+            return;
+        }
+        self.remove(peer);
+        while self.entries.len() >= self.capacity {
+            let seqno = *self.lru.keys().next().expect("never fails");
+            let oldest = self.lru.remove(&seqno).expect("never fails");
+            self.entries.remove(&oldest);
+        }
+        let seqno = self.next_seqno;
+        self.next_seqno += 1;
+        self.entries.insert(peer, (rto, seqno));
+        self.lru.insert(seqno, peer);
+    }
+
+    fn remove(&mut self, peer: SocketAddr) {
+        if let Some((_, seqno)) = self.entries.remove(&peer) {
+            self.lru.remove(&seqno);
+        }
+    }
+}
+
+/// Returns `true` if the given error is caused by an ICMP message
+/// (e.g., port-unreachable) reported via the socket.
+fn is_icmp_error(e: &fibers_transport::Error) -> bool {
+    e.concrete_cause::<io::Error>()
+        .map_or(false, |e| match e.kind() {
+            io::ErrorKind::ConnectionRefused | io::ErrorKind::ConnectionReset => true,
+            _ => is_unreachable_os_error(e),
+        })
+}
+
+#[cfg(unix)]
+fn is_unreachable_os_error(e: &io::Error) -> bool {
+    matches!(
+        e.raw_os_error(),
+        Some(libc::EHOSTUNREACH) | Some(libc::ENETUNREACH)
+    )
+}
+
+#[cfg(not(unix))]
+fn is_unreachable_os_error(_e: &io::Error) -> bool {
+    false
+}
+
+/// Takes the peers reported by the ICMP errors queued in the socket,
+/// and makes a new transporter that restarts receiving datagrams from the socket.
+fn recover_from_icmp_error<E, D>(
+    transporter: &UdpTransporter<E, D>,
+) -> Result<(UdpTransporter<E, D>, Vec<SocketAddr>)>
+where
+    E: Encode + Default,
+    D: Decode + Default,
+{
+    let socket = transporter.socket_ref().clone();
+    let peers = track!(socket
+        .with_inner(take_unreachable_peers)
+        .map_err(fibers_transport::Error::from))?;
+    let transporter = track!(UdpTransporter::from_socket(socket))?;
+    Ok((transporter, peers))
+}
+
+fn has_integrity_or_fingerprint<A: Attribute>(message: &Message<A>) -> bool {
+    let is_trailer = |t: AttributeType| {
+        t.as_u16() == MessageIntegrity::CODEPOINT || t.as_u16() == Fingerprint::CODEPOINT
+    };
+    message.attributes().any(|a| is_trailer(a.get_type()))
+        || message.unknown_attributes().any(|a| is_trailer(a.get_type()))
+}
+
+#[cfg(test)]
+mod tests {
+    use fibers_global;
+    use futures;
+    use std::sync::atomic::{AtomicBool, Ordering};
+    use std::sync::{Arc, Mutex};
+    use stun_codec::rfc5389;
+
+    use super::*;
+    use message::{Indication, Request};
+
+    /// A transporter over a stub socket of which the send buffer is full until `writable` is set.
+    ///
+    /// The transporters made by `recover` share the socket with the original one.
+    #[derive(Debug, Default)]
+    struct CongestedUdpTransporter {
+        writable: Arc<AtomicBool>,
+        queue: VecDeque<(SocketAddr, Message<rfc5389::Attribute>)>,
+        sent: Arc<Mutex<Vec<(SocketAddr, Message<rfc5389::Attribute>)>>>,
+        recv_error: Option<io::ErrorKind>,
+    }
+    impl CongestedUdpTransporter {
+        fn writable() -> Self {
+            let this = Self::default();
+            this.set_writable(true);
+            this
+        }
+
+        fn set_writable(&self, writable: bool) {
+            self.writable.store(writable, Ordering::SeqCst);
+        }
+
+        fn sent(&self) -> Vec<(SocketAddr, TransactionId)> {
+            let sent = self.sent.lock().unwrap();
+            sent.iter()
+                .map(|&(p, ref m)| (p, m.transaction_id()))
+                .collect()
+        }
+
+        fn recover(&self) -> Result<(Self, Vec<SocketAddr>)> {
+            let transporter = CongestedUdpTransporter {
+                writable: Arc::clone(&self.writable),
+                sent: Arc::clone(&self.sent),
+                ..Self::default()
+            };
+            Ok((transporter, vec!["127.0.0.1:7777".parse().unwrap()]))
+        }
+    }
+    impl Transport for CongestedUdpTransporter {
+        type PeerAddr = SocketAddr;
+        type SendItem = Message<rfc5389::Attribute>;
+        type RecvItem = DecodedMessage<rfc5389::Attribute>;
+
+        fn start_send(&mut self, peer: SocketAddr, item: Self::SendItem) -> Result<()> {
+            self.queue.push_back((peer, item));
+            Ok(())
+        }
+
+        fn poll_send(&mut self) -> PollSend {
+            if !self.writable.load(Ordering::SeqCst) {
+                return Ok(Async::NotReady);
+            }
+            self.sent.lock().unwrap().extend(self.queue.drain(..));
+            Ok(Async::Ready(()))
+        }
+
+        fn poll_recv(&mut self) -> PollRecv<(SocketAddr, Self::RecvItem)> {
+            if let Some(kind) = self.recv_error.take() {
+                return Err(track!(fibers_transport::Error::from(io::Error::from(kind))));
+            }
+            Ok(Async::NotReady)
+        }
+    }
+    impl UdpTransport for CongestedUdpTransporter {
+        fn local_addr(&self) -> SocketAddr {
+            "127.0.0.1:3478".parse().unwrap()
+        }
+    }
+
+    #[test]
+    fn full_send_buffer_does_not_drop_requests() {
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(10))
+            .finish(CongestedUdpTransporter::default());
+        let peer = "127.0.0.1:9999".parse().unwrap();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        transporter
+            .start_send(peer, request.into_message())
+            .unwrap();
+
+        // The send buffer stays full over several RTOs
+        let mut transporter = Some(transporter);
+        let future =
+            futures::future::poll_fn(move || -> futures::Poll<_, fibers_transport::Error> {
+                {
+                    let transporter = transporter.as_mut().expect("never fails");
+                    assert!(track!(transporter.poll_send())?.is_not_ready());
+                    if transporter.inner_ref().queue.len() < 3 {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Ok(Async::Ready(transporter.take().expect("never fails")))
+            });
+        let mut transporter = fibers_global::execute(future).unwrap();
+
+        // The transaction is still alive, and the request and its retransmissions are kept
+        assert!(transporter.inner.peers[&peer]
+            .transactions
+            .contains(&transaction_id));
+        assert!(transporter.inner_ref().sent().is_empty());
+        assert!(transporter
+            .inner_ref()
+            .queue
+            .iter()
+            .all(|&(p, ref m)| p == peer && m.transaction_id() == transaction_id));
+
+        // ... and they are written once the send buffer becomes writable
+        let queued = transporter.inner_ref().queue.len();
+        transporter.inner_ref().set_writable(true);
+        assert!(transporter.poll_send().unwrap().is_ready());
+        let sent = transporter.inner_ref().sent();
+        assert!(sent.len() >= queued);
+        assert!(sent.iter().all(|&x| x == (peer, transaction_id)));
+    }
+
+    #[test]
+    fn cached_rto_outlives_transactions() {
+        let mut transporter = StunUdpTransporterBuilder::new()
+            .rto(Duration::from_millis(20))
+            .finish(CongestedUdpTransporter::writable());
+        let peer = "127.0.0.1:9999".parse().unwrap();
+        let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+        let transaction_id = request.transaction_id();
+        transporter
+            .start_send(peer, request.into_message())
+            .unwrap();
+
+        // Waits for the first retransmission
+        let mut transporter = Some(transporter);
+        let future =
+            futures::future::poll_fn(move || -> futures::Poll<_, fibers_transport::Error> {
+                {
+                    let transporter = transporter.as_mut().expect("never fails");
+                    track!(transporter.poll_send())?;
+                    if transporter.inner_ref().sent().len() < 2 {
+                        return Ok(Async::NotReady);
+                    }
+                }
+                Ok(Async::Ready(transporter.take().expect("never fails")))
+            });
+        let mut transporter = fibers_global::execute(future).unwrap();
+        assert_eq!(transporter.rto_for(peer), Some(Duration::from_millis(40)));
+
+        // The cached RTO remains after the transaction has finished
+        transporter
+            .finish_transaction(&peer, transaction_id)
+            .unwrap();
+        assert!(transporter.inner.peers.is_empty());
+        assert_eq!(transporter.rto_for(peer), Some(Duration::from_millis(40)));
+    }
+
+    #[test]
+    fn queued_messages_survive_icmp_recovery() {
+        let test = futures::lazy(|| -> Result<()> {
+            let mut transporter = StunUdpTransporter::new(CongestedUdpTransporter::default());
+            transporter.inner.recover_recv = Some(CongestedUdpTransporter::recover);
+            let peer0 = "127.0.0.1:8888".parse().unwrap();
+            let peer1 = "127.0.0.1:9999".parse().unwrap();
+            let request = Request::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let indication = Indication::<rfc5389::Attribute>::new(rfc5389::methods::BINDING);
+            let ids = vec![
+                (peer0, request.transaction_id()),
+                (peer1, indication.transaction_id()),
+            ];
+            track!(transporter.start_send(peer0, request.into_message()))?;
+            track!(transporter.start_send(peer1, indication.into_message()))?;
+            assert!(track!(transporter.poll_send())?.is_not_ready());
+
+            // An ICMP error of another peer is reported while the messages are queued
+            transporter.inner_mut().recv_error = Some(io::ErrorKind::ConnectionRefused);
+            assert!(track!(transporter.poll_recv())?.is_not_ready());
+            assert_eq!(
+                transporter.take_unreachable_peer(),
+                Some("127.0.0.1:7777".parse().unwrap())
+            );
+            assert!(transporter.inner_ref().queue.is_empty());
+
+            // The queued messages are still written
+            transporter.inner_ref().set_writable(true);
+            assert!(track!(transporter.poll_send())?.is_ready());
+            assert_eq!(transporter.inner_ref().sent(), ids);
+            Ok(())
+        });
+        fibers_global::execute(test).unwrap();
     }
 }